@@ -0,0 +1,66 @@
+//! Replays a session file recorded by giuroll (`record_sessions=yes`), and shows the pauses,
+//! the rollbacks and the frames where the netcode has acted differently than while recording.
+//!
+//! Usage: `cargo run --example replay-session -- <file> [--all]`; `--all` shows every frame.
+
+use netcore::{
+    record::{replay, Session},
+    ENABLE_PRINTLN,
+};
+
+fn main() {
+    let mut args = std::env::args().skip(1);
+    let Some(path) = args.next() else {
+        eprintln!("usage: replay-session <file> [--all]");
+        std::process::exit(2);
+    };
+    let all = args.any(|x| x == "--all");
+
+    let file = match std::fs::File::open(&path) {
+        Ok(x) => std::io::BufReader::new(x),
+        Err(e) => {
+            eprintln!("{}: {}", path, e);
+            std::process::exit(1);
+        }
+    };
+    let session = match Session::read(file) {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("{}: {}", path, e);
+            std::process::exit(1);
+        }
+    };
+    println!("{:?}", session.header);
+    if session.truncated {
+        println!("the session is truncated, its last event is left out");
+    }
+    ENABLE_PRINTLN.store(all, std::sync::atomic::Ordering::Relaxed);
+
+    let (mut pauses, mut rollbacks, mut diverged) = (0, 0, 0);
+    let frames = replay(&session, |frame| {
+        let notable = frame.pause.is_some() || frame.simulated > 1 || frame.diverged;
+        if all || notable {
+            println!(
+                "{:>10.3}ms id {:>6} received {:>2} simulated {:>2}{}{}",
+                frame.time as f64 / 1_000_000.0,
+                frame.id.map_or("-".to_string(), |x| x.to_string()),
+                frame.received,
+                frame.simulated,
+                frame
+                    .pause
+                    .map_or(String::new(), |x| format!(" paused: {:?}", x)),
+                if frame.diverged { " DIVERGED" } else { "" },
+            );
+        }
+        pauses += frame.pause.is_some() as usize;
+        rollbacks += (frame.simulated > 1) as usize;
+        diverged += frame.diverged as usize;
+    });
+    println!(
+        "{} frames, {} paused, {} rolled back, {} diverged",
+        frames.len(),
+        pauses,
+        rollbacks,
+        diverged
+    );
+}
@@ -0,0 +1,253 @@
+//! Authentication of the packets of giuroll with a key agreed by both peers.
+//!
+//! Each peer draws a nonce, and sends it in its 0x6b packets until it sees a packet signed with
+//! the key made from both nonces, which proves that the opponent has it. Once a nonce of the
+//! opponent is known, every packet is signed: a tag (`TAG_LEN` bytes of HMAC-SHA256) is appended
+//! as a trailing extension block. The nonces received are only candidates until a packet has a
+//! valid tag under the key of one of them, since anyone can send one; after that packet, the key
+//! is settled. Once the opponent has sent a nonce, only the packets with a valid tag are used.
+//!
+//! This only keeps out blind spoofing: the nonces are sent in clear, so whoever sees the traffic
+//! can make the key. A blind spoofer can still hold up a match against a peer which doesn't send
+//! a nonce (an older version of giuroll, never authenticated) by sending one in its place.
+
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    time::SystemTime,
+};
+
+#[cfg(test)]
+mod tests;
+
+pub const NONCE_LEN: usize = 16;
+/// bytes of HMAC-SHA256 kept in a tag
+pub const TAG_LEN: usize = 16;
+/// the extension tag of the tag; it's always the last extension block of a packet
+pub const EXT_AUTH: u8 = 0x06;
+/// the tag, with the header of its extension block
+pub const TRAILER_LEN: usize = TAG_LEN + 2;
+/// the most nonces of the opponent kept before one is verified; the first one, which the packets
+/// are signed with meanwhile, is always kept
+const MAX_CANDIDATES: usize = 8;
+
+const K: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+/// SHA-256 of the concatenation of `parts`
+pub fn sha256(parts: &[&[u8]]) -> [u8; 32] {
+    let mut h: [u32; 8] = [
+        0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab,
+        0x5be0cd19,
+    ];
+    let len: usize = parts.iter().map(|x| x.len()).sum();
+    let mut data = Vec::with_capacity(len + 72);
+    for part in parts {
+        data.extend_from_slice(part);
+    }
+    data.push(0x80);
+    while data.len() % 64 != 56 {
+        data.push(0);
+    }
+    data.extend_from_slice(&(len as u64 * 8).to_be_bytes());
+
+    for block in data.chunks(64) {
+        let mut w = [0u32; 64];
+        for (n, word) in block.chunks(4).enumerate() {
+            w[n] = u32::from_be_bytes(word.try_into().unwrap());
+        }
+        for n in 16..64 {
+            let s0 = w[n - 15].rotate_right(7) ^ w[n - 15].rotate_right(18) ^ (w[n - 15] >> 3);
+            let s1 = w[n - 2].rotate_right(17) ^ w[n - 2].rotate_right(19) ^ (w[n - 2] >> 10);
+            w[n] = w[n - 16]
+                .wrapping_add(s0)
+                .wrapping_add(w[n - 7])
+                .wrapping_add(s1);
+        }
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut hh] = h;
+        for n in 0..64 {
+            let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+            let ch = (e & f) ^ (!e & g);
+            let t1 = hh
+                .wrapping_add(s1)
+                .wrapping_add(ch)
+                .wrapping_add(K[n])
+                .wrapping_add(w[n]);
+            let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+            let maj = (a & b) ^ (a & c) ^ (b & c);
+            let t2 = s0.wrapping_add(maj);
+            hh = g;
+            g = f;
+            f = e;
+            e = d.wrapping_add(t1);
+            d = c;
+            c = b;
+            b = a;
+            a = t1.wrapping_add(t2);
+        }
+        for (x, y) in h.iter_mut().zip([a, b, c, d, e, f, g, hh]) {
+            *x = x.wrapping_add(y);
+        }
+    }
+
+    let mut out = [0; 32];
+    for (n, x) in h.iter().enumerate() {
+        out[n * 4..n * 4 + 4].copy_from_slice(&x.to_be_bytes());
+    }
+    out
+}
+
+pub fn hmac_sha256(key: &[u8], data: &[u8]) -> [u8; 32] {
+    let mut block = [0u8; 64];
+    match key.len() > 64 {
+        true => block[..32].copy_from_slice(&sha256(&[key])),
+        false => block[..key.len()].copy_from_slice(key),
+    }
+    let inner_pad = block.map(|x| x ^ 0x36);
+    let outer_pad = block.map(|x| x ^ 0x5c);
+    let inner = sha256(&[&inner_pad, data]);
+    sha256(&[&outer_pad, &inner])
+}
+
+/// a nonce drawn from the hashers of std, which are seeded by the system
+fn random_nonce() -> [u8; NONCE_LEN] {
+    let time = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |x| x.as_nanos());
+    let mut nonce = [0; NONCE_LEN];
+    for chunk in nonce.chunks_mut(8) {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u128(time);
+        chunk.copy_from_slice(&hasher.finish().to_le_bytes());
+    }
+    nonce
+}
+
+/// The key of a `Netcoder`, and what it knows about the one of the opponent
+#[derive(Clone, Debug)]
+pub struct SessionAuth {
+    pub nonce: [u8; NONCE_LEN],
+    /// the nonce of the opponent, once a packet signed with it has come
+    pub opponent_nonce: Option<[u8; NONCE_LEN]>,
+    /// SHA-256 of the nonce of p1 and the one of p2, once `opponent_nonce` is known
+    key: Option<[u8; 32]>,
+    /// the nonces received before, with the keys made from them
+    candidates: Vec<([u8; NONCE_LEN], [u8; 32])>,
+    /// a packet with a valid tag has been received, so every packet has to have one
+    pub verified: bool,
+    /// the packets dropped since their tag is missing or wrong, after the key is settled
+    pub rejected: usize,
+}
+
+impl Default for SessionAuth {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SessionAuth {
+    pub fn new() -> Self {
+        Self::with_nonce(random_nonce())
+    }
+
+    pub fn with_nonce(nonce: [u8; NONCE_LEN]) -> Self {
+        Self {
+            nonce,
+            opponent_nonce: None,
+            key: None,
+            candidates: vec![],
+            verified: false,
+            rejected: 0,
+        }
+    }
+
+    /// the nonce to be sent, until the opponent has shown that it knows it
+    pub fn nonce_to_send(&self) -> Option<[u8; NONCE_LEN]> {
+        (!self.verified).then_some(self.nonce)
+    }
+
+    /// called with the nonce of every packet of the opponent, before verifying it
+    pub fn add_opponent_nonce(&mut self, nonce: [u8; NONCE_LEN], is_p1: bool) {
+        if self.verified || self.candidates.iter().any(|x| x.0 == nonce) {
+            return;
+        }
+        if self.candidates.len() >= MAX_CANDIDATES {
+            self.candidates.remove(1);
+        }
+        let (p1, p2) = match is_p1 {
+            true => (self.nonce, nonce),
+            false => (nonce, self.nonce),
+        };
+        self.candidates
+            .push((nonce, sha256(&[b"giuroll session", &p1, &p2])));
+    }
+
+    fn tag(&self, key: &[u8; 32], data: &[u8]) -> [u8; TAG_LEN] {
+        hmac_sha256(key, data)[..TAG_LEN].try_into().unwrap()
+    }
+
+    /// appends the tag to a packet about to be sent, once a nonce of the opponent is known; with
+    /// the first one until the key is settled
+    pub fn sign(&self, data: Box<[u8]>) -> Box<[u8]> {
+        let Some(key) = self.key.as_ref().or(self.candidates.first().map(|x| &x.1)) else {
+            return data;
+        };
+        let mut data = data.into_vec();
+        let tag = self.tag(key, &data);
+        data.extend_from_slice(&[EXT_AUTH, TAG_LEN as u8]);
+        data.extend_from_slice(&tag);
+        data.into_boxed_slice()
+    }
+
+    /// checks a packet of the opponent, after its nonce has been given to `add_opponent_nonce`;
+    /// returns the length of the packet without its tag, or `None` if it has to be dropped
+    pub fn verify(&mut self, data: &[u8]) -> Option<usize> {
+        let signed = data.len() >= TRAILER_LEN
+            && data[data.len() - TRAILER_LEN..][..2] == [EXT_AUTH, TAG_LEN as u8];
+        let accepted = match signed {
+            true => {
+                let (payload, tag) = data.split_at(data.len() - TAG_LEN);
+                let payload = &payload[..payload.len() - 2];
+                match self.key.as_ref() {
+                    Some(key) => self.tag(key, payload) == tag,
+                    None => {
+                        let valid = self
+                            .candidates
+                            .iter()
+                            .find(|x| self.tag(&x.1, payload) == tag)
+                            .copied();
+                        let Some((nonce, key)) = valid else {
+                            // signed with a nonce which hasn't come (yet), or forged
+                            return None;
+                        };
+                        self.opponent_nonce = Some(nonce);
+                        self.key = Some(key);
+                        self.verified = true;
+                        self.candidates.clear();
+                        true
+                    }
+                }
+            }
+            // once the opponent has sent its nonce, its packets are only used when they are
+            // signed, as soon as it has ours; an older version never sends one
+            false if !self.verified && !self.candidates.is_empty() => return None,
+            false => !self.verified,
+        };
+        if !accepted {
+            self.rejected += 1;
+            return None;
+        }
+        Some(match signed {
+            true => data.len() - TRAILER_LEN,
+            false => data.len(),
+        })
+    }
+}
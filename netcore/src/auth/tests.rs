@@ -0,0 +1,118 @@
+use super::*;
+
+fn hex(text: &str) -> Vec<u8> {
+    (0..text.len())
+        .step_by(2)
+        .map(|x| u8::from_str_radix(&text[x..x + 2], 16).unwrap())
+        .collect()
+}
+
+#[test]
+fn sha256_vectors() {
+    assert_eq!(
+        sha256(&[b""])[..],
+        hex("e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855")
+    );
+    assert_eq!(
+        sha256(&[b"ab", b"c"])[..],
+        hex("ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad")
+    );
+    // two blocks
+    assert_eq!(
+        sha256(&[b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"])[..],
+        hex("248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1")
+    );
+}
+
+#[test]
+fn hmac_sha256_vectors() {
+    // RFC 4231, test cases 2 and 6
+    assert_eq!(
+        hmac_sha256(b"Jefe", b"what do ya want for nothing?")[..],
+        hex("5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843")
+    );
+    assert_eq!(
+        hmac_sha256(
+            &[0xaa; 131],
+            b"Test Using Larger Than Block-Size Key - Hash Key First"
+        )[..],
+        hex("60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54")
+    );
+}
+
+/// both sides after exchanging their nonces
+fn pair() -> (SessionAuth, SessionAuth) {
+    let (mut p1, mut p2) = (SessionAuth::new(), SessionAuth::new());
+    assert_ne!(p1.nonce, p2.nonce);
+    p1.add_opponent_nonce(p2.nonce, true);
+    p2.add_opponent_nonce(p1.nonce, false);
+    (p1, p2)
+}
+
+#[test]
+fn signed_packets_are_verified() {
+    let (p1, mut p2) = pair();
+    let packet: Box<[u8]> = [0x6b, 1, 1, 0, 5, 0, 0, 0].into();
+    let signed = p1.sign(packet.clone());
+    assert_eq!(signed.len(), packet.len() + TRAILER_LEN);
+    assert_eq!(p2.verify(&signed), Some(packet.len()));
+    assert!(p2.verified);
+    assert_eq!(p2.nonce_to_send(), None);
+
+    // once verified, only signed packets are accepted
+    assert_eq!(p2.verify(&packet), None);
+    let mut forged = signed.to_vec();
+    forged[4] = 6;
+    assert_eq!(p2.verify(&forged), None);
+    assert_eq!(p2.rejected, 2);
+}
+
+#[test]
+fn unsigned_packets_until_the_handshake_is_done() {
+    let (mut p1, p2) = (SessionAuth::new(), SessionAuth::new());
+    // nothing can be signed before the nonce of the opponent is known
+    let packet: Box<[u8]> = [0x6f, 3, 0, 0, 0, 0].into();
+    assert_eq!(p2.sign(packet.clone()), packet);
+    assert_eq!(p1.verify(&packet), Some(packet.len()));
+
+    // the nonces are only candidates until a packet signed with one comes
+    p1.add_opponent_nonce(p2.nonce, true);
+    p1.add_opponent_nonce([0; NONCE_LEN], true);
+    assert_eq!(p1.opponent_nonce, None);
+    // and the opponent has sent one, so its packets are only used once they are signed
+    assert_eq!(p1.verify(&packet), None);
+    assert_eq!(p1.rejected, 0);
+}
+
+#[test]
+fn a_spoofed_nonce_does_not_settle_the_key() {
+    let (mut p1, mut p2) = (SessionAuth::new(), SessionAuth::new());
+    // a blind spoofer is faster than p2
+    p1.add_opponent_nonce([7; NONCE_LEN], true);
+    p1.add_opponent_nonce(p2.nonce, true);
+    p2.add_opponent_nonce(p1.nonce, false);
+
+    // p1 signs with the wrong nonce, which p2 can't check yet
+    let packet: Box<[u8]> = [0x6b, 1, 1, 0, 5, 0, 0, 0].into();
+    assert_eq!(p2.verify(&p1.sign(packet.clone())), None);
+    assert!(!p2.verified);
+
+    // the packets of p2 are signed with the right one, which settles the key of p1
+    assert_eq!(p1.verify(&p2.sign(packet.clone())), Some(packet.len()));
+    assert!(p1.verified);
+    assert_eq!(p1.opponent_nonce, Some(p2.nonce));
+    assert_eq!(p2.verify(&p1.sign(packet.clone())), Some(packet.len()));
+    assert!(p2.verified);
+    assert_eq!(p1.rejected + p2.rejected, 0);
+}
+
+#[test]
+fn wrong_key_is_rejected() {
+    let (mut p1, mut p2) = pair();
+    p2.verify(&p1.sign([0x6b, 1, 1, 0].into())).unwrap();
+    p1.verify(&p2.sign([0x6b, 2, 1, 0].into())).unwrap();
+    let (other, _) = pair();
+    let signed = other.sign([0x6b, 1, 1, 0].into());
+    assert_eq!(p2.verify(&signed), None);
+    assert_eq!(p2.rejected, 1);
+}
@@ -0,0 +1,108 @@
+use std::{collections::VecDeque, time::Duration};
+
+/// the most input delay which can be chosen
+pub const MAX_DELAY: usize = 9;
+
+/// how many of the latest measurements are used
+const WINDOW: usize = 180;
+/// no delay is proposed before this many round trips have been measured
+const MIN_SAMPLES: usize = 40;
+/// how far (in frames) the ideal delay has to go past the current one before it changes, so
+/// that a latency close to the border between two delays doesn't make the delay go back and
+/// forth
+const HYSTERESIS: f64 = 0.35;
+
+/// Chooses the input delay from the latency and the rollbacks measured during the whole match.
+///
+/// It only proposes a delay; `Netcoder::apply_agreed_delay` applies it at the safe points
+/// chosen by the game, like the start of a round.
+pub struct AutoDelay {
+    /// the rollback (in frames) the delay is chosen for; a higher one means less delay
+    pub target_rollback: i8,
+    /// the time of a frame of the game, 1/62 s with `enable_f62`
+    frame_time: Duration,
+    rtts: VecDeque<Duration>,
+    /// the latencies (in frames) told by the rollbacks and the delay in use back then
+    rollback_latencies: VecDeque<usize>,
+    proposal: Option<usize>,
+    /// the last delay decided by `Netcoder::apply_agreed_delay`
+    pub applied: Option<usize>,
+}
+
+impl AutoDelay {
+    /// `fps` is the frames per second of the game, 60 or 62
+    pub fn new(target_rollback: i8, fps: u8) -> Self {
+        Self {
+            target_rollback,
+            frame_time: Duration::from_secs(1) / fps as u32,
+            rtts: VecDeque::new(),
+            rollback_latencies: VecDeque::new(),
+            proposal: None,
+            applied: None,
+        }
+    }
+
+    /// the time from sending an input to knowing that the opponent got it
+    pub fn add_rtt(&mut self, rtt: Duration) {
+        if self.rtts.len() >= WINDOW {
+            self.rtts.pop_front();
+        }
+        self.rtts.push_back(rtt);
+    }
+
+    /// the frames which are still guessed with the input delay `delay`, measured once per frame
+    pub fn add_rollback(&mut self, frames: usize, delay: usize) {
+        if self.rollback_latencies.len() >= WINDOW {
+            self.rollback_latencies.pop_front();
+        }
+        // the guessed frames include the frame being simulated; without rollbacks, they only
+        // tell that the latency isn't above the delay
+        self.rollback_latencies.push_back(match frames {
+            0 | 1 => 0,
+            x => delay + x - 1,
+        });
+    }
+
+    /// the delay the measurements call for, `None` until there are enough of them
+    pub fn proposal(&self) -> Option<usize> {
+        self.proposal
+    }
+
+    /// the one-way latency in frames, from the median round trip, or from the rollbacks if
+    /// they are deeper (e.g. with a lot of jitter)
+    fn latency(&self) -> Option<f64> {
+        if self.rtts.len() < MIN_SAMPLES {
+            return None;
+        }
+        let mut rtts: Vec<_> = self.rtts.iter().collect();
+        rtts.sort();
+        let from_rtt = rtts[rtts.len() / 2].as_secs_f64() / 2.0 / self.frame_time.as_secs_f64();
+
+        let mut rollbacks: Vec<_> = self.rollback_latencies.iter().collect();
+        rollbacks.sort();
+        let from_rollbacks = rollbacks
+            .get(rollbacks.len() * 9 / 10)
+            .map_or(0.0, |x| **x as f64);
+
+        Some(from_rtt.max(from_rollbacks))
+    }
+
+    /// updates the proposal; called once per frame with the delay in use
+    pub fn update(&mut self, delay: usize) {
+        let Some(latency) = self.latency() else {
+            return;
+        };
+        let ideal = latency - self.target_rollback as f64;
+        let current = self.proposal.unwrap_or(delay);
+        // the delay `x` is right for `ideal` in `x - 1..x`, the range is widened by
+        // `HYSTERESIS` on both sides
+        let new = if ideal > current as f64 + HYSTERESIS {
+            (ideal - HYSTERESIS).ceil()
+        } else if ideal <= current as f64 - 1.0 - HYSTERESIS {
+            (ideal + HYSTERESIS).ceil()
+        } else {
+            current as f64
+        };
+        self.proposal = Some((new.max(0.0) as usize).min(MAX_DELAY));
+    }
+}
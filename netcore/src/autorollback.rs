@@ -0,0 +1,127 @@
+use std::{collections::VecDeque, time::Duration};
+
+/// the most frames of rollback and delay together before the game pauses, see
+/// `Netcoder::process_and_send`
+pub const MAX_WINDOW: usize = 15;
+
+/// how many of the latest round trips are used
+const WINDOW: usize = 180;
+/// nothing is measured before this many round trips
+const MIN_SAMPLES: usize = 40;
+/// how far (in frames) the latency has to go below the current one before it's lowered, so
+/// that the max rollback doesn't go back and forth
+const HYSTERESIS: f64 = 0.5;
+
+/// The max rollback of both players, within the bounds both of them set
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RollbackBounds {
+    pub min: usize,
+    pub max: usize,
+}
+
+impl RollbackBounds {
+    pub fn new(min: usize, max: usize) -> Self {
+        let max = max.min(MAX_WINDOW);
+        Self {
+            min: min.min(max),
+            max,
+        }
+    }
+
+    /// the bounds used by both players, `agree` applied to each of them
+    pub fn agree(mine: Self, theirs: Self) -> Self {
+        // `agree` is non-decreasing in both arguments, so `min` can't go above `max`
+        Self {
+            min: agree(mine.min, theirs.min),
+            max: agree(mine.max, theirs.max),
+        }
+    }
+
+    /// the max rollback with which the game doesn't pause for inputs coming `latency` frames
+    /// late, with the given delays
+    pub fn window(&self, latency: usize, delay: usize, opponent_delay: usize) -> usize {
+        latency
+            .saturating_sub(delay.max(opponent_delay))
+            .clamp(self.min, self.max)
+    }
+}
+
+/// The max rollback used when `n1` and `n2` are chosen by p1 and p2: 6 (the old default
+/// value) if one of them is above 6 and the other below, or the one nearest to 6.
+///
+/// Assuming all preferences of max rollback are single peaked, it can be proved that, if the
+/// game automatically sets a max rollback by a binary function (f) with rollbacks chosen by p1
+/// and p2 (denoted as n1 and n2) as arguments, this one is the only one that satisfies all the
+/// following:
+/// 1. unanimous consent: f(n, n) = n;
+/// 2. symmetry: f(n1, n2) = f(n2, n1);
+/// 3. Pareto improvement to the default 6: f(n1, n2) is always not worse than 6 for any player
+///    who likes n1 rollbacks most;
+/// 4. Nash equilibrium: with rollback set by the opponent fixed, choosing the favorite rollback
+///    will always lead to the best result for a player;
+/// 5. Pareto optimality: it is impossible that they dishonestly choose different rollbacks and
+///    finally get a result which is better for both of them;
+/// 6. min(n1, n2) <= f(n1, n2) <= max(n1, n2).
+pub fn agree(n1: usize, n2: usize) -> usize {
+    // the median of n1, n2 and 6
+    n1.max(n2).min(6).max(n1.min(n2))
+}
+
+/// Measures how late the inputs of the opponent come, in frames, for the max rollback to be
+/// chosen from.
+///
+/// Like the delay of `AutoDelay`, the max rollback is chosen by p1 (`RollbackBounds::window`)
+/// from the latencies measured by both sides, and p2 uses the one in the packets of p1.
+pub struct AutoRollback {
+    /// the bounds set by this player
+    pub bounds: RollbackBounds,
+    /// the time of a frame of the game, 1/62 s with `enable_f62`
+    frame_time: Duration,
+    rtts: VecDeque<Duration>,
+    latency: Option<usize>,
+}
+
+impl AutoRollback {
+    /// `fps` is the frames per second of the game, 60 or 62
+    pub fn new(bounds: RollbackBounds, fps: u8) -> Self {
+        Self {
+            bounds,
+            frame_time: Duration::from_secs(1) / fps as u32,
+            rtts: VecDeque::new(),
+            latency: None,
+        }
+    }
+
+    /// the time from sending an input to knowing that the opponent got it
+    pub fn add_rtt(&mut self, rtt: Duration) {
+        if self.rtts.len() >= WINDOW {
+            self.rtts.pop_front();
+        }
+        self.rtts.push_back(rtt);
+    }
+
+    /// how many frames after its frame an input of the opponent comes, including the frame it
+    /// is read on; `None` until there are enough measurements
+    pub fn latency(&self) -> Option<usize> {
+        self.latency
+    }
+
+    /// updates the latency; called once per frame
+    pub fn update(&mut self) {
+        if self.rtts.len() < MIN_SAMPLES {
+            return;
+        }
+        let mut rtts: Vec<_> = self.rtts.iter().collect();
+        rtts.sort();
+        // the slow ones are what makes the game pause
+        let one_way = rtts[rtts.len() * 9 / 10].as_secs_f64() / 2.0 / self.frame_time.as_secs_f64();
+        let ideal = one_way + 1.0;
+        let current = self.latency.unwrap_or(0);
+        let new = if ideal > current as f64 || ideal <= current as f64 - 1.0 - HYSTERESIS {
+            ideal.ceil() as usize
+        } else {
+            current
+        };
+        self.latency = Some(new.min(MAX_WINDOW));
+    }
+}
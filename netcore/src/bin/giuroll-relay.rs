@@ -0,0 +1,44 @@
+//! Relays the traffic of players who can't reach each other directly, see `netcore::relay`.
+//!
+//! Usage: `giuroll-relay [address] [--stats-every <seconds>]`; the address defaults to
+//! `0.0.0.0:10900`, and with `--stats-every` the stats of every room are logged as JSON.
+
+use std::{net::UdpSocket, sync::atomic::Ordering, time::Duration};
+
+use netcore::{relay::serve, ENABLE_PRINTLN};
+
+const USAGE: &str = "usage: giuroll-relay [address] [--stats-every <seconds>]";
+
+fn main() {
+    let mut address = "0.0.0.0:10900".to_string();
+    let mut log_every = None;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--stats-every" {
+            let Some(seconds) = args.next().and_then(|x| x.parse().ok()) else {
+                eprintln!("{}", USAGE);
+                std::process::exit(2);
+            };
+            log_every = Some(Duration::from_secs(seconds));
+        } else if arg.starts_with('-') {
+            eprintln!("{}", USAGE);
+            std::process::exit(2);
+        } else {
+            address = arg;
+        }
+    }
+
+    ENABLE_PRINTLN.store(true, Ordering::Relaxed);
+    let socket = match UdpSocket::bind(&address) {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("{}: {}", address, e);
+            std::process::exit(1);
+        }
+    };
+    println!("relaying on {}", socket.local_addr().unwrap());
+    if let Err(e) = serve(&socket, log_every) {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
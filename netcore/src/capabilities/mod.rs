@@ -0,0 +1,197 @@
+//! What each side of a match supports, sent in the first packets of every battle, and what
+//! both of them agree to use.
+//!
+//! Before it, the version byte of soku told whether two versions of giuroll can play together,
+//! and everything else was guessed from the packets. Peers which don't send their capabilities
+//! (older versions of giuroll) are still guessed the old way.
+
+use std::fmt;
+
+use crate::netcode::KNOWN_EXTENSIONS;
+
+#[cfg(test)]
+mod tests;
+
+/// bits of `Capabilities::input_encodings`
+pub const INPUTS_LEGACY: u8 = 1;
+/// see `netcode::encode_inputs`
+pub const INPUTS_COMPACT: u8 = 2;
+
+/// bits of `Capabilities::hash_algorithms`
+/// the weather byte (`NetworkPacket::desyncdetect`) of old versions
+pub const HASH_WEATHER: u8 = 1;
+/// FNV-1a of the memory of the game, see `GameState::state_hash`
+pub const HASH_FNV: u8 = 2;
+
+/// the bytes every version writes; more may follow (`Capabilities::layout`)
+pub const CAPABILITIES_LEN: usize = 10;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Capabilities {
+    /// the version of giuroll: major, minor, patch
+    pub version: [u8; 3],
+    /// bit `n` is set if the extension block with the tag `n` is understood
+    pub extensions: u32,
+    /// the frames per second of the game, 60 or 62 (`enable_f62`)
+    pub fps: u8,
+    /// `INPUTS_*` bits
+    pub input_encodings: u8,
+    /// `HASH_*` bits
+    pub hash_algorithms: u8,
+    /// `Layout::hash` of the snapshot layout; `None` from versions without layouts
+    pub layout: Option<u32>,
+}
+
+impl Default for Capabilities {
+    fn default() -> Self {
+        Self {
+            version: [0; 3],
+            extensions: KNOWN_EXTENSIONS,
+            fps: 60,
+            input_encodings: INPUTS_LEGACY | INPUTS_COMPACT,
+            hash_algorithms: HASH_WEATHER | HASH_FNV,
+            layout: None,
+        }
+    }
+}
+
+impl Capabilities {
+    /// `version` is like "0.6.18"; the missing or broken parts are 0
+    pub fn with_version(version: &str) -> Self {
+        let mut parts = version.split(['.', '-']).map(|x| x.parse().unwrap_or(0));
+        Self {
+            version: std::array::from_fn(|_| parts.next().unwrap_or(0)),
+            ..Default::default()
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = vec![0; CAPABILITIES_LEN];
+        buf[0..3].copy_from_slice(&self.version);
+        buf[3..7].copy_from_slice(&self.extensions.to_le_bytes());
+        buf[7] = self.fps;
+        buf[8] = self.input_encodings;
+        buf[9] = self.hash_algorithms;
+        if let Some(layout) = self.layout {
+            buf.extend_from_slice(&layout.to_le_bytes());
+        }
+        buf
+    }
+
+    pub fn decode(data: &[u8]) -> Option<Self> {
+        let layout = data
+            .get(CAPABILITIES_LEN..CAPABILITIES_LEN + 4)
+            .map(|x| u32::from_le_bytes(x.try_into().unwrap()));
+        let data = data.get(..CAPABILITIES_LEN)?;
+        Some(Self {
+            version: data[0..3].try_into().unwrap(),
+            extensions: u32::from_le_bytes(data[3..7].try_into().unwrap()),
+            fps: data[7],
+            input_encodings: data[8],
+            hash_algorithms: data[9],
+            layout,
+        })
+    }
+}
+
+/// What both peers use, the common subset of their capabilities
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Agreement {
+    pub opponent_version: [u8; 3],
+    /// the extensions both understand
+    pub extensions: u32,
+    pub compact_inputs: bool,
+    /// one of `HASH_*`, the best both have; `None` if desyncs can't be detected
+    pub hash_algorithm: Option<u8>,
+    /// false if the snapshot layouts differ: the state hashes can't be compared, and the
+    /// snapshots can't be merged, so only the weather is compared and desyncs aren't recovered
+    pub same_layout: bool,
+}
+
+impl Agreement {
+    /// what the player is told about the agreement, if anything
+    pub fn warning(&self) -> Option<String> {
+        (!self.same_layout).then(|| {
+            format!(
+                "The opponent (giuroll {}) uses another snapshot layout (snapshot_layout in \
+                 giuroll.ini). Desyncs are only detected by the weather, and can't be recovered.",
+                version_string(self.opponent_version)
+            )
+        })
+    }
+}
+
+/// Why two peers can't play together
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Incompatibility {
+    Fps {
+        mine: u8,
+        theirs: u8,
+        version: [u8; 3],
+    },
+    /// no input encoding in common
+    Inputs { version: [u8; 3] },
+}
+
+fn version_string(version: [u8; 3]) -> String {
+    format!("{}.{}.{}", version[0], version[1], version[2])
+}
+
+impl fmt::Display for Incompatibility {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Incompatibility::Fps {
+                mine,
+                theirs,
+                version,
+            } => write!(
+                f,
+                "The opponent (giuroll {}) plays at {} fps, but you play at {} fps. \
+                 Both have to set enable_f62 the same way.",
+                version_string(*version),
+                theirs,
+                mine
+            ),
+            Incompatibility::Inputs { version } => write!(
+                f,
+                "The opponent (giuroll {}) can't read the inputs sent by this version of \
+                 giuroll, nor send ones it can read.",
+                version_string(*version)
+            ),
+        }
+    }
+}
+
+/// the common subset of `mine` and `theirs`
+pub fn negotiate(mine: &Capabilities, theirs: &Capabilities) -> Result<Agreement, Incompatibility> {
+    let version = theirs.version;
+    if mine.fps != theirs.fps {
+        return Err(Incompatibility::Fps {
+            mine: mine.fps,
+            theirs: theirs.fps,
+            version,
+        });
+    }
+    let inputs = mine.input_encodings & theirs.input_encodings;
+    if inputs == 0 {
+        return Err(Incompatibility::Inputs { version });
+    }
+    let same_layout = match (mine.layout, theirs.layout) {
+        (Some(a), Some(b)) => a == b,
+        _ => true,
+    };
+    let mut hashes = mine.hash_algorithms & theirs.hash_algorithms;
+    if !same_layout {
+        // the layout decides which memory is hashed
+        hashes &= !HASH_FNV;
+    }
+    Ok(Agreement {
+        opponent_version: version,
+        extensions: mine.extensions & theirs.extensions,
+        compact_inputs: inputs & INPUTS_COMPACT != 0,
+        hash_algorithm: [HASH_FNV, HASH_WEATHER]
+            .into_iter()
+            .find(|x| hashes & x != 0),
+        same_layout,
+    })
+}
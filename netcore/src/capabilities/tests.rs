@@ -0,0 +1,119 @@
+use super::*;
+
+#[test]
+fn round_trip() {
+    let capabilities = Capabilities {
+        fps: 62,
+        hash_algorithms: HASH_WEATHER,
+        ..Capabilities::with_version("0.6.18")
+    };
+    assert_eq!(capabilities.version, [0, 6, 18]);
+    let mut encoded = capabilities.encode().to_vec();
+    assert_eq!(Capabilities::decode(&encoded), Some(capabilities));
+    // what later versions add is skipped
+    encoded.push(0xff);
+    assert_eq!(Capabilities::decode(&encoded), Some(capabilities));
+    assert_eq!(Capabilities::decode(&encoded[..CAPABILITIES_LEN - 1]), None);
+
+    let with_layout = Capabilities {
+        layout: Some(0x12345678),
+        ..capabilities
+    };
+    let encoded = with_layout.encode();
+    assert_eq!(encoded.len(), CAPABILITIES_LEN + 4);
+    assert_eq!(Capabilities::decode(&encoded), Some(with_layout));
+    // versions without layouts only read the first bytes
+    assert_eq!(
+        Capabilities::decode(&encoded[..CAPABILITIES_LEN]),
+        Some(capabilities)
+    );
+
+    assert_eq!(Capabilities::with_version("1.2-rc1").version, [1, 2, 0]);
+}
+
+#[test]
+fn the_common_subset_is_used() {
+    let mine = Capabilities::with_version("0.6.18");
+    let theirs = Capabilities {
+        extensions: 1 << 1 | 1 << 3,
+        input_encodings: INPUTS_LEGACY,
+        hash_algorithms: HASH_WEATHER,
+        ..Capabilities::with_version("0.6.19")
+    };
+    assert_eq!(
+        negotiate(&mine, &theirs),
+        Ok(Agreement {
+            opponent_version: [0, 6, 19],
+            extensions: 1 << 1 | 1 << 3,
+            compact_inputs: false,
+            hash_algorithm: Some(HASH_WEATHER),
+            same_layout: true,
+        })
+    );
+    let agreement = negotiate(&mine, &mine).unwrap();
+    assert!(agreement.compact_inputs);
+    assert_eq!(agreement.hash_algorithm, Some(HASH_FNV));
+    assert_eq!(agreement.extensions, KNOWN_EXTENSIONS);
+
+    let no_hash = Capabilities {
+        hash_algorithms: 0,
+        ..mine
+    };
+    assert_eq!(negotiate(&mine, &no_hash).unwrap().hash_algorithm, None);
+}
+
+#[test]
+fn different_layouts_only_compare_the_weather() {
+    let mine = Capabilities {
+        layout: Some(1),
+        ..Capabilities::with_version("0.6.18")
+    };
+    let agreement = negotiate(&mine, &mine).unwrap();
+    assert!(agreement.same_layout);
+    assert_eq!(agreement.warning(), None);
+
+    let theirs = Capabilities {
+        layout: Some(2),
+        ..mine
+    };
+    let agreement = negotiate(&mine, &theirs).unwrap();
+    assert!(!agreement.same_layout);
+    assert_eq!(agreement.hash_algorithm, Some(HASH_WEATHER));
+    assert!(agreement.warning().unwrap().contains("snapshot_layout"));
+
+    // older versions don't tell their layout
+    let old = Capabilities {
+        layout: None,
+        ..mine
+    };
+    assert!(negotiate(&mine, &old).unwrap().same_layout);
+}
+
+#[test]
+fn incompatible_peers() {
+    let mine = Capabilities::with_version("0.6.18");
+    let f62 = Capabilities { fps: 62, ..mine };
+    let error = negotiate(&mine, &f62).unwrap_err();
+    assert_eq!(
+        error,
+        Incompatibility::Fps {
+            mine: 60,
+            theirs: 62,
+            version: [0, 6, 18]
+        }
+    );
+    assert!(error.to_string().contains("enable_f62"));
+
+    let compact_only = Capabilities {
+        input_encodings: INPUTS_COMPACT,
+        ..mine
+    };
+    let legacy_only = Capabilities {
+        input_encodings: INPUTS_LEGACY,
+        ..mine
+    };
+    assert!(matches!(
+        negotiate(&compact_only, &legacy_only),
+        Err(Incompatibility::Inputs { .. })
+    ));
+}
@@ -0,0 +1,87 @@
+use std::collections::VecDeque;
+
+/// the measurements of this many latest frames are fitted
+const WINDOW: usize = 120;
+/// no correction is made before this many measurements
+const MIN_SAMPLES: usize = 10;
+/// the offset is brought to 0 in about this many frames; both peers correct it, so it's about
+/// half as long if the opponent does the same
+const OFFSET_FRAMES: f64 = 240.0;
+/// how much of the offset is added to `skew` every frame, so that an offset which stays means a
+/// difference of speed; about `1 / (2 * OFFSET_FRAMES²)`, which neither overshoots nor lags
+const SKEW_GAIN: f64 = 0.00001;
+/// the largest correction (in µs) of a single frame
+const MAX_CORRECTION: f64 = 2000.0;
+
+/// Estimates how far the frames of the opponent are behind the ones here, and how fast it
+/// changes, with a linear regression over the differences of the arrival times of the frames
+/// measured on both sides.
+///
+/// `correction` turns them into a smooth correction of the frame time, made of `skew`, which
+/// cancels the difference of speed of both clocks, and a share of the offset.
+#[derive(Debug, Clone, Default)]
+pub struct ClockSync {
+    /// the measured offsets (in µs) by frame, the oldest first
+    measurements: VecDeque<(usize, f64)>,
+    /// how far (in µs) the opponent is behind, at the frame `frame`; negative if ahead
+    pub offset: f64,
+    /// how fast (in µs per frame) `offset` changes, with the corrections in use
+    pub drift: f64,
+    /// the frame of the latest measurement
+    pub frame: Option<usize>,
+    /// the measurements made during the whole match
+    pub samples: usize,
+    /// the part (in µs per frame) of the correction which cancels the difference of speed of
+    /// both clocks; about half of it if the opponent corrects too
+    pub skew: f64,
+}
+
+impl ClockSync {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// `offset` (in µs) is how far the opponent was behind at the frame `frame`
+    pub fn add_sample(&mut self, frame: usize, offset: f64) {
+        self.samples += 1;
+        self.measurements.push_back((frame, offset));
+        let last = self.frame.map_or(frame, |x| x.max(frame));
+        self.frame = Some(last);
+        // the packets may come out of order, so may the measurements
+        self.measurements.retain(|(x, _)| x + WINDOW > last);
+
+        let n = self.measurements.len() as f64;
+        let mean_frame = self.measurements.iter().map(|x| x.0 as f64).sum::<f64>() / n;
+        let mean_offset = self.measurements.iter().map(|x| x.1).sum::<f64>() / n;
+        let (mut covariance, mut variance) = (0.0, 0.0);
+        for (x, y) in self.measurements.iter() {
+            let dx = *x as f64 - mean_frame;
+            covariance += dx * (y - mean_offset);
+            variance += dx * dx;
+        }
+        self.drift = match variance {
+            0.0 => 0.0,
+            _ => covariance / variance,
+        };
+        self.offset = mean_offset + self.drift * (last as f64 - mean_frame);
+    }
+
+    /// the estimated offset (in µs) at the frame `now`
+    pub fn offset_at(&self, now: usize) -> f64 {
+        match self.frame {
+            Some(frame) => self.offset + self.drift * now.saturating_sub(frame) as f64,
+            None => 0.0,
+        }
+    }
+
+    /// the microseconds to be added to the time of the frame `now`; called once per frame
+    pub fn correction(&mut self, now: usize) -> i32 {
+        if self.samples < MIN_SAMPLES {
+            return 0;
+        }
+        // the measurements are about 30 frames old, `drift` tells how the offset has gone since
+        let offset = self.offset_at(now);
+        self.skew = (self.skew + SKEW_GAIN * offset).clamp(-MAX_CORRECTION, MAX_CORRECTION);
+        (self.skew + offset / OFFSET_FRAMES).clamp(-MAX_CORRECTION, MAX_CORRECTION) as i32
+    }
+}
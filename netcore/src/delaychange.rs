@@ -0,0 +1,111 @@
+use crate::autodelay::MAX_DELAY;
+
+/// a change takes effect this many frames after it's proposed, so that it reaches the opponent
+/// before: the opponent can't be that many frames ahead or behind
+pub const LEAD: usize = 60;
+/// the most changes one side can have proposed and not yet applied
+pub const MAX_PROPOSED: usize = 4;
+/// a change is still sent, and remembered to tell it from a new one, for this many frames
+/// after it has been applied
+const KEPT: usize = 2 * LEAD;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DelayChange {
+    /// the frame (`Netcoder::id`) from which `delay` is used
+    pub frame: usize,
+    pub delay: usize,
+    pub from_p1: bool,
+}
+
+impl DelayChange {
+    /// both sides apply the changes in this order, the one of p1 last if both are at the same
+    /// frame
+    fn key(&self) -> (usize, bool) {
+        (self.frame, self.from_p1)
+    }
+}
+
+/// Delay changes proposed by either side, which both sides apply at the same frame.
+///
+/// The proposals are repeated in every packet until long after they have been applied, and
+/// are never withdrawn, so both sides end up with the same list, in the same order.
+#[derive(Clone, Debug, Default)]
+pub struct DelaySchedule {
+    /// sorted by `DelayChange::key`, with whether they have been applied
+    changes: Vec<(DelayChange, bool)>,
+}
+
+impl DelaySchedule {
+    fn insert(&mut self, change: DelayChange, applied: bool) {
+        let index = self.changes.partition_point(|x| x.0.key() < change.key());
+        self.changes.insert(index, (change, applied));
+    }
+
+    /// proposes `delay` from `LEAD` frames after `now`; false if too many changes of this side
+    /// are still waiting
+    pub fn propose(&mut self, now: usize, delay: usize, is_p1: bool) -> bool {
+        let mine = self.changes.iter().filter(|x| x.0.from_p1 == is_p1);
+        if mine.clone().filter(|x| !x.1).count() >= MAX_PROPOSED {
+            return false;
+        }
+        // two changes of a side are never at the same frame, even if proposed while paused
+        let frame = mine.map(|x| x.0.frame + 1).fold(now + LEAD, usize::max);
+        self.insert(
+            DelayChange {
+                frame,
+                delay: delay.min(MAX_DELAY),
+                from_p1: is_p1,
+            },
+            false,
+        );
+        true
+    }
+
+    /// a change sent by the opponent, which may be known already; returns whether it has come
+    /// too late to be applied at its frame
+    pub fn receive(&mut self, now: usize, change: DelayChange) -> bool {
+        if self.changes.iter().any(|x| x.0.key() == change.key()) || change.frame + KEPT < now {
+            return false;
+        }
+        self.insert(
+            DelayChange {
+                delay: change.delay.min(MAX_DELAY),
+                ..change
+            },
+            false,
+        );
+        change.frame < now
+    }
+
+    /// applies the changes due at `now`; returns the delay to be used, if it changes
+    pub fn take_due(&mut self, now: usize) -> Option<usize> {
+        let mut delay = None;
+        for (change, applied) in self.changes.iter_mut() {
+            if change.frame <= now && !*applied {
+                *applied = true;
+                delay = Some(change.delay);
+            }
+        }
+        self.changes.retain(|x| !x.1 || x.0.frame + KEPT >= now);
+        delay
+    }
+
+    /// the last change not yet applied
+    pub fn pending(&self) -> Option<DelayChange> {
+        self.changes.iter().rev().find(|x| !x.1).map(|x| x.0)
+    }
+
+    /// the latest `MAX_PROPOSED` changes of this side, which include every one not applied
+    pub fn to_send(&self, is_p1: bool) -> Vec<DelayChange> {
+        let mut changes: Vec<_> = self
+            .changes
+            .iter()
+            .rev()
+            .filter(|x| x.0.from_p1 == is_p1)
+            .take(MAX_PROPOSED)
+            .map(|x| x.0)
+            .collect();
+        changes.reverse();
+        changes
+    }
+}
@@ -0,0 +1,434 @@
+use std::{
+    io::Write,
+    ops::Range,
+    path::PathBuf,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use crate::netcode::RemoteRegion;
+
+/// What a region of a saved state belongs to, used to tell where a desync comes from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegionKind {
+    /// heap objects reached from the global pointer at this address
+    Object(usize),
+    /// the effect linked list of the object at this global pointer
+    Effects(usize),
+    BattleManager,
+    /// netplay input buffers
+    NetInputs,
+    /// character data of the n-th player
+    Player(u8),
+    /// bullets of the n-th player
+    Bullets(u8),
+    /// static ranges in the image of soku, such as `to_be_read` in `dump_frame` of giuroll
+    Static,
+    /// not synced between the players (e.g. F-key states)
+    Local,
+}
+
+impl RegionKind {
+    pub fn to_raw(self) -> (u8, u32) {
+        match self {
+            RegionKind::Object(x) => (0, x as u32),
+            RegionKind::Effects(x) => (1, x as u32),
+            RegionKind::BattleManager => (2, 0),
+            RegionKind::NetInputs => (3, 0),
+            RegionKind::Player(x) => (4, x as u32),
+            RegionKind::Bullets(x) => (5, x as u32),
+            RegionKind::Static => (6, 0),
+            RegionKind::Local => (7, 0),
+        }
+    }
+
+    pub fn from_raw(tag: u8, param: u32) -> Option<Self> {
+        Some(match tag {
+            0 => RegionKind::Object(param as usize),
+            1 => RegionKind::Effects(param as usize),
+            2 => RegionKind::BattleManager,
+            3 => RegionKind::NetInputs,
+            4 => RegionKind::Player(param as u8),
+            5 => RegionKind::Bullets(param as u8),
+            6 => RegionKind::Static,
+            7 => RegionKind::Local,
+            _ => return None,
+        })
+    }
+}
+
+impl std::fmt::Display for RegionKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RegionKind::Object(x) => write!(f, "object *{:#x}", x),
+            RegionKind::Effects(x) => write!(f, "effects of *{:#x}", x),
+            RegionKind::BattleManager => write!(f, "battle manager"),
+            RegionKind::NetInputs => write!(f, "netplay inputs"),
+            RegionKind::Player(x) => write!(f, "player {}", x),
+            RegionKind::Bullets(x) => write!(f, "bullets of player {}", x),
+            RegionKind::Static => write!(f, "static"),
+            RegionKind::Local => write!(f, "local"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RegionInfo {
+    pub kind: RegionKind,
+    pub pos: usize,
+    pub size: usize,
+    pub hash: u64,
+}
+
+/// hashes of every region of a frame which did happen
+#[derive(Debug, Clone)]
+pub struct RegionTable {
+    pub frame: usize,
+    pub regions: Box<[RegionInfo]>,
+}
+
+/// Gives up waiting for the rest of the region hashes of the opponent after this time,
+/// and writes what has been received
+const REGION_REPORT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Asks again for the missing region hashes when none came for this time
+const REGION_REQUEST_RESEND: Duration = Duration::from_millis(1000);
+
+/// How many missing ranges are asked for at once
+const MAX_REQUESTED_RANGES: usize = 8;
+
+/// Compares the region hashes of a desynced frame with the ones of the opponent
+pub struct RegionReport {
+    local: RegionTable,
+    remote: Vec<Option<RemoteRegion>>,
+    remote_total: Option<usize>,
+    received: usize,
+    started: Instant,
+    /// when the last request was sent or the last new region received
+    last_progress: Option<Instant>,
+}
+
+impl RegionReport {
+    pub fn new(local: RegionTable) -> Self {
+        Self {
+            local,
+            remote: vec![],
+            remote_total: None,
+            received: 0,
+            started: Instant::now(),
+            last_progress: None,
+        }
+    }
+
+    pub fn frame(&self) -> usize {
+        self.local.frame
+    }
+
+    pub fn add(&mut self, total: usize, start: usize, regions: Vec<RemoteRegion>) {
+        if self.remote_total.is_none() {
+            self.remote_total = Some(total);
+            self.remote.resize(total, None);
+        }
+        for (n, region) in regions.into_iter().enumerate() {
+            if let Some(slot) = self.remote.get_mut(start + n)
+                && slot.is_none()
+            {
+                *slot = Some(region);
+                self.received += 1;
+                self.last_progress = Some(Instant::now());
+            }
+        }
+    }
+
+    /// the ranges of regions to ask the opponent for, when it's time to ask again
+    pub fn ranges_to_request(&mut self) -> Vec<Range<usize>> {
+        if self
+            .last_progress
+            .is_some_and(|x| x.elapsed() < REGION_REQUEST_RESEND)
+        {
+            return vec![];
+        }
+        self.last_progress = Some(Instant::now());
+
+        let mut ranges: Vec<Range<usize>> = vec![];
+        if self.remote_total.is_none() {
+            ranges.push(0..usize::MAX);
+            return ranges;
+        }
+        for (n, _) in self.remote.iter().enumerate().filter(|(_, x)| x.is_none()) {
+            if let Some(range) = ranges.last_mut()
+                && range.end == n
+            {
+                range.end += 1;
+            } else if ranges.len() < MAX_REQUESTED_RANGES {
+                ranges.push(n..n + 1);
+            } else {
+                break;
+            }
+        }
+        ranges
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.remote_total.is_some_and(|x| x == self.received)
+            || self.started.elapsed() > REGION_REPORT_TIMEOUT
+    }
+
+    /// writes the report into the current directory, and returns its path
+    pub fn write(&self, version: &str) -> std::io::Result<PathBuf> {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |x| x.as_secs());
+        let path = PathBuf::from(format!("desync_{}_{}.txt", self.local.frame, time));
+        let mut f = std::fs::File::create(&path)?;
+
+        writeln!(f, "giuroll {} desync report", version)?;
+        writeln!(f, "frame: {}", self.local.frame)?;
+        writeln!(f, "local regions: {}", self.local.regions.len())?;
+        match self.remote_total {
+            Some(total) => writeln!(f, "remote regions: {} ({} received)", total, self.received)?,
+            None => writeln!(f, "remote regions: no answer from the opponent")?,
+        }
+        if self.remote_total == Some(0) {
+            writeln!(f, "the opponent no longer has this frame")?;
+        }
+        writeln!(f)?;
+        writeln!(
+            f,
+            "words which look like heap pointers are not compared; addresses are local"
+        )?;
+        writeln!(f, "mismatching regions:")?;
+        writeln!(
+            f,
+            "{:>6} {:<22} {:>10} {:>8} {:>8} {:<22}",
+            "index", "kind", "address", "size", "remote", "remote kind"
+        )?;
+
+        let mut mismatches = 0;
+        let mut missing = 0;
+        for (n, local) in self.local.regions.iter().enumerate() {
+            let remote = match self.remote.get(n) {
+                Some(Some(remote)) => remote,
+                Some(None) => {
+                    missing += 1;
+                    continue;
+                }
+                None => break,
+            };
+            if remote.hash == local.hash && remote.size == local.size {
+                continue;
+            }
+            mismatches += 1;
+            writeln!(
+                f,
+                "{:>6} {:<22} {:>#10x} {:>#8x} {:>#8x} {:<22}",
+                n,
+                local.kind.to_string(),
+                local.pos,
+                local.size,
+                remote.size,
+                remote
+                    .kind
+                    .map_or_else(|| "unknown".to_string(), |x| x.to_string()),
+            )?;
+        }
+        writeln!(f)?;
+        writeln!(f, "{} mismatching, {} not received", mismatches, missing)?;
+        if self.local.regions.len() != self.remote.len() && self.remote_total.is_some() {
+            writeln!(
+                f,
+                "the numbers of regions differ, so the regions after the first mismatching \
+                 structure may not correspond to each other"
+            )?;
+        }
+        Ok(path)
+    }
+}
+
+/// bytes of a snapshot in a `ControlMessage::RecoveryChunk`, so that it fits into a packet
+pub const RECOVERY_CHUNK_SIZE: usize = 360;
+/// the state of the frame this many frames after the request (plus the delay) is transferred
+pub const RECOVERY_MARGIN: usize = 30;
+
+/// Desync recovery: p2 asks for the state of p1 at an agreed frame, and restores it.
+///
+/// Only the bytes of the state are merged: a desync which changed which objects exist (e.g. a
+/// bullet on one side only) can't be recovered, see `MergeError::DifferentLayout`.
+pub enum Recovery {
+    /// p2: asked p1 for its state at the frame `sent_at`
+    Requested { sent_at: usize },
+    /// p1: waiting for `frame` to be confirmed to take a snapshot of it
+    Snapshotting { frame: usize },
+    /// p1: streaming the snapshot of `frame`
+    Sending {
+        frame: usize,
+        sender: SnapshotSender,
+    },
+    /// p2: receiving the snapshot of `frame`, which is held by the `Rollbacker` meanwhile
+    Receiving {
+        frame: usize,
+        receiver: SnapshotReceiver,
+    },
+}
+
+impl Recovery {
+    pub fn frame(&self) -> Option<usize> {
+        match self {
+            Recovery::Requested { .. } => None,
+            Recovery::Snapshotting { frame }
+            | Recovery::Sending { frame, .. }
+            | Recovery::Receiving { frame, .. } => Some(*frame),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RecoveryNotice {
+    InProgress(usize),
+    Done(usize),
+    Failed(usize),
+    /// the states have different objects, see `MergeError::DifferentLayout`
+    Unrecoverable(usize),
+}
+
+/// Why a snapshot of the opponent couldn't be merged by `Frame::merge_snapshot`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MergeError {
+    /// the regions of the states differ, in number or in size: the objects of one side (e.g.
+    /// bullets, effects) aren't all on the other one, and only the game itself could rebuild them
+    DifferentLayout(String),
+    /// the snapshot is malformed, or of another frame
+    Invalid(String),
+}
+
+impl std::fmt::Display for MergeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MergeError::DifferentLayout(x) => write!(f, "the objects differ: {}", x),
+            MergeError::Invalid(x) => write!(f, "{}", x),
+        }
+    }
+}
+
+/// Sends the chunks of a snapshot round-robin from the first one not acknowledged
+pub struct SnapshotSender {
+    data: Box<[u8]>,
+    acked: usize,
+    cursor: usize,
+}
+
+impl SnapshotSender {
+    pub fn new(data: Box<[u8]>) -> Self {
+        Self {
+            data,
+            acked: 0,
+            cursor: 0,
+        }
+    }
+
+    fn chunk_count(&self) -> usize {
+        self.data.len().div_ceil(RECOVERY_CHUNK_SIZE)
+    }
+
+    pub fn total(&self) -> usize {
+        self.data.len()
+    }
+
+    /// returns up to `n` (offset, data) to be sent
+    pub fn next_chunks(&mut self, n: usize) -> Vec<(usize, &[u8])> {
+        let count = self.chunk_count();
+        let mut ret = vec![];
+        for _ in 0..n.min(count - self.acked) {
+            if self.cursor >= count {
+                self.cursor = self.acked;
+            }
+            let offset = self.cursor * RECOVERY_CHUNK_SIZE;
+            let end = (offset + RECOVERY_CHUNK_SIZE).min(self.data.len());
+            ret.push((offset, &self.data[offset..end]));
+            self.cursor += 1;
+        }
+        ret
+    }
+
+    /// `first_missing` is the first chunk the receiver doesn't have
+    pub fn ack(&mut self, first_missing: usize) {
+        self.acked = self.acked.max(first_missing.min(self.chunk_count()));
+        self.cursor = self.cursor.max(self.acked);
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.acked == self.chunk_count()
+    }
+}
+
+/// Gives up the recovery if the snapshot isn't received in this time
+const RECOVERY_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub struct SnapshotReceiver {
+    data: Vec<u8>,
+    received: Vec<bool>,
+    first_missing: usize,
+    started: Instant,
+    polls: usize,
+}
+
+impl Default for SnapshotReceiver {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SnapshotReceiver {
+    pub fn new() -> Self {
+        Self {
+            data: vec![],
+            received: vec![],
+            first_missing: 0,
+            started: Instant::now(),
+            polls: 0,
+        }
+    }
+
+    /// called once per frame (also while paused); returns whether to send an acknowledgement
+    pub fn poll(&mut self) -> bool {
+        self.polls += 1;
+        self.polls % 10 == 0
+    }
+
+    pub fn is_timed_out(&self) -> bool {
+        self.started.elapsed() > RECOVERY_TIMEOUT
+    }
+
+    pub fn add(&mut self, total: usize, offset: usize, chunk: &[u8]) {
+        if self.received.is_empty() {
+            self.data = vec![0; total];
+            self.received = vec![false; total.div_ceil(RECOVERY_CHUNK_SIZE)];
+        }
+        if total != self.data.len()
+            || offset % RECOVERY_CHUNK_SIZE != 0
+            || offset + chunk.len() > total
+        {
+            return;
+        }
+        let n = offset / RECOVERY_CHUNK_SIZE;
+        if !self.received[n] {
+            self.data[offset..offset + chunk.len()].copy_from_slice(chunk);
+            self.received[n] = true;
+        }
+        while self.received.get(self.first_missing) == Some(&true) {
+            self.first_missing += 1;
+        }
+    }
+
+    /// the first chunk which hasn't been received; 0 if nothing has been received yet
+    pub fn first_missing(&self) -> usize {
+        self.first_missing
+    }
+
+    pub fn is_done(&self) -> bool {
+        !self.received.is_empty() && self.first_missing == self.received.len()
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+}
@@ -0,0 +1,625 @@
+//! The layout of the memory saved for each frame: which structures of soku are read, where
+//! they are, and in which order they are saved.
+//!
+//! A layout is a text file of sections, each a list of operations, one per line; `#` starts a
+//! comment. Numbers are decimal, or hexadecimal with `0x`. `DEFAULT` is the layout of soku
+//! (and of the known character mods), and describes every operation.
+//!
+//! The order of the regions is a part of the state hash exchanged with the opponent, so both
+//! players have to use the same layout.
+
+use std::collections::HashMap;
+
+use crate::desync::RegionKind;
+
+#[cfg(test)]
+mod tests;
+
+/// the built-in layout
+pub const DEFAULT: &str = include_str!("default.txt");
+
+/// the largest region read at once
+pub const MAX_SIZE: usize = 10000;
+/// how deep characters can be nested (see `CharacterOp::Player`)
+pub const MAX_NESTED_PLAYERS: usize = 4;
+
+/// what a new `object` saves its regions as
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ObjectKind {
+    Object,
+    BattleManager,
+    NetInputs,
+    Local,
+}
+
+/// Saves the regions of a frame. The offsets are from the current object, set by `Object`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Op {
+    /// `object <address> [battle_manager|net_inputs|local] [optional]`: the object pointed to
+    /// by the static `address`; if it is optional and null, the operations up to the next
+    /// `object` or `section` are skipped
+    Object {
+        address: usize,
+        kind: ObjectKind,
+        optional: bool,
+    },
+    /// `section static|local|object|effects`: the kind of the next regions; `object` and
+    /// `effects` are of the current object
+    Section(SectionKind),
+    /// `static <address> <size>`
+    Static { address: usize, size: usize },
+    /// `field <offset> <size>`
+    Field { offset: usize, size: usize },
+    /// `vec <offset>`: the vector (start, capacity, end) itself
+    Vec { offset: usize },
+    /// `vec_data <offset> [nonempty]`: the elements of the vector; with `nonempty`, only if it
+    /// has a buffer
+    VecData { offset: usize, nonempty: bool },
+    /// `list <offset> [<size>]`: the linked list, its nodes and `size` bytes of the data of
+    /// each node but the first
+    List { offset: usize, size: usize },
+    /// `ring <offset> <size>`: the ring buffer of pointers, and `size` bytes of each element
+    Ring { offset: usize, size: usize },
+    /// `deque <offset> <size>`: the deque of pointers, and `size` bytes of each element
+    Deque { offset: usize, size: usize },
+    /// `autosize_list <offset>`: the linked list, and the data of each node, as large as its
+    /// heap block
+    AutosizeList { offset: usize },
+    /// `replay_inputs <offset>`: the latest inputs of the bit deque at `offset`
+    ReplayInputs { offset: usize },
+    /// `players`: the players of the game manager, the current object; see `Layout::player`
+    Players,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SectionKind {
+    Static,
+    Local,
+    Object,
+    Effects,
+}
+
+/// Saves a bullet, from the data of a node of the bullet list of a player
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BulletOp {
+    /// `list <offset>`: the linked list, if it has nodes
+    List { offset: usize },
+    /// `heap <offset> <max>`: the heap block pointed to, of at most `max` bytes
+    Heap { offset: usize, max: usize },
+    /// `itself`: the data of the bullet, as large as the bullet size of the character
+    Itself,
+    /// `pattern <offset>`: the pattern pointed to, see `Layout::pattern`
+    Pattern { offset: usize },
+}
+
+/// Saves a player, from the player itself unless an `Object` is read
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PlayerOp {
+    /// `id <offset>`: the byte telling the character, which the size of the data of the player
+    /// and `Layout::characters` depend on; the first operation
+    Id { offset: usize },
+    /// `bullets <offset>`: the bullet list, each bullet saved by `Layout::bullet`
+    Bullets { offset: usize },
+    /// `characters`: the operations of the character of the player, see `CharacterOp`
+    Characters,
+    /// `chain <offset> <size>`: `size` bytes of each node of the linked list, from its head,
+    /// following the pointers at the start of the nodes
+    Chain { offset: usize, size: usize },
+    /// `itself`: the data of the player, as large as the player size of the character
+    Itself,
+    /// `object <offset> <size>`: `size` bytes of the object pointed to by `offset` of the data
+    /// of the player; the offsets of the next operations are from it
+    Object { offset: usize, size: usize },
+    /// `player`: the offsets of the next operations are from the player again
+    Player,
+    /// `pointer_vec <offset> <field> <size> [<field> <size>]...`: for each pointer up to the
+    /// capacity of the vector, `size` bytes at each `field` of what it points to
+    PointerVec {
+        offset: usize,
+        fields: Vec<(usize, usize)>,
+    },
+    /// `vec <offset>`, like `Op::Vec`
+    Vec { offset: usize },
+    /// `vec_data <offset>`, like `Op::VecData`
+    VecData { offset: usize },
+    /// `list <offset> [<size>]`, like `Op::List`
+    List { offset: usize, size: usize },
+    /// `ring <offset> <size>`, like `Op::Ring`
+    Ring { offset: usize, size: usize },
+}
+
+/// Saves a pattern of a bullet; the offsets are from its header
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PatternOp {
+    /// `header <size>`: the pattern itself, which the other operations read their pointers and
+    /// counts from
+    Header { size: usize },
+    /// `pointers <pointer> <count> <size>`: `size` bytes of each element of the array of
+    /// `count` pointers, then the array itself
+    Pointers {
+        pointer: usize,
+        count: usize,
+        size: usize,
+    },
+    /// `grid <pointer> <width> <height>`: the `width * height` cells of 2 bytes, and 2 more
+    Grid {
+        pointer: usize,
+        width: usize,
+        height: usize,
+    },
+}
+
+/// Saves what only a character has, after the bullets of its player
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CharacterOp {
+    /// `deque <offset> <size>`, like `Op::Deque`
+    Deque { offset: usize, size: usize },
+    /// `player <offset>`: another player pointed to, saved as a part of this one
+    Player { offset: usize },
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Layout {
+    /// `[frame]`
+    pub frame: Vec<Op>,
+    /// `[player]`
+    pub player: Vec<PlayerOp>,
+    /// `[bullet]`
+    pub bullet: Vec<BulletOp>,
+    /// `[pattern]`
+    pub pattern: Vec<PatternOp>,
+    /// `[character <id>]`
+    pub characters: HashMap<u8, Vec<CharacterOp>>,
+    /// the line of each operation of `player`, for the errors found while reading
+    pub player_lines: Vec<usize>,
+    /// the line of each operation of `bullet`
+    pub bullet_lines: Vec<usize>,
+    /// the line of each operation of `pattern`
+    pub pattern_lines: Vec<usize>,
+    /// the line of each operation of `characters`
+    pub character_lines: HashMap<u8, Vec<usize>>,
+}
+
+impl Op {
+    /// the kind of the regions after this operation, in `object`
+    pub fn region_kind(&self, object: usize) -> Option<RegionKind> {
+        match self {
+            Op::Object {
+                address,
+                kind: ObjectKind::Object,
+                ..
+            } => Some(RegionKind::Object(*address)),
+            Op::Object {
+                kind: ObjectKind::BattleManager,
+                ..
+            } => Some(RegionKind::BattleManager),
+            Op::Object {
+                kind: ObjectKind::NetInputs,
+                ..
+            } => Some(RegionKind::NetInputs),
+            Op::Object {
+                kind: ObjectKind::Local,
+                ..
+            } => Some(RegionKind::Local),
+            Op::Section(SectionKind::Static) => Some(RegionKind::Static),
+            Op::Section(SectionKind::Local) => Some(RegionKind::Local),
+            Op::Section(SectionKind::Object) => Some(RegionKind::Object(object)),
+            Op::Section(SectionKind::Effects) => Some(RegionKind::Effects(object)),
+            _ => None,
+        }
+    }
+}
+
+fn number(word: &str) -> Result<usize, String> {
+    match word.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16),
+        None => word.parse(),
+    }
+    .map_err(|_| format!("{:?} is not a number", word))
+}
+
+fn size(word: &str) -> Result<usize, String> {
+    let size = number(word)?;
+    match size <= MAX_SIZE {
+        true => Ok(size),
+        false => Err(format!("{:#x} is larger than {:#x}", size, MAX_SIZE)),
+    }
+}
+
+/// the arguments of an operation: `N` numbers, then the flags it accepts
+fn arguments<'a, const N: usize>(
+    args: &[&'a str],
+    optional: usize,
+    flags: &[&str],
+) -> Result<([Option<&'a str>; N], Vec<&'a str>), String> {
+    let count = args.iter().take_while(|x| !flags.contains(*x)).count();
+    if count < N - optional || count > N {
+        return Err(match optional {
+            0 => format!("expected {} arguments", N),
+            _ => format!("expected {} to {} arguments", N - optional, N),
+        });
+    }
+    let mut numbers = [None; N];
+    for (n, x) in args[..count].iter().enumerate() {
+        numbers[n] = Some(*x);
+    }
+    let mut found = args[count..].to_vec();
+    if let Some(x) = found.iter().find(|x| !flags.contains(*x)) {
+        return Err(format!("unexpected {:?}", x));
+    }
+    found.sort();
+    if found.windows(2).any(|x| x[0] == x[1]) {
+        return Err("repeated flag".to_string());
+    }
+    Ok((numbers, found))
+}
+
+fn parse_op(name: &str, args: &[&str]) -> Result<Op, String> {
+    Ok(match name {
+        "object" => {
+            let flags = ["battle_manager", "net_inputs", "local", "optional"];
+            let ([address], found) = arguments::<1>(args, 0, &flags)?;
+            let kinds: Vec<&str> = found.iter().copied().filter(|x| *x != "optional").collect();
+            let kind = match kinds.as_slice() {
+                [] => ObjectKind::Object,
+                ["battle_manager"] => ObjectKind::BattleManager,
+                ["net_inputs"] => ObjectKind::NetInputs,
+                ["local"] => ObjectKind::Local,
+                _ => return Err("more than one kind of object".to_string()),
+            };
+            Op::Object {
+                address: number(address.unwrap())?,
+                kind,
+                optional: found.contains(&"optional"),
+            }
+        }
+        "section" => Op::Section(match args {
+            ["static"] => SectionKind::Static,
+            ["local"] => SectionKind::Local,
+            ["object"] => SectionKind::Object,
+            ["effects"] => SectionKind::Effects,
+            _ => return Err("expected static, local, object or effects".to_string()),
+        }),
+        "static" => {
+            let ([address, x], _) = arguments::<2>(args, 0, &[])?;
+            Op::Static {
+                address: number(address.unwrap())?,
+                size: size(x.unwrap())?,
+            }
+        }
+        "field" => {
+            let ([offset, x], _) = arguments::<2>(args, 0, &[])?;
+            Op::Field {
+                offset: number(offset.unwrap())?,
+                size: size(x.unwrap())?,
+            }
+        }
+        "vec" => {
+            let ([offset], _) = arguments::<1>(args, 0, &[])?;
+            Op::Vec {
+                offset: number(offset.unwrap())?,
+            }
+        }
+        "vec_data" => {
+            let ([offset], found) = arguments::<1>(args, 0, &["nonempty"])?;
+            Op::VecData {
+                offset: number(offset.unwrap())?,
+                nonempty: !found.is_empty(),
+            }
+        }
+        "list" => {
+            let ([offset, x], _) = arguments::<2>(args, 1, &[])?;
+            Op::List {
+                offset: number(offset.unwrap())?,
+                size: x.map_or(Ok(0), size)?,
+            }
+        }
+        "ring" | "deque" => {
+            let ([offset, x], _) = arguments::<2>(args, 0, &[])?;
+            let (offset, size) = (number(offset.unwrap())?, size(x.unwrap())?);
+            match name {
+                "ring" => Op::Ring { offset, size },
+                _ => Op::Deque { offset, size },
+            }
+        }
+        "autosize_list" | "replay_inputs" => {
+            let ([offset], _) = arguments::<1>(args, 0, &[])?;
+            let offset = number(offset.unwrap())?;
+            match name {
+                "autosize_list" => Op::AutosizeList { offset },
+                _ => Op::ReplayInputs { offset },
+            }
+        }
+        "players" => {
+            arguments::<0>(args, 0, &[])?;
+            Op::Players
+        }
+        x => return Err(format!("unknown operation {:?}", x)),
+    })
+}
+
+fn parse_player_op(name: &str, args: &[&str]) -> Result<PlayerOp, String> {
+    Ok(match name {
+        "id" | "bullets" | "vec" | "vec_data" => {
+            let ([offset], _) = arguments::<1>(args, 0, &[])?;
+            let offset = number(offset.unwrap())?;
+            match name {
+                "id" => PlayerOp::Id { offset },
+                "bullets" => PlayerOp::Bullets { offset },
+                "vec" => PlayerOp::Vec { offset },
+                _ => PlayerOp::VecData { offset },
+            }
+        }
+        "chain" | "object" | "ring" => {
+            let ([offset, x], _) = arguments::<2>(args, 0, &[])?;
+            let (offset, size) = (number(offset.unwrap())?, size(x.unwrap())?);
+            match name {
+                "chain" => PlayerOp::Chain { offset, size },
+                "object" => PlayerOp::Object { offset, size },
+                _ => PlayerOp::Ring { offset, size },
+            }
+        }
+        "list" => {
+            let ([offset, x], _) = arguments::<2>(args, 1, &[])?;
+            PlayerOp::List {
+                offset: number(offset.unwrap())?,
+                size: x.map_or(Ok(0), size)?,
+            }
+        }
+        "pointer_vec" => {
+            if args.len() < 3 || args.len() % 2 == 0 {
+                return Err("expected an offset, then fields and sizes".to_string());
+            }
+            let fields = args[1..]
+                .chunks(2)
+                .map(|x| Ok((number(x[0])?, size(x[1])?)))
+                .collect::<Result<_, String>>()?;
+            PlayerOp::PointerVec {
+                offset: number(args[0])?,
+                fields,
+            }
+        }
+        "characters" | "itself" | "player" => {
+            arguments::<0>(args, 0, &[])?;
+            match name {
+                "characters" => PlayerOp::Characters,
+                "itself" => PlayerOp::Itself,
+                _ => PlayerOp::Player,
+            }
+        }
+        x => return Err(format!("unknown player operation {:?}", x)),
+    })
+}
+
+fn parse_pattern_op(name: &str, args: &[&str]) -> Result<PatternOp, String> {
+    Ok(match name {
+        "header" => {
+            let ([x], _) = arguments::<1>(args, 0, &[])?;
+            PatternOp::Header {
+                size: size(x.unwrap())?,
+            }
+        }
+        "pointers" => {
+            let ([pointer, count, x], _) = arguments::<3>(args, 0, &[])?;
+            PatternOp::Pointers {
+                pointer: number(pointer.unwrap())?,
+                count: number(count.unwrap())?,
+                size: size(x.unwrap())?,
+            }
+        }
+        "grid" => {
+            let ([pointer, width, height], _) = arguments::<3>(args, 0, &[])?;
+            PatternOp::Grid {
+                pointer: number(pointer.unwrap())?,
+                width: number(width.unwrap())?,
+                height: number(height.unwrap())?,
+            }
+        }
+        x => return Err(format!("unknown pattern operation {:?}", x)),
+    })
+}
+
+fn parse_bullet_op(name: &str, args: &[&str]) -> Result<BulletOp, String> {
+    Ok(match name {
+        "list" | "pattern" => {
+            let ([offset], _) = arguments::<1>(args, 0, &[])?;
+            let offset = number(offset.unwrap())?;
+            match name {
+                "list" => BulletOp::List { offset },
+                _ => BulletOp::Pattern { offset },
+            }
+        }
+        "heap" => {
+            let ([offset, max], _) = arguments::<2>(args, 0, &[])?;
+            BulletOp::Heap {
+                offset: number(offset.unwrap())?,
+                max: size(max.unwrap())?,
+            }
+        }
+        "itself" => {
+            arguments::<0>(args, 0, &[])?;
+            BulletOp::Itself
+        }
+        x => return Err(format!("unknown bullet operation {:?}", x)),
+    })
+}
+
+fn parse_character_op(name: &str, args: &[&str]) -> Result<CharacterOp, String> {
+    Ok(match name {
+        "deque" => {
+            let ([offset, x], _) = arguments::<2>(args, 0, &[])?;
+            CharacterOp::Deque {
+                offset: number(offset.unwrap())?,
+                size: size(x.unwrap())?,
+            }
+        }
+        "player" => {
+            let ([offset], _) = arguments::<1>(args, 0, &[])?;
+            CharacterOp::Player {
+                offset: number(offset.unwrap())?,
+            }
+        }
+        x => return Err(format!("unknown character operation {:?}", x)),
+    })
+}
+
+enum Section {
+    Frame,
+    Player,
+    Bullet,
+    Pattern,
+    Character(u8),
+}
+
+impl Layout {
+    pub fn default_layout() -> Self {
+        Self::parse(DEFAULT).expect("the built-in layout is valid")
+    }
+
+    /// FNV-1a of the operations, sent to the opponent in `Capabilities::layout`; comments,
+    /// spacing and the order of the sections don't change it
+    pub fn hash(&self) -> u32 {
+        let mut characters: Vec<_> = self.characters.iter().collect();
+        characters.sort_by_key(|x| x.0);
+        let text = format!(
+            "{:?}{:?}{:?}{:?}{:?}",
+            self.frame, self.player, self.bullet, self.pattern, characters
+        );
+        text.bytes().fold(0x811c9dc5, |hash, x| {
+            (hash ^ x as u32).wrapping_mul(0x01000193)
+        })
+    }
+
+    /// parses and validates a layout; the error tells the line
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut layout = Layout {
+            frame: vec![],
+            player: vec![],
+            bullet: vec![],
+            pattern: vec![],
+            characters: HashMap::new(),
+            player_lines: vec![],
+            bullet_lines: vec![],
+            pattern_lines: vec![],
+            character_lines: HashMap::new(),
+        };
+        let mut section = None;
+        let mut has_object = false;
+        for (n, line) in text.lines().enumerate() {
+            let error = |e: String| format!("line {}: {}", n + 1, e);
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+            if let Some(header) = line.strip_prefix('[') {
+                let header = header
+                    .strip_suffix(']')
+                    .ok_or_else(|| error("expected ]".to_string()))?;
+                let words: Vec<_> = header.split_whitespace().collect();
+                let new = match words.as_slice() {
+                    ["frame"] => Section::Frame,
+                    ["player"] => Section::Player,
+                    ["bullet"] => Section::Bullet,
+                    ["pattern"] => Section::Pattern,
+                    ["character", id] => Section::Character(
+                        number(id)
+                            .ok()
+                            .and_then(|x| u8::try_from(x).ok())
+                            .ok_or_else(|| error(format!("invalid character {:?}", id)))?,
+                    ),
+                    _ => return Err(error(format!("unknown section [{}]", header))),
+                };
+                let repeated = match new {
+                    Section::Frame => !layout.frame.is_empty(),
+                    Section::Player => !layout.player.is_empty(),
+                    Section::Bullet => !layout.bullet.is_empty(),
+                    Section::Pattern => !layout.pattern.is_empty(),
+                    Section::Character(id) => layout.characters.contains_key(&id),
+                };
+                if repeated {
+                    return Err(error(format!("repeated section [{}]", header)));
+                }
+                if let Section::Character(id) = new {
+                    layout.characters.insert(id, vec![]);
+                    layout.character_lines.insert(id, vec![]);
+                }
+                section = Some(new);
+                continue;
+            }
+
+            let words: Vec<_> = line.split_whitespace().collect();
+            let (name, args) = (words[0], &words[1..]);
+            match section {
+                None => return Err(error("expected a section".to_string())),
+                Some(Section::Frame) => {
+                    let op = parse_op(name, args).map_err(error)?;
+                    let relative = !matches!(
+                        op,
+                        Op::Object { .. }
+                            | Op::Static { .. }
+                            | Op::Section(SectionKind::Static | SectionKind::Local)
+                    );
+                    if relative && !has_object {
+                        return Err(error(format!("{} needs an object before it", name)));
+                    }
+                    has_object |= matches!(op, Op::Object { .. });
+                    layout.frame.push(op);
+                }
+                Some(Section::Player) => {
+                    let op = parse_player_op(name, args).map_err(error)?;
+                    if layout.player.is_empty() != matches!(op, PlayerOp::Id { .. }) {
+                        return Err(error("id has to be the first operation".to_string()));
+                    }
+                    layout.player.push(op);
+                    layout.player_lines.push(n + 1);
+                }
+                Some(Section::Bullet) => {
+                    layout
+                        .bullet
+                        .push(parse_bullet_op(name, args).map_err(error)?);
+                    layout.bullet_lines.push(n + 1);
+                }
+                Some(Section::Pattern) => {
+                    layout
+                        .pattern
+                        .push(parse_pattern_op(name, args).map_err(error)?);
+                    layout.pattern_lines.push(n + 1);
+                }
+                Some(Section::Character(id)) => {
+                    let op = parse_character_op(name, args).map_err(error)?;
+                    layout.characters.get_mut(&id).unwrap().push(op);
+                    layout.character_lines.get_mut(&id).unwrap().push(n + 1);
+                }
+            }
+        }
+
+        let count = |op: &Op| layout.frame.iter().filter(|x| *x == op).count();
+        if count(&Op::Players) != 1 {
+            return Err("[frame] has to read the players once".to_string());
+        }
+        let itself = layout
+            .bullet
+            .iter()
+            .filter(|x| **x == BulletOp::Itself)
+            .count();
+        if itself != 1 {
+            return Err("[bullet] has to read the bullet itself once".to_string());
+        }
+        if layout.player.is_empty() {
+            return Err("[player] has to tell the character of the player".to_string());
+        }
+        let reads_patterns = layout
+            .bullet
+            .iter()
+            .any(|x| matches!(x, BulletOp::Pattern { .. }));
+        let headers = layout
+            .pattern
+            .iter()
+            .filter(|x| matches!(x, PatternOp::Header { .. }))
+            .count();
+        if reads_patterns && headers != 1 {
+            return Err("[pattern] has to read the header once".to_string());
+        }
+        Ok(layout)
+    }
+}
@@ -0,0 +1,190 @@
+use super::*;
+
+#[test]
+fn the_default_layout_is_valid() {
+    let layout = Layout::default_layout();
+    assert_eq!(
+        layout.frame[0],
+        Op::Object {
+            address: 0x8985ec,
+            kind: ObjectKind::Object,
+            optional: false,
+        }
+    );
+    assert!(layout.frame.contains(&Op::Static {
+        address: 0x8971d8,
+        size: 0x1400
+    }));
+    assert!(layout.frame.contains(&Op::List {
+        offset: 0x5c,
+        size: 0x178
+    }));
+    assert_eq!(layout.player[0], PlayerOp::Id { offset: 0x34c });
+    assert!(layout.player.contains(&PlayerOp::PointerVec {
+        offset: 0x10,
+        fields: vec![(0, 4), (0x154, 4)]
+    }));
+    assert_eq!(
+        layout.bullet,
+        [
+            BulletOp::List { offset: 0x3a4 },
+            BulletOp::List { offset: 0x17c },
+            BulletOp::Heap {
+                offset: 0x35c,
+                max: 4000
+            },
+            BulletOp::Itself,
+            BulletOp::Pattern { offset: 0x354 },
+        ]
+    );
+    assert_eq!(
+        layout.pattern,
+        [
+            PatternOp::Pointers {
+                pointer: 0x2c,
+                count: 0x30,
+                size: 0x10
+            },
+            PatternOp::Pointers {
+                pointer: 0x40,
+                count: 0x44,
+                size: 0x10
+            },
+            PatternOp::Header { size: 0x54 },
+            PatternOp::Grid {
+                pointer: 0x50,
+                width: 4,
+                height: 8
+            },
+        ]
+    );
+    assert_eq!(
+        layout.characters[&5],
+        [CharacterOp::Deque {
+            offset: 0x8bc,
+            size: 0x2c
+        }]
+    );
+    assert_eq!(
+        layout.characters[&36],
+        [CharacterOp::Player { offset: 0x890 }]
+    );
+}
+
+#[test]
+fn a_character_can_be_added() {
+    let text = format!("{}\n[character 40]  # a mod\ndeque 0x900 16\n", DEFAULT);
+    let layout = Layout::parse(&text).unwrap();
+    assert_eq!(
+        layout.characters[&40],
+        [CharacterOp::Deque {
+            offset: 0x900,
+            size: 16
+        }]
+    );
+    assert_eq!(layout.character_lines[&40], [DEFAULT.lines().count() + 3]);
+    assert_eq!(layout.player_lines.len(), layout.player.len());
+    assert_eq!(layout.bullet_lines.len(), layout.bullet.len());
+    assert_eq!(layout.pattern_lines.len(), layout.pattern.len());
+}
+
+#[test]
+fn the_hash_only_depends_on_the_operations() {
+    let default = Layout::default_layout();
+    let commented = format!("# a comment\n{}\n\n", DEFAULT.replace(" 0x", "   0x"));
+    assert_eq!(Layout::parse(&commented).unwrap().hash(), default.hash());
+    let added = format!("{}\n[character 40]\ndeque 0x900 16\n", DEFAULT);
+    assert_ne!(Layout::parse(&added).unwrap().hash(), default.hash());
+}
+
+#[test]
+fn region_kinds_follow_objects_and_sections() {
+    let layout = Layout::parse(
+        "[frame]\nobject 0x10 net_inputs optional\nsection effects\nobject 0x20\nplayers\n\
+         [player]\nid 0x34c\n[bullet]\nitself\n",
+    )
+    .unwrap();
+    let kinds: Vec<_> = layout.frame.iter().map(|x| x.region_kind(0x10)).collect();
+    assert_eq!(
+        kinds,
+        [
+            Some(RegionKind::NetInputs),
+            Some(RegionKind::Effects(0x10)),
+            Some(RegionKind::Object(0x20)),
+            None
+        ]
+    );
+}
+
+#[test]
+fn invalid_layouts_are_rejected() {
+    let error = |text: &str| Layout::parse(text).unwrap_err();
+    let valid = "[frame]\nobject 0x10\nplayers\n[bullet]\nitself\n[player]\nid 0x34c\n";
+    assert!(Layout::parse(valid).is_ok());
+
+    assert_eq!(error("field 0 4"), "line 1: expected a section");
+    assert_eq!(
+        error("[frame]\nfield 0 4"),
+        "line 2: field needs an object before it"
+    );
+    assert_eq!(
+        error(&valid.replace("players", "players\nfield 0 20000")),
+        "line 4: 0x4e20 is larger than 0x2710"
+    );
+    assert_eq!(
+        error(&valid.replace("players", "players\nlist")),
+        "line 4: expected 1 to 2 arguments"
+    );
+    assert_eq!(
+        error(&valid.replace("0x10", "0x10 optional optional")),
+        "line 2: repeated flag"
+    );
+    assert_eq!(
+        error(&valid.replace("0x10", "0x10 local net_inputs")),
+        "line 2: more than one kind of object"
+    );
+    assert_eq!(
+        error(&valid.replace("0x10", "0x10 later")),
+        "line 2: expected 1 arguments"
+    );
+    assert_eq!(
+        error(&valid.replace("players", "players\njump 4")),
+        "line 4: unknown operation \"jump\""
+    );
+    assert_eq!(
+        error(&format!("{}[character 256]\n", valid)),
+        "line 8: invalid character \"256\""
+    );
+    assert_eq!(
+        error(&format!("{}[bullet]\n", valid)),
+        "line 8: repeated section [bullet]"
+    );
+    assert_eq!(
+        error(&format!("{}id 0x34c\n", valid)),
+        "line 8: id has to be the first operation"
+    );
+    assert_eq!(
+        error(&valid.replace("id 0x34c", "itself")),
+        "line 7: id has to be the first operation"
+    );
+    assert_eq!(
+        error(&format!("{}pointer_vec 0x10 0\n", valid)),
+        "line 8: expected an offset, then fields and sizes"
+    );
+    assert_eq!(
+        error(&valid.replace("[player]\nid 0x34c\n", "")),
+        "[player] has to tell the character of the player"
+    );
+    assert_eq!(
+        error(&valid.replace("itself", "itself\npattern 0x354")),
+        "[pattern] has to read the header once"
+    );
+    assert_eq!(
+        error(&valid.replace("players\n", "")),
+        "[frame] has to read the players once"
+    );
+    assert_eq!(
+        error(&valid.replace("itself\n", "")),
+        "[bullet] has to read the bullet itself once"
+    );
+}
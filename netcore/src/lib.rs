@@ -0,0 +1,48 @@
+//! The netcode and rollback logic of giuroll.
+//!
+//! Nothing here touches the memory of soku: the game is reached through `rollback::Game`,
+//! and the opponent through `transport::Transport`, so that it can be tested on any platform.
+#![feature(let_chains)]
+
+use std::sync::atomic::AtomicBool;
+
+pub mod auth;
+pub mod autodelay;
+pub mod autorollback;
+pub mod capabilities;
+pub mod clocksync;
+pub mod delaychange;
+pub mod desync;
+pub mod layout;
+pub mod netcode;
+pub mod predict;
+pub mod record;
+pub mod relay;
+pub mod rollback;
+pub mod sim;
+pub mod snapshot;
+pub mod stats;
+pub mod transport;
+
+pub const INPUT_KEYS_NUMBERS: usize = 12;
+
+pub static ENABLE_PRINTLN: AtomicBool = AtomicBool::new(false);
+
+#[macro_export]
+macro_rules! println {
+    ($($arg:tt)*) => {{
+        if $crate::ENABLE_PRINTLN.load(std::sync::atomic::Ordering::Relaxed) {
+            std::println!($($arg)*);
+        }
+    }};
+}
+
+pub fn input_to_accum(inp: &[bool; INPUT_KEYS_NUMBERS]) -> u16 {
+    let mut inputaccum = 0u16;
+    for (a, pressed) in inp.iter().enumerate() {
+        if *pressed {
+            inputaccum += 0x1 << a;
+        }
+    }
+    inputaccum
+}
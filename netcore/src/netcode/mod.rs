@@ -0,0 +1,2200 @@
+#[cfg(feature = "logtofile")]
+use log::info;
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    auth::{SessionAuth, EXT_AUTH, NONCE_LEN, TRAILER_LEN},
+    autodelay::{AutoDelay, MAX_DELAY},
+    autorollback::{agree, AutoRollback, RollbackBounds},
+    capabilities::{negotiate, Agreement, Capabilities, Incompatibility, HASH_FNV},
+    clocksync::ClockSync,
+    delaychange::{DelayChange, DelaySchedule},
+    desync::{
+        MergeError, Recovery, RecoveryNotice, RegionKind, RegionReport, RegionTable,
+        SnapshotReceiver, SnapshotSender, RECOVERY_CHUNK_SIZE, RECOVERY_MARGIN,
+    },
+    input_to_accum, println,
+    rollback::{Game, GameState, Rollbacker},
+    stats::MatchStats,
+    transport::Transport,
+    INPUT_KEYS_NUMBERS,
+};
+
+/// Version of the giuroll packet layout, written to the byte 2 of every packet.
+///
+/// Version 0 is the legacy layout, which left the byte 2 zeroed. Since version 1 the
+/// `initial_max_rollback` byte is always present, and it is followed by extension blocks.
+pub const PACKET_VERSION: u8 = 1;
+
+/// The receive buffer of soku is 400 bytes long, longer packets are truncated.
+pub const MAX_PACKET_SIZE: usize = 400;
+
+/// extension tags; an extension is only sent when its field is set
+/// frame number (u32) and `Frame::state_hash` (u64) of a confirmed frame
+const EXT_STATE_HASH: u8 = 0x01;
+/// the delay (u8, in milliseconds) after which the sender would send a copy of every packet
+const EXT_RESEND: u8 = 0x02;
+/// the delay (u8) proposed by the `AutoDelay` of p2; p1 sends the delay it has decided for
+/// both sides instead, followed by the frame (u32) from which it's used
+const EXT_DELAY_PROPOSAL: u8 = 0x04;
+/// the inputs, see `encode_inputs`; empty if they are sent the legacy way, which tells that the
+/// sender can read them
+const EXT_COMPACT_INPUTS: u8 = 0x03;
+/// the nonce (`NONCE_LEN` bytes) of the sender, see `auth`; 0x06 is `auth::EXT_AUTH`
+const EXT_SESSION_NONCE: u8 = 0x05;
+/// `Capabilities::encode` of the sender, in the first packets of a battle
+const EXT_CAPABILITIES: u8 = 0x07;
+/// the delay changes (`DelaySchedule::to_send`) of the sender: frame (u32) and delay (u8) each
+pub const EXT_DELAY_CHANGES: u8 = 0x08;
+/// the bounds of the max rollback (u8, u8) and the latency (u8, 0xff if not known yet) of the
+/// `AutoRollback` of the sender
+const EXT_ROLLBACK_WINDOW: u8 = 0x09;
+/// the number (u32) of packets the sender had sent before, which its copies share
+const EXT_SEQUENCE: u8 = 0x0a;
+/// the extensions this version understands, see `Capabilities::extensions`
+pub const KNOWN_EXTENSIONS: u32 = 1 << EXT_STATE_HASH
+    | 1 << EXT_RESEND
+    | 1 << EXT_COMPACT_INPUTS
+    | 1 << EXT_DELAY_PROPOSAL
+    | 1 << EXT_SESSION_NONCE
+    | 1 << EXT_AUTH
+    | 1 << EXT_CAPABILITIES
+    | 1 << EXT_DELAY_CHANGES
+    | 1 << EXT_ROLLBACK_WINDOW
+    | 1 << EXT_SEQUENCE;
+/// the capabilities are sent in the packets of the first frames, and until the ones of the
+/// opponent have come
+const CAPABILITY_FRAMES: usize = 120;
+
+/// the bytes of a packet before its extension blocks, apart from the legacy inputs
+const HEADER_LEN: usize = 21;
+/// the most inputs a packet can claim, so that a broken one doesn't allocate too much
+const MAX_DECODED_INPUTS: usize = 1024;
+/// how many frames after deciding a delay p1 starts using it, so that the decision reaches p2
+/// before
+const DELAY_DECISION_LEAD: usize = 30;
+/// how many of the latest sequence numbers of the opponent are remembered
+const SEQUENCE_WINDOW: u32 = 1024;
+/// the timing data (`sync`) of a packet is about the frame this much before its id
+const TIME_SYNC_LAG: usize = 30;
+
+#[derive(Clone, Debug)]
+pub struct NetworkPacket {
+    id: usize,
+    desyncdetect: u8,
+
+    delay: u8,
+    max_rollback: u8,
+
+    inputs: Vec<u16>, //also u8 in size? starts out at id + delay
+    //confirms: Vec<bool>,
+    last_confirm: usize,
+    sync: Option<i32>,
+
+    initial_max_rollback: Option<u8>,
+
+    state_hash: Option<(usize, u64)>,
+    resend_after: Option<u8>,
+    /// when encoding: send `inputs` with `encode_inputs`;
+    /// when decoding: the sender can read them (they may have been sent either way)
+    compact_inputs: bool,
+    delay_proposal: Option<u8>,
+    /// the frame and the delay decided by p1, sent in place of `delay_proposal`
+    delay_decision: Option<(usize, u8)>,
+    session_nonce: Option<[u8; NONCE_LEN]>,
+    capabilities: Option<Capabilities>,
+    /// `DelayChange::frame` and `DelayChange::delay`, proposed by the sender
+    delay_changes: Vec<(usize, u8)>,
+    /// min and max max rollback, and latency, see `EXT_ROLLBACK_WINDOW`
+    rollback_window: Option<[u8; 3]>,
+    /// see `EXT_SEQUENCE`
+    sequence: Option<u32>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PacketDecodeError {
+    /// the packet ended before the field at `offset` could be read
+    Truncated {
+        offset: usize,
+        needed: usize,
+        len: usize,
+    },
+    /// the first byte isn't 0x6b
+    WrongType(u8),
+    /// a versioned packet without the `initial_max_rollback` byte
+    MissingInitialMaxRollback,
+    /// the value of an extension block goes past the end of the packet
+    ExtensionOverrun {
+        tag: u8,
+        len: usize,
+        remaining: usize,
+    },
+    /// a control message (0x6f) of unknown kind
+    UnknownControl(u8),
+    /// the compact inputs can't be decoded
+    BadInputs,
+}
+
+impl std::fmt::Display for PacketDecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PacketDecodeError::Truncated {
+                offset,
+                needed,
+                len,
+            } => write!(
+                f,
+                "packet truncated: needs {} bytes at offset {}, but it is {} bytes long",
+                needed, offset, len
+            ),
+            PacketDecodeError::WrongType(t) => write!(f, "not a giuroll packet (type {:#x})", t),
+            PacketDecodeError::MissingInitialMaxRollback => {
+                write!(f, "versioned packet without initial max rollback")
+            }
+            PacketDecodeError::ExtensionOverrun {
+                tag,
+                len,
+                remaining,
+            } => write!(
+                f,
+                "extension {:#x} claims {} bytes, but only {} are left",
+                tag, len, remaining
+            ),
+            PacketDecodeError::UnknownControl(kind) => {
+                write!(f, "unknown control message {:#x}", kind)
+            }
+            PacketDecodeError::BadInputs => write!(f, "malformed compact inputs"),
+        }
+    }
+}
+
+impl std::error::Error for PacketDecodeError {}
+
+/// Bounds-checked little-endian cursor over a received packet
+struct PacketReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> PacketReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data, pos: 0 }
+    }
+
+    fn remaining(&self) -> usize {
+        self.data.len() - self.pos
+    }
+
+    fn bytes(&mut self, n: usize) -> Result<&'a [u8], PacketDecodeError> {
+        if self.remaining() < n {
+            return Err(PacketDecodeError::Truncated {
+                offset: self.pos,
+                needed: n,
+                len: self.data.len(),
+            });
+        }
+        let ret = &self.data[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(ret)
+    }
+
+    fn u8(&mut self) -> Result<u8, PacketDecodeError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, PacketDecodeError> {
+        Ok(u16::from_le_bytes(self.bytes(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32, PacketDecodeError> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, PacketDecodeError> {
+        Ok(u64::from_le_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+
+    fn i32(&mut self) -> Result<i32, PacketDecodeError> {
+        Ok(i32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    /// reads the next type-length-value block; `None` at the end of the packet
+    fn extension(&mut self) -> Result<Option<(u8, &'a [u8])>, PacketDecodeError> {
+        if self.remaining() == 0 {
+            return Ok(None);
+        }
+        let tag = self.u8()?;
+        let len = self.u8()? as usize;
+        if self.remaining() < len {
+            return Err(PacketDecodeError::ExtensionOverrun {
+                tag,
+                len,
+                remaining: self.remaining(),
+            });
+        }
+        Ok(Some((tag, self.bytes(len)?)))
+    }
+}
+
+fn write_varint(buf: &mut Vec<u8>, mut value: usize) {
+    while value >= 0x80 {
+        buf.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+fn read_varint(data: &mut &[u8]) -> Option<usize> {
+    let mut value = 0;
+    for shift in (0..32).step_by(7) {
+        let (byte, rest) = data.split_first()?;
+        *data = rest;
+        value |= ((byte & 0x7f) as usize) << shift;
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+/// Run-length encoding of inputs: every run of equal inputs is its length and its xor with the
+/// previous run (or 0), both as LEB128. Held buttons make long runs, and pressing or releasing
+/// a button flips a few bits, so a run usually takes 2 or 3 bytes.
+///
+/// Only as many whole runs as fit into `max_len` bytes are written; returns the number of
+/// inputs written.
+fn encode_inputs(inputs: &[u16], buf: &mut Vec<u8>, max_len: usize) -> usize {
+    let start = buf.len();
+    let (mut written, mut previous) = (0, 0);
+    for run in inputs.chunk_by(|x, y| x == y) {
+        let mut encoded = vec![];
+        write_varint(&mut encoded, run.len());
+        write_varint(&mut encoded, (run[0] ^ previous) as usize);
+        if buf.len() - start + encoded.len() > max_len {
+            break;
+        }
+        buf.extend_from_slice(&encoded);
+        written += run.len();
+        previous = run[0];
+    }
+    written
+}
+
+fn decode_inputs(mut data: &[u8]) -> Result<Vec<u16>, PacketDecodeError> {
+    let mut inputs = vec![];
+    let mut previous = 0;
+    while !data.is_empty() {
+        let len = read_varint(&mut data).ok_or(PacketDecodeError::BadInputs)?;
+        let xor = read_varint(&mut data).ok_or(PacketDecodeError::BadInputs)?;
+        if len == 0 || inputs.len() + len > MAX_DECODED_INPUTS || xor > u16::MAX as usize {
+            return Err(PacketDecodeError::BadInputs);
+        }
+        previous ^= xor as u16;
+        inputs.extend(std::iter::repeat(previous).take(len));
+    }
+    Ok(inputs)
+}
+
+/// appends a type-length-value extension block to an encoded packet
+/// an extension longer than 255 bytes can't be written, and is left out
+fn write_extension(buf: &mut Vec<u8>, tag: u8, value: &[u8]) {
+    let Ok(len) = u8::try_from(value.len()) else {
+        println!(
+            "leaving out the extension {:#x} of {} bytes",
+            tag,
+            value.len()
+        );
+        return;
+    };
+    buf.push(tag);
+    buf.push(len);
+    buf.extend_from_slice(value);
+}
+
+impl NetworkPacket {
+    /// layout:
+    /// - 0: 0x6b, 1: player (filled by `send_packet`), 2: `PACKET_VERSION`, 3: reserved
+    /// - 4..8: id, 8: desyncdetect, 9: delay, 10: max_rollback, 11: input count
+    /// - inputs (u16 each, newest first; none if sent compactly), last_confirm (u32),
+    ///   sync (i32, `i32::MAX` for none)
+    /// - initial_max_rollback (u8)
+    /// - extension blocks: tag (u8), length (u8), value, until the end of the packet
+    ///
+    /// Legacy decoders ignore the bytes 1..4 and everything after `initial_max_rollback`,
+    /// so the packet stays readable for them.
+    fn encode(&self) -> Box<[u8]> {
+        let mut buf = Vec::with_capacity(MAX_PACKET_SIZE);
+        buf.extend_from_slice(&[0x6b, 0, PACKET_VERSION, 0]);
+        buf.extend_from_slice(&(self.id as u32).to_le_bytes());
+        buf.push(self.desyncdetect);
+        buf.push(self.delay);
+        buf.push(self.max_rollback);
+
+        let mut extensions = vec![];
+        if let Some((frame, hash)) = self.state_hash {
+            let mut value = [0; 12];
+            value[0..4].copy_from_slice(&(frame as u32).to_le_bytes());
+            value[4..12].copy_from_slice(&hash.to_le_bytes());
+            write_extension(&mut extensions, EXT_STATE_HASH, &value);
+        }
+        if let Some(resend_after) = self.resend_after {
+            write_extension(&mut extensions, EXT_RESEND, &[resend_after]);
+        }
+        if let Some((frame, delay)) = self.delay_decision {
+            let mut value = [delay; 5];
+            value[1..5].copy_from_slice(&(frame as u32).to_le_bytes());
+            write_extension(&mut extensions, EXT_DELAY_PROPOSAL, &value);
+        } else if let Some(delay) = self.delay_proposal {
+            write_extension(&mut extensions, EXT_DELAY_PROPOSAL, &[delay]);
+        }
+        if let Some(capabilities) = self.capabilities {
+            write_extension(&mut extensions, EXT_CAPABILITIES, &capabilities.encode());
+        }
+        if !self.delay_changes.is_empty() {
+            let mut value = vec![];
+            for (frame, delay) in self.delay_changes.iter() {
+                value.extend_from_slice(&(*frame as u32).to_le_bytes());
+                value.push(*delay);
+            }
+            write_extension(&mut extensions, EXT_DELAY_CHANGES, &value);
+        }
+        if let Some(window) = self.rollback_window {
+            write_extension(&mut extensions, EXT_ROLLBACK_WINDOW, &window);
+        }
+        if let Some(sequence) = self.sequence {
+            write_extension(&mut extensions, EXT_SEQUENCE, &sequence.to_le_bytes());
+        }
+        if let Some(nonce) = self.session_nonce {
+            write_extension(&mut extensions, EXT_SESSION_NONCE, &nonce);
+        }
+
+        // the inputs have to fit, the extensions are only extras
+        if HEADER_LEN + extensions.len() + 2 + TRAILER_LEN > MAX_PACKET_SIZE {
+            println!("leaving out {} bytes of extensions", extensions.len());
+            extensions.clear();
+        }
+        // the room left for the inputs, with the header of their extension block and the tag
+        // added by `SessionAuth::sign`
+        let room = MAX_PACKET_SIZE - (HEADER_LEN + extensions.len() + 2 + TRAILER_LEN);
+        let mut compact_inputs = vec![];
+        let (legacy_inputs, written) = match self.compact_inputs {
+            true => {
+                let max_len = room.min(u8::MAX as usize);
+                let written = encode_inputs(&self.inputs, &mut compact_inputs, max_len);
+                (&[][..], written)
+            }
+            false => {
+                let written = self.inputs.len().min(room / 2).min(u8::MAX as usize);
+                (&self.inputs[..written], written)
+            }
+        };
+        if written < self.inputs.len() {
+            println!("leaving out {} old inputs", self.inputs.len() - written);
+        }
+        buf.push(legacy_inputs.len() as u8); //inputs, confirms are the same length
+        for input in legacy_inputs.iter() {
+            buf.extend_from_slice(&input.to_le_bytes());
+        }
+
+        buf.extend_from_slice(&(self.last_confirm as u32).to_le_bytes());
+        buf.extend_from_slice(&self.sync.unwrap_or(i32::MAX).to_le_bytes());
+
+        // legacy decoders take any byte here as the initial max rollback, so it has to be
+        // present whenever something follows it
+        buf.push(
+            self.initial_max_rollback
+                .expect("versioned packets always carry initial_max_rollback"),
+        );
+        buf.extend_from_slice(&extensions);
+        // also sent with the legacy inputs, to tell that we can read compact ones
+        write_extension(&mut buf, EXT_COMPACT_INPUTS, &compact_inputs);
+
+        debug_assert!(buf.len() + TRAILER_LEN <= MAX_PACKET_SIZE);
+        buf.into_boxed_slice()
+    }
+
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    /// the frame and the delay decided by p1
+    pub fn delay_decision(&self) -> Option<(usize, u8)> {
+        self.delay_decision
+    }
+
+    /// the newest packet of the opponent the sender had received
+    pub fn last_confirm(&self) -> usize {
+        self.last_confirm
+    }
+
+    /// whether both packets tell the same, apart from what comes from the state of the game
+    /// (`desyncdetect` and `state_hash`), the nonce and the capabilities
+    pub fn same_netcode(&self, other: &Self) -> bool {
+        let strip = |x: &Self| {
+            Self {
+                desyncdetect: 0,
+                state_hash: None,
+                session_nonce: None,
+                capabilities: None,
+                ..x.clone()
+            }
+            .encode()
+        };
+        strip(self) == strip(other)
+    }
+
+    pub fn decode(d: &[u8]) -> Result<Self, PacketDecodeError> {
+        let mut r = PacketReader::new(d);
+        let packet_type = r.u8()?;
+        if packet_type != 0x6b {
+            return Err(PacketDecodeError::WrongType(packet_type));
+        }
+        let _player = r.u8()?;
+        let version = r.u8()?;
+        let _reserved = r.u8()?;
+
+        let id = r.u32()? as usize;
+        let desyncdetect = r.u8()?;
+        let delay = r.u8()?;
+        let max_rollback = r.u8()?;
+        let inputsize = r.u8()?;
+        let mut inputs = (0..inputsize)
+            .map(|_| r.u16())
+            .collect::<Result<Vec<_>, _>>()?;
+        let last_confirm = r.u32()? as usize;
+
+        let sync = match r.i32()? {
+            i32::MAX => None,
+            x => Some(x),
+        };
+
+        let initial_max_rollback = match version {
+            // the legacy layout only has an optional trailing byte, anything after it is junk
+            0 => (r.remaining() > 0).then(|| r.u8()).transpose()?,
+            // newer versions keep this layout and only add extensions, so read them as ours
+            _ => Some(
+                r.u8()
+                    .map_err(|_| PacketDecodeError::MissingInitialMaxRollback)?,
+            ),
+        };
+
+        let mut state_hash = None;
+        let mut resend_after = None;
+        let mut compact_inputs = false;
+        let mut delay_proposal = None;
+        let mut delay_decision = None;
+        let mut session_nonce = None;
+        let mut capabilities = None;
+        let mut delay_changes = vec![];
+        let mut rollback_window = None;
+        let mut sequence = None;
+        if version > 0 {
+            while let Some((tag, value)) = r.extension()? {
+                match tag {
+                    EXT_STATE_HASH if value.len() >= 12 => {
+                        let frame = u32::from_le_bytes(value[0..4].try_into().unwrap());
+                        let hash = u64::from_le_bytes(value[4..12].try_into().unwrap());
+                        state_hash = Some((frame as usize, hash));
+                    }
+                    EXT_RESEND if !value.is_empty() => resend_after = Some(value[0]),
+                    EXT_DELAY_PROPOSAL if value.len() >= 5 => {
+                        let frame = u32::from_le_bytes(value[1..5].try_into().unwrap());
+                        delay_decision = Some((frame as usize, value[0]));
+                    }
+                    EXT_DELAY_PROPOSAL if !value.is_empty() => delay_proposal = Some(value[0]),
+                    EXT_CAPABILITIES => capabilities = Capabilities::decode(value),
+                    EXT_ROLLBACK_WINDOW if value.len() >= 3 => {
+                        rollback_window = Some([value[0], value[1], value[2]])
+                    }
+                    EXT_SEQUENCE if value.len() >= 4 => {
+                        sequence = Some(u32::from_le_bytes(value[0..4].try_into().unwrap()))
+                    }
+                    EXT_DELAY_CHANGES => {
+                        delay_changes = value
+                            .chunks_exact(5)
+                            .map(|x| {
+                                let frame = u32::from_le_bytes(x[0..4].try_into().unwrap());
+                                (frame as usize, x[4])
+                            })
+                            .collect()
+                    }
+                    EXT_SESSION_NONCE if value.len() >= NONCE_LEN => {
+                        session_nonce = Some(value[..NONCE_LEN].try_into().unwrap())
+                    }
+                    EXT_COMPACT_INPUTS => {
+                        compact_inputs = true;
+                        if !value.is_empty() {
+                            inputs = decode_inputs(value)?;
+                        }
+                    }
+                    // unknown (or too short) extensions are skipped
+                    _ => (),
+                }
+            }
+        }
+
+        Ok(Self {
+            id,
+            desyncdetect,
+            delay,
+            max_rollback,
+            inputs,
+            last_confirm,
+            sync,
+            initial_max_rollback,
+            state_hash,
+            resend_after,
+            compact_inputs,
+            delay_proposal,
+            delay_decision,
+            session_nonce,
+            capabilities,
+            delay_changes,
+            rollback_window,
+            sequence,
+        })
+    }
+}
+
+/// Messages which aren't sent every frame, with the packet type 0x6f.
+///
+/// Soku ignores the packet types 0x6d..=0x80, so old versions of giuroll ignore them too.
+#[derive(Clone, Debug)]
+pub enum ControlMessage {
+    /// asks for the hashes of the regions `start..end` of a frame which did happen;
+    /// `end` may be past the last region
+    RegionHashRequest {
+        frame: usize,
+        start: usize,
+        end: usize,
+    },
+    /// `regions` are the regions `start..start + regions.len()` of `total` regions;
+    /// `total` is 0 if the frame is no longer known
+    RegionHashes {
+        frame: usize,
+        total: usize,
+        start: usize,
+        regions: Vec<RemoteRegion>,
+    },
+    /// p2 asks p1 for its state, since the frame `frame` is desynced
+    RecoveryRequest { frame: usize },
+    /// p1 will send its state at the start of `frame`
+    RecoveryStart { frame: usize },
+    /// `data` is the part `offset..offset + data.len()` of the snapshot of `total` bytes
+    RecoveryChunk {
+        frame: usize,
+        total: usize,
+        offset: usize,
+        data: Vec<u8>,
+    },
+    /// p2 has received every chunk before `first_missing`
+    RecoveryAck { frame: usize, first_missing: usize },
+    /// the recovery at `frame` is given up, or refused if `frame` is 0
+    RecoveryFailed { frame: usize },
+}
+
+#[derive(Clone, Debug)]
+pub struct RemoteRegion {
+    pub kind: Option<RegionKind>,
+    pub size: usize,
+    pub hash: u64,
+}
+
+impl ControlMessage {
+    /// 17 bytes each, so that a message fits into `MAX_PACKET_SIZE` with its tag
+    pub const REGIONS_PER_MESSAGE: usize = 21;
+    /// region hashes are sent over several frames, so that they don't flood the connection
+    pub const REGION_MESSAGES_PER_FRAME: usize = 4;
+
+    pub fn encode(&self) -> Box<[u8]> {
+        let mut buf = Vec::with_capacity(MAX_PACKET_SIZE);
+        buf.push(0x6f);
+        match self {
+            ControlMessage::RegionHashRequest { frame, start, end } => {
+                buf.push(1);
+                buf.extend_from_slice(&(*frame as u32).to_le_bytes());
+                buf.extend_from_slice(&(*start as u32).to_le_bytes());
+                buf.extend_from_slice(&((*end).min(u32::MAX as usize) as u32).to_le_bytes());
+            }
+            ControlMessage::RegionHashes {
+                frame,
+                total,
+                start,
+                regions,
+            } => {
+                buf.push(2);
+                buf.extend_from_slice(&(*frame as u32).to_le_bytes());
+                buf.extend_from_slice(&(*total as u32).to_le_bytes());
+                buf.extend_from_slice(&(*start as u32).to_le_bytes());
+                buf.push(regions.len() as u8);
+                for region in regions {
+                    let (tag, param) = region.kind.map_or((0xff, 0), |x| x.to_raw());
+                    buf.push(tag);
+                    buf.extend_from_slice(&param.to_le_bytes());
+                    buf.extend_from_slice(&(region.size as u32).to_le_bytes());
+                    buf.extend_from_slice(&region.hash.to_le_bytes());
+                }
+            }
+            ControlMessage::RecoveryRequest { frame } => {
+                buf.push(3);
+                buf.extend_from_slice(&(*frame as u32).to_le_bytes());
+            }
+            ControlMessage::RecoveryStart { frame } => {
+                buf.push(4);
+                buf.extend_from_slice(&(*frame as u32).to_le_bytes());
+            }
+            ControlMessage::RecoveryChunk {
+                frame,
+                total,
+                offset,
+                data,
+            } => {
+                buf.push(5);
+                buf.extend_from_slice(&(*frame as u32).to_le_bytes());
+                buf.extend_from_slice(&(*total as u32).to_le_bytes());
+                buf.extend_from_slice(&(*offset as u32).to_le_bytes());
+                buf.extend_from_slice(&(data.len() as u16).to_le_bytes());
+                buf.extend_from_slice(data);
+            }
+            ControlMessage::RecoveryAck {
+                frame,
+                first_missing,
+            } => {
+                buf.push(6);
+                buf.extend_from_slice(&(*frame as u32).to_le_bytes());
+                buf.extend_from_slice(&(*first_missing as u32).to_le_bytes());
+            }
+            ControlMessage::RecoveryFailed { frame } => {
+                buf.push(7);
+                buf.extend_from_slice(&(*frame as u32).to_le_bytes());
+            }
+        }
+        debug_assert!(buf.len() <= MAX_PACKET_SIZE);
+        buf.into_boxed_slice()
+    }
+
+    pub fn decode(d: &[u8]) -> Result<Self, PacketDecodeError> {
+        let mut r = PacketReader::new(d);
+        let packet_type = r.u8()?;
+        if packet_type != 0x6f {
+            return Err(PacketDecodeError::WrongType(packet_type));
+        }
+        match r.u8()? {
+            1 => Ok(ControlMessage::RegionHashRequest {
+                frame: r.u32()? as usize,
+                start: r.u32()? as usize,
+                end: r.u32()? as usize,
+            }),
+            2 => {
+                let frame = r.u32()? as usize;
+                let total = r.u32()? as usize;
+                let start = r.u32()? as usize;
+                let count = r.u8()?;
+                let regions = (0..count)
+                    .map(|_| {
+                        let tag = r.u8()?;
+                        let param = r.u32()?;
+                        Ok(RemoteRegion {
+                            kind: RegionKind::from_raw(tag, param),
+                            size: r.u32()? as usize,
+                            hash: r.u64()?,
+                        })
+                    })
+                    .collect::<Result<Vec<_>, _>>()?;
+                Ok(ControlMessage::RegionHashes {
+                    frame,
+                    total,
+                    start,
+                    regions,
+                })
+            }
+            3 => Ok(ControlMessage::RecoveryRequest {
+                frame: r.u32()? as usize,
+            }),
+            4 => Ok(ControlMessage::RecoveryStart {
+                frame: r.u32()? as usize,
+            }),
+            5 => {
+                let frame = r.u32()? as usize;
+                let total = r.u32()? as usize;
+                let offset = r.u32()? as usize;
+                let len = r.u16()? as usize;
+                Ok(ControlMessage::RecoveryChunk {
+                    frame,
+                    total,
+                    offset,
+                    data: r.bytes(len)?.to_vec(),
+                })
+            }
+            6 => Ok(ControlMessage::RecoveryAck {
+                frame: r.u32()? as usize,
+                first_missing: r.u32()? as usize,
+            }),
+            7 => Ok(ControlMessage::RecoveryFailed {
+                frame: r.u32()? as usize,
+            }),
+            x => Err(PacketDecodeError::UnknownControl(x)),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum FrameTimeData {
+    Empty,
+    LocalFirst(Instant),
+    RemoteFirst(Instant),
+    Done(i32),
+}
+
+/// Why `Netcoder::process_and_send` paused the game
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PauseReason {
+    /// the opponent hasn't confirmed our inputs for too long
+    FrameMissing,
+    /// the inputs of the opponent are too old to keep guessing
+    InputMissing,
+    /// waiting for the state of p1 (desync recovery)
+    Recovery,
+}
+
+pub struct Netcoder<T: Transport> {
+    pub transport: T,
+    pub is_p1: bool,
+
+    last_opponent_confirm: usize,
+
+    id: usize,
+
+    //ideally we shouldn't be keeping a separate input stack from the Rollbacker but for now it's what I have
+    opponent_inputs: Vec<Option<u16>>,
+    last_opponent_input: usize,
+
+    inputs: Vec<u16>,
+
+    send_times: HashMap<usize, Instant>,
+    recv_delays: HashMap<usize, Duration>,
+    real_rollback_to_be_showed: usize,
+
+    pub delay: usize,
+    pub max_rollback: usize,
+    pub display_stats: bool,
+    pub last_opponent_delay: usize,
+    pub initial_opponent_max_rollback: Option<usize>,
+    pub initial_my_max_rollback: usize,
+
+    past_frame_starts: Vec<FrameTimeData>,
+
+    /// received control messages, handled after the packets of the frame
+    control_messages: Vec<ControlMessage>,
+    /// the offset and skew between both clocks, read from the timing data of the packets
+    pub clock: ClockSync,
+
+    pub autodelay: Option<AutoDelay>,
+    /// collects the stats of the match, if they are exported
+    pub stats: Option<MatchStats>,
+    /// the key the packets are signed with, and the packets dropped for a wrong tag
+    pub auth: SessionAuth,
+    /// what we support, sent in the first packets of the battle
+    pub capabilities: Capabilities,
+    pub opponent_capabilities: Option<Capabilities>,
+    /// what both use, or why they can't play together, once the capabilities of the opponent
+    /// are known
+    pub agreement: Option<Result<Agreement, Incompatibility>>,
+    /// the delay the opponent's `AutoDelay` proposes
+    opponent_delay_proposal: Option<usize>,
+    /// the frame from which both sides use a delay, as decided by p1
+    delay_decision: Option<(usize, usize)>,
+    /// `delay_decision` until the frame comes
+    decision_to_apply: Option<(usize, usize)>,
+    /// the delay changes proposed by both sides, see `propose_delay`
+    delay_schedule: DelaySchedule,
+    /// adapts `max_rollback` to the latency if the opponent has it too
+    pub autorollback: Option<AutoRollback>,
+    /// the bounds and the latency of the `AutoRollback` of the opponent
+    opponent_rollback: Option<(RollbackBounds, Option<usize>)>,
+
+    /// sends a copy of every packet this long after it, if the opponent does it too, so that
+    /// a short burst of loss doesn't lose both
+    pub resend_after: Option<Duration>,
+    opponent_resend_after: Option<Duration>,
+    /// sends the inputs with `encode_inputs` if the opponent can read them
+    pub compact_inputs: bool,
+    opponent_reads_compact_inputs: bool,
+
+    /// the copy of the last packet, and when to send it, see `take_copy`
+    pending_copy: Option<(Box<[u8]>, Instant)>,
+
+    /// which packets of the opponent have arrived, by id
+    received_ids: Vec<bool>,
+    /// the packets of the opponent before this id have been counted by `lost_packets`
+    loss_checked_to: usize,
+    /// the packets of the opponent which never arrived
+    pub lost_packets: usize,
+    lost_packets_shown: usize,
+    /// the packets of the opponent dropped or partly ignored as they are malformed
+    pub malformed_packets: usize,
+    /// the packets of the opponent which arrived more than once, besides the expected copies
+    pub duplicate_packets: usize,
+    /// the packets of the opponent which arrived after a packet sent later
+    pub reordered_packets: usize,
+    /// the packets of the opponent which arrived too late for their inputs to be used
+    pub late_packets: usize,
+    /// the duplicate, reordered and late packets shown last
+    anomalies_shown: [usize; 3],
+    /// the lost, duplicate, reordered and late packets logged last
+    packets_logged: [usize; 4],
+    /// how many times each of the latest `EXT_SEQUENCE` numbers of the opponent has arrived
+    received_sequences: BTreeMap<u32, u8>,
+    /// the packets sent, see `EXT_SEQUENCE`
+    sent_packets: u32,
+
+    old_to_be_sent: Option<NetworkPacket>,
+    old_input: [bool; INPUT_KEYS_NUMBERS],
+
+    /// state hashes received from the opponent which haven't been compared yet
+    opponent_state_hashes: HashMap<usize, u64>,
+    /// old versions of giuroll only send the weather byte
+    opponent_sends_state_hashes: bool,
+    /// the region hashes of the first desynced frame, waiting for the ones of the opponent
+    region_report: Option<RegionReport>,
+    has_requested_region_hashes: bool,
+    /// region hash messages asked by the opponent which haven't been sent yet
+    region_hash_queue: VecDeque<Box<[u8]>>,
+
+    pub recovery_enabled: bool,
+    recovery: Option<Recovery>,
+    /// the opponent refused to recover, or the states can't be merged
+    recovery_given_up: bool,
+    last_recovered_frame: Option<usize>,
+    /// desyncs before this frame have been recovered
+    desync_check_from: usize,
+
+    /// the first frame which is likely to have desynced
+    pub likely_desynced: Option<usize>,
+    /// microseconds to be added to the frame time target, taken by `take_target_offset`
+    target_offset: i32,
+    /// set by every `process_and_send`
+    pub pause: Option<PauseReason>,
+    pub draw_ping: Option<i32>,
+    pub draw_rollback: Option<i32>,
+    pub draw_enemy_delay: Option<i32>,
+    /// the packets lost in the last second
+    pub draw_packet_loss: Option<i32>,
+    /// the duplicate, reordered and late packets in the last second
+    pub draw_packet_anomalies: Option<[i32; 3]>,
+    /// `ClockSync::offset` in 0.1 ms and `ClockSync::skew` in µs per frame, once a second
+    pub draw_clock: Option<(i32, i32)>,
+    /// the latest change of the desync recovery, to be shown
+    pub recovery_notice: Option<RecoveryNotice>,
+    /// a region report which has got every answer (or timed out), to be written
+    pub finished_region_report: Option<RegionReport>,
+}
+
+/// The packets are only sent once per frame; a packet contains all previous unconfirmed inputs; a lost "main" packet is not recovered whenever it's not neccesseary
+impl<T: Transport> Netcoder<T> {
+    pub fn new(transport: T, is_p1: bool, my_max_rollback: u8) -> Self {
+        Self {
+            transport,
+            is_p1,
+            last_opponent_confirm: 0,
+            inputs: Vec::new(),
+
+            opponent_inputs: Vec::new(),
+
+            send_times: HashMap::new(),
+            recv_delays: HashMap::new(),
+            real_rollback_to_be_showed: 0,
+
+            last_opponent_delay: 0,
+            last_opponent_input: 0,
+            id: 0,
+            delay: 0,
+            max_rollback: 6,
+            display_stats: false,
+            initial_opponent_max_rollback: None,
+            initial_my_max_rollback: my_max_rollback as usize,
+
+            past_frame_starts: Vec::new(),
+            control_messages: Vec::new(),
+
+            clock: ClockSync::new(),
+            autodelay: None,
+            stats: None,
+            auth: SessionAuth::new(),
+            capabilities: Capabilities::default(),
+            opponent_capabilities: None,
+            agreement: None,
+            opponent_delay_proposal: None,
+            delay_decision: None,
+            decision_to_apply: None,
+            delay_schedule: DelaySchedule::default(),
+            autorollback: None,
+            opponent_rollback: None,
+
+            resend_after: None,
+            opponent_resend_after: None,
+            pending_copy: None,
+
+            compact_inputs: true,
+            opponent_reads_compact_inputs: false,
+
+            received_ids: Vec::new(),
+            loss_checked_to: 0,
+            lost_packets: 0,
+            lost_packets_shown: 0,
+            malformed_packets: 0,
+            duplicate_packets: 0,
+            reordered_packets: 0,
+            late_packets: 0,
+            anomalies_shown: [0; 3],
+            packets_logged: [0; 4],
+            received_sequences: BTreeMap::new(),
+            sent_packets: 0,
+
+            old_to_be_sent: None,
+            old_input: [false; INPUT_KEYS_NUMBERS],
+
+            opponent_state_hashes: HashMap::new(),
+            opponent_sends_state_hashes: false,
+            region_report: None,
+            has_requested_region_hashes: false,
+            region_hash_queue: VecDeque::new(),
+
+            recovery_enabled: false,
+            recovery: None,
+            recovery_given_up: false,
+            last_recovered_frame: None,
+            desync_check_from: 0,
+
+            likely_desynced: None,
+            target_offset: 0,
+            pause: None,
+            draw_ping: None,
+            draw_rollback: None,
+            draw_enemy_delay: None,
+            draw_packet_loss: None,
+            draw_packet_anomalies: None,
+            draw_clock: None,
+            recovery_notice: None,
+            finished_region_report: None,
+        }
+    }
+
+    /// the accumulated correction of the frame time target since the last call
+    pub fn take_target_offset(&mut self) -> i32 {
+        std::mem::take(&mut self.target_offset)
+    }
+
+    /// the delay of the copies of packets, if both sides want them
+    pub fn negotiated_resend_after(&self) -> Option<Duration> {
+        Some(self.resend_after?.max(self.opponent_resend_after?))
+    }
+
+    /// the copy of the last packet and when to send it, if both sides want copies.
+    ///
+    /// It has to be sent with `Transport::send` while waiting for the next frame; it's dropped
+    /// by the next `process_and_send` otherwise.
+    pub fn take_copy(&mut self) -> Option<(Box<[u8]>, Instant)> {
+        self.pending_copy.take()
+    }
+
+    /// the delay both sides should use: the higher of the proposals of both `AutoDelay`s;
+    /// only ours if the opponent doesn't propose any
+    pub fn agreed_delay(&self) -> Option<usize> {
+        let mine = self.autodelay.as_ref()?.proposal()?;
+        Some(self.opponent_delay_proposal.map_or(mine, |x| x.max(mine)))
+    }
+
+    /// applies `agreed_delay` if it has changed since it was last applied.
+    /// Changing the delay stalls or skips inputs, so the game calls this only when it doesn't
+    /// matter, like during the start of a round.
+    ///
+    /// p1 decides the delay of both sides, which both use from `DELAY_DECISION_LEAD` frames
+    /// later on, so that they never play with different delays; p2 only proposes one.
+    pub fn apply_agreed_delay(&mut self) {
+        if !self.is_p1 {
+            return;
+        }
+        let Some(agreed) = self.agreed_delay() else {
+            return;
+        };
+        let autodelay = self.autodelay.as_mut().unwrap();
+        if autodelay.applied == Some(agreed) {
+            return;
+        }
+        autodelay.applied = Some(agreed);
+        println!("auto delay: {} -> {}", self.delay, agreed);
+        self.decide_delay(self.id + DELAY_DECISION_LEAD, agreed);
+    }
+
+    /// uses `delay` from `frame` on, which p1 sends to p2 to do the same
+    pub fn decide_delay(&mut self, frame: usize, delay: usize) {
+        if self.delay_decision != Some((frame, delay)) {
+            self.delay_decision = Some((frame, delay));
+            self.decision_to_apply = Some((frame, delay));
+        }
+    }
+
+    fn mark_received(&mut self, id: usize) {
+        while self.received_ids.len() <= id {
+            self.received_ids.push(false);
+        }
+        self.received_ids[id] = true;
+    }
+
+    /// proposes to change the delay of both sides to `delay`, from `delaychange::LEAD` frames
+    /// later; false if the opponent can't take it, or too many changes are waiting
+    pub fn propose_delay(&mut self, delay: usize) -> bool {
+        let understood = matches!(
+            self.agreement,
+            Some(Ok(x)) if x.extensions & 1 << EXT_DELAY_CHANGES != 0
+        );
+        understood && self.delay_schedule.propose(self.id, delay, self.is_p1)
+    }
+
+    /// the change of the delay which is going to be applied last, if any
+    pub fn pending_delay(&self) -> Option<DelayChange> {
+        self.delay_schedule.pending()
+    }
+
+    /// the bounds of `max_rollback` agreed by both sides, if both adapt it to the latency
+    pub fn rollback_bounds(&self) -> Option<RollbackBounds> {
+        let mine = self.autorollback.as_ref()?.bounds;
+        Some(RollbackBounds::agree(mine, self.opponent_rollback?.0))
+    }
+
+    fn delay_changes_to_send(&self) -> Vec<(usize, u8)> {
+        self.delay_schedule
+            .to_send(self.is_p1)
+            .into_iter()
+            .map(|x| (x.frame, x.delay as u8))
+            .collect()
+    }
+
+    fn sends_compact_inputs(&self) -> bool {
+        self.compact_inputs
+            && match self.agreement {
+                Some(Ok(x)) => x.compact_inputs,
+                // guessed from the packets of the opponent
+                _ => self.opponent_reads_compact_inputs,
+            }
+    }
+
+    /// the opponent compares the hashes of the states, unless it has told it can't
+    fn sends_state_hashes(&self) -> bool {
+        !matches!(self.agreement, Some(Ok(x)) if x.hash_algorithm != Some(HASH_FNV))
+    }
+
+    /// the snapshots of the opponent can be merged, unless it has told its layout differs
+    fn recovers(&self) -> bool {
+        self.recovery_enabled && !matches!(self.agreement, Some(Ok(x)) if !x.same_layout)
+    }
+
+    fn schedule_copy(&mut self, data: Box<[u8]>) {
+        let now = self.transport.now();
+        self.pending_copy = self.negotiated_resend_after().map(|x| (data, now + x));
+    }
+
+    fn handle_control_messages<G: Game>(&mut self, rollbacker: &mut Rollbacker<G>) {
+        for message in std::mem::take(&mut self.control_messages) {
+            match message {
+                ControlMessage::RecoveryRequest { .. }
+                | ControlMessage::RecoveryStart { .. }
+                | ControlMessage::RecoveryChunk { .. }
+                | ControlMessage::RecoveryAck { .. }
+                | ControlMessage::RecoveryFailed { .. } => {
+                    self.handle_recovery_message(message, rollbacker)
+                }
+                ControlMessage::RegionHashRequest { frame, start, end } => {
+                    let table = rollbacker.region_tables.iter().find(|x| x.frame == frame);
+                    self.region_hash_queue
+                        .extend(region_hash_messages(frame, start..end, table));
+                }
+                ControlMessage::RegionHashes {
+                    frame,
+                    total,
+                    start,
+                    regions,
+                } => {
+                    if let Some(report) = self.region_report.as_mut()
+                        && report.frame() == frame
+                    {
+                        report.add(total, start, regions);
+                    }
+                }
+            }
+        }
+
+        for _ in 0..ControlMessage::REGION_MESSAGES_PER_FRAME {
+            let Some(message) = self.region_hash_queue.pop_front() else {
+                break;
+            };
+            self.transport.send(&self.auth.sign(message));
+        }
+
+        // asks again for what is missing when the opponent stops answering
+        if let Some(report) = self.region_report.as_mut() {
+            for range in report.ranges_to_request() {
+                let message = ControlMessage::RegionHashRequest {
+                    frame: report.frame(),
+                    start: range.start,
+                    end: range.end,
+                };
+                send_control(&mut self.transport, &self.auth, message);
+            }
+        }
+
+        if self.region_report.as_ref().is_some_and(|x| x.is_done()) {
+            self.finished_region_report = self.region_report.take();
+        }
+
+        // only the first desync is reported; the later ones are likely caused by it
+        if let Some(frame) = self.likely_desynced
+            && self.opponent_sends_state_hashes
+            && !self.has_requested_region_hashes
+        {
+            self.has_requested_region_hashes = true;
+            match rollbacker.region_tables.iter().find(|x| x.frame == frame) {
+                Some(table) => {
+                    // the request is sent by the next call
+                    self.region_report = Some(RegionReport::new(table.clone()));
+                }
+                None => println!("region hashes of the desynced frame {} are gone", frame),
+            }
+        }
+
+        self.update_recovery(rollbacker);
+    }
+
+    fn handle_recovery_message<G: Game>(
+        &mut self,
+        message: ControlMessage,
+        rollbacker: &mut Rollbacker<G>,
+    ) {
+        let is_p1 = self.is_p1;
+        match message {
+            ControlMessage::RecoveryRequest { frame: desynced } if is_p1 => {
+                if !self.recovers() {
+                    send_control(
+                        &mut self.transport,
+                        &self.auth,
+                        ControlMessage::RecoveryFailed { frame: 0 },
+                    );
+                    return;
+                }
+                let frame = match self.recovery.as_ref().and_then(|x| x.frame()) {
+                    Some(frame) => frame,
+                    None => {
+                        let frame = rollbacker.game.frame_count() + self.delay + RECOVERY_MARGIN;
+                        println!(
+                            "desync recovery: desynced at {}, sending the state at {}",
+                            desynced, frame
+                        );
+                        rollbacker.snapshot_frame = Some(frame);
+                        rollbacker.snapshot = None;
+                        self.recovery = Some(Recovery::Snapshotting { frame });
+                        self.recovery_notice = Some(RecoveryNotice::InProgress(frame));
+                        frame
+                    }
+                };
+                send_control(
+                    &mut self.transport,
+                    &self.auth,
+                    ControlMessage::RecoveryStart { frame },
+                );
+            }
+            ControlMessage::RecoveryStart { frame } if !is_p1 => {
+                if !matches!(self.recovery, Some(Recovery::Requested { .. })) {
+                    return;
+                }
+                // the frame must not be confirmed yet, otherwise it can't be restored anymore
+                let oldest = rollbacker
+                    .guessed
+                    .first()
+                    .map_or(rollbacker.game.frame_count(), |x| x.prev_state.number());
+                if frame < oldest {
+                    println!("desync recovery: frame {} is already confirmed", frame);
+                    send_control(
+                        &mut self.transport,
+                        &self.auth,
+                        ControlMessage::RecoveryFailed { frame },
+                    );
+                    self.recovery = None;
+                    return;
+                }
+                rollbacker.hold_from = Some(frame);
+                self.recovery = Some(Recovery::Receiving {
+                    frame,
+                    receiver: SnapshotReceiver::new(),
+                });
+                self.recovery_notice = Some(RecoveryNotice::InProgress(frame));
+            }
+            ControlMessage::RecoveryChunk {
+                frame,
+                total,
+                offset,
+                data,
+            } if !is_p1 => match self.recovery.as_mut() {
+                Some(Recovery::Receiving {
+                    frame: receiving,
+                    receiver,
+                }) if *receiving == frame => receiver.add(total, offset, &data),
+                _ => {
+                    // our last acknowledgement may be lost
+                    if self.last_recovered_frame == Some(frame) {
+                        send_control(
+                            &mut self.transport,
+                            &self.auth,
+                            ControlMessage::RecoveryAck {
+                                frame,
+                                first_missing: total.div_ceil(RECOVERY_CHUNK_SIZE),
+                            },
+                        );
+                    }
+                }
+            },
+            ControlMessage::RecoveryAck {
+                frame,
+                first_missing,
+            } if is_p1 => {
+                if let Some(Recovery::Sending {
+                    frame: sending,
+                    sender,
+                }) = self.recovery.as_mut()
+                    && *sending == frame
+                {
+                    sender.ack(first_missing);
+                    if sender.is_done() {
+                        self.recovery = None;
+                        self.recovered(frame);
+                    }
+                }
+            }
+            ControlMessage::RecoveryFailed { frame } => {
+                if is_p1 {
+                    if self.recovery.as_ref().and_then(|x| x.frame()) != Some(frame) {
+                        return;
+                    }
+                    rollbacker.snapshot_frame = None;
+                    rollbacker.snapshot = None;
+                } else {
+                    // refused by p1
+                    self.recovery_given_up = true;
+                    rollbacker.hold_from = None;
+                }
+                println!("desync recovery at frame {} failed", frame);
+                self.recovery = None;
+                self.recovery_notice = Some(RecoveryNotice::Failed(frame));
+            }
+            _ => (),
+        }
+    }
+
+    fn update_recovery<G: Game>(&mut self, rollbacker: &mut Rollbacker<G>) {
+        if !self.is_p1
+            && self.recovers()
+            && !self.recovery_given_up
+            && self.opponent_sends_state_hashes
+            && let Some(desynced) = self.likely_desynced
+        {
+            let resend = match self.recovery {
+                None => true,
+                Some(Recovery::Requested { sent_at }) => self.id > sent_at + 120,
+                Some(_) => false,
+            };
+            if resend {
+                send_control(
+                    &mut self.transport,
+                    &self.auth,
+                    ControlMessage::RecoveryRequest { frame: desynced },
+                );
+                self.recovery = Some(Recovery::Requested { sent_at: self.id });
+            }
+        }
+
+        match self.recovery.as_mut() {
+            Some(Recovery::Snapshotting { frame }) => {
+                if let Some(data) = rollbacker.snapshot.take() {
+                    println!("desync recovery: sending {} bytes", data.len());
+                    self.recovery = Some(Recovery::Sending {
+                        frame: *frame,
+                        sender: SnapshotSender::new(data),
+                    });
+                }
+            }
+            Some(Recovery::Sending { frame, sender }) => {
+                let total = sender.total();
+                for (offset, data) in sender.next_chunks(16) {
+                    send_control(
+                        &mut self.transport,
+                        &self.auth,
+                        ControlMessage::RecoveryChunk {
+                            frame: *frame,
+                            total,
+                            offset,
+                            data: data.to_vec(),
+                        },
+                    );
+                }
+            }
+            Some(Recovery::Receiving { frame, receiver }) => {
+                let frame = *frame;
+                if receiver.poll() {
+                    send_control(
+                        &mut self.transport,
+                        &self.auth,
+                        ControlMessage::RecoveryAck {
+                            frame,
+                            first_missing: receiver.first_missing(),
+                        },
+                    );
+                }
+
+                let held = rollbacker
+                    .guessed
+                    .first_mut()
+                    .filter(|x| x.prev_state.number() == frame);
+                let result = if let Some(held) = held
+                    && receiver.is_done()
+                {
+                    held.prev_state
+                        .merge_snapshot(receiver.data())
+                        .map(|_| held.force_rollback = true)
+                } else if receiver.is_timed_out() {
+                    Err(MergeError::Invalid("timed out".to_string()))
+                } else {
+                    return;
+                };
+                let chunks = receiver.first_missing();
+
+                rollbacker.hold_from = None;
+                self.recovery = None;
+                match result {
+                    Ok(()) => {
+                        send_control(
+                            &mut self.transport,
+                            &self.auth,
+                            ControlMessage::RecoveryAck {
+                                frame,
+                                first_missing: chunks,
+                            },
+                        );
+                        self.last_recovered_frame = Some(frame);
+                        self.recovered(frame);
+                    }
+                    Err(e) => {
+                        println!("desync recovery at frame {} failed: {}", frame, e);
+                        send_control(
+                            &mut self.transport,
+                            &self.auth,
+                            ControlMessage::RecoveryFailed { frame },
+                        );
+                        self.recovery_given_up = true;
+                        self.recovery_notice = Some(match e {
+                            MergeError::DifferentLayout(_) => RecoveryNotice::Unrecoverable(frame),
+                            MergeError::Invalid(_) => RecoveryNotice::Failed(frame),
+                        });
+                    }
+                }
+            }
+            Some(Recovery::Requested { .. }) | None => (),
+        }
+    }
+
+    /// the states from `frame` are the same again
+    fn recovered(&mut self, frame: usize) {
+        println!("desync recovered at frame {}", frame);
+        self.likely_desynced = None;
+        self.desync_check_from = frame;
+        self.opponent_state_hashes.retain(|x, _| *x >= frame);
+        self.recovery_notice = Some(RecoveryNotice::Done(frame));
+    }
+
+    /// compares the state hashes known by both sides, and records the first diverged frame
+    fn check_state_hashes<G: Game>(&mut self, rollbacker: &Rollbacker<G>) {
+        let mut compared = vec![];
+        let from = self.desync_check_from;
+        self.opponent_state_hashes.retain(|x, _| *x >= from);
+        for (frame, remote) in self.opponent_state_hashes.iter() {
+            let Some(local) = rollbacker.state_hashes.get(frame) else {
+                continue;
+            };
+            compared.push(*frame);
+            if local != remote {
+                #[cfg(feature = "logtofile")]
+                info!(
+                    "DESYNC at frame {}: local hash: {:016x}, remote hash: {:016x}",
+                    frame, local, remote
+                );
+                self.likely_desynced = Some(self.likely_desynced.map_or(*frame, |x| x.min(*frame)));
+            }
+        }
+        for frame in compared {
+            self.opponent_state_hashes.remove(&frame);
+        }
+        // the opponent hashes frames which may never be confirmed here (e.g. the end of round)
+        let oldest = self.id.saturating_sub(600);
+        self.opponent_state_hashes.retain(|x, _| *x >= oldest);
+    }
+
+    fn refresh_clock(&mut self) {
+        self.draw_clock = Some((
+            (self.clock.offset_at(self.id) / 100.0) as i32,
+            self.clock.skew as i32,
+        ));
+    }
+
+    fn log_clock(&self) {
+        let line = format!(
+            "clock at frame {}: offset {:.0}us, drift {:.2}us/frame, skew {:.2}us/frame, {} samples",
+            self.id,
+            self.clock.offset_at(self.id),
+            self.clock.drift,
+            self.clock.skew,
+            self.clock.samples
+        );
+        println!("{}", line);
+        #[cfg(feature = "logtofile")]
+        info!("{}", line);
+    }
+
+    fn refresh_packet_loss(&mut self) {
+        self.draw_packet_loss = Some((self.lost_packets - self.lost_packets_shown) as i32);
+        self.lost_packets_shown = self.lost_packets;
+        let anomalies = self.packet_anomalies();
+        self.draw_packet_anomalies = Some(std::array::from_fn(|x| {
+            (anomalies[x] - self.anomalies_shown[x]) as i32
+        }));
+        self.anomalies_shown = anomalies;
+    }
+
+    fn packet_anomalies(&self) -> [usize; 3] {
+        [
+            self.duplicate_packets,
+            self.reordered_packets,
+            self.late_packets,
+        ]
+    }
+
+    /// logs the packets of the opponent lost, duplicated, reordered or late, if any more were
+    /// since the last time
+    pub fn log_packets(&mut self) {
+        let [duplicate, reordered, late] = self.packet_anomalies();
+        let packets = [self.lost_packets, duplicate, reordered, late];
+        if packets == self.packets_logged {
+            return;
+        }
+        self.packets_logged = packets;
+        let line = format!(
+            "packets of the opponent at frame {}: {} lost, {} duplicated, {} reordered, {} late",
+            self.id, packets[0], packets[1], packets[2], packets[3]
+        );
+        println!("{}", line);
+        #[cfg(feature = "logtofile")]
+        info!("{}", line);
+    }
+
+    /// counts an arrival of the packet with the `EXT_SEQUENCE` number `sequence`
+    fn count_sequence(&mut self, sequence: u32) {
+        // with copies, every packet is expected twice
+        let expected = 1 + self.negotiated_resend_after().is_some() as u8;
+        let newest = self.received_sequences.last_key_value().map(|x| *x.0);
+        let arrivals = self.received_sequences.entry(sequence).or_insert(0);
+        *arrivals = arrivals.saturating_add(1);
+        let arrivals = *arrivals;
+        if arrivals > expected {
+            self.duplicate_packets += 1;
+            if let Some(stats) = self.stats.as_mut() {
+                stats.current.duplicate_packets += 1;
+            }
+        } else if arrivals == 1 && newest.is_some_and(|x| x > sequence) {
+            self.reordered_packets += 1;
+            if let Some(stats) = self.stats.as_mut() {
+                stats.current.reordered_packets += 1;
+            }
+        }
+        let newest = newest.map_or(sequence, |x| x.max(sequence));
+        while let Some(x) = self.received_sequences.first_entry()
+            && *x.key() + SEQUENCE_WINDOW < newest
+        {
+            x.remove();
+        }
+    }
+
+    fn refresh_ping(&mut self) {
+        if self.display_stats && self.id > 90 {
+            let now = self.transport.now();
+            let max = ((self.id - 90)..self.id)
+                .map(|a| match self.recv_delays.get(&a) {
+                    Some(x) => x.as_millis(),
+                    None => now
+                        .saturating_duration_since(self.send_times[&a])
+                        .as_millis(),
+                })
+                .max()
+                .unwrap();
+            let max = (max / 2) as i32;
+
+            self.draw_ping = Some(max);
+        }
+    }
+
+    /// returns whether or not we are allowed to proceed based on the confirmations we received
+    /// and sends the following frame to the opponent
+    pub fn process_and_send<G: Game>(
+        &mut self,
+        rollbacker: &mut Rollbacker<G>,
+        current_input: [bool; INPUT_KEYS_NUMBERS],
+    ) -> u32 {
+        let function_start_time = self.transport.now();
+        self.transport
+            .frame_started(function_start_time, &current_input, self.delay);
+
+        while self.past_frame_starts.len() <= self.id {
+            self.past_frame_starts.push(FrameTimeData::Empty);
+        }
+
+        let is_p1 = self.is_p1;
+
+        while let Some((data, time)) = self.transport.recv() {
+            let packet = match data.first() {
+                Some(0x6b) => match NetworkPacket::decode(&data) {
+                    // the tag is an unknown extension to the decoder
+                    Ok(x) => {
+                        if let Some(nonce) = x.session_nonce {
+                            self.auth.add_opponent_nonce(nonce, is_p1);
+                        }
+                        if self.auth.verify(&data).is_none() {
+                            // the packets of the opponent are dropped too until the key is
+                            // settled, but they haven't been lost
+                            if !self.auth.verified && x.id <= self.id + 20 {
+                                self.mark_received(x.id);
+                            }
+                            continue;
+                        }
+                        x
+                    }
+                    Err(e) => {
+                        println!("dropping malformed packet: {}", e);
+                        self.malformed_packets += 1;
+                        continue;
+                    }
+                },
+                Some(0x6f) => {
+                    let Some(len) = self.auth.verify(&data) else {
+                        continue;
+                    };
+                    match ControlMessage::decode(&data[..len]) {
+                        Ok(x) => self.control_messages.push(x),
+                        Err(e) => {
+                            println!("dropping malformed control message: {}", e);
+                            self.malformed_packets += 1;
+                        }
+                    }
+                    continue;
+                }
+                _ => continue,
+            };
+
+            if packet.id > self.id + 20 {
+                //these are probably packets comming from the last round, we better avoid them
+
+                continue;
+            }
+
+            // overtaken by newer packets, and so old that the game would have paused waiting
+            // for it, see the pause for `InputMissing`
+            if !self.received_ids.get(packet.id).copied().unwrap_or(false)
+                && packet.id < self.last_opponent_input
+                && self.id
+                    > packet.id
+                        + (self.max_rollback + self.delay.max(self.last_opponent_delay)).min(15)
+            {
+                self.late_packets += 1;
+                if let Some(stats) = self.stats.as_mut() {
+                    stats.current.late_packets += 1;
+                }
+            }
+            self.mark_received(packet.id);
+            if let Some(sequence) = packet.sequence {
+                self.count_sequence(sequence);
+            }
+            self.opponent_resend_after =
+                packet.resend_after.map(|x| Duration::from_millis(x as u64));
+            self.opponent_reads_compact_inputs = packet.compact_inputs;
+            if let Some(capabilities) = packet.capabilities
+                && self.opponent_capabilities.is_none()
+            {
+                let agreement = negotiate(&self.capabilities, &capabilities);
+                match &agreement {
+                    Ok(x) => println!("agreed with the opponent: {:?}", x),
+                    Err(e) => println!("{}", e),
+                }
+                self.opponent_capabilities = Some(capabilities);
+                self.agreement = Some(agreement);
+            }
+            if let Some(delay) = packet.delay_proposal {
+                self.opponent_delay_proposal = Some((delay as usize).min(MAX_DELAY));
+            }
+            if let Some((frame, delay)) = packet.delay_decision
+                && !is_p1
+            {
+                self.decide_delay(frame, (delay as usize).min(MAX_DELAY));
+            }
+            if let Some([min, max, latency]) = packet.rollback_window {
+                self.opponent_rollback = Some((
+                    RollbackBounds::new(min as usize, max as usize),
+                    (latency != 0xff).then_some(latency as usize),
+                ));
+            }
+            for (frame, delay) in packet.delay_changes.iter() {
+                let change = DelayChange {
+                    frame: *frame,
+                    delay: *delay as usize,
+                    from_p1: !is_p1,
+                };
+                if self.delay_schedule.receive(self.id, change) {
+                    println!("the delay change at frame {} has come too late", frame);
+                }
+            }
+
+            // time how long it took us to handlne that frame.
+            // If we did not handle it in time we just send a -1000, meaning the opponent will slow down by a 1000 microseconds,
+            // later on it should be worth to send information about frames ariving way too late,
+            // that would make the opponent pause, or severely slow down for multiple frames
+
+            //todo, handle time data packets not ariving at all, by taking the time of arrival of the subsequent packet
+
+            if packet.id >= self.opponent_inputs.len() {
+                if !is_p1 {
+                    //self.delay = packet.delay as usize;
+                    self.max_rollback = packet.max_rollback as usize;
+                }
+
+                if self.display_stats {
+                    self.draw_enemy_delay = Some(packet.delay as i32);
+                } else {
+                    self.draw_enemy_delay = None;
+                }
+
+                self.last_opponent_delay = packet.delay as usize;
+
+                // is the first arrival of the newest packet
+                let last = self
+                    .past_frame_starts
+                    .get(packet.id)
+                    .cloned()
+                    .unwrap_or(FrameTimeData::Empty);
+
+                match last {
+                    //bug! this value is set to -1000 even if we are less than 1000 microseconds from completing out frame, which is possible only for targets with
+                    // less than 1000 microsecond ping. nevertheless it should be fixed at some point
+                    FrameTimeData::Empty => {
+                        //let r = if self.id + 1 < packet.id {
+                        //    -((time.elapsed().as_micros()) as i128 / 100)
+                        //} else {
+                        //    -((time.elapsed().as_micros()) as i128 / 1000)
+                        //};
+
+                        while self.past_frame_starts.len() <= packet.id {
+                            self.past_frame_starts.push(FrameTimeData::Empty);
+                        }
+
+                        self.past_frame_starts[packet.id] = FrameTimeData::RemoteFirst(time);
+                        //Some(r)
+                    }
+                    FrameTimeData::LocalFirst(x) => {
+                        let r = time
+                            .checked_duration_since(x)
+                            .unwrap_or_else(|| {
+                                {
+                                    {
+                                        x.checked_duration_since(time)
+                                            .expect("either of these opperation should succeed")
+                                    }
+                                }
+                            })
+                            .as_micros() as i128;
+                        //info!("time passed: {}", r);
+
+                        self.past_frame_starts[packet.id] = FrameTimeData::Done(r as i32);
+
+                        //Some(r)
+                    }
+
+                    FrameTimeData::RemoteFirst(_) => {
+                        //info!("same frame received twice");
+                    }
+                    FrameTimeData::Done(_) => (),
+                };
+
+                //if let Some(my_diff) = my_diff {
+                //    while self.past_frame_starts.len() <= packet.id {
+                //        self.past_frame_starts.push(FrameTimeData::Empty);
+                //    }
+                //    self.past_frame_starts[packet.id] = FrameTimeData::Done(my_diff as i32);
+                //}
+
+                // handle opponents timing data
+                // `sync` is when our packet of `sync_frame` arrived, from the start of that frame
+                // there; minus the same measured here, the latency cancels out, and half of the
+                // difference is how far the opponent is behind
+                let sync_frame = packet.id.saturating_sub(TIME_SYNC_LAG);
+                if let Some(remote) = packet.sync
+                    && let Some(FrameTimeData::Done(local)) = self.past_frame_starts.get(sync_frame)
+                {
+                    self.clock
+                        .add_sample(sync_frame, (*local as f64 - remote as f64) / 2.0);
+                }
+
+                if let Some((frame, hash)) = packet.state_hash {
+                    self.opponent_sends_state_hashes = true;
+                    self.opponent_state_hashes.insert(frame, hash);
+                } else if !self.opponent_sends_state_hashes {
+                    // fallback for old versions: only the weather can be compared
+                    let weather_frame = packet.id.saturating_sub(20);
+                    let weather_remote = packet.desyncdetect;
+                    let weather_local = rollbacker
+                        .weathers
+                        .get(&weather_frame)
+                        .cloned()
+                        .unwrap_or(0);
+                    if weather_remote != weather_local {
+                        //#[cfg(feature = "allocconsole")]
+                        //println!("desync");
+                        self.likely_desynced = Some(weather_frame);
+                        #[cfg(feature = "logtofile")]
+                        info!(
+                            "DESYNC: local: {}, remote: {}",
+                            weather_local, weather_remote
+                        )
+                    } else {
+                        self.likely_desynced = None;
+                    }
+                }
+            }
+
+            if let Some(initial_opponent_max_rollback) = packet.initial_max_rollback {
+                let initial_opponent_max_rollback = initial_opponent_max_rollback as usize;
+                self.initial_opponent_max_rollback = Some(initial_opponent_max_rollback);
+                // with adaptive max rollbacks, p2 uses the one chosen by p1 instead
+                if self.rollback_bounds().is_none() {
+                    self.max_rollback =
+                        agree(initial_opponent_max_rollback, self.initial_my_max_rollback);
+                }
+            }
+
+            let latest = packet.id as usize; //last delay
+            while self.opponent_inputs.len() <= latest as usize {
+                self.opponent_inputs.push(None);
+            }
+            let mut fr = latest;
+
+            self.last_opponent_input = self.last_opponent_input.max(packet.id);
+
+            // the opponent can't have received packets we haven't sent
+            if packet.last_confirm > self.id {
+                println!(
+                    "packet {} confirms our packet {}, which wasn't sent",
+                    packet.id, packet.last_confirm
+                );
+                self.malformed_packets += 1;
+            }
+            let last_confirm = packet.last_confirm.min(self.id);
+            for a in (self.last_opponent_confirm + 1)..=last_confirm {
+                let Some(sent) = self.send_times.get(&a) else {
+                    continue;
+                };
+                let x = time.saturating_duration_since(*sent);
+                self.recv_delays.insert(a, x);
+                if let Some(autodelay) = self.autodelay.as_mut() {
+                    autodelay.add_rtt(x);
+                }
+                if let Some(autorollback) = self.autorollback.as_mut() {
+                    autorollback.add_rtt(x);
+                }
+                if let Some(stats) = self.stats.as_mut() {
+                    stats.add_rtt(x);
+                }
+            }
+
+            self.last_opponent_confirm = self.last_opponent_confirm.max(last_confirm);
+
+            for a in packet.inputs {
+                if self.opponent_inputs.get(fr) == Some(&None) {
+                    //println!("{:?}", self.send_times[fr].elapsed());
+
+                    // rollbacking to frame 0 causes problems (such as crash)
+                    let inp_a = match fr {
+                        0 => 0,
+                        _ => a,
+                    };
+
+                    self.opponent_inputs[fr] = Some(inp_a);
+
+                    // todo: move into it's own function
+
+                    let inp = (0..INPUT_KEYS_NUMBERS)
+                        .map(|x| (inp_a & (1 << x)) > 0)
+                        .collect::<Vec<_>>()
+                        .try_into()
+                        .unwrap();
+                    rollbacker.enemy_inputs.insert(inp, fr);
+                }
+
+                if fr == 0 {
+                    break;
+                }
+                fr -= 1;
+            }
+        }
+
+        // also while paused, so that the recovery can progress
+        self.handle_control_messages(rollbacker);
+
+        if let Some((frame, delay)) = self.decision_to_apply
+            && self.id >= frame
+        {
+            println!("delay: {} -> {}", self.delay, delay);
+            self.delay = delay;
+            self.decision_to_apply = None;
+        }
+        if let Some(delay) = self.delay_schedule.take_due(self.id) {
+            println!(
+                "delay changed at frame {}: {} -> {}",
+                self.id, self.delay, delay
+            );
+            self.delay = delay;
+        }
+
+        // merge current input with the inputs from the time when the game was paused
+        for (index, x) in current_input.into_iter().enumerate() {
+            self.old_input[index] |= x;
+        }
+        // a packet still missing when 10 newer ones have arrived is taken as lost
+        while self.loss_checked_to + 10 < self.last_opponent_input {
+            if !self.received_ids[self.loss_checked_to] {
+                self.lost_packets += 1;
+                if let Some(stats) = self.stats.as_mut() {
+                    stats.current.lost_packets += 1;
+                }
+            }
+            self.loss_checked_to += 1;
+        }
+
+        if self.display_stats {
+            if self.id % 60 == 0 {
+                self.refresh_ping();
+                self.refresh_packet_loss();
+                self.refresh_clock();
+            }
+        } else {
+            self.draw_ping = None;
+            self.draw_packet_loss = None;
+            self.draw_packet_anomalies = None;
+            self.draw_clock = None;
+        }
+
+        let pause = if self.id > self.last_opponent_confirm + 30 {
+            //crate::TARGET_OFFSET.fetch_add(1000 * m as i32, Relaxed);
+            println!(
+                "frame is missing: id: {}, confirm: {}",
+                self.id, self.last_opponent_confirm
+            );
+            if self.display_stats {
+                self.refresh_ping();
+            }
+            Some(PauseReason::FrameMissing)
+        } else if self.id
+            > self.last_opponent_input
+                + (self.max_rollback + self.delay.max(self.last_opponent_delay)).min(15)
+        {
+            //crate::TARGET_OFFSET.fetch_add(1000 * m as i32, Relaxed);
+            println!(
+                "frame is missing for reason 2: id: {}, confirm: {}",
+                self.id, self.last_opponent_confirm
+            );
+            if self.display_stats {
+                self.refresh_ping();
+                self.real_rollback_to_be_showed = self
+                    .real_rollback_to_be_showed
+                    .max(self.id - self.last_opponent_input - 1 - self.delay);
+                self.draw_rollback = Some(self.real_rollback_to_be_showed as i32);
+            }
+            Some(PauseReason::InputMissing)
+        } else if let Some(Recovery::Receiving { frame, .. }) = self.recovery
+            && rollbacker.game.frame_count() > frame + 10
+        {
+            // don't go too far from the held frame while waiting for the state of p1
+            Some(PauseReason::Recovery)
+        } else {
+            None
+        };
+        self.pause = pause;
+        if let Some(stats) = self.stats.as_mut() {
+            stats.current.delay = self.delay;
+            stats.current.opponent_delay = self.last_opponent_delay;
+            stats.current.max_rollback = self.max_rollback;
+            stats.current.desynced = self.likely_desynced.is_some();
+            if stats.desynced_at.is_none() {
+                stats.desynced_at = self.likely_desynced;
+            }
+            if let Some(reason) = pause {
+                stats.add_pause(reason);
+            }
+        }
+        if pause.is_some() {
+            let compact_inputs = self.sends_compact_inputs();
+            let delay_changes = self.delay_changes_to_send();
+            if let Some(old_to_be_sent) = self.old_to_be_sent.as_mut() {
+                old_to_be_sent.last_confirm =
+                    (self.last_opponent_input).min(old_to_be_sent.id + 30);
+                old_to_be_sent.max_rollback = self.max_rollback as u8;
+                old_to_be_sent.compact_inputs = compact_inputs;
+                old_to_be_sent.session_nonce = self.auth.nonce_to_send();
+                old_to_be_sent.delay_changes = delay_changes;
+                old_to_be_sent.sequence = Some(self.sent_packets);
+                self.sent_packets = self.sent_packets.wrapping_add(1);
+                let sent = send_packet(
+                    &mut self.transport,
+                    &self.auth,
+                    is_p1,
+                    old_to_be_sent.encode(),
+                );
+                self.schedule_copy(sent);
+            }
+            return 0;
+        }
+
+        let input_head = self.id;
+
+        let input_range = self.last_opponent_confirm..=input_head;
+        let merged_current_input = self.old_input;
+        self.old_input = [false; INPUT_KEYS_NUMBERS];
+
+        // do not override existing inputs; this can happen when delay is changed
+        while rollbacker.self_inputs.len() <= input_head {
+            // rollbacking to frame 0 causes problems (such as crash)
+            let index = rollbacker.self_inputs.len();
+            rollbacker.self_inputs.push(match index {
+                0 => [false; INPUT_KEYS_NUMBERS],
+                _ => merged_current_input,
+            });
+        }
+
+        while self.inputs.len() <= input_head {
+            // rollbacking to frame 0 causes problems (such as crash)
+            let index = self.inputs.len();
+            self.inputs.push(input_to_accum(&match index {
+                0 => [false; INPUT_KEYS_NUMBERS],
+                _ => merged_current_input,
+            }));
+        }
+
+        let mut ivec = self.inputs[input_range.clone()].to_vec();
+        ivec.reverse();
+
+        let past = match self
+            .past_frame_starts
+            .get(self.id.saturating_sub(TIME_SYNC_LAG))
+        {
+            Some(FrameTimeData::Done(x)) => Some(*x),
+            _ => None,
+        };
+
+        let to_be_sent = NetworkPacket {
+            id: self.id,
+            desyncdetect: rollbacker
+                .weathers
+                .get(&(self.id.saturating_sub(20)))
+                .cloned()
+                .unwrap_or(0),
+            delay: self.delay as u8,
+            max_rollback: self.max_rollback as u8,
+            inputs: ivec,
+            last_confirm: (self.last_opponent_input).min(self.id + 30),
+            sync: past,
+            // resent in every packet: the negotiation is idempotent, and the byte has to be
+            // there for the extensions to follow
+            initial_max_rollback: Some(self.initial_my_max_rollback as u8),
+            state_hash: rollbacker
+                .state_hashes
+                .keys()
+                .max()
+                .filter(|_| self.sends_state_hashes())
+                .map(|frame| (*frame, rollbacker.state_hashes[frame])),
+            resend_after: self.resend_after.map(|x| x.as_millis().clamp(1, 255) as u8),
+            compact_inputs: self.sends_compact_inputs(),
+            delay_proposal: self
+                .autodelay
+                .as_ref()
+                .and_then(|x| x.proposal())
+                .map(|x| x as u8),
+            delay_decision: self
+                .delay_decision
+                .filter(|_| is_p1)
+                .map(|(frame, delay)| (frame, delay as u8)),
+            session_nonce: self.auth.nonce_to_send(),
+            capabilities: (self.id < CAPABILITY_FRAMES || self.opponent_capabilities.is_none())
+                .then_some(self.capabilities),
+            delay_changes: self.delay_changes_to_send(),
+            sequence: Some(self.sent_packets),
+            rollback_window: self.autorollback.as_ref().map(|x| {
+                [
+                    x.bounds.min as u8,
+                    x.bounds.max as u8,
+                    x.latency().map_or(0xff, |x| x as u8),
+                ]
+            }),
+        };
+        self.sent_packets = self.sent_packets.wrapping_add(1);
+        self.old_to_be_sent = Some(to_be_sent.clone());
+
+        let sent = send_packet(&mut self.transport, &self.auth, is_p1, to_be_sent.encode());
+        self.schedule_copy(sent);
+        let now = self.transport.now();
+        self.send_times.insert(input_head, now);
+
+        let m = rollbacker.start();
+        self.check_state_hashes(rollbacker);
+
+        let diff = self.id as i64 - rollbacker.game.frame_count() as i64;
+
+        let m = match diff.cmp(&(self.delay as i64)) {
+            std::cmp::Ordering::Less => m.saturating_sub(1),
+            std::cmp::Ordering::Greater => m + 1,
+            std::cmp::Ordering::Equal => m,
+        };
+
+        //println!("m: {m}");
+
+        //if rollbacker.guessed.len() > 13 {
+        //    panic!("WHAT 13");
+        //}
+
+        if self.display_stats {
+            self.real_rollback_to_be_showed = rollbacker
+                .guessed
+                .len()
+                .max(self.real_rollback_to_be_showed);
+            if self.id % 60 == 0 {
+                self.draw_rollback = Some(self.real_rollback_to_be_showed as i32);
+                self.real_rollback_to_be_showed = 0;
+            }
+        } else {
+            self.draw_rollback = None;
+            self.real_rollback_to_be_showed = 0;
+        }
+
+        if let Some(autodelay) = self.autodelay.as_mut() {
+            autodelay.add_rollback(rollbacker.guessed.len(), self.delay);
+            autodelay.update(self.delay);
+        }
+        if let Some(autorollback) = self.autorollback.as_mut() {
+            autorollback.update();
+        }
+        if is_p1 && let Some(bounds) = self.rollback_bounds() {
+            let mine = self.autorollback.as_ref().unwrap().latency();
+            let theirs = self.opponent_rollback.unwrap().1;
+            if let Some(latency) = mine.max(theirs) {
+                self.max_rollback = bounds.window(latency, self.delay, self.last_opponent_delay);
+            }
+        }
+        if let Some(stats) = self.stats.as_mut() {
+            stats.add_frame(self.id, rollbacker.guessed.len());
+        }
+
+        self.target_offset += self.clock.correction(self.id);
+        if self.id % 600 == 0 && self.clock.samples > 0 {
+            self.log_clock();
+        }
+        if self.id % 600 == 0 {
+            self.log_packets();
+        }
+
+        {
+            //todo: consider moving to it's own function
+            match self.past_frame_starts[self.id].clone() {
+                FrameTimeData::Empty => {
+                    self.past_frame_starts[self.id] = FrameTimeData::LocalFirst(function_start_time)
+                }
+                FrameTimeData::LocalFirst(_) => todo!("should be unreachable"),
+                FrameTimeData::RemoteFirst(x) => {
+                    self.past_frame_starts[self.id] = FrameTimeData::Done(
+                        x.saturating_duration_since(function_start_time).as_micros() as i32,
+                    )
+                }
+                FrameTimeData::Done(_) => (),
+            }
+
+            self.id += 1;
+            m as u32
+        }
+    }
+}
+
+/// sends an encoded `NetworkPacket`, with the player byte filled and signed; returns the packet
+/// as sent
+fn send_packet(
+    transport: &mut impl Transport,
+    auth: &SessionAuth,
+    is_p1: bool,
+    mut data: Box<[u8]>,
+) -> Box<[u8]> {
+    //info!("sending packet");
+    data[0] = 0x6b;
+    data[1] = if is_p1 { 1 } else { 2 };
+    let data = auth.sign(data);
+    transport.send(&data);
+    data
+}
+
+fn send_control(transport: &mut impl Transport, auth: &SessionAuth, message: ControlMessage) {
+    transport.send(&auth.sign(message.encode()));
+}
+
+/// answers `ControlMessage::RegionHashRequest`, splitting the asked regions into several messages
+fn region_hash_messages(
+    frame: usize,
+    range: std::ops::Range<usize>,
+    table: Option<&RegionTable>,
+) -> Vec<Box<[u8]>> {
+    let Some(table) = table else {
+        let message = ControlMessage::RegionHashes {
+            frame,
+            total: 0,
+            start: 0,
+            regions: vec![],
+        };
+        return vec![message.encode()];
+    };
+    let end = range.end.min(table.regions.len());
+    let start = range.start.min(end);
+    let mut messages = vec![];
+    for (n, chunk) in table.regions[start..end]
+        .chunks(ControlMessage::REGIONS_PER_MESSAGE)
+        .enumerate()
+    {
+        let message = ControlMessage::RegionHashes {
+            frame,
+            total: table.regions.len(),
+            start: start + n * ControlMessage::REGIONS_PER_MESSAGE,
+            regions: chunk
+                .iter()
+                .map(|x| RemoteRegion {
+                    kind: Some(x.kind),
+                    size: x.size,
+                    hash: x.hash,
+                })
+                .collect(),
+        };
+        messages.push(message.encode());
+    }
+    messages
+}
+
+#[cfg(test)]
+mod tests;
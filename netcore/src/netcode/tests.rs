@@ -0,0 +1,441 @@
+use super::*;
+use crate::{rollback::RInput, sim::Peer, transport::MemoryTransport};
+
+fn input_of(player: usize, frame: usize) -> RInput {
+    std::array::from_fn(|key| (frame / (key + 3) + player) % 5 == 0)
+}
+
+#[test]
+fn two_netcoders_over_memory_transport() {
+    let (t1, t2) = MemoryTransport::pair();
+    let mut p1 = Peer::new(t1, true, 1, 6);
+    let mut p2 = Peer::new(t2, false, 1, 6);
+
+    for frame in 0..600 {
+        p1.frame(input_of(1, frame));
+        p2.frame(input_of(2, frame));
+    }
+
+    let h1 = &p1.rollbacker.state_hashes;
+    let h2 = &p2.rollbacker.state_hashes;
+    let common: Vec<_> = h1.keys().filter(|x| h2.contains_key(x)).collect();
+    assert!(common.len() > 400, "only {} frames confirmed", common.len());
+    for frame in common {
+        assert_eq!(h1[frame], h2[frame], "desynced at frame {}", frame);
+    }
+    assert_eq!(p1.netcoder.likely_desynced, None);
+    assert_eq!(p2.netcoder.likely_desynced, None);
+    assert_eq!(p1.report.pauses + p2.report.pauses, 0);
+}
+
+#[test]
+fn malformed_packets_are_dropped() {
+    let (t1, mut t2) = MemoryTransport::pair();
+    let mut p1 = Peer::new(t1, true, 1, 6);
+    t2.send(&[0x6b, 2, PACKET_VERSION, 0, 1]);
+    t2.send(&[0x6f, 0xee]);
+    p1.frame(input_of(1, 0));
+    assert_eq!(p1.netcoder.last_opponent_input, 0);
+}
+
+#[test]
+fn copies_are_only_sent_if_both_want_them() {
+    for (resend_1, resend_2, expected) in [
+        (Some(5), None, None),
+        (None, Some(5), None),
+        (Some(5), Some(8), Some(8)),
+    ] {
+        let (t1, t2) = MemoryTransport::pair();
+        let mut p1 = Peer::new(t1, true, 1, 6);
+        let mut p2 = Peer::new(t2, false, 1, 6);
+        p1.netcoder.resend_after = resend_1.map(Duration::from_millis);
+        p2.netcoder.resend_after = resend_2.map(Duration::from_millis);
+        for frame in 0..10 {
+            p1.frame(input_of(1, frame));
+            p2.frame(input_of(2, frame));
+        }
+        let expected = expected.map(Duration::from_millis);
+        assert_eq!(p1.netcoder.negotiated_resend_after(), expected);
+        assert_eq!(p2.netcoder.negotiated_resend_after(), expected);
+        assert_eq!(p1.netcoder.take_copy().is_some(), expected.is_some());
+    }
+}
+
+fn packet_with_inputs(inputs: Vec<u16>, compact_inputs: bool) -> NetworkPacket {
+    NetworkPacket {
+        id: 500,
+        desyncdetect: 0,
+        delay: 2,
+        max_rollback: 6,
+        inputs,
+        last_confirm: 400,
+        sync: None,
+        initial_max_rollback: Some(6),
+        state_hash: Some((300, 0x1234)),
+        resend_after: None,
+        compact_inputs,
+        delay_proposal: None,
+        delay_decision: None,
+        session_nonce: None,
+        capabilities: None,
+        delay_changes: vec![],
+        rollback_window: None,
+        sequence: None,
+    }
+}
+
+#[test]
+fn compact_inputs_round_trip() {
+    // held buttons
+    let inputs = [[0x1; 20], [0x11; 20], [0x110; 20]].concat();
+    let legacy = packet_with_inputs(inputs.clone(), false).encode();
+    let compact = packet_with_inputs(inputs.clone(), true).encode();
+    assert!(compact.len() * 3 < legacy.len());
+    for encoded in [legacy, compact] {
+        let decoded = NetworkPacket::decode(&encoded).unwrap();
+        assert_eq!(decoded.inputs, inputs);
+        assert!(decoded.compact_inputs);
+    }
+}
+
+#[test]
+fn long_input_windows_fit_into_a_packet() {
+    // every input differs from the previous one, the worst case of the run-length encoding
+    let inputs: Vec<u16> = (0..300).map(|x| (x * 0x9e5) as u16 & 0xfff).collect();
+    let mut auth = SessionAuth::with_nonce([1; NONCE_LEN]);
+    auth.add_opponent_nonce([2; NONCE_LEN], true);
+    for compact_inputs in [false, true] {
+        // with every extension, and signed
+        let packet = NetworkPacket {
+            state_hash: Some((100, 0x1234)),
+            resend_after: Some(5),
+            delay_decision: Some((600, 3)),
+            session_nonce: Some([1; NONCE_LEN]),
+            ..packet_with_inputs(inputs.clone(), compact_inputs)
+        };
+        let encoded = packet.encode();
+        assert!(auth.sign(encoded.clone()).len() <= MAX_PACKET_SIZE);
+        // the newest ones are kept
+        let decoded = NetworkPacket::decode(&encoded).unwrap();
+        assert!(decoded.inputs.len() > 50);
+        assert_eq!(decoded.inputs[..], inputs[..decoded.inputs.len()]);
+    }
+}
+
+#[test]
+fn oversized_extensions_are_left_out() {
+    let mut buf = vec![];
+    write_extension(&mut buf, EXT_RESEND, &[0; 300]);
+    assert!(buf.is_empty());
+    write_extension(&mut buf, EXT_RESEND, &[5]);
+    assert_eq!(buf, [EXT_RESEND, 1, 5]);
+}
+
+#[test]
+fn delay_decisions_round_trip() {
+    let packet = NetworkPacket {
+        delay_proposal: Some(2),
+        ..packet_with_inputs(vec![0; 2], true)
+    };
+    let decoded = NetworkPacket::decode(&packet.encode()).unwrap();
+    assert_eq!(decoded.delay_proposal, Some(2));
+    assert_eq!(decoded.delay_decision, None);
+
+    // p1 sends its decision instead of its proposal
+    let packet = NetworkPacket {
+        delay_decision: Some((1000, 3)),
+        ..packet
+    };
+    let decoded = NetworkPacket::decode(&packet.encode()).unwrap();
+    assert_eq!(decoded.delay_proposal, None);
+    assert_eq!(decoded.delay_decision, Some((1000, 3)));
+}
+
+#[test]
+fn malformed_compact_inputs_are_rejected() {
+    let mut encoded = packet_with_inputs(vec![1, 1, 2], true).encode().into_vec();
+    // a varint which never ends
+    let len = encoded.len();
+    encoded[len - 1] |= 0x80;
+    assert_eq!(
+        NetworkPacket::decode(&encoded).unwrap_err(),
+        PacketDecodeError::BadInputs
+    );
+}
+
+#[test]
+fn compact_inputs_are_only_sent_to_peers_reading_them() {
+    let (t1, mut t2) = MemoryTransport::pair();
+    let mut p1 = Peer::new(t1, true, 1, 6);
+    let last_sent_inputs = |t2: &mut MemoryTransport| {
+        let mut last = None;
+        while let Some((data, _)) = t2.recv() {
+            last = Some(data);
+        }
+        last.unwrap()[11]
+    };
+
+    p1.frame(input_of(1, 0));
+    p1.frame(input_of(1, 1));
+    assert!(last_sent_inputs(&mut t2) > 0);
+
+    let mut legacy = packet_with_inputs(vec![0; 2], false);
+    legacy.id = 1;
+    legacy.last_confirm = 0;
+    let mut data = legacy.encode();
+    data[1] = 2;
+    // a packet of an old version, without extensions
+    t2.send(&data[..data.len() - 2 - 12 - 2]);
+    p1.frame(input_of(1, 2));
+    assert!(last_sent_inputs(&mut t2) > 0);
+
+    legacy.id = 2;
+    t2.send(&legacy.encode());
+    p1.frame(input_of(1, 3));
+    assert_eq!(last_sent_inputs(&mut t2), 0);
+}
+
+#[test]
+fn forged_packets_are_dropped_once_authenticated() {
+    let (t1, t2) = MemoryTransport::pair();
+    let mut p1 = Peer::new(t1, true, 1, 6);
+    let mut p2 = Peer::new(t2, false, 1, 6);
+    for frame in 0..60 {
+        p1.frame(input_of(1, frame));
+        p2.frame(input_of(2, frame));
+    }
+    assert!(p1.netcoder.auth.verified && p2.netcoder.auth.verified);
+
+    // someone spoofing the address of p2, with inputs which differ from the real ones
+    let mut forged = packet_with_inputs(vec![0xfff; 10], true);
+    forged.id = p2.netcoder.id + 5;
+    forged.last_confirm = p1.netcoder.id;
+    let mut data = forged.encode();
+    data[1] = 2;
+    p2.netcoder.transport.send(&data);
+    let mut signed = p2.netcoder.auth.sign(data).into_vec();
+    let len = signed.len();
+    signed[len - 1] ^= 1;
+    p2.netcoder.transport.send(&signed);
+
+    for frame in 60..600 {
+        p1.frame(input_of(1, frame));
+        p2.frame(input_of(2, frame));
+    }
+    assert_eq!(p1.netcoder.auth.rejected, 2);
+    assert_eq!(p2.netcoder.auth.rejected, 0);
+    assert_eq!(p1.rollbacker.enemy_inputs.conflicts, 0);
+    assert_eq!(p1.netcoder.likely_desynced, None);
+    assert_eq!(p2.netcoder.likely_desynced, None);
+}
+
+#[test]
+fn a_spoofed_nonce_does_not_stall_the_handshake() {
+    let (t1, t2) = MemoryTransport::pair();
+    let mut p1 = Peer::new(t1, true, 1, 6);
+    let mut p2 = Peer::new(t2, false, 1, 6);
+    let mut spoofed = packet_with_inputs(vec![0; 3], true);
+    spoofed.id = 0;
+    spoofed.last_confirm = 0;
+    spoofed.session_nonce = Some([9; NONCE_LEN]);
+    let mut data = spoofed.encode();
+    data[1] = 2;
+    p2.netcoder.transport.send(&data);
+
+    for frame in 0..120 {
+        p1.frame(input_of(1, frame));
+        p2.frame(input_of(2, frame));
+    }
+    assert!(p1.netcoder.auth.verified && p2.netcoder.auth.verified);
+    assert_eq!(
+        p1.netcoder.auth.opponent_nonce,
+        Some(p2.netcoder.auth.nonce)
+    );
+    assert_eq!(p1.netcoder.auth.rejected + p2.netcoder.auth.rejected, 0);
+    assert_eq!(p1.rollbacker.enemy_inputs.conflicts, 0);
+}
+
+#[test]
+fn unsigned_packets_are_not_used_once_a_nonce_has_come() {
+    let (t1, mut t2) = MemoryTransport::pair();
+    let mut p1 = Peer::new(t1, true, 1, 6);
+    let mut packet = packet_with_inputs(vec![0xfff; 3], true);
+    packet.id = 2;
+    packet.last_confirm = 0;
+    packet.session_nonce = Some([9; NONCE_LEN]);
+    let mut data = packet.encode();
+    data[1] = 2;
+    t2.send(&data);
+    p1.frame(input_of(1, 0));
+    // its nonce is kept, but anyone could have sent its inputs
+    assert_eq!(p1.netcoder.last_opponent_input, 0);
+    assert_eq!(p1.netcoder.auth.rejected, 0);
+
+    // the opponent signs its packets once it has our nonce
+    packet.id = 3;
+    let mut auth = SessionAuth::with_nonce([9; NONCE_LEN]);
+    auth.add_opponent_nonce(p1.netcoder.auth.nonce, false);
+    let mut data = packet.encode();
+    data[1] = 2;
+    t2.send(&auth.sign(data));
+    p1.frame(input_of(1, 1));
+    assert_eq!(p1.netcoder.last_opponent_input, 3);
+    assert!(p1.netcoder.auth.verified);
+}
+
+#[test]
+fn confirms_of_packets_never_sent_are_ignored() {
+    let (t1, mut t2) = MemoryTransport::pair();
+    let mut p1 = Peer::new(t1, true, 1, 6);
+    let mut packet = packet_with_inputs(vec![0; 3], true);
+    packet.id = 2;
+    packet.last_confirm = 5000;
+    let mut data = packet.encode();
+    data[1] = 2;
+    t2.send(&data);
+    p1.frame(input_of(1, 0));
+    assert_eq!(p1.netcoder.malformed_packets, 1);
+    assert_eq!(p1.netcoder.last_opponent_input, 2);
+}
+
+#[test]
+fn peers_without_nonces_are_not_authenticated() {
+    let (t1, mut t2) = MemoryTransport::pair();
+    let mut p1 = Peer::new(t1, true, 1, 6);
+    for id in 0..5 {
+        let mut packet = packet_with_inputs(vec![0; 3], true);
+        packet.id = id;
+        packet.last_confirm = 0;
+        let mut data = packet.encode();
+        data[1] = 2;
+        t2.send(&data);
+        p1.frame(input_of(1, id));
+    }
+    assert_eq!(p1.netcoder.last_opponent_input, 4);
+    assert!(!p1.netcoder.auth.verified);
+    assert_eq!(p1.netcoder.auth.rejected, 0);
+    // nothing is signed without the nonce of the opponent
+    while let Some((data, _)) = t2.recv() {
+        assert!(NetworkPacket::decode(&data)
+            .unwrap()
+            .session_nonce
+            .is_some());
+        assert_ne!(data[data.len() - 18], crate::auth::EXT_AUTH);
+    }
+}
+
+#[test]
+fn capabilities_are_exchanged() {
+    let (t1, t2) = MemoryTransport::pair();
+    let mut p1 = Peer::new(t1, true, 1, 6);
+    let mut p2 = Peer::new(t2, false, 1, 6);
+    p2.netcoder.capabilities.input_encodings = crate::capabilities::INPUTS_LEGACY;
+    for frame in 0..200 {
+        p1.frame(input_of(1, frame));
+        p2.frame(input_of(2, frame));
+    }
+    let agreement = p1.netcoder.agreement.unwrap().unwrap();
+    assert_eq!(p2.netcoder.agreement, Some(Ok(agreement)));
+    assert!(!agreement.compact_inputs);
+    assert!(!p1.netcoder.sends_compact_inputs());
+    assert_eq!(p1.netcoder.likely_desynced, None);
+
+    // only sent in the first packets
+    while p1.netcoder.transport.recv().is_some() {}
+    p2.frame(input_of(2, 200));
+    let (data, _) = p1.netcoder.transport.recv().unwrap();
+    assert!(NetworkPacket::decode(&data).unwrap().capabilities.is_none());
+}
+
+#[test]
+fn incompatible_capabilities_are_reported() {
+    let (t1, t2) = MemoryTransport::pair();
+    let mut p1 = Peer::new(t1, true, 1, 6);
+    let mut p2 = Peer::new(t2, false, 1, 6);
+    p2.netcoder.capabilities.fps = 62;
+    for frame in 0..10 {
+        p1.frame(input_of(1, frame));
+        p2.frame(input_of(2, frame));
+    }
+    assert!(matches!(
+        p1.netcoder.agreement,
+        Some(Err(crate::capabilities::Incompatibility::Fps {
+            theirs: 62,
+            ..
+        }))
+    ));
+}
+
+#[test]
+fn delay_changes_are_applied_on_both_sides() {
+    let (t1, t2) = MemoryTransport::pair();
+    let mut p1 = Peer::new(t1, true, 1, 6);
+    let mut p2 = Peer::new(t2, false, 1, 6);
+    for frame in 0..150 {
+        p1.frame(input_of(1, frame));
+        p2.frame(input_of(2, frame));
+    }
+    assert!(p1.netcoder.propose_delay(3));
+    let change = p1.netcoder.pending_delay().unwrap();
+    for frame in 150..160 {
+        p1.frame(input_of(1, frame));
+        p2.frame(input_of(2, frame));
+    }
+    assert_eq!(p2.netcoder.pending_delay(), Some(change));
+    for frame in 160..300 {
+        p1.frame(input_of(1, frame));
+        p2.frame(input_of(2, frame));
+        for peer in [&p1, &p2] {
+            let expected = if peer.netcoder.id > change.frame {
+                3
+            } else {
+                1
+            };
+            assert_eq!(peer.netcoder.delay, expected, "at {}", peer.netcoder.id);
+        }
+    }
+    assert_eq!(p1.netcoder.pending_delay(), None);
+    assert_eq!(p2.netcoder.pending_delay(), None);
+
+    // proposed at the same time by both, p1's is applied last on both sides
+    assert!(p1.netcoder.propose_delay(2));
+    assert!(p2.netcoder.propose_delay(4));
+    for frame in 300..450 {
+        p1.frame(input_of(1, frame));
+        p2.frame(input_of(2, frame));
+    }
+    assert_eq!(p1.netcoder.delay, 2);
+    assert_eq!(p2.netcoder.delay, 2);
+}
+
+#[test]
+fn delay_changes_need_the_capability() {
+    let (t1, t2) = MemoryTransport::pair();
+    let mut p1 = Peer::new(t1, true, 1, 6);
+    let mut p2 = Peer::new(t2, false, 1, 6);
+    // not negotiated yet
+    assert!(!p1.netcoder.propose_delay(3));
+    p2.netcoder.capabilities.extensions &= !(1 << EXT_DELAY_CHANGES);
+    for frame in 0..150 {
+        p1.frame(input_of(1, frame));
+        p2.frame(input_of(2, frame));
+    }
+    assert!(!p1.netcoder.propose_delay(3));
+    assert_eq!(p1.netcoder.pending_delay(), None);
+}
+
+#[test]
+fn max_rollback_bounds_are_agreed_like_the_max_rollback() {
+    use crate::autorollback::{agree, RollbackBounds};
+    assert_eq!(agree(2, 8), 6);
+    assert_eq!(agree(8, 2), 6);
+    assert_eq!(agree(2, 4), 4);
+    assert_eq!(agree(10, 8), 8);
+    assert_eq!(agree(3, 3), 3);
+
+    let bounds = RollbackBounds::agree(RollbackBounds::new(0, 15), RollbackBounds::new(8, 10));
+    assert_eq!(bounds, RollbackBounds::new(6, 10));
+    assert_eq!(bounds.window(9, 1, 2), 7);
+    assert_eq!(bounds.window(20, 1, 2), 10);
+    assert_eq!(bounds.window(2, 1, 2), 6);
+}
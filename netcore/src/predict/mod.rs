@@ -0,0 +1,178 @@
+use crate::{rollback::RInput, INPUT_KEYS_NUMBERS};
+
+#[cfg(test)]
+mod tests;
+
+/// the first keys of an input are the directions (up, down, left, right), the others buttons
+const DIRECTION_KEYS: usize = 4;
+
+/// Guesses the inputs of the opponent which haven't arrived yet.
+///
+/// A wrong guess only costs a rollback, so the strategies don't have to agree between the peers.
+pub trait Predictor {
+    /// the input of `frame`, which isn't known yet; `inputs` are the known ones, by frame
+    fn predict(&self, inputs: &[Option<RInput>], frame: usize) -> RInput;
+    /// called with every input of the opponent, in order, once it and all the previous ones
+    /// are known
+    fn learn(&mut self, _input: RInput) {}
+}
+
+/// the latest known input before `frame`, and its frame
+pub fn last_known(inputs: &[Option<RInput>], frame: usize) -> Option<(usize, RInput)> {
+    inputs[..frame.min(inputs.len())]
+        .iter()
+        .enumerate()
+        .rev()
+        .find_map(|(frame, x)| Some((frame, (*x)?)))
+}
+
+/// The opponent keeps the last known input
+pub struct RepeatLast;
+
+impl Predictor for RepeatLast {
+    fn predict(&self, inputs: &[Option<RInput>], frame: usize) -> RInput {
+        last_known(inputs, frame).map_or([false; INPUT_KEYS_NUMBERS], |x| x.1)
+    }
+}
+
+/// The opponent keeps the last known directions, and releases the buttons, which are rarely
+/// held for long except for charging
+pub struct DirectionsOnly;
+
+impl Predictor for DirectionsOnly {
+    fn predict(&self, inputs: &[Option<RInput>], frame: usize) -> RInput {
+        let mut input = RepeatLast.predict(inputs, frame);
+        input[DIRECTION_KEYS..].fill(false);
+        input
+    }
+}
+
+/// runs longer than this are counted as this long
+const MAX_RUN: usize = 60;
+/// a key isn't predicted to change before this many runs as long as the current one are known
+const MIN_RUNS: u32 = 5;
+
+/// Learns how long each key is usually held and released during the match, and predicts that
+/// a key changes once two thirds of the runs as long as its current one have ended.
+pub struct Frequency {
+    /// the number of runs by key, by state (released, held), by length
+    runs: [[[u32; MAX_RUN + 1]; 2]; INPUT_KEYS_NUMBERS],
+    last: Option<RInput>,
+    /// the length of the current run of each key
+    current: [usize; INPUT_KEYS_NUMBERS],
+}
+
+impl Frequency {
+    pub fn new() -> Self {
+        Self {
+            runs: [[[0; MAX_RUN + 1]; 2]; INPUT_KEYS_NUMBERS],
+            last: None,
+            current: [0; INPUT_KEYS_NUMBERS],
+        }
+    }
+
+    /// the number of runs of `key` in `state` which have lasted at least `length` frames
+    fn lasted(&self, key: usize, state: bool, length: usize) -> u32 {
+        self.runs[key][state as usize][length.min(MAX_RUN)..]
+            .iter()
+            .sum()
+    }
+}
+
+impl Default for Frequency {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Predictor for Frequency {
+    fn predict(&self, inputs: &[Option<RInput>], frame: usize) -> RInput {
+        let Some((known, mut input)) = last_known(inputs, frame) else {
+            return [false; INPUT_KEYS_NUMBERS];
+        };
+        for key in 0..INPUT_KEYS_NUMBERS {
+            let state = input[key];
+            // the current run, as far as it's known
+            let length = inputs[..=known]
+                .iter()
+                .rev()
+                .take(MAX_RUN)
+                .take_while(|x| x.is_some_and(|x| x[key] == state))
+                .count();
+            let runs = self.lasted(key, state, length);
+            if runs >= MIN_RUNS && self.lasted(key, state, length + frame - known) * 3 < runs {
+                input[key] = !state;
+            }
+        }
+        input
+    }
+
+    fn learn(&mut self, input: RInput) {
+        for key in 0..INPUT_KEYS_NUMBERS {
+            match self.last {
+                Some(last) if last[key] != input[key] => {
+                    self.runs[key][last[key] as usize][self.current[key].min(MAX_RUN)] += 1;
+                    self.current[key] = 1;
+                }
+                _ => self.current[key] += 1,
+            }
+        }
+        self.last = Some(input);
+    }
+}
+
+/// The strategies, by the names used in the ini
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PredictorKind {
+    #[default]
+    RepeatLast,
+    DirectionsOnly,
+    Frequency,
+}
+
+impl PredictorKind {
+    pub const ALL: [PredictorKind; 3] = [
+        PredictorKind::RepeatLast,
+        PredictorKind::DirectionsOnly,
+        PredictorKind::Frequency,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            PredictorKind::RepeatLast => "repeat_last",
+            PredictorKind::DirectionsOnly => "directions_only",
+            PredictorKind::Frequency => "frequency",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|x| x.name() == name)
+    }
+
+    pub fn build(self) -> Box<dyn Predictor> {
+        match self {
+            PredictorKind::RepeatLast => Box::new(RepeatLast),
+            PredictorKind::DirectionsOnly => Box::new(DirectionsOnly),
+            PredictorKind::Frequency => Box::new(Frequency::new()),
+        }
+    }
+}
+
+/// How often the guesses of a `Predictor` were wrong
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PredictionStats {
+    /// the frames whose input was guessed before it arrived
+    pub predicted: usize,
+    /// the ones of them whose guess was wrong
+    pub mispredicted: usize,
+    /// the keys guessed wrong, in all of them
+    pub wrong_keys: usize,
+}
+
+impl PredictionStats {
+    pub fn add(&mut self, other: PredictionStats) {
+        self.predicted += other.predicted;
+        self.mispredicted += other.mispredicted;
+        self.wrong_keys += other.wrong_keys;
+    }
+}
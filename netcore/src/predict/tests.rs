@@ -0,0 +1,70 @@
+use super::*;
+
+/// the button 4 tapped for 3 frames every 20 frames, the direction 3 always held
+fn taps(frames: usize) -> Vec<Option<RInput>> {
+    (0..frames)
+        .map(|frame| {
+            let mut input = [false; INPUT_KEYS_NUMBERS];
+            input[3] = true;
+            input[4] = frame % 20 < 3;
+            Some(input)
+        })
+        .collect()
+}
+
+fn learned(predictor: &mut dyn Predictor, inputs: &[Option<RInput>]) {
+    for input in inputs {
+        predictor.learn(input.unwrap());
+    }
+}
+
+#[test]
+fn repeat_last_repeats_the_last_known_input() {
+    let mut inputs = taps(202);
+    inputs.push(None);
+    inputs.push(None);
+    assert_eq!(RepeatLast.predict(&inputs, 203), inputs[201].unwrap());
+    assert_eq!(RepeatLast.predict(&[], 0), [false; INPUT_KEYS_NUMBERS]);
+}
+
+#[test]
+fn directions_only_releases_the_buttons() {
+    let inputs = taps(202);
+    let guess = DirectionsOnly.predict(&inputs, 202);
+    assert!(inputs[201].unwrap()[4]);
+    assert!(guess[3]);
+    assert!(!guess[4]);
+}
+
+#[test]
+fn frequency_learns_how_long_buttons_are_held() {
+    let inputs = taps(200);
+    let mut frequency = Frequency::new();
+    learned(&mut frequency, &inputs);
+
+    // the button has been held for 2 frames: still held on the 3rd, released on the 4th
+    let known = taps(202);
+    assert!(frequency.predict(&known, 202)[4]);
+    assert!(!frequency.predict(&known, 203)[4]);
+    // released for 10 frames: pressed again after 17
+    let known = taps(213);
+    assert!(!frequency.predict(&known, 219)[4]);
+    assert!(frequency.predict(&known, 220)[4]);
+    assert!(frequency.predict(&known, 220)[3]);
+}
+
+#[test]
+fn frequency_repeats_until_it_has_learned_enough() {
+    let inputs = taps(30);
+    let mut frequency = Frequency::new();
+    learned(&mut frequency, &inputs[..22]);
+    assert_eq!(frequency.predict(&inputs[..22], 25), inputs[21].unwrap());
+}
+
+#[test]
+fn predictor_names() {
+    for kind in PredictorKind::ALL {
+        assert_eq!(PredictorKind::from_name(kind.name()), Some(kind));
+    }
+    assert_eq!(PredictorKind::from_name("nostradamus"), None);
+}
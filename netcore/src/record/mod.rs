@@ -0,0 +1,469 @@
+//! Recording of the traffic of a `Netcoder`, and replaying it offline.
+//!
+//! A session file starts with `MAGIC`, `SESSION_VERSION` and a `SessionHeader`, followed by
+//! events, each made of a tag, the time in nanoseconds since the start of the session
+//! (i64 LE), and:
+//! - `EVENT_FRAME`: the local input (u16 LE) and the delay (u8), at the start of
+//!   `Netcoder::process_and_send`
+//! - `EVENT_SENT`, `EVENT_RECEIVED`: the length (u16 LE) and the packet; the time of a
+//!   received packet is the time it arrived
+
+use std::{
+    collections::VecDeque,
+    io::{Read, Write},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    auth::{SessionAuth, NONCE_LEN},
+    autodelay::AutoDelay,
+    autorollback::{AutoRollback, RollbackBounds},
+    capabilities::{Capabilities, CAPABILITIES_LEN},
+    input_to_accum,
+    netcode::{NetworkPacket, PauseReason},
+    predict::PredictorKind,
+    rollback::RInput,
+    sim::Peer,
+    transport::Transport,
+};
+
+#[cfg(test)]
+mod tests;
+
+pub const MAGIC: &[u8; 8] = b"GIUROREC";
+/// 2 adds `SessionHeader::nonce`, 3 `SessionHeader::capabilities`, 4
+/// `SessionHeader::auto_rollback`, 5 the length of the capabilities (for `Capabilities::layout`)
+pub const SESSION_VERSION: u8 = 5;
+
+const EVENT_FRAME: u8 = 1;
+const EVENT_SENT: u8 = 2;
+const EVENT_RECEIVED: u8 = 3;
+
+/// The settings of the `Netcoder` a session was recorded with
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SessionHeader {
+    pub is_p1: bool,
+    /// `Netcoder::initial_my_max_rollback`
+    pub max_rollback: u8,
+    pub compact_inputs: bool,
+    pub recovery_enabled: bool,
+    /// `Netcoder::resend_after` in milliseconds, 0 for none
+    pub resend_after: u8,
+    /// `AutoDelay::target_rollback`, if auto delay was enabled
+    pub auto_delay: Option<i8>,
+    /// the predictor of the inputs of the opponent
+    pub input_predictor: PredictorKind,
+    /// the frames per second of the game, 60 or 62
+    pub fps: u8,
+    /// `SessionAuth::nonce`, for the tags of the packets received to be checked again
+    pub nonce: [u8; NONCE_LEN],
+    /// `Netcoder::capabilities`, which the agreement with the opponent is made from
+    pub capabilities: Capabilities,
+    /// `AutoRollback::bounds`, if the max rollback adapted to the latency
+    pub auto_rollback: Option<RollbackBounds>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SessionEvent {
+    Frame { input: u16, delay: u8 },
+    Sent(Box<[u8]>),
+    Received(Box<[u8]>),
+}
+
+/// Writes a session file
+pub struct Recorder<W: Write> {
+    writer: W,
+    start: Instant,
+    /// the first error, after which nothing more is written
+    pub error: Option<std::io::Error>,
+}
+
+impl<W: Write> Recorder<W> {
+    pub fn new(mut writer: W, header: &SessionHeader) -> std::io::Result<Self> {
+        writer.write_all(MAGIC)?;
+        writer.write_all(&[
+            SESSION_VERSION,
+            header.is_p1 as u8,
+            header.max_rollback,
+            header.compact_inputs as u8,
+            header.recovery_enabled as u8,
+            header.resend_after,
+            header.auto_delay.is_some() as u8,
+            header.auto_delay.unwrap_or(0) as u8,
+            PredictorKind::ALL
+                .iter()
+                .position(|x| *x == header.input_predictor)
+                .unwrap() as u8,
+            header.fps,
+        ])?;
+        writer.write_all(&header.nonce)?;
+        let capabilities = header.capabilities.encode();
+        writer.write_all(&[capabilities.len() as u8])?;
+        writer.write_all(&capabilities)?;
+        let bounds = header.auto_rollback.unwrap_or(RollbackBounds::new(0, 0));
+        writer.write_all(&[
+            header.auto_rollback.is_some() as u8,
+            bounds.min as u8,
+            bounds.max as u8,
+        ])?;
+        Ok(Self {
+            writer,
+            start: Instant::now(),
+            error: None,
+        })
+    }
+
+    fn event(&mut self, tag: u8, at: Instant, data: &[u8]) {
+        if self.error.is_some() {
+            return;
+        }
+        let time = match at.checked_duration_since(self.start) {
+            Some(x) => x.as_nanos() as i64,
+            None => -(self.start.duration_since(at).as_nanos() as i64),
+        };
+        let mut buf = Vec::with_capacity(data.len() + 9);
+        buf.push(tag);
+        buf.extend_from_slice(&time.to_le_bytes());
+        buf.extend_from_slice(data);
+        if let Err(e) = self.writer.write_all(&buf) {
+            self.error = Some(e);
+        }
+    }
+
+    fn packet(&mut self, tag: u8, at: Instant, data: &[u8]) {
+        let mut buf = Vec::with_capacity(data.len() + 2);
+        buf.extend_from_slice(&(data.len().min(u16::MAX as usize) as u16).to_le_bytes());
+        buf.extend_from_slice(&data[..data.len().min(u16::MAX as usize)]);
+        self.event(tag, at, &buf);
+    }
+
+    pub fn frame(&mut self, at: Instant, input: &RInput, delay: usize) {
+        let mut buf = input_to_accum(input).to_le_bytes().to_vec();
+        buf.push(delay as u8);
+        self.event(EVENT_FRAME, at, &buf);
+    }
+
+    pub fn sent(&mut self, at: Instant, data: &[u8]) {
+        self.packet(EVENT_SENT, at, data);
+    }
+
+    pub fn received(&mut self, at: Instant, data: &[u8]) {
+        self.packet(EVENT_RECEIVED, at, data);
+    }
+
+    pub fn flush(&mut self) -> std::io::Result<()> {
+        self.writer.flush()
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+/// A transport which records its traffic, if `recorder` is set
+pub struct Recording<T: Transport, W: Write> {
+    pub inner: T,
+    pub recorder: Option<Recorder<W>>,
+}
+
+impl<T: Transport, W: Write> Recording<T, W> {
+    pub fn new(inner: T, recorder: Option<Recorder<W>>) -> Self {
+        Self { inner, recorder }
+    }
+}
+
+impl<T: Transport, W: Write> Transport for Recording<T, W> {
+    fn send(&mut self, data: &[u8]) {
+        if let Some(recorder) = self.recorder.as_mut() {
+            recorder.sent(self.inner.now(), data);
+        }
+        self.inner.send(data);
+    }
+
+    fn recv(&mut self) -> Option<(Box<[u8]>, Instant)> {
+        let (data, at) = self.inner.recv()?;
+        if let Some(recorder) = self.recorder.as_mut() {
+            recorder.received(at, &data);
+        }
+        Some((data, at))
+    }
+
+    fn now(&self) -> Instant {
+        self.inner.now()
+    }
+
+    fn frame_started(&mut self, at: Instant, input: &RInput, delay: usize) {
+        if let Some(recorder) = self.recorder.as_mut() {
+            recorder.frame(at, input, delay);
+        }
+    }
+}
+
+/// A session file, read by `Session::read`
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Session {
+    pub header: SessionHeader,
+    /// the events and their times since the start of the session, in ns
+    pub events: Vec<(i64, SessionEvent)>,
+    /// the session ends with an event cut in half, e.g. the game was closed while recording
+    pub truncated: bool,
+}
+
+impl Session {
+    pub fn read(mut reader: impl Read) -> Result<Self, String> {
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data).map_err(|e| e.to_string())?;
+        let rest = data
+            .strip_prefix(MAGIC.as_slice())
+            .ok_or("not a giuroll session file")?;
+        let [version, is_p1, max_rollback, compact_inputs, recovery_enabled, resend_after, has_auto_delay, auto_delay, input_predictor, fps, rest @ ..] =
+            rest
+        else {
+            return Err("the header is cut".to_string());
+        };
+        if *version == 0 || *version > SESSION_VERSION {
+            return Err(format!("unknown session version {}", version));
+        }
+        let (nonce, rest) = match *version {
+            1 => ([0; NONCE_LEN], rest),
+            _ => {
+                let (nonce, rest) = rest
+                    .split_first_chunk::<NONCE_LEN>()
+                    .ok_or("the header is cut")?;
+                (*nonce, rest)
+            }
+        };
+        let (capabilities, rest) = match *version {
+            1 | 2 => (Capabilities::default(), rest),
+            3 | 4 => {
+                let (capabilities, rest) = rest
+                    .split_first_chunk::<CAPABILITIES_LEN>()
+                    .ok_or("the header is cut")?;
+                (Capabilities::decode(capabilities).unwrap(), rest)
+            }
+            _ => {
+                let (len, rest) = rest.split_first().ok_or("the header is cut")?;
+                if rest.len() < *len as usize {
+                    return Err("the header is cut".to_string());
+                }
+                let (capabilities, rest) = rest.split_at(*len as usize);
+                let capabilities =
+                    Capabilities::decode(capabilities).ok_or("the capabilities are cut")?;
+                (capabilities, rest)
+            }
+        };
+        let (auto_rollback, rest) = match *version {
+            1..=3 => (None, rest),
+            _ => {
+                let ([has_auto_rollback, min, max], rest) =
+                    rest.split_first_chunk::<3>().ok_or("the header is cut")?;
+                let bounds = RollbackBounds::new(*min as usize, *max as usize);
+                ((*has_auto_rollback != 0).then_some(bounds), rest)
+            }
+        };
+        let input_predictor = *PredictorKind::ALL
+            .get(*input_predictor as usize)
+            .ok_or(format!("unknown input predictor {}", input_predictor))?;
+        let mut session = Session {
+            header: SessionHeader {
+                is_p1: *is_p1 != 0,
+                max_rollback: *max_rollback,
+                compact_inputs: *compact_inputs != 0,
+                recovery_enabled: *recovery_enabled != 0,
+                resend_after: *resend_after,
+                auto_delay: (*has_auto_delay != 0).then_some(*auto_delay as i8),
+                input_predictor,
+                fps: *fps,
+                nonce,
+                capabilities,
+                auto_rollback,
+            },
+            ..Default::default()
+        };
+
+        let mut rest = rest;
+        while let [tag, tail @ ..] = rest {
+            let Some((time, tail)) = tail.split_first_chunk::<8>() else {
+                session.truncated = true;
+                break;
+            };
+            let time = i64::from_le_bytes(*time);
+            let (event, tail) = match *tag {
+                EVENT_FRAME => {
+                    let [a, b, delay, tail @ ..] = tail else {
+                        session.truncated = true;
+                        break;
+                    };
+                    let input = u16::from_le_bytes([*a, *b]);
+                    (
+                        SessionEvent::Frame {
+                            input,
+                            delay: *delay,
+                        },
+                        tail,
+                    )
+                }
+                EVENT_SENT | EVENT_RECEIVED => {
+                    let Some((len, tail)) = tail.split_first_chunk::<2>() else {
+                        session.truncated = true;
+                        break;
+                    };
+                    let len = u16::from_le_bytes(*len) as usize;
+                    if tail.len() < len {
+                        session.truncated = true;
+                        break;
+                    }
+                    let (packet, tail) = tail.split_at(len);
+                    let packet = packet.into();
+                    match *tag {
+                        EVENT_SENT => (SessionEvent::Sent(packet), tail),
+                        _ => (SessionEvent::Received(packet), tail),
+                    }
+                }
+                x => return Err(format!("unknown event {} at {}ns", x, time)),
+            };
+            session.events.push((time, event));
+            rest = tail;
+        }
+        Ok(session)
+    }
+}
+
+/// Feeds the received packets of a `Session` back to a `Netcoder`, on the recorded clock
+pub struct ReplayTransport {
+    base: Instant,
+    now: Instant,
+    received: VecDeque<(Box<[u8]>, Instant)>,
+    pub sent: Vec<Box<[u8]>>,
+}
+
+impl ReplayTransport {
+    fn at(&self, time: i64) -> Instant {
+        match time {
+            0.. => self.base + Duration::from_nanos(time as u64),
+            _ => self
+                .base
+                .checked_sub(Duration::from_nanos(time.unsigned_abs()))
+                .unwrap_or(self.base),
+        }
+    }
+}
+
+impl Transport for ReplayTransport {
+    fn send(&mut self, data: &[u8]) {
+        self.sent.push(data.into());
+    }
+
+    fn recv(&mut self) -> Option<(Box<[u8]>, Instant)> {
+        self.received.pop_front()
+    }
+
+    fn now(&self) -> Instant {
+        self.now
+    }
+}
+
+/// What happened in one `Netcoder::process_and_send` of a replayed session
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ReplayedFrame {
+    /// the time of the frame since the start of the session, in ns
+    pub time: i64,
+    /// `Netcoder::id`, as told by the packet sent
+    pub id: Option<usize>,
+    pub pause: Option<PauseReason>,
+    /// the frames simulated, more than 1 for a rollback
+    pub simulated: u32,
+    pub received: usize,
+    /// the packets sent differ from the recorded ones, apart from what comes from the state of
+    /// the game
+    pub diverged: bool,
+}
+
+fn same_traffic(sent: &[Box<[u8]>], expected: &[Box<[u8]>]) -> bool {
+    sent.len() == expected.len()
+        && sent.iter().zip(expected).all(|(a, b)| {
+            match (NetworkPacket::decode(a), NetworkPacket::decode(b)) {
+                (Ok(a), Ok(b)) => a.same_netcode(&b),
+                _ => a == b,
+            }
+        })
+}
+
+/// Replays `session` through `Netcoder::process_and_send`, calling `on_frame` after every frame.
+///
+/// The game is `sim::Toy`, so the desyncs found with the hashes of the opponent are not
+/// replayed: the desync recovery is disabled
+pub fn replay(session: &Session, mut on_frame: impl FnMut(&ReplayedFrame)) -> Vec<ReplayedFrame> {
+    let header = &session.header;
+    let base = Instant::now();
+    let transport = ReplayTransport {
+        base,
+        now: base,
+        received: VecDeque::new(),
+        sent: Vec::new(),
+    };
+    let mut peer = Peer::new(transport, header.is_p1, 0, header.max_rollback);
+    peer.netcoder.compact_inputs = header.compact_inputs;
+    peer.netcoder.resend_after =
+        (header.resend_after != 0).then(|| Duration::from_millis(header.resend_after as u64));
+    peer.netcoder.autodelay = header.auto_delay.map(|x| AutoDelay::new(x, header.fps));
+    peer.netcoder.autorollback = header
+        .auto_rollback
+        .map(|x| AutoRollback::new(x, header.fps));
+    peer.rollbacker.enemy_inputs.predictor = header.input_predictor.build();
+    peer.netcoder.auth = SessionAuth::with_nonce(header.nonce);
+    peer.netcoder.capabilities = header.capabilities;
+
+    let mut frames = Vec::new();
+    let mut events = session.events.iter().peekable();
+    while let Some((time, event)) = events.next() {
+        let SessionEvent::Frame { input, delay } = event else {
+            continue;
+        };
+        let mut expected = Vec::new();
+        let transport = &mut peer.netcoder.transport;
+        transport.now = transport.at(*time);
+        while let Some((at, event)) = events.next_if(|x| !matches!(x.1, SessionEvent::Frame { .. }))
+        {
+            match event {
+                SessionEvent::Received(data) => {
+                    let at = transport.at(*at);
+                    transport.received.push_back((data.clone(), at))
+                }
+                SessionEvent::Sent(data) => expected.push(data.clone()),
+                SessionEvent::Frame { .. } => unreachable!(),
+            }
+        }
+        let received = transport.received.len();
+
+        peer.netcoder.delay = *delay as usize;
+        // p1 decides the delay at the safe points of the game, which aren't recorded
+        for packet in expected
+            .iter()
+            .filter_map(|x| NetworkPacket::decode(x).ok())
+        {
+            if let Some((frame, delay)) = packet.delay_decision() {
+                peer.netcoder.decide_delay(frame, delay as usize);
+            }
+        }
+        let input: RInput = std::array::from_fn(|x| input & (1 << x) != 0);
+        let simulated = peer.advance(input);
+        let _ = peer.netcoder.take_copy();
+
+        let sent = std::mem::take(&mut peer.netcoder.transport.sent);
+        let frame = ReplayedFrame {
+            time: *time,
+            id: sent
+                .iter()
+                .find_map(|x| NetworkPacket::decode(x).ok())
+                .map(|x| x.id()),
+            pause: peer.netcoder.pause,
+            simulated,
+            received,
+            diverged: !same_traffic(&sent, &expected),
+        };
+        on_frame(&frame);
+        frames.push(frame);
+    }
+    frames
+}
@@ -0,0 +1,128 @@
+use super::*;
+use crate::{
+    sim::{scripted_input, Peer},
+    transport::MemoryTransport,
+};
+
+fn header() -> SessionHeader {
+    SessionHeader {
+        is_p1: true,
+        max_rollback: 6,
+        compact_inputs: true,
+        recovery_enabled: false,
+        resend_after: 0,
+        auto_delay: None,
+        input_predictor: PredictorKind::Frequency,
+        fps: 60,
+        nonce: *b"0123456789abcdef",
+        capabilities: Capabilities {
+            layout: Some(0x12345678),
+            ..Capabilities::with_version("1.2.3")
+        },
+        auto_rollback: Some(RollbackBounds::new(2, 10)),
+    }
+}
+
+/// records a match of p1; returns the session file, and the pause and the frames simulated
+/// of every frame
+fn record_a_match(frames: usize) -> (Vec<u8>, Vec<(Option<PauseReason>, u32)>) {
+    let (t1, t2) = MemoryTransport::pair();
+    let recorder = Recorder::new(Vec::new(), &header()).unwrap();
+    let mut p1 = Peer::new(Recording::new(t1, Some(recorder)), true, 2, 6);
+    p1.rollbacker.enemy_inputs.predictor = header().input_predictor.build();
+    p1.netcoder.auth = SessionAuth::with_nonce(header().nonce);
+    p1.netcoder.capabilities = header().capabilities;
+    p1.netcoder.autorollback = header()
+        .auto_rollback
+        .map(|x| AutoRollback::new(x, header().fps));
+    let mut p2 = Peer::new(t2, false, 2, 6);
+    p2.netcoder.autorollback = Some(AutoRollback::new(RollbackBounds::new(4, 8), 60));
+
+    let mut played = Vec::new();
+    for frame in 0..frames {
+        let simulated = p1.advance(scripted_input(0, 1, frame));
+        played.push((p1.netcoder.pause, simulated));
+        // p2 stops answering for a while, then lags behind
+        if !(200..230).contains(&frame) && (frame < 400 || frame % 4 != 0) {
+            p2.advance(scripted_input(0, 2, frame));
+        }
+    }
+    let recorder = p1.netcoder.transport.recorder.take().unwrap();
+    assert!(recorder.error.is_none());
+    (recorder.into_inner(), played)
+}
+
+#[test]
+fn replay_reproduces_the_match() {
+    let (file, played) = record_a_match(600);
+    let session = Session::read(file.as_slice()).unwrap();
+    assert_eq!(session.header, header());
+    assert!(!session.truncated);
+
+    let mut called = 0;
+    let frames = replay(&session, |_| called += 1);
+    assert_eq!(called, played.len());
+    let replayed: Vec<_> = frames.iter().map(|x| (x.pause, x.simulated)).collect();
+    assert_eq!(replayed, played);
+    assert!(frames.iter().all(|x| !x.diverged), "{:#?}", frames);
+    assert!(frames.iter().any(|x| x.pause.is_some()));
+    assert!(frames.iter().any(|x| x.simulated > 1));
+    assert!(frames.windows(2).all(|x| x[0].time <= x[1].time));
+}
+
+#[test]
+fn truncated_session() {
+    let (file, _) = record_a_match(60);
+    let whole = Session::read(file.as_slice()).unwrap();
+    let cut = Session::read(&file[..file.len() - 3]).unwrap();
+    assert!(cut.truncated);
+    assert_eq!(cut.events[..], whole.events[..whole.events.len() - 1]);
+}
+
+#[test]
+fn not_a_session() {
+    assert!(Session::read(b"GIUROLL!\x01".as_slice()).is_err());
+    assert!(Session::read(b"GIUROREC\x01\x00".as_slice()).is_err());
+    let mut file = MAGIC.to_vec();
+    file.extend_from_slice(&[SESSION_VERSION + 1, 0, 0, 0, 0, 0, 0, 0, 0, 60]);
+    assert!(Session::read(file.as_slice()).is_err());
+    file[8] = SESSION_VERSION;
+    // without the nonce
+    assert!(Session::read(file.as_slice()).is_err());
+}
+
+#[test]
+fn replay_reproduces_the_delays_decided_by_p1() {
+    let header = SessionHeader {
+        auto_delay: Some(1),
+        ..header()
+    };
+    let (t1, t2) = MemoryTransport::pair();
+    let recorder = Recorder::new(Vec::new(), &header).unwrap();
+    let mut p1 = Peer::new(Recording::new(t1, Some(recorder)), true, 4, 6);
+    p1.rollbacker.enemy_inputs.predictor = header.input_predictor.build();
+    p1.netcoder.auth = SessionAuth::with_nonce(header.nonce);
+    p1.netcoder.capabilities = header.capabilities;
+    p1.netcoder.autorollback = header
+        .auto_rollback
+        .map(|x| AutoRollback::new(x, header.fps));
+    let mut p2 = Peer::new(t2, false, 4, 6);
+    p1.netcoder.autodelay = Some(AutoDelay::new(1, 60));
+    p2.netcoder.autodelay = Some(AutoDelay::new(1, 60));
+
+    for frame in 0..300 {
+        // a round starts
+        if frame == 200 {
+            p1.netcoder.apply_agreed_delay();
+        }
+        p1.advance(scripted_input(0, 1, frame));
+        p2.advance(scripted_input(0, 2, frame));
+    }
+    assert_ne!(p1.netcoder.delay, 4);
+    assert_eq!(p1.netcoder.delay, p2.netcoder.delay);
+
+    let recorder = p1.netcoder.transport.recorder.take().unwrap();
+    let session = Session::read(recorder.into_inner().as_slice()).unwrap();
+    let frames = replay(&session, |_| ());
+    assert!(frames.iter().all(|x| !x.diverged), "{:#?}", frames);
+}
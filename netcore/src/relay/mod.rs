@@ -0,0 +1,366 @@
+//! A relay forwarding the UDP traffic of two players who can't reach each other directly
+//! (e.g. behind CGNAT), and the messages clients send to it.
+//!
+//! A client joins a room by sending `Message::Register` to the relay, from the socket it
+//! plays with. Every other datagram a member of a room sends to the relay is forwarded as it
+//! is to the other member, whether it comes from soku or from giuroll. The relay sends nothing
+//! else to the members, so that soku never sees a packet it doesn't know.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    io::{self, ErrorKind},
+    net::{SocketAddr, UdpSocket},
+    time::{Duration, Instant},
+};
+
+use crate::{netcode::NetworkPacket, println};
+
+#[cfg(test)]
+mod tests;
+
+/// the first bytes of the messages to the relay itself; no packet of soku starts with them
+pub const MAGIC: &[u8; 4] = b"GRLY";
+const MESSAGE_REGISTER: u8 = 1;
+const MESSAGE_STATS_REQUEST: u8 = 2;
+const MESSAGE_STATS: u8 = 3;
+
+/// room codes are made of 1 to this many ASCII letters, digits, `-` and `_`
+pub const MAX_ROOM_LEN: usize = 32;
+/// a member which hasn't sent anything for this long leaves its room
+pub const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+/// how many of the latest round trips of a member are used for its stats
+const RTT_WINDOW: usize = 120;
+/// how many of the latest packets forwarded to a member are waiting to be confirmed by it
+const FORWARDED_WINDOW: usize = 256;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Message {
+    /// joins the room, from the address the packets are going to be sent from
+    Register { room: String },
+    /// asks for the `RoomStats` of the room, answered with `Message::Stats`
+    StatsRequest { room: String },
+    /// `RoomStats::to_json`
+    Stats { json: String },
+}
+
+pub fn valid_room(room: &str) -> bool {
+    (1..=MAX_ROOM_LEN).contains(&room.len())
+        && room
+            .bytes()
+            .all(|x| x.is_ascii_alphanumeric() || x == b'-' || x == b'_')
+}
+
+impl Message {
+    pub fn encode(&self) -> Box<[u8]> {
+        let (tag, value) = match self {
+            Message::Register { room } => (MESSAGE_REGISTER, room),
+            Message::StatsRequest { room } => (MESSAGE_STATS_REQUEST, room),
+            Message::Stats { json } => (MESSAGE_STATS, json),
+        };
+        let mut buf = MAGIC.to_vec();
+        buf.push(tag);
+        buf.extend_from_slice(value.as_bytes());
+        buf.into()
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Self, String> {
+        let rest = data
+            .strip_prefix(MAGIC.as_slice())
+            .ok_or("not a relay message")?;
+        let [tag, value @ ..] = rest else {
+            return Err("empty relay message".to_string());
+        };
+        let value = std::str::from_utf8(value)
+            .map_err(|e| e.to_string())?
+            .to_string();
+        let room = |room: String| match valid_room(&room) {
+            true => Ok(room),
+            false => Err(format!("invalid room code {:?}", room)),
+        };
+        match *tag {
+            MESSAGE_REGISTER => Ok(Message::Register { room: room(value)? }),
+            MESSAGE_STATS_REQUEST => Ok(Message::StatsRequest { room: room(value)? }),
+            MESSAGE_STATS => Ok(Message::Stats { json: value }),
+            x => Err(format!("unknown relay message {}", x)),
+        }
+    }
+}
+
+/// The traffic of a member of a room, and the round trips between the relay and it
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MemberStats {
+    pub address: SocketAddr,
+    /// the packets and bytes it has sent to be forwarded
+    pub packets: u64,
+    pub bytes: u64,
+    /// in milliseconds, from forwarding a giuroll packet to it to getting the confirmation of
+    /// it, which may wait for the next packet of the member (up to a frame)
+    pub rtt_avg: Option<u32>,
+    pub rtt_max: Option<u32>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RoomStats {
+    pub room: String,
+    pub members: Vec<MemberStats>,
+}
+
+impl RoomStats {
+    /// the round trip (ms) between the members through the relay, if both have been measured
+    pub fn rtt(&self) -> Option<u32> {
+        match self.members.as_slice() {
+            [a, b] => Some(a.rtt_avg? + b.rtt_avg?),
+            _ => None,
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        fn option(x: Option<u32>) -> String {
+            x.map_or("null".to_string(), |x| x.to_string())
+        }
+        let members: Vec<_> = self
+            .members
+            .iter()
+            .map(|x| {
+                format!(
+                    "{{\"address\": \"{}\", \"packets\": {}, \"bytes\": {}, \"rtt_avg\": {}, \
+                     \"rtt_max\": {}}}",
+                    x.address,
+                    x.packets,
+                    x.bytes,
+                    option(x.rtt_avg),
+                    option(x.rtt_max)
+                )
+            })
+            .collect();
+        format!(
+            "{{\"room\": \"{}\", \"rtt\": {}, \"members\": [{}]}}",
+            self.room,
+            option(self.rtt()),
+            members.join(", ")
+        )
+    }
+}
+
+struct Member {
+    address: SocketAddr,
+    last_seen: Instant,
+    packets: u64,
+    bytes: u64,
+    /// the ids of the giuroll packets forwarded to it, and when
+    forwarded: VecDeque<(usize, Instant)>,
+    rtts: VecDeque<Duration>,
+}
+
+impl Member {
+    fn new(address: SocketAddr, now: Instant) -> Self {
+        Self {
+            address,
+            last_seen: now,
+            packets: 0,
+            bytes: 0,
+            forwarded: VecDeque::new(),
+            rtts: VecDeque::new(),
+        }
+    }
+
+    fn stats(&self) -> MemberStats {
+        let ms = |x: Duration| x.as_millis() as u32;
+        let sum: Duration = self.rtts.iter().sum();
+        MemberStats {
+            address: self.address,
+            packets: self.packets,
+            bytes: self.bytes,
+            rtt_avg: (!self.rtts.is_empty()).then(|| ms(sum / self.rtts.len() as u32)),
+            rtt_max: self.rtts.iter().max().map(|x| ms(*x)),
+        }
+    }
+
+    /// a packet of this member confirms it has received the packet `id` of the other one
+    fn confirmed(&mut self, id: usize, now: Instant) {
+        if let Some((_, at)) = self.forwarded.iter().find(|x| x.0 == id) {
+            if self.rtts.len() >= RTT_WINDOW {
+                self.rtts.pop_front();
+            }
+            self.rtts.push_back(now.saturating_duration_since(*at));
+        }
+        self.forwarded.retain(|x| x.0 > id);
+    }
+
+    fn forwarding(&mut self, id: usize, now: Instant) {
+        // the packets resent while paused keep their ids
+        if self.forwarded.iter().any(|x| x.0 == id) {
+            return;
+        }
+        if self.forwarded.len() >= FORWARDED_WINDOW {
+            self.forwarded.pop_front();
+        }
+        self.forwarded.push_back((id, now));
+    }
+}
+
+/// The rooms of a relay, without the socket: `handle` tells what to send for each datagram
+#[derive(Default)]
+pub struct Relay {
+    /// at most two members each
+    rooms: HashMap<String, Vec<Member>>,
+    room_of: HashMap<SocketAddr, String>,
+}
+
+impl Relay {
+    /// a datagram received from `from`; returns the datagrams to be sent
+    pub fn handle(
+        &mut self,
+        now: Instant,
+        from: SocketAddr,
+        data: &[u8],
+    ) -> Vec<(SocketAddr, Box<[u8]>)> {
+        if !data.starts_with(MAGIC) {
+            return self.forward(now, from, data).into_iter().collect();
+        }
+        match Message::decode(data) {
+            Ok(Message::Register { room }) => {
+                self.register(now, from, room);
+                vec![]
+            }
+            Ok(Message::StatsRequest { room }) => {
+                let json = self
+                    .stats(&room)
+                    .map_or("null".to_string(), |x| x.to_json());
+                vec![(from, Message::Stats { json }.encode())]
+            }
+            Ok(Message::Stats { .. }) => vec![],
+            Err(e) => {
+                println!("dropping a relay message from {}: {}", from, e);
+                vec![]
+            }
+        }
+    }
+
+    fn register(&mut self, now: Instant, from: SocketAddr, room: String) {
+        if let Some(old) = self.room_of.get(&from) {
+            if *old == room {
+                self.member(from).unwrap().last_seen = now;
+                return;
+            }
+            self.leave(from);
+        }
+        let members = self.rooms.entry(room.clone()).or_default();
+        if members.len() >= 2 {
+            println!("room {} is full, {} can't join it", room, from);
+            return;
+        }
+        println!("{} joined room {}", from, room);
+        members.push(Member::new(from, now));
+        self.room_of.insert(from, room);
+    }
+
+    fn leave(&mut self, address: SocketAddr) {
+        let Some(room) = self.room_of.remove(&address) else {
+            return;
+        };
+        println!("{} left room {}", address, room);
+        let members = self.rooms.get_mut(&room).unwrap();
+        members.retain(|x| x.address != address);
+        if members.is_empty() {
+            self.rooms.remove(&room);
+        }
+    }
+
+    fn member(&mut self, address: SocketAddr) -> Option<&mut Member> {
+        let room = self.room_of.get(&address)?;
+        self.rooms
+            .get_mut(room)?
+            .iter_mut()
+            .find(|x| x.address == address)
+    }
+
+    fn forward(
+        &mut self,
+        now: Instant,
+        from: SocketAddr,
+        data: &[u8],
+    ) -> Option<(SocketAddr, Box<[u8]>)> {
+        let room = self.room_of.get(&from)?;
+        let members = self.rooms.get_mut(room)?;
+        let [a, b] = members.as_mut_slice() else {
+            // nobody to forward to yet
+            members[0].last_seen = now;
+            return None;
+        };
+        let (sender, receiver) = if a.address == from { (a, b) } else { (b, a) };
+        sender.last_seen = now;
+        sender.packets += 1;
+        sender.bytes += data.len() as u64;
+        if data.first() == Some(&0x6b)
+            && let Ok(packet) = NetworkPacket::decode(data)
+        {
+            sender.confirmed(packet.last_confirm(), now);
+            receiver.forwarding(packet.id(), now);
+        }
+        Some((receiver.address, data.into()))
+    }
+
+    /// removes the members idle for `IDLE_TIMEOUT`
+    pub fn expire(&mut self, now: Instant) {
+        let idle: Vec<_> = self
+            .rooms
+            .values()
+            .flatten()
+            .filter(|x| now.saturating_duration_since(x.last_seen) >= IDLE_TIMEOUT)
+            .map(|x| x.address)
+            .collect();
+        for address in idle {
+            self.leave(address);
+        }
+    }
+
+    pub fn stats(&self, room: &str) -> Option<RoomStats> {
+        Some(RoomStats {
+            room: room.to_string(),
+            members: self.rooms.get(room)?.iter().map(Member::stats).collect(),
+        })
+    }
+
+    /// the stats of every room, sorted by room code
+    pub fn all_stats(&self) -> Vec<RoomStats> {
+        let mut rooms: Vec<_> = self.rooms.keys().collect();
+        rooms.sort();
+        rooms.into_iter().filter_map(|x| self.stats(x)).collect()
+    }
+}
+
+/// runs a relay on `socket` until receiving fails, and logs the stats of every room every
+/// `log_every`
+pub fn serve(socket: &UdpSocket, log_every: Option<Duration>) -> io::Result<()> {
+    let mut relay = Relay::default();
+    let mut buf = [0; 2048];
+    let mut logged = Instant::now();
+    socket.set_read_timeout(Some(Duration::from_secs(1)))?;
+    loop {
+        match socket.recv_from(&mut buf) {
+            Ok((len, from)) => {
+                for (to, data) in relay.handle(Instant::now(), from, &buf[..len]) {
+                    if let Err(e) = socket.send_to(&data, to) {
+                        println!("failed to send to {}: {}", to, e);
+                    }
+                }
+            }
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => (),
+            // a member has gone, reported by some systems on the next receive
+            Err(e) if e.kind() == ErrorKind::ConnectionReset => (),
+            Err(e) => return Err(e),
+        }
+
+        let now = Instant::now();
+        relay.expire(now);
+        if let Some(every) = log_every
+            && now.saturating_duration_since(logged) >= every
+        {
+            for stats in relay.all_stats() {
+                println!("{}", stats.to_json());
+            }
+            logged = now;
+        }
+    }
+}
@@ -0,0 +1,156 @@
+use super::*;
+use crate::{
+    rollback::Game,
+    sim::{scripted_input, Peer},
+    transport::UdpTransport,
+};
+
+#[test]
+fn messages_round_trip() {
+    for message in [
+        Message::Register {
+            room: "tournament-1_a".to_string(),
+        },
+        Message::StatsRequest {
+            room: "x".to_string(),
+        },
+        Message::Stats {
+            json: "{}".to_string(),
+        },
+    ] {
+        assert_eq!(Message::decode(&message.encode()), Ok(message));
+    }
+    assert!(Message::decode(b"GRLY").is_err());
+    assert!(Message::decode(b"GRLY\x01").is_err());
+    assert!(Message::decode(b"GRLY\x01a b").is_err());
+    assert!(Message::decode(b"GRLY\x09abc").is_err());
+    assert!(!valid_room(&"a".repeat(MAX_ROOM_LEN + 1)));
+}
+
+fn address(port: u16) -> SocketAddr {
+    SocketAddr::from(([10, 0, 0, 1], port))
+}
+
+fn register(room: &str) -> Box<[u8]> {
+    Message::Register {
+        room: room.to_string(),
+    }
+    .encode()
+}
+
+#[test]
+fn members_of_a_room_are_forwarded_to_each_other() {
+    let now = Instant::now();
+    let mut relay = Relay::default();
+    let (a, b, c) = (address(1), address(2), address(3));
+
+    // nobody is registered, or nobody else is in the room
+    assert!(relay.handle(now, a, &[0x6b, 1]).is_empty());
+    assert!(relay.handle(now, a, &register("room")).is_empty());
+    assert!(relay.handle(now, a, &[0x6b, 1]).is_empty());
+
+    relay.handle(now, b, &register("room"));
+    assert_eq!(
+        relay.handle(now, a, &[1, 2, 3]),
+        vec![(b, [1, 2, 3].into())]
+    );
+    assert_eq!(relay.handle(now, b, &[4]), vec![(a, [4].into())]);
+
+    // the room is full
+    relay.handle(now, c, &register("room"));
+    assert!(relay.handle(now, c, &[5]).is_empty());
+    let stats = relay.stats("room").unwrap();
+    assert_eq!(stats.members.len(), 2);
+    // what a sent while alone in the room isn't counted
+    assert_eq!((stats.members[0].packets, stats.members[0].bytes), (1, 3));
+
+    // a leaves by joining another room, and c can take its place
+    relay.handle(now, a, &register("other"));
+    relay.handle(now, c, &register("room"));
+    assert_eq!(relay.handle(now, c, &[6]), vec![(b, [6].into())]);
+
+    // b is idle, c is not
+    relay.handle(now + IDLE_TIMEOUT / 2, c, &[7]);
+    relay.expire(now + IDLE_TIMEOUT);
+    let stats = relay.stats("room").unwrap();
+    assert_eq!(stats.members.len(), 1);
+    assert_eq!(stats.members[0].address, c);
+    relay.expire(now + IDLE_TIMEOUT * 2);
+    assert_eq!(relay.all_stats(), vec![]);
+}
+
+#[test]
+fn stats_are_answered() {
+    let now = Instant::now();
+    let mut relay = Relay::default();
+    relay.handle(now, address(1), &register("room"));
+    let request = Message::StatsRequest {
+        room: "room".to_string(),
+    };
+    let replies = relay.handle(now, address(9), &request.encode());
+    let [(to, reply)] = replies.as_slice() else {
+        panic!("{:?}", replies);
+    };
+    assert_eq!(*to, address(9));
+    let Ok(Message::Stats { json }) = Message::decode(reply) else {
+        panic!("{:?}", reply);
+    };
+    assert_eq!(
+        json,
+        "{\"room\": \"room\", \"rtt\": null, \"members\": [{\"address\": \"10.0.0.1:1\", \
+         \"packets\": 0, \"bytes\": 0, \"rtt_avg\": null, \"rtt_max\": null}]}"
+    );
+}
+
+/// two peers playing through a relay on local sockets
+#[test]
+fn a_match_through_the_relay() {
+    let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let relay = server.local_addr().unwrap();
+    std::thread::spawn(move || serve(&server, None));
+
+    let mut peers = [true, false].map(|is_p1| {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.send_to(&register("e2e"), relay).unwrap();
+        Peer::new(UdpTransport::new(socket, relay).unwrap(), is_p1, 2, 6)
+    });
+
+    let start = Instant::now();
+    while peers.iter().any(|x| x.rollbacker.game.frame_count() < 300) {
+        assert!(
+            start.elapsed() < Duration::from_secs(20),
+            "the match is stuck"
+        );
+        for (player, peer) in peers.iter_mut().enumerate() {
+            let frame = peer.rollbacker.game.frame_count();
+            peer.frame(scripted_input(0, player + 1, frame));
+        }
+        std::thread::sleep(Duration::from_millis(1));
+    }
+    let [p1, p2] = &peers;
+    let (h1, h2) = (&p1.rollbacker.state_hashes, &p2.rollbacker.state_hashes);
+    let common: Vec<_> = h1.keys().filter(|x| h2.contains_key(x)).collect();
+    assert!(common.len() > 100);
+    assert!(common.iter().all(|x| h1[x] == h2[x]));
+
+    let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+    client
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let request = Message::StatsRequest {
+        room: "e2e".to_string(),
+    };
+    client.send_to(&request.encode(), relay).unwrap();
+    let mut buf = [0; 2048];
+    let (len, _) = client.recv_from(&mut buf).unwrap();
+    let Ok(Message::Stats { json }) = Message::decode(&buf[..len]) else {
+        panic!("{:?}", &buf[..len]);
+    };
+    assert!(
+        json.starts_with("{\"room\": \"e2e\", \"rtt\": "),
+        "{}",
+        json
+    );
+    assert!(!json.contains("\"rtt\": null"), "{}", json);
+    assert!(!json.contains("\"packets\": 0,"), "{}", json);
+}
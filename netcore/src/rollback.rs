@@ -0,0 +1,316 @@
+use crate::println;
+use std::collections::{HashMap, VecDeque};
+
+use crate::{
+    desync::{MergeError, RegionTable},
+    predict::{PredictionStats, Predictor, RepeatLast},
+    INPUT_KEYS_NUMBERS,
+};
+
+pub type RInput = [bool; INPUT_KEYS_NUMBERS];
+
+/// What the rollback needs from the game
+pub trait Game {
+    type State: GameState;
+
+    /// the number of the frame which is about to be simulated
+    fn frame_count(&self) -> usize;
+    /// saves the current state
+    fn dump(&mut self) -> Self::State;
+    /// loads `state`; `dropped` are the states after it, which will never happen
+    fn restore<'a>(
+        &mut self,
+        state: &Self::State,
+        dropped: impl Iterator<Item = &'a mut Self::State>,
+    ) where
+        Self::State: 'a;
+    /// sets the inputs of the frame which is about to be simulated
+    fn apply_input(&mut self, input: RInput, opponent_input: RInput);
+    /// called before rolling back to `frame`, while the game is at `current`
+    fn rollback_from(&mut self, _frame: usize, _current: usize) {}
+    /// called before the last simulated frame of a rollback
+    fn rollback_done(&mut self) {}
+}
+
+/// A state saved by `Game::dump`
+pub trait GameState {
+    fn number(&self) -> usize;
+    /// a byte compared with old versions of giuroll, which don't send state hashes
+    fn weather_sync_check(&self) -> u8;
+    /// the frame is confirmed and will never be restored
+    fn did_happen(&mut self);
+    /// set by `did_happen` if the state is hashed
+    fn state_hash(&self) -> Option<u64>;
+    /// set by `did_happen` if the state is hashed
+    fn take_region_table(&mut self) -> Option<RegionTable>;
+    /// serializes the state for desync recovery
+    fn snapshot(&self) -> Box<[u8]>;
+    /// overwrites the state with a `snapshot` of the opponent
+    fn merge_snapshot(&mut self, snapshot: &[u8]) -> Result<(), MergeError>;
+}
+
+// pub enum MemoryManip {
+//     Alloc(usize),
+//     Free(usize),
+// }
+pub struct EnemyInputHolder {
+    pub i: Vec<Option<RInput>>,
+    /// guesses the inputs which haven't arrived yet
+    pub predictor: Box<dyn Predictor>,
+    pub stats: PredictionStats,
+    /// the inputs before this frame have been guessed at least once, if they weren't known
+    predicted_to: usize,
+    /// the inputs before this frame have been given to `Predictor::learn`
+    learned_to: usize,
+    /// the inputs which arrived again with different keys, and were dropped
+    pub conflicts: usize,
+}
+
+impl EnemyInputHolder {
+    fn new() -> Self {
+        Self {
+            i: Vec::new(),
+            predictor: Box::new(RepeatLast),
+            stats: PredictionStats::default(),
+            predicted_to: 0,
+            learned_to: 0,
+            conflicts: 0,
+        }
+    }
+    fn get(&mut self, count: usize) -> RInput {
+        match self.get_result(count) {
+            Ok(x) => x,
+            Err(x) => {
+                self.predicted_to = self.predicted_to.max(count + 1);
+                x
+            }
+        }
+    }
+
+    /// the first input of a frame is kept, a different one can only come from a broken or
+    /// forged packet
+    pub fn insert(&mut self, input: RInput, frame: usize) {
+        while frame >= self.i.len() {
+            self.i.push(None);
+        }
+        if let Some(x) = self.i[frame] {
+            if x != input {
+                println!("dropping a different input of frame {}", frame);
+                self.conflicts += 1;
+            }
+            return;
+        }
+        if self.i[frame].is_none() && frame < self.predicted_to {
+            // the inputs arrive newest first, so this is still the guess that has been used
+            let guess = self.predictor.predict(&self.i, frame);
+            let wrong_keys = (0..INPUT_KEYS_NUMBERS)
+                .filter(|x| guess[*x] != input[*x])
+                .count();
+            self.stats.predicted += 1;
+            self.stats.mispredicted += (wrong_keys > 0) as usize;
+            self.stats.wrong_keys += wrong_keys;
+        }
+        self.i[frame] = Some(input);
+        while let Some(Some(x)) = self.i.get(self.learned_to) {
+            self.predictor.learn(*x);
+            self.learned_to += 1;
+        }
+    }
+
+    fn get_result(&self, frame: usize) -> Result<RInput, RInput> {
+        match self.i.get(frame) {
+            Some(Some(x)) => Ok(*x),
+            _ => Err(self.predictor.predict(&self.i, frame)),
+        }
+    }
+}
+
+pub struct Rollbacker<G: Game> {
+    pub game: G,
+    pub guessed: Vec<RollFrame<G::State>>,
+
+    current: usize,
+    rolling_back: bool,
+
+    // pub future_sound: HashMap<usize, usize>,
+    // first element is the sound, second is the frame it occured at, whenever a frame comes true we can delete all future sounds with that value
+
+    // stores all the sounds that happened in "guessed" frames. Will also need to be topped up *after* last frame.
+    // every frame we can store this as "past_sounds", and if any sound in future sounds did not appear in past_sounds, we can then cancell that sound by force calling 0x401d50
+    // which we hook, and set a static to ignore the sound.
+
+    //also, while rolling back, we should not play sounds that already did appear in past_sounds (and instead remove them, so we can see what is)
+    pub enemy_inputs: EnemyInputHolder,
+    pub self_inputs: Vec<RInput>,
+
+    pub weathers: HashMap<usize, u8>,
+    /// `GameState::state_hash` of the frames that did happen, by frame number
+    pub state_hashes: HashMap<usize, u64>,
+    /// `GameState::take_region_table` of the latest frames that did happen, the oldest first
+    pub region_tables: VecDeque<RegionTable>,
+
+    /// frames from this one are kept in `guessed` even if confirmed, so that they can still be
+    /// restored after the state of the opponent is received (desync recovery)
+    pub hold_from: Option<usize>,
+    /// when this frame is confirmed, `GameState::snapshot` of it is stored into `snapshot`
+    pub snapshot_frame: Option<usize>,
+    pub snapshot: Option<Box<[u8]>>,
+}
+
+/// the number of region tables kept by `Rollbacker`, so that the opponent can ask for them
+const REGION_TABLE_FRAMES: usize = 90;
+
+impl<G: Game> Rollbacker<G> {
+    pub fn new(game: G) -> Self {
+        Self {
+            game,
+            guessed: Vec::new(),
+            current: 0,
+            rolling_back: false,
+            enemy_inputs: EnemyInputHolder::new(),
+            self_inputs: Vec::new(),
+            weathers: HashMap::new(),
+            state_hashes: HashMap::new(),
+            region_tables: VecDeque::new(),
+            hold_from: None,
+            snapshot_frame: None,
+            snapshot: None,
+            // future_sound: HashMap::new(),
+        }
+    }
+
+    /// fill in inputs before calling this function
+    pub fn start(&mut self) -> usize {
+        //this should only be called on the 0th iteration.
+        self.current = self.game.frame_count();
+        //let newsound = std::mem::replace(&mut *SOUNDS_THAT_DID_HAPPEN.lock().unwrap(), BTreeMap::new());
+
+        while !self.guessed.is_empty()
+            && (self
+                .enemy_inputs
+                .get_result(self.guessed[0].prev_state.number())
+                .map(|x| x == self.guessed[0].enemy_input)
+                .unwrap_or(false))
+            && !self.guessed[0].force_rollback
+            && self
+                .hold_from
+                .map_or(true, |x| self.guessed[0].prev_state.number() < x)
+        {
+            let mut m = self.guessed.remove(0);
+            let number = m.prev_state.number();
+
+            if self.snapshot_frame == Some(number) {
+                self.snapshot = Some(m.prev_state.snapshot());
+                self.snapshot_frame = None;
+            }
+
+            self.weathers
+                .insert(number, m.prev_state.weather_sync_check());
+            m.prev_state.did_happen();
+            if let Some(hash) = m.prev_state.state_hash() {
+                self.state_hashes.insert(number, hash);
+                let oldest = number.saturating_sub(600);
+                self.state_hashes.retain(|x, _| *x >= oldest);
+            }
+            if let Some(table) = m.prev_state.take_region_table() {
+                if self.region_tables.len() >= REGION_TABLE_FRAMES {
+                    self.region_tables.pop_front();
+                }
+                self.region_tables.push_back(table);
+            }
+            #[cfg(feature = "logrollback")]
+            println!("did_happen {}", number);
+            //let b = &mut *FREEMUTEX.lock().unwrap();
+            //for a in m.prev_state.frees {
+            //    b.insert(a);
+            //}
+        }
+
+        //*SOUND_DELET_MUTEX.lock().unwrap() = newsound;
+
+        self.rolling_back = false;
+        self.guessed.len() + 1
+    }
+
+    fn apply_input(&mut self, input: RInput, opponent_input: RInput) {
+        #[cfg(feature = "logrollback")]
+        println!("apply input {:?}", opponent_input);
+        self.game.apply_input(input, opponent_input);
+    }
+
+    pub fn step(&mut self, iteration_number: usize) -> Option<()> {
+        let tbr = if self.guessed.len() == iteration_number {
+            //last iteration for this frame, handle sound here
+            if self.rolling_back {
+                self.game.rollback_done();
+            }
+
+            let current = self.game.frame_count();
+
+            let si = self.self_inputs[current];
+            let ei = self.enemy_inputs.get(current);
+            self.apply_input(si, ei);
+            let prev_state = self.game.dump();
+            #[cfg(feature = "logrollback")]
+            println!("dump {} with guess", prev_state.number());
+            self.guessed.push(RollFrame {
+                prev_state,
+                player_input: si,
+                enemy_input: ei,
+                force_rollback: false,
+            });
+
+            Some(())
+        } else {
+            let (fr_, remain) = self.guessed[iteration_number..].split_at_mut(1);
+            let fr = &mut fr_[0];
+            if self.rolling_back {
+                fr.prev_state = self.game.dump();
+                #[cfg(feature = "logrollback")]
+                println!("dump {}", fr.prev_state.number());
+                // the following have been done when `restore`:
+                // let prev = std::mem::replace(&mut fr.prev_state, frame);
+                // prev.never_happened();
+                //let b = &mut *ALLOCMUTEX.lock().unwrap();
+                //for a in prev.allocs {
+                //    b.insert(a);
+                //}
+                fr.enemy_input = self.enemy_inputs.get(fr.prev_state.number());
+                let (player_input, enemy_input) = (fr.player_input, fr.enemy_input);
+                self.apply_input(player_input, enemy_input);
+                Some(())
+            } else if fr.force_rollback
+                || fr.enemy_input != self.enemy_inputs.get(fr.prev_state.number())
+            {
+                //info!("ROLLBACK");
+                fr.force_rollback = false;
+                self.game
+                    .rollback_from(fr.prev_state.number(), self.current);
+                self.rolling_back = true;
+                self.game
+                    .restore(&fr.prev_state, remain.iter_mut().map(|x| &mut x.prev_state));
+                #[cfg(feature = "logrollback")]
+                println!("restore {}", fr.prev_state.number());
+                //fr.prev_state.clone().never_happened();
+
+                fr.enemy_input = self.enemy_inputs.get(fr.prev_state.number());
+                let (player_input, enemy_input) = (fr.player_input, fr.enemy_input);
+                self.apply_input(player_input, enemy_input);
+                Some(())
+            } else {
+                None
+            }
+        };
+
+        tbr
+    }
+}
+
+pub struct RollFrame<S> {
+    pub prev_state: S,
+    pub player_input: RInput,
+    pub enemy_input: RInput,
+    /// restore `prev_state` in the next `Rollbacker::step` even if the guess was right,
+    /// e.g. after it's been replaced by the state of the opponent
+    pub force_rollback: bool,
+}
@@ -0,0 +1,633 @@
+//! A deterministic simulation of two peers playing over a virtual link.
+//!
+//! Everything runs on a virtual clock, so that a given `SimConfig` always gives the same
+//! `SimReport`, and a whole match takes milliseconds.
+
+use std::{cell::RefCell, rc::Rc, time::Duration, time::Instant};
+
+use crate::{
+    autodelay::AutoDelay,
+    autorollback::{AutoRollback, RollbackBounds},
+    desync::{MergeError, RecoveryNotice, RegionTable},
+    input_to_accum,
+    netcode::{Netcoder, PauseReason},
+    predict::{PredictionStats, PredictorKind},
+    rollback::{Game, GameState, RInput, Rollbacker},
+    transport::Transport,
+};
+
+#[cfg(test)]
+mod tests;
+
+/// the frame time soku aims at, in microseconds
+pub const FRAME_TIME: i64 = 1_000_000 / 60;
+/// the length of the rounds, whose first `ROUND_START_FRAMES` frames are safe to change the
+/// delay, like the round start animation of soku
+pub const ROUND_FRAMES: usize = 600;
+pub const ROUND_START_FRAMES: usize = 120;
+
+/// A desync made on purpose on p2
+#[derive(Clone, Copy, Debug)]
+pub struct Desync {
+    pub frame: usize,
+    /// p2 also gets an object p1 doesn't have, so that the snapshots of p1 can't be merged
+    pub extra_object: bool,
+}
+
+/// A game whose whole state is a number mixed with the inputs of every frame, and a number of
+/// objects, which only a `Desync` changes
+pub struct Toy {
+    frame: usize,
+    value: u64,
+    objects: usize,
+    inputs: (u16, u16),
+    is_p1: bool,
+    pub desync: Option<Desync>,
+    /// the number of rollbacks
+    pub rollbacks: usize,
+    /// the most frames simulated again by one rollback
+    pub max_rollback: usize,
+}
+
+pub struct ToyState {
+    number: usize,
+    value: u64,
+    objects: usize,
+    hash: Option<u64>,
+}
+
+impl Toy {
+    pub fn new(is_p1: bool) -> Self {
+        Self {
+            frame: 0,
+            value: 1,
+            objects: 0,
+            inputs: (0, 0),
+            is_p1,
+            desync: None,
+            rollbacks: 0,
+            max_rollback: 0,
+        }
+    }
+
+    /// simulates the frame with the inputs of the last `apply_input`
+    pub fn simulate(&mut self) {
+        let (p1, p2) = self.inputs;
+        self.value = self
+            .value
+            .wrapping_mul(0x100000001b3)
+            .wrapping_add(((p1 as u64) << 16) | p2 as u64);
+        if let Some(desync) = self.desync
+            && desync.frame == self.frame
+        {
+            self.value ^= 1;
+            self.objects += desync.extra_object as usize;
+        }
+        self.frame += 1;
+    }
+}
+
+impl GameState for ToyState {
+    fn number(&self) -> usize {
+        self.number
+    }
+
+    fn weather_sync_check(&self) -> u8 {
+        0
+    }
+
+    fn did_happen(&mut self) {
+        self.hash = Some(self.value ^ (self.objects as u64) << 56);
+    }
+
+    fn state_hash(&self) -> Option<u64> {
+        self.hash
+    }
+
+    fn take_region_table(&mut self) -> Option<RegionTable> {
+        None
+    }
+
+    fn snapshot(&self) -> Box<[u8]> {
+        [self.value, self.objects as u64]
+            .map(u64::to_le_bytes)
+            .concat()
+            .into()
+    }
+
+    /// like `Frame::merge_snapshot` of giuroll, only the states with the same objects merge
+    fn merge_snapshot(&mut self, snapshot: &[u8]) -> Result<(), MergeError> {
+        let word = |n: usize| {
+            snapshot
+                .get(n * 8..n * 8 + 8)
+                .map(|x| u64::from_le_bytes(x.try_into().unwrap()))
+                .ok_or_else(|| MergeError::Invalid("bad snapshot".to_string()))
+        };
+        let (value, objects) = (word(0)?, word(1)? as usize);
+        if objects != self.objects {
+            return Err(MergeError::DifferentLayout(format!(
+                "{} objects in the snapshot, {} here",
+                objects, self.objects
+            )));
+        }
+        self.value = value;
+        Ok(())
+    }
+}
+
+impl Game for Toy {
+    type State = ToyState;
+
+    fn frame_count(&self) -> usize {
+        self.frame
+    }
+
+    fn dump(&mut self) -> ToyState {
+        ToyState {
+            number: self.frame,
+            value: self.value,
+            objects: self.objects,
+            hash: None,
+        }
+    }
+
+    fn restore<'a>(&mut self, state: &ToyState, _: impl Iterator<Item = &'a mut ToyState>) {
+        self.frame = state.number;
+        self.value = state.value;
+        self.objects = state.objects;
+    }
+
+    fn apply_input(&mut self, input: RInput, opponent_input: RInput) {
+        let (input, opponent_input) = (input_to_accum(&input), input_to_accum(&opponent_input));
+        self.inputs = match self.is_p1 {
+            true => (input, opponent_input),
+            false => (opponent_input, input),
+        };
+    }
+
+    fn rollback_from(&mut self, frame: usize, current: usize) {
+        self.rollbacks += 1;
+        self.max_rollback = self.max_rollback.max(current - frame);
+    }
+}
+
+/// splitmix64, so that the simulation doesn't depend on anything but its seed
+#[derive(Clone)]
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Self(seed)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    /// true with the probability `p`
+    pub fn chance(&mut self, p: f64) -> bool {
+        p > 0.0 && ((self.next_u64() >> 11) as f64) < p * (1u64 << 53) as f64
+    }
+
+    /// uniform in `0..=max`
+    pub fn duration(&mut self, max: Duration) -> Duration {
+        match max.as_micros() as u64 {
+            0 => Duration::ZERO,
+            x => Duration::from_micros(self.next_u64() % (x + 1)),
+        }
+    }
+}
+
+/// One direction of the virtual link
+#[derive(Clone, Debug)]
+pub struct LinkConfig {
+    /// the one-way delay of every packet
+    pub latency: Duration,
+    /// a random delay in `0..=jitter` added to each packet
+    pub jitter: Duration,
+    /// the probability that a packet is lost
+    pub loss: f64,
+    /// after a packet is lost, the packets sent within this time are lost too
+    pub burst: Duration,
+    /// the probability that a packet arrives twice
+    pub duplicate: f64,
+    /// the probability that a packet is held back for another `latency`, arriving after the
+    /// following ones
+    pub reorder: f64,
+}
+
+impl Default for LinkConfig {
+    fn default() -> Self {
+        Self {
+            latency: Duration::from_millis(30),
+            jitter: Duration::ZERO,
+            loss: 0.0,
+            burst: Duration::ZERO,
+            duplicate: 0.0,
+            reorder: 0.0,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct SimConfig {
+    pub seed: u64,
+    /// the number of frames both peers have to simulate
+    pub frames: usize,
+    pub delay: usize,
+    pub max_rollback: u8,
+    /// from p1 to p2
+    pub p1_to_p2: LinkConfig,
+    /// from p2 to p1
+    pub p2_to_p1: LinkConfig,
+    /// how much faster the clock of p2 runs than the one of p1, e.g. `0.001` for 0.1%
+    pub p2_clock_skew: f64,
+    /// how long after p1 p2 starts the match
+    pub p2_start: Duration,
+    /// `Netcoder::recovery_enabled` of both peers
+    pub recovery: bool,
+    pub desync: Option<Desync>,
+    /// `Netcoder::resend_after` of both peers
+    pub resend_after: Option<Duration>,
+    /// `AutoDelay::target_rollback` of both peers, if they use auto delay
+    pub auto_delay: Option<i8>,
+    /// the bounds of `AutoRollback` of both peers, if they adapt the max rollback
+    pub auto_rollback: Option<RollbackBounds>,
+    /// how both peers guess the inputs of the opponent
+    pub predictor: PredictorKind,
+}
+
+impl Default for SimConfig {
+    fn default() -> Self {
+        Self {
+            seed: 0,
+            frames: 1200,
+            delay: 2,
+            max_rollback: 6,
+            p1_to_p2: LinkConfig::default(),
+            p2_to_p1: LinkConfig::default(),
+            p2_clock_skew: 0.0,
+            p2_start: Duration::ZERO,
+            recovery: false,
+            desync: None,
+            resend_after: None,
+            auto_delay: None,
+            auto_rollback: None,
+            predictor: PredictorKind::RepeatLast,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct LinkStats {
+    pub sent: usize,
+    pub lost: usize,
+    pub duplicated: usize,
+    pub reordered: usize,
+}
+
+struct InFlight {
+    at: Duration,
+    seq: u64,
+    data: Box<[u8]>,
+}
+
+struct Link {
+    config: LinkConfig,
+    rng: Rng,
+    seq: u64,
+    in_flight: Vec<InFlight>,
+    /// the packets are lost until then
+    burst_until: Option<Duration>,
+    stats: LinkStats,
+}
+
+impl Link {
+    fn send(&mut self, now: Duration, data: &[u8]) {
+        self.stats.sent += 1;
+        if self.burst_until.is_some_and(|x| now < x) {
+            self.stats.lost += 1;
+            return;
+        }
+        if self.rng.chance(self.config.loss) {
+            self.stats.lost += 1;
+            self.burst_until = Some(now + self.config.burst);
+            return;
+        }
+        let copies = match self.rng.chance(self.config.duplicate) {
+            true => {
+                self.stats.duplicated += 1;
+                2
+            }
+            false => 1,
+        };
+        for _ in 0..copies {
+            let mut at = now + self.config.latency + self.rng.duration(self.config.jitter);
+            if self.rng.chance(self.config.reorder) {
+                self.stats.reordered += 1;
+                at += self.config.latency.max(Duration::from_millis(1));
+            }
+            self.seq += 1;
+            self.in_flight.push(InFlight {
+                at,
+                seq: self.seq,
+                data: data.into(),
+            });
+        }
+    }
+
+    /// the earliest packet which has arrived by `now`
+    fn recv(&mut self, now: Duration) -> Option<InFlight> {
+        let index = (0..self.in_flight.len())
+            .filter(|x| self.in_flight[*x].at <= now)
+            .min_by_key(|x| (self.in_flight[*x].at, self.in_flight[*x].seq))?;
+        Some(self.in_flight.swap_remove(index))
+    }
+}
+
+struct Network {
+    now: Duration,
+    /// `links[x]` carries the packets to the peer `x` (0 for p1)
+    links: [Link; 2],
+}
+
+/// One end of the virtual link, with the virtual clock
+pub struct SimTransport {
+    network: Rc<RefCell<Network>>,
+    base: Instant,
+    /// 0 for p1
+    side: usize,
+}
+
+impl Transport for SimTransport {
+    fn send(&mut self, data: &[u8]) {
+        let mut network = self.network.borrow_mut();
+        let now = network.now;
+        network.links[1 - self.side].send(now, data);
+    }
+
+    fn recv(&mut self) -> Option<(Box<[u8]>, Instant)> {
+        let mut network = self.network.borrow_mut();
+        let now = network.now;
+        network.links[self.side]
+            .recv(now)
+            .map(|x| (x.data, self.base + x.at))
+    }
+
+    fn now(&self) -> Instant {
+        self.base + self.network.borrow().now
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PeerReport {
+    /// the frames simulated (not counting the ones simulated again by rollbacks)
+    pub frames: usize,
+    pub pauses: usize,
+    pub frame_missing_pauses: usize,
+    pub input_missing_pauses: usize,
+    pub rollbacks: usize,
+    pub max_rollback: usize,
+    /// the sum of every correction of the frame time target, in microseconds
+    pub target_offset: i64,
+    /// the largest correction of the frame time target in one frame, in microseconds
+    pub max_target_offset: i32,
+    /// the last `Netcoder::recovery_notice`
+    pub recovery_notice: Option<RecoveryNotice>,
+    /// `ClockSync::offset_at` the end, in microseconds
+    pub clock_offset: i64,
+    /// `ClockSync::skew` at the end, in microseconds per frame
+    pub clock_skew: i64,
+    /// the packets of the opponent `Netcoder` has counted as lost
+    pub lost_packets: usize,
+    /// `Netcoder::duplicate_packets`, `reordered_packets` and `late_packets`
+    pub duplicate_packets: usize,
+    pub reordered_packets: usize,
+    pub late_packets: usize,
+    /// the delay at the end
+    pub delay: usize,
+    pub delay_changes: usize,
+    /// the frame at which the delay last changed
+    pub last_delay_change: Option<usize>,
+    /// `Netcoder::max_rollback` at the end
+    pub max_rollback_setting: usize,
+    /// how well the inputs of the opponent were guessed
+    pub prediction: PredictionStats,
+    /// what the link from this peer to the opponent has done
+    pub link: LinkStats,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SimReport {
+    pub p1: PeerReport,
+    pub p2: PeerReport,
+    /// both peers have simulated `SimConfig::frames` frames before the time ran out
+    pub finished: bool,
+    /// the number of frames confirmed by both peers whose states were compared
+    pub compared_frames: usize,
+    /// the first of them whose states differ
+    pub first_mismatch: Option<usize>,
+    /// the largest difference between the frame counts of the two peers
+    pub max_frame_lead: usize,
+}
+
+impl SimReport {
+    /// the peers have finished the match in the same state
+    pub fn states_match(&self) -> bool {
+        self.finished && self.compared_frames > 0 && self.first_mismatch.is_none()
+    }
+}
+
+/// A peer driving its `Netcoder` and `Rollbacker` the way `handle_online` of giuroll does
+pub struct Peer<T: Transport> {
+    pub netcoder: Netcoder<T>,
+    pub rollbacker: Rollbacker<Toy>,
+    pub report: PeerReport,
+}
+
+impl<T: Transport> Peer<T> {
+    pub fn new(transport: T, is_p1: bool, delay: usize, max_rollback: u8) -> Self {
+        let mut netcoder = Netcoder::new(transport, is_p1, max_rollback);
+        netcoder.delay = delay;
+        Self {
+            netcoder,
+            rollbacker: Rollbacker::new(Toy::new(is_p1)),
+            report: PeerReport::default(),
+        }
+    }
+
+    /// one frame of the game; returns the correction of the frame time target
+    pub fn frame(&mut self, input: RInput) -> i32 {
+        if self.rollbacker.game.frame_count() % ROUND_FRAMES < ROUND_START_FRAMES {
+            self.netcoder.apply_agreed_delay();
+        }
+        self.advance(input);
+
+        // the same bounds as `timing_loop` of giuroll
+        let offset = self.netcoder.take_target_offset().clamp(-1000, 10000);
+        self.report.target_offset += offset as i64;
+        if offset.abs() > self.report.max_target_offset.abs() {
+            self.report.max_target_offset = offset;
+        }
+        offset
+    }
+
+    /// `process_and_send` and the simulation of the frames it asks for, which it returns
+    pub fn advance(&mut self, input: RInput) -> u32 {
+        let delay = self.netcoder.delay;
+        let speed = self.netcoder.process_and_send(&mut self.rollbacker, input);
+        if self.netcoder.delay != delay {
+            self.report.delay_changes += 1;
+            self.report.last_delay_change = Some(self.rollbacker.game.frame_count());
+        }
+        match self.netcoder.pause {
+            Some(PauseReason::FrameMissing) => self.report.frame_missing_pauses += 1,
+            Some(PauseReason::InputMissing) => self.report.input_missing_pauses += 1,
+            _ => (),
+        }
+        if self.netcoder.pause.is_some() {
+            self.report.pauses += 1;
+        }
+        for iteration in 0..speed as usize {
+            if self.rollbacker.step(iteration).is_none() {
+                break;
+            }
+            self.rollbacker.game.simulate();
+        }
+
+        let game = &self.rollbacker.game;
+        self.report.frames = game.frame_count();
+        self.report.rollbacks = game.rollbacks;
+        self.report.max_rollback = game.max_rollback;
+        self.report.recovery_notice = self.netcoder.recovery_notice;
+        self.report.lost_packets = self.netcoder.lost_packets;
+        self.report.duplicate_packets = self.netcoder.duplicate_packets;
+        self.report.reordered_packets = self.netcoder.reordered_packets;
+        self.report.late_packets = self.netcoder.late_packets;
+        self.report.delay = self.netcoder.delay;
+        self.report.max_rollback_setting = self.netcoder.max_rollback;
+        self.report.prediction = self.rollbacker.enemy_inputs.stats;
+        self.report.clock_offset = self.netcoder.clock.offset_at(game.frame_count()) as i64;
+        self.report.clock_skew = self.netcoder.clock.skew as i64;
+        speed
+    }
+}
+
+/// the input of `player` (1 or 2) on `frame`: keys are held for a while, then released
+pub fn scripted_input(seed: u64, player: usize, frame: usize) -> RInput {
+    std::array::from_fn(|key| {
+        let period = 7 + key * 3;
+        let held = seed ^ (player as u64) << 48 ^ (key as u64) << 40 ^ (frame / period) as u64;
+        Rng::new(held).next_u64() % 3 == 0
+    })
+}
+
+/// runs a match between two peers over the virtual links of `config`
+pub fn simulate(config: &SimConfig) -> SimReport {
+    let link = |config: &LinkConfig, seed: u64| Link {
+        config: config.clone(),
+        rng: Rng::new(seed),
+        seq: 0,
+        in_flight: Vec::new(),
+        burst_until: None,
+        stats: LinkStats::default(),
+    };
+    let network = Rc::new(RefCell::new(Network {
+        now: Duration::ZERO,
+        // the links shouldn't draw the same numbers
+        links: [
+            link(&config.p2_to_p1, config.seed),
+            link(&config.p1_to_p2, !config.seed),
+        ],
+    }));
+
+    let base = Instant::now();
+    let mut peers = [0, 1].map(|side| {
+        let transport = SimTransport {
+            network: network.clone(),
+            base,
+            side,
+        };
+        let mut peer = Peer::new(transport, side == 0, config.delay, config.max_rollback);
+        peer.netcoder.recovery_enabled = config.recovery;
+        if side == 1 {
+            peer.rollbacker.game.desync = config.desync;
+        }
+        peer.netcoder.resend_after = config.resend_after;
+        peer.netcoder.autodelay = config.auto_delay.map(|x| AutoDelay::new(x, 60));
+        peer.netcoder.autorollback = config.auto_rollback.map(|x| AutoRollback::new(x, 60));
+        peer.rollbacker.enemy_inputs.predictor = config.predictor.build();
+        peer
+    });
+    // when each peer runs its next frame, on the clock of the simulation
+    let mut targets = [Duration::ZERO, config.p2_start];
+    let rates = [1.0, 1.0 + config.p2_clock_skew];
+    // from `Netcoder::take_copy`
+    let mut copies: [Option<(Box<[u8]>, Instant)>; 2] = [None, None];
+
+    let time_limit = Duration::from_micros(FRAME_TIME as u64 * config.frames as u64 * 4)
+        + config.p2_start
+        + Duration::from_secs(10);
+    let mut report = SimReport::default();
+
+    loop {
+        let done = peers
+            .iter()
+            .all(|x| x.rollbacker.game.frame_count() >= config.frames);
+        // the copies of packets are sent while waiting for the next frame
+        let events = [0, 1].map(|side| match &copies[side] {
+            Some((_, x)) if x.saturating_duration_since(base) < targets[side] => {
+                (x.saturating_duration_since(base), true)
+            }
+            _ => (targets[side], false),
+        });
+        let side = (events[1].0 < events[0].0) as usize;
+        let (time, is_resend) = events[side];
+        if done || time > time_limit {
+            report.finished = done;
+            break;
+        }
+
+        {
+            let now = &mut network.borrow_mut().now;
+            *now = time.max(*now);
+        }
+        let peer = &mut peers[side];
+        if is_resend {
+            let (data, _) = copies[side].take().unwrap();
+            peer.netcoder.transport.send(&data);
+            continue;
+        }
+        let input = scripted_input(config.seed, side + 1, peer.rollbacker.game.frame_count());
+        let offset = peer.frame(input);
+        copies[side] = peer.netcoder.take_copy();
+
+        let frame_time = (FRAME_TIME + offset as i64) as f64 / rates[side];
+        targets[side] += Duration::from_micros(frame_time.max(0.0) as u64);
+
+        let (f1, f2) = (
+            peers[0].rollbacker.game.frame_count(),
+            peers[1].rollbacker.game.frame_count(),
+        );
+        report.max_frame_lead = report.max_frame_lead.max(f1.abs_diff(f2));
+    }
+
+    let network = network.borrow();
+    for (side, peer) in peers.iter_mut().enumerate() {
+        peer.report.link = network.links[1 - side].stats.clone();
+    }
+    let [p1, p2] = peers;
+
+    let (h1, h2) = (&p1.rollbacker.state_hashes, &p2.rollbacker.state_hashes);
+    let mut common: Vec<_> = h1.keys().filter(|x| h2.contains_key(x)).collect();
+    common.sort();
+    report.compared_frames = common.len();
+    report.first_mismatch = common.into_iter().find(|x| h1[x] != h2[x]).cloned();
+    report.p1 = p1.report;
+    report.p2 = p2.report;
+    report
+}
@@ -0,0 +1,371 @@
+use super::*;
+use crate::predict::PredictorKind;
+
+fn lossy() -> LinkConfig {
+    LinkConfig {
+        latency: Duration::from_millis(40),
+        jitter: Duration::from_millis(15),
+        loss: 0.05,
+        duplicate: 0.02,
+        reorder: 0.05,
+        ..Default::default()
+    }
+}
+
+#[test]
+fn perfect_link() {
+    let report = simulate(&SimConfig::default());
+    assert!(report.states_match(), "{:#?}", report);
+    assert_eq!(report.p1.pauses + report.p2.pauses, 0);
+    assert_eq!(report.p1.rollbacks + report.p2.rollbacks, 0);
+}
+
+#[test]
+fn lossy_link() {
+    let report = simulate(&SimConfig {
+        p1_to_p2: lossy(),
+        p2_to_p1: lossy(),
+        ..Default::default()
+    });
+    assert!(report.states_match(), "{:#?}", report);
+    assert!(report.p1.link.lost > 0 && report.p1.link.reordered > 0);
+    assert!(report.p1.rollbacks > 0);
+    assert!(report.p1.max_rollback <= 6 && report.p2.max_rollback <= 6);
+}
+
+#[test]
+fn one_way_heavy_loss() {
+    let report = simulate(&SimConfig {
+        p1_to_p2: LinkConfig {
+            loss: 0.3,
+            duplicate: 0.2,
+            reorder: 0.2,
+            ..lossy()
+        },
+        p2_to_p1: lossy(),
+        ..Default::default()
+    });
+    assert!(report.states_match(), "{:#?}", report);
+}
+
+#[test]
+fn skewed_clocks_are_compensated() {
+    let report = simulate(&SimConfig {
+        frames: 3600,
+        p2_clock_skew: 0.01,
+        ..Default::default()
+    });
+    assert!(report.states_match(), "{:#?}", report);
+    // the faster peer waits longer, the slower one shorter
+    assert!(report.p2.target_offset > 0, "{:#?}", report);
+    assert!(report.p1.target_offset < 0, "{:#?}", report);
+    assert!(report.max_frame_lead <= 3, "{:#?}", report);
+    // p2 is faster by 1% of a frame, each peer makes up half of it
+    assert!((60..=110).contains(&report.p2.clock_skew), "{:#?}", report);
+    assert!(
+        (-110..=-60).contains(&report.p1.clock_skew),
+        "{:#?}",
+        report
+    );
+    assert!(report.p1.clock_offset.abs() < 1000, "{:#?}", report);
+}
+
+#[test]
+fn late_start_is_caught_up() {
+    let report = simulate(&SimConfig {
+        frames: 3600,
+        p2_start: Duration::from_millis(200),
+        ..Default::default()
+    });
+    assert!(report.states_match(), "{:#?}", report);
+    // p1 waits for p2
+    assert!(report.p1.target_offset > 0, "{:#?}", report);
+    assert!(report.max_frame_lead <= 15, "{:#?}", report);
+    assert!(report.p1.clock_offset.abs() < 1000, "{:#?}", report);
+    assert!(report.p1.clock_skew.abs() < 30, "{:#?}", report);
+}
+
+#[test]
+fn same_seed_same_report() {
+    let config = SimConfig {
+        seed: 42,
+        p1_to_p2: lossy(),
+        p2_to_p1: lossy(),
+        ..Default::default()
+    };
+    let report = simulate(&config);
+    assert_eq!(report, simulate(&config));
+    let other = simulate(&SimConfig { seed: 43, ..config });
+    assert_ne!(report.p1.link, other.p1.link);
+}
+
+#[test]
+fn lost_packets_are_counted() {
+    let link = LinkConfig {
+        loss: 0.05,
+        ..Default::default()
+    };
+    let report = simulate(&SimConfig {
+        p1_to_p2: link.clone(),
+        p2_to_p1: link,
+        ..Default::default()
+    });
+    assert!(report.states_match(), "{:#?}", report);
+    for (peer, opponent) in [(&report.p1, &report.p2), (&report.p2, &report.p1)] {
+        assert!(opponent.link.lost > 0);
+        // the newest packets haven't been counted yet
+        assert!(opponent.link.lost - peer.lost_packets <= 2, "{:#?}", report);
+    }
+}
+
+#[test]
+fn duplicate_and_reordered_packets_are_counted() {
+    let link = LinkConfig {
+        duplicate: 0.05,
+        reorder: 0.05,
+        ..Default::default()
+    };
+    let report = simulate(&SimConfig {
+        p1_to_p2: link.clone(),
+        p2_to_p1: link,
+        ..Default::default()
+    });
+    assert!(report.states_match(), "{:#?}", report);
+    for (peer, opponent) in [(&report.p1, &report.p2), (&report.p2, &report.p1)] {
+        assert!(opponent.link.duplicated > 0 && opponent.link.reordered > 0);
+        // the packets still in flight at the end
+        assert!(
+            opponent.link.duplicated - peer.duplicate_packets <= 2,
+            "{:#?}",
+            report
+        );
+        // a copy held back counts as a duplicate
+        assert!(
+            peer.reordered_packets <= opponent.link.reordered,
+            "{:#?}",
+            report
+        );
+        assert!(
+            peer.reordered_packets * 3 > opponent.link.reordered * 2,
+            "{:#?}",
+            report
+        );
+        assert_eq!(peer.lost_packets, 0, "{:#?}", report);
+    }
+
+    // the copies are expected
+    let report = simulate(&SimConfig {
+        resend_after: Some(Duration::from_millis(8)),
+        ..Default::default()
+    });
+    assert_eq!(report.p1.duplicate_packets, 0, "{:#?}", report);
+    assert_eq!(report.p1.reordered_packets, 0, "{:#?}", report);
+    assert_eq!(report.p1.late_packets, 0, "{:#?}", report);
+}
+
+#[test]
+fn late_packets_are_counted() {
+    // held back for 10 frames, past the max rollback and the delay
+    let link = LinkConfig {
+        latency: Duration::from_millis(170),
+        reorder: 0.05,
+        ..Default::default()
+    };
+    let report = simulate(&SimConfig {
+        max_rollback: 15,
+        p1_to_p2: link.clone(),
+        p2_to_p1: link,
+        ..Default::default()
+    });
+    assert!(report.states_match(), "{:#?}", report);
+    assert!(report.p1.late_packets > 0, "{:#?}", report);
+    assert!(
+        report.p1.late_packets <= report.p1.reordered_packets,
+        "{:#?}",
+        report
+    );
+}
+
+#[test]
+fn copies_recover_bursts_of_loss() {
+    let link = LinkConfig {
+        loss: 0.05,
+        burst: Duration::from_millis(4),
+        jitter: Duration::from_millis(3),
+        ..Default::default()
+    };
+    let config = SimConfig {
+        p1_to_p2: link.clone(),
+        p2_to_p1: link,
+        ..Default::default()
+    };
+    let without = simulate(&config);
+    let with = simulate(&SimConfig {
+        resend_after: Some(Duration::from_millis(8)),
+        ..config
+    });
+    assert!(with.states_match(), "{:#?}", with);
+    assert!(with.p1.link.sent > without.p1.link.sent * 3 / 2);
+    assert!(
+        with.p1.lost_packets * 5 < without.p1.lost_packets,
+        "{:#?}",
+        with
+    );
+    assert!(
+        with.p2.lost_packets * 5 < without.p2.lost_packets,
+        "{:#?}",
+        with
+    );
+}
+
+fn symmetric(link: LinkConfig) -> SimConfig {
+    SimConfig {
+        p1_to_p2: link.clone(),
+        p2_to_p1: link,
+        ..Default::default()
+    }
+}
+
+#[test]
+fn auto_delay_follows_latency() {
+    for (latency, delay, expected) in [(80, 2, 4), (10, 5, 1)] {
+        let report = simulate(&SimConfig {
+            frames: 1800,
+            delay,
+            auto_delay: Some(1),
+            ..symmetric(LinkConfig {
+                latency: Duration::from_millis(latency),
+                ..Default::default()
+            })
+        });
+        assert!(report.states_match(), "{:#?}", report);
+        assert_eq!(report.p1.delay, expected, "{:#?}", report);
+        assert_eq!(report.p2.delay, expected, "{:#?}", report);
+        assert_eq!(report.p1.delay_changes, 1, "{:#?}", report);
+        // both sides change it at the same time
+        assert_eq!(
+            report.p1.last_delay_change, report.p2.last_delay_change,
+            "{:#?}",
+            report
+        );
+    }
+}
+
+#[test]
+fn auto_delay_doesnt_oscillate() {
+    for jitter in [10, 20, 40] {
+        let report = simulate(&SimConfig {
+            frames: 3000,
+            auto_delay: Some(1),
+            ..symmetric(LinkConfig {
+                latency: Duration::from_millis(70),
+                jitter: Duration::from_millis(jitter),
+                ..lossy()
+            })
+        });
+        assert!(report.states_match(), "{:#?}", report);
+        assert_eq!(report.p1.delay, report.p2.delay, "{:#?}", report);
+        assert!(report.p1.delay_changes <= 2, "{:#?}", report);
+        assert!(report.p2.delay_changes <= 2, "{:#?}", report);
+    }
+}
+
+#[test]
+fn auto_rollback_avoids_pauses() {
+    let config = SimConfig {
+        frames: 1800,
+        delay: 1,
+        max_rollback: 2,
+        ..symmetric(LinkConfig {
+            latency: Duration::from_millis(110),
+            jitter: Duration::from_millis(10),
+            ..Default::default()
+        })
+    };
+    let fixed = simulate(&config);
+    assert!(fixed.states_match(), "{:#?}", fixed);
+    assert_eq!(fixed.p1.max_rollback_setting, 2);
+
+    let adaptive = simulate(&SimConfig {
+        auto_rollback: Some(RollbackBounds::new(2, 12)),
+        ..config.clone()
+    });
+    assert!(adaptive.states_match(), "{:#?}", adaptive);
+    let window = adaptive.p1.max_rollback_setting;
+    assert_eq!(adaptive.p2.max_rollback_setting, window, "{:#?}", adaptive);
+    assert!((6..=9).contains(&window), "{:#?}", adaptive);
+    assert!(
+        adaptive.p1.input_missing_pauses * 4 < fixed.p1.input_missing_pauses,
+        "{:#?} {:#?}",
+        fixed,
+        adaptive
+    );
+
+    // within the bounds
+    let capped = simulate(&SimConfig {
+        auto_rollback: Some(RollbackBounds::new(2, 4)),
+        ..config
+    });
+    assert_eq!(capped.p1.max_rollback_setting, 4, "{:#?}", capped);
+    assert_eq!(capped.p2.max_rollback_setting, 4, "{:#?}", capped);
+}
+
+#[test]
+fn every_predictor_keeps_the_states_in_sync() {
+    for predictor in PredictorKind::ALL {
+        let report = simulate(&SimConfig {
+            p1_to_p2: lossy(),
+            p2_to_p1: lossy(),
+            predictor,
+            ..Default::default()
+        });
+        assert!(report.states_match(), "{:?}: {:#?}", predictor, report);
+        for peer in [&report.p1, &report.p2] {
+            let stats = peer.prediction;
+            assert!(stats.predicted > 0, "{:?}: {:#?}", predictor, report);
+            assert!(stats.mispredicted <= stats.predicted);
+            assert!(stats.wrong_keys >= stats.mispredicted);
+        }
+    }
+}
+
+#[test]
+fn desyncs_are_recovered() {
+    let report = simulate(&SimConfig {
+        recovery: true,
+        desync: Some(Desync {
+            frame: 300,
+            extra_object: false,
+        }),
+        ..Default::default()
+    });
+    assert!(
+        matches!(report.p2.recovery_notice, Some(RecoveryNotice::Done(_))),
+        "{:#?}",
+        report
+    );
+    assert!(report.states_match(), "{:#?}", report);
+}
+
+#[test]
+fn different_objects_are_not_recovered() {
+    let report = simulate(&SimConfig {
+        recovery: true,
+        desync: Some(Desync {
+            frame: 300,
+            extra_object: true,
+        }),
+        ..Default::default()
+    });
+    // the match goes on desynced
+    assert!(report.finished, "{:#?}", report);
+    assert!(report.first_mismatch.is_some(), "{:#?}", report);
+    assert!(
+        matches!(
+            report.p2.recovery_notice,
+            Some(RecoveryNotice::Unrecoverable(_))
+        ),
+        "{:#?}",
+        report
+    );
+}
@@ -0,0 +1,229 @@
+//! The bytes of a saved game state, stored as the changes from the previous one.
+//!
+//! Most bytes of the game state don't change between adjacent frames, so `Snapshots` keeps a
+//! full copy only every `keyframe_interval` snapshots (keyframes), and only the words changed
+//! from the last keyframe in between. A delta keeps its keyframe alive, so it can be restored as
+//! long as it is kept, whatever is dropped around it, and no other delta is needed for it.
+
+use std::{borrow::Cow, sync::Arc};
+
+#[cfg(test)]
+mod tests;
+
+/// changed words closer than this many bytes are stored in the same run, since a run costs as
+/// much as these bytes
+const RUN_GAP: usize = 8;
+
+/// where the zeros past the end of a keyframe come from
+static ZEROS: [u8; 256] = [0; 256];
+
+/// `len` bytes at `offset` replaced in the keyframe
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Run {
+    offset: u32,
+    len: u32,
+}
+
+#[derive(Debug)]
+pub enum Snapshot {
+    Full(Box<[u8]>),
+    /// `keyframe` (always a `Full` one) resized to `len` (padded with zeros), with the runs
+    /// replaced by `data`
+    Delta {
+        keyframe: Arc<Snapshot>,
+        len: usize,
+        runs: Box<[Run]>,
+        data: Box<[u8]>,
+    },
+}
+
+impl Snapshot {
+    /// the sparse diff from the keyframe of `base` (`base` itself if it's a keyframe) to `bytes`
+    pub fn delta(base: Arc<Snapshot>, bytes: &[u8]) -> Self {
+        let keyframe = match &*base {
+            Snapshot::Full(_) => base,
+            Snapshot::Delta { keyframe, .. } => keyframe.clone(),
+        };
+        let Snapshot::Full(base_bytes) = &*keyframe else {
+            unreachable!("the keyframe of a delta is a full snapshot")
+        };
+        let mut runs: Vec<Run> = vec![];
+        let mut data = vec![];
+        for (n, word) in bytes.chunks(4).enumerate() {
+            let offset = n * 4;
+            if base_bytes.get(offset..offset + word.len()) == Some(word) {
+                continue;
+            }
+            match runs.last_mut() {
+                Some(run) if offset <= (run.offset + run.len) as usize + RUN_GAP => {
+                    let end = (run.offset + run.len) as usize;
+                    data.extend_from_slice(&bytes[end..offset + word.len()]);
+                    run.len = (offset + word.len()) as u32 - run.offset;
+                }
+                _ => {
+                    data.extend_from_slice(word);
+                    runs.push(Run {
+                        offset: offset as u32,
+                        len: word.len() as u32,
+                    });
+                }
+            }
+        }
+        Snapshot::Delta {
+            keyframe,
+            len: bytes.len(),
+            runs: runs.into(),
+            data: data.into(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            Snapshot::Full(x) => x.len(),
+            Snapshot::Delta { len, .. } => *len,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// the bytes kept by this snapshot itself, without its keyframe
+    pub fn stored_len(&self) -> usize {
+        match self {
+            Snapshot::Full(x) => x.len(),
+            Snapshot::Delta { runs, data, .. } => {
+                runs.len() * std::mem::size_of::<Run>() + data.len()
+            }
+        }
+    }
+
+    /// how many deltas have to be applied to get the bytes, 1 at most
+    pub fn depth(&self) -> usize {
+        match self {
+            Snapshot::Full(_) => 0,
+            Snapshot::Delta { .. } => 1,
+        }
+    }
+
+    /// calls `f` with each piece of the saved bytes and its offset, in order and without
+    /// overlaps: the runs of a delta, and the bytes of the keyframe (or zeros past its end)
+    /// between them
+    pub fn for_each_piece(&self, mut f: impl FnMut(usize, &[u8])) {
+        let (keyframe, len, runs, data) = match self {
+            Snapshot::Full(x) => return f(0, x),
+            Snapshot::Delta {
+                keyframe,
+                len,
+                runs,
+                data,
+            } => (keyframe, *len, runs, data),
+        };
+        let Snapshot::Full(keyframe) = &**keyframe else {
+            unreachable!("the keyframe of a delta is a full snapshot")
+        };
+        let mut offset = 0;
+        let mut data = &data[..];
+        for run in runs.iter() {
+            let (start, run_len) = (run.offset as usize, run.len as usize);
+            unchanged(keyframe, offset, start, &mut f);
+            f(start, &data[..run_len]);
+            data = &data[run_len..];
+            offset = start + run_len;
+        }
+        unchanged(keyframe, offset, len, &mut f);
+    }
+
+    /// the saved bytes, the keyframe with the runs of a delta on a copy of it
+    pub fn bytes(&self) -> Cow<'_, [u8]> {
+        match self {
+            Snapshot::Full(x) => Cow::Borrowed(x),
+            Snapshot::Delta { len, .. } => {
+                let mut bytes = vec![0; *len];
+                self.for_each_piece(|offset, piece| {
+                    bytes[offset..offset + piece.len()].copy_from_slice(piece)
+                });
+                Cow::Owned(bytes)
+            }
+        }
+    }
+}
+
+/// the pieces of `from..to` of a delta which are the same as `keyframe`, zeros past its end
+fn unchanged(keyframe: &[u8], from: usize, to: usize, f: &mut impl FnMut(usize, &[u8])) {
+    if from < keyframe.len().min(to) {
+        f(from, &keyframe[from..keyframe.len().min(to)]);
+    }
+    let mut offset = from.max(keyframe.len());
+    while offset < to {
+        let n = ZEROS.len().min(to - offset);
+        f(offset, &ZEROS[..n]);
+        offset += n;
+    }
+}
+
+/// Makes the snapshots of successive frames, each a delta from the last keyframe, and a
+/// keyframe every `keyframe_interval` snapshots; 0 makes only keyframes
+pub struct Snapshots {
+    pub keyframe_interval: usize,
+    /// the keyframe the next deltas are made from
+    keyframe: Option<Arc<Snapshot>>,
+    since_keyframe: usize,
+}
+
+impl Snapshots {
+    pub const fn new(keyframe_interval: usize) -> Self {
+        Self {
+            keyframe_interval,
+            keyframe: None,
+            since_keyframe: 0,
+        }
+    }
+
+    pub fn push(&mut self, bytes: Box<[u8]>) -> Arc<Snapshot> {
+        if self.keyframe_interval == 0 {
+            self.keyframe = None;
+            return Arc::new(Snapshot::Full(bytes));
+        }
+        match &self.keyframe {
+            Some(keyframe) if self.since_keyframe + 1 < self.keyframe_interval => {
+                self.since_keyframe += 1;
+                Arc::new(Snapshot::delta(keyframe.clone(), &bytes))
+            }
+            _ => {
+                self.since_keyframe = 0;
+                let snapshot = Arc::new(Snapshot::Full(bytes));
+                self.keyframe = Some(snapshot.clone());
+                snapshot
+            }
+        }
+    }
+
+    /// goes on from `snapshot`, which has just been restored: the next deltas are made from its
+    /// keyframe, so that the one of the frames rolled back over isn't kept alive
+    pub fn rebase(&mut self, snapshot: &Arc<Snapshot>) {
+        if self.keyframe_interval == 0 {
+            return;
+        }
+        let keyframe = match &**snapshot {
+            Snapshot::Full(_) => snapshot,
+            Snapshot::Delta { keyframe, .. } => keyframe,
+        };
+        if !self
+            .keyframe
+            .as_ref()
+            .is_some_and(|x| Arc::ptr_eq(x, keyframe))
+        {
+            self.keyframe = Some(keyframe.clone());
+            // the restored frame may be a few frames past its keyframe, which only makes the next
+            // keyframe come a bit later
+            self.since_keyframe = 0;
+        }
+    }
+
+    /// starts again from a keyframe, and lets the last snapshots go
+    pub fn reset(&mut self) {
+        self.keyframe = None;
+        self.since_keyframe = 0;
+    }
+}
@@ -0,0 +1,85 @@
+use super::*;
+
+/// a state of `len` bytes, of which a few words change with `frame`
+fn state(frame: u32, len: usize) -> Box<[u8]> {
+    let mut bytes: Vec<u8> = (0..len).map(|x| (x * 7) as u8).collect();
+    for offset in [0, 4, 100, 108, 2000] {
+        if offset + 4 <= len {
+            bytes[offset..offset + 4].copy_from_slice(&(frame * (offset as u32 + 1)).to_le_bytes());
+        }
+    }
+    bytes.into()
+}
+
+#[test]
+fn deltas_restore_the_same_bytes() {
+    let mut snapshots = Snapshots::new(10);
+    let kept: Vec<_> = (0..25)
+        .map(|frame| (frame, snapshots.push(state(frame, 0x1400))))
+        .collect();
+    for (frame, snapshot) in &kept {
+        assert_eq!(&*snapshot.bytes(), &*state(*frame, 0x1400));
+        assert_eq!(snapshot.depth(), (frame % 10 != 0) as usize);
+    }
+    let delta = &kept[5].1;
+    assert!(delta.stored_len() < 64, "{}", delta.stored_len());
+    assert_eq!(kept[10].1.stored_len(), 0x1400);
+}
+
+#[test]
+fn deltas_outlive_the_snapshots_around_them() {
+    let mut snapshots = Snapshots::new(30);
+    let mut kept: Vec<_> = (0..20).map(|x| snapshots.push(state(x, 256))).collect();
+    let last = kept.pop().unwrap();
+    drop(kept);
+    snapshots.reset();
+    assert_eq!(&*last.bytes(), &*state(19, 256));
+    assert_eq!(snapshots.push(state(20, 256)).depth(), 0);
+}
+
+#[test]
+fn pieces_cover_the_bytes_in_order() {
+    let mut snapshots = Snapshots::new(8);
+    for (frame, len) in [(0, 600), (1, 600), (2, 1000), (3, 300)] {
+        let snapshot = snapshots.push(state(frame, len));
+        let mut bytes = vec![];
+        snapshot.for_each_piece(|offset, piece| {
+            assert_eq!(offset, bytes.len());
+            bytes.extend_from_slice(piece);
+        });
+        assert_eq!(&*bytes, &*state(frame, len));
+    }
+}
+
+#[test]
+fn rolling_back_goes_on_from_the_restored_keyframe() {
+    let mut snapshots = Snapshots::new(4);
+    let kept: Vec<_> = (0..6).map(|x| snapshots.push(state(x, 256))).collect();
+    // frame 4 is a keyframe, which frame 2 doesn't need
+    snapshots.rebase(&kept[2]);
+    let next = snapshots.push(state(3, 256));
+    let Snapshot::Delta { keyframe, .. } = &*next else {
+        panic!("{:?}", next)
+    };
+    assert!(Arc::ptr_eq(keyframe, &kept[0]));
+    assert_eq!(&*next.bytes(), &*state(3, 256));
+}
+
+#[test]
+fn the_size_can_change() {
+    let mut snapshots = Snapshots::new(4);
+    for (frame, len) in [(0, 300), (1, 310), (2, 2), (3, 2001)] {
+        let snapshot = snapshots.push(state(frame, len));
+        assert_eq!(snapshot.len(), len);
+        assert_eq!(&*snapshot.bytes(), &*state(frame, len));
+    }
+}
+
+#[test]
+fn no_interval_makes_only_keyframes() {
+    let mut snapshots = Snapshots::new(0);
+    for frame in 0..3 {
+        let snapshot = snapshots.push(state(frame, 64));
+        assert!(matches!(*snapshot, Snapshot::Full(_)));
+    }
+}
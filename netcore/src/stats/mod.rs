@@ -0,0 +1,252 @@
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::netcode::PauseReason;
+
+#[cfg(test)]
+mod tests;
+
+/// the frames summarized by a `SecondStats`
+pub const FRAMES_PER_SECOND: usize = 60;
+
+/// The network and rollback figures of one second (60 frames) of a match
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SecondStats {
+    /// the first frame of the second
+    pub frame: usize,
+    /// the average and the largest round trip (in ms) measured during the second
+    pub rtt_avg: Option<u32>,
+    pub rtt_max: Option<u32>,
+    /// the deepest rollback, in frames
+    pub rollback: usize,
+    /// the frames paused because the opponent hasn't confirmed our inputs
+    pub frame_missing_pauses: u32,
+    /// the frames paused because the inputs of the opponent are too old
+    pub input_missing_pauses: u32,
+    /// the frames paused for the desync recovery
+    pub recovery_pauses: u32,
+    /// the frames `timing_loop` of giuroll was too late for
+    pub frameskips: u32,
+    pub delay: usize,
+    pub opponent_delay: usize,
+    /// the negotiated max rollback
+    pub max_rollback: usize,
+    /// the packets of the opponent which never arrived
+    pub lost_packets: usize,
+    /// the packets of the opponent which arrived more than once, besides the expected copies
+    pub duplicate_packets: usize,
+    /// the packets of the opponent which arrived after a packet sent later
+    pub reordered_packets: usize,
+    /// the packets of the opponent which arrived too late for their inputs to be rolled back to
+    pub late_packets: usize,
+    /// a desync has been detected and not recovered
+    pub desynced: bool,
+}
+
+/// The figures of a whole match, one row per second, filled by `Netcoder::process_and_send`
+#[derive(Clone, Debug)]
+pub struct MatchStats {
+    pub is_p1: bool,
+    /// the unix time the match started at
+    pub started: u64,
+    pub seconds: Vec<SecondStats>,
+    /// the second being measured
+    pub current: SecondStats,
+    /// the frames simulated in the current second
+    current_frames: usize,
+    rtts: Vec<Duration>,
+    /// the first frame found desynced
+    pub desynced_at: Option<usize>,
+}
+
+/// The formats `MatchStats::write` can write
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StatsFormat {
+    Json,
+    Csv,
+}
+
+impl StatsFormat {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "json" => Some(StatsFormat::Json),
+            "csv" => Some(StatsFormat::Csv),
+            _ => None,
+        }
+    }
+
+    fn extension(self) -> &'static str {
+        match self {
+            StatsFormat::Json => "json",
+            StatsFormat::Csv => "csv",
+        }
+    }
+}
+
+impl MatchStats {
+    pub fn new(is_p1: bool) -> Self {
+        Self {
+            is_p1,
+            started: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |x| x.as_secs()),
+            seconds: Vec::new(),
+            current: SecondStats::default(),
+            current_frames: 0,
+            rtts: Vec::new(),
+            desynced_at: None,
+        }
+    }
+
+    pub fn add_rtt(&mut self, rtt: Duration) {
+        self.rtts.push(rtt);
+    }
+
+    /// called for every frame `process_and_send` pauses
+    pub fn add_pause(&mut self, reason: PauseReason) {
+        match reason {
+            PauseReason::FrameMissing => self.current.frame_missing_pauses += 1,
+            PauseReason::InputMissing => self.current.input_missing_pauses += 1,
+            PauseReason::Recovery => self.current.recovery_pauses += 1,
+        }
+    }
+
+    /// called for every frame which isn't paused, with the rollback shown by the overlay
+    /// (`Rollbacker::guessed`); the row is finished after the last frame of the second
+    pub fn add_frame(&mut self, frame: usize, rollback: usize) {
+        self.current.rollback = self.current.rollback.max(rollback);
+        self.current_frames += 1;
+        if frame % FRAMES_PER_SECOND == FRAMES_PER_SECOND - 1 {
+            self.finish_second(frame + 1);
+        }
+    }
+
+    /// finishes the current row; the next one starts at `next_frame`
+    pub fn finish_second(&mut self, next_frame: usize) {
+        if !self.rtts.is_empty() {
+            let sum: Duration = self.rtts.iter().sum();
+            self.current.rtt_avg = Some((sum / self.rtts.len() as u32).as_millis() as u32);
+            self.current.rtt_max = self.rtts.iter().max().map(|x| x.as_millis() as u32);
+        }
+        self.rtts.clear();
+        self.current_frames = 0;
+        let next = SecondStats {
+            frame: next_frame,
+            delay: self.current.delay,
+            opponent_delay: self.current.opponent_delay,
+            max_rollback: self.current.max_rollback,
+            desynced: self.current.desynced,
+            ..Default::default()
+        };
+        self.seconds
+            .push(std::mem::replace(&mut self.current, next));
+    }
+
+    pub fn write_json(&self, w: &mut impl Write, version: &str) -> std::io::Result<()> {
+        fn option(x: Option<impl ToString>) -> String {
+            x.map_or("null".to_string(), |x| x.to_string())
+        }
+
+        writeln!(w, "{{")?;
+        writeln!(w, "  \"giuroll\": \"{}\",", version)?;
+        writeln!(w, "  \"started\": {},", self.started)?;
+        writeln!(w, "  \"is_p1\": {},", self.is_p1)?;
+        writeln!(w, "  \"desynced_at\": {},", option(self.desynced_at))?;
+        writeln!(w, "  \"seconds\": [")?;
+        for (n, x) in self.seconds.iter().enumerate() {
+            writeln!(
+                w,
+                "    {{\"frame\": {}, \"rtt_avg\": {}, \"rtt_max\": {}, \"rollback\": {}, \
+                 \"frame_missing_pauses\": {}, \"input_missing_pauses\": {}, \
+                 \"recovery_pauses\": {}, \"frameskips\": {}, \"delay\": {}, \
+                 \"opponent_delay\": {}, \"max_rollback\": {}, \"lost_packets\": {}, \
+                 \"duplicate_packets\": {}, \"reordered_packets\": {}, \"late_packets\": {}, \
+                 \"desynced\": {}}}{}",
+                x.frame,
+                option(x.rtt_avg),
+                option(x.rtt_max),
+                x.rollback,
+                x.frame_missing_pauses,
+                x.input_missing_pauses,
+                x.recovery_pauses,
+                x.frameskips,
+                x.delay,
+                x.opponent_delay,
+                x.max_rollback,
+                x.lost_packets,
+                x.duplicate_packets,
+                x.reordered_packets,
+                x.late_packets,
+                x.desynced,
+                if n + 1 == self.seconds.len() { "" } else { "," }
+            )?;
+        }
+        writeln!(w, "  ]")?;
+        writeln!(w, "}}")
+    }
+
+    pub fn write_csv(&self, w: &mut impl Write) -> std::io::Result<()> {
+        fn option(x: Option<u32>) -> String {
+            x.map_or(String::new(), |x| x.to_string())
+        }
+
+        writeln!(
+            w,
+            "frame,rtt_avg,rtt_max,rollback,frame_missing_pauses,input_missing_pauses,\
+             recovery_pauses,frameskips,delay,opponent_delay,max_rollback,lost_packets,\
+             duplicate_packets,reordered_packets,late_packets,desynced"
+        )?;
+        for x in self.seconds.iter() {
+            writeln!(
+                w,
+                "{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{}",
+                x.frame,
+                option(x.rtt_avg),
+                option(x.rtt_max),
+                x.rollback,
+                x.frame_missing_pauses,
+                x.input_missing_pauses,
+                x.recovery_pauses,
+                x.frameskips,
+                x.delay,
+                x.opponent_delay,
+                x.max_rollback,
+                x.lost_packets,
+                x.duplicate_packets,
+                x.reordered_packets,
+                x.late_packets,
+                x.desynced as u8
+            )?;
+        }
+        Ok(())
+    }
+
+    /// finishes the last second and writes the stats into `dir`; returns the path of the file
+    pub fn write(
+        &mut self,
+        dir: &Path,
+        format: StatsFormat,
+        version: &str,
+    ) -> std::io::Result<PathBuf> {
+        if self.current_frames > 0 {
+            self.finish_second(self.current.frame + FRAMES_PER_SECOND);
+        }
+        std::fs::create_dir_all(dir)?;
+        let path = dir.join(format!(
+            "giuroll_stats_{}_{}.{}",
+            self.started,
+            if self.is_p1 { "p1" } else { "p2" },
+            format.extension()
+        ));
+        let mut f = std::io::BufWriter::new(std::fs::File::create(&path)?);
+        match format {
+            StatsFormat::Json => self.write_json(&mut f, version)?,
+            StatsFormat::Csv => self.write_csv(&mut f)?,
+        }
+        f.flush()?;
+        Ok(path)
+    }
+}
@@ -0,0 +1,80 @@
+use super::*;
+use crate::{sim::Peer, transport::MemoryTransport};
+
+fn stats_of_a_match(frames: usize) -> MatchStats {
+    let (t1, t2) = MemoryTransport::pair();
+    let mut p1 = Peer::new(t1, true, 2, 6);
+    let mut p2 = Peer::new(t2, false, 1, 6);
+    p1.netcoder.stats = Some(MatchStats::new(true));
+
+    for frame in 0..frames {
+        p1.frame(crate::sim::scripted_input(0, 1, frame));
+        // p2 stops answering for a while
+        if !(300..330).contains(&frame) {
+            p2.frame(crate::sim::scripted_input(0, 2, frame));
+        }
+    }
+    p1.netcoder.stats.take().unwrap()
+}
+
+#[test]
+fn a_row_per_second() {
+    let stats = stats_of_a_match(600);
+    assert!(stats.seconds.len() >= 9, "{:#?}", stats.seconds);
+    for (n, second) in stats.seconds.iter().enumerate() {
+        assert_eq!(second.frame, n * FRAMES_PER_SECOND);
+        assert_eq!(second.delay, 2);
+        assert_eq!(second.opponent_delay, 1);
+        assert_eq!(second.max_rollback, 6);
+        assert!(!second.desynced);
+    }
+    assert!(stats.seconds[1].rtt_avg.is_some());
+    assert!(stats.seconds[1].rtt_avg <= stats.seconds[1].rtt_max);
+    let pauses: u32 = stats
+        .seconds
+        .iter()
+        .map(|x| x.frame_missing_pauses + x.input_missing_pauses)
+        .sum();
+    assert!(pauses > 0);
+    assert_eq!(stats.desynced_at, None);
+}
+
+#[test]
+fn json_and_csv() {
+    let mut stats = MatchStats::new(false);
+    stats.current.delay = 3;
+    stats.add_rtt(Duration::from_millis(40));
+    stats.add_rtt(Duration::from_millis(60));
+    stats.add_pause(PauseReason::InputMissing);
+    for frame in 0..90 {
+        stats.add_frame(frame, frame % 4);
+    }
+    stats.current.frameskips = 1;
+    stats.finish_second(120);
+
+    let mut json = Vec::new();
+    stats.write_json(&mut json, "1.0").unwrap();
+    let json = String::from_utf8(json).unwrap();
+    assert!(json.contains("\"is_p1\": false,"), "{}", json);
+    assert!(json.contains("\"desynced_at\": null,"), "{}", json);
+    let seconds: Vec<_> = json.lines().filter(|x| x.contains("\"frame\"")).collect();
+    assert_eq!(seconds.len(), 2, "{}", json);
+    assert!(seconds[0]
+        .starts_with("    {\"frame\": 0, \"rtt_avg\": 50, \"rtt_max\": 60, \"rollback\": 3,"));
+    assert!(seconds[0].contains("\"input_missing_pauses\": 1,"));
+    assert!(seconds[0].contains("\"delay\": 3,"));
+    assert!(seconds[0].ends_with("},"));
+    assert!(seconds[1].starts_with("    {\"frame\": 60, \"rtt_avg\": null, \"rtt_max\": null,"));
+    assert!(seconds[1].contains("\"frameskips\": 1,"));
+    assert!(seconds[1].ends_with("\"desynced\": false}"));
+    assert!(json.ends_with("  ]\n}\n"));
+
+    let mut csv = Vec::new();
+    stats.write_csv(&mut csv).unwrap();
+    let csv = String::from_utf8(csv).unwrap();
+    let lines: Vec<_> = csv.lines().collect();
+    assert_eq!(lines.len(), 3);
+    assert_eq!(lines[0].split(',').count(), lines[1].split(',').count());
+    assert_eq!(lines[1], "0,50,60,3,0,1,0,0,3,0,0,0,0,0,0,0");
+    assert_eq!(lines[2], "60,,,3,0,0,0,1,3,0,0,0,0,0,0,0");
+}
@@ -0,0 +1,96 @@
+use std::{
+    io,
+    net::{SocketAddr, UdpSocket},
+    sync::mpsc::{channel, Receiver, Sender},
+    time::Instant,
+};
+
+use crate::rollback::RInput;
+
+/// How `Netcoder` reaches the opponent
+pub trait Transport {
+    /// sends a packet as it is; the player byte of 0x6b packets is filled by `Netcoder`
+    fn send(&mut self, data: &[u8]);
+    /// the next received packet (0x6b or 0x6f) and the time it arrived, if any
+    fn recv(&mut self) -> Option<(Box<[u8]>, Instant)>;
+    /// the clock the arrival times of `recv` are measured with
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+    /// called at the start of `Netcoder::process_and_send`, at `at` (its `now`), with the
+    /// input and the delay it's called with
+    fn frame_started(&mut self, _at: Instant, _input: &RInput, _delay: usize) {}
+}
+
+/// One end of an in-memory link, created by `MemoryTransport::pair`.
+///
+/// Packets are delivered instantly, in order, and never lost.
+pub struct MemoryTransport {
+    sender: Sender<(Box<[u8]>, Instant)>,
+    receiver: Receiver<(Box<[u8]>, Instant)>,
+}
+
+impl MemoryTransport {
+    pub fn pair() -> (Self, Self) {
+        let (s1, r1) = channel();
+        let (s2, r2) = channel();
+        (
+            Self {
+                sender: s1,
+                receiver: r2,
+            },
+            Self {
+                sender: s2,
+                receiver: r1,
+            },
+        )
+    }
+}
+
+impl Transport for MemoryTransport {
+    fn send(&mut self, data: &[u8]) {
+        // the other end may have been dropped already, like a closed socket
+        let _ = self.sender.send((data.into(), Instant::now()));
+    }
+
+    fn recv(&mut self) -> Option<(Box<[u8]>, Instant)> {
+        self.receiver.try_recv().ok()
+    }
+}
+
+/// Sends to `peer` (the opponent or a `relay`) over a UDP socket of any address family, and
+/// receives only what comes from it
+pub struct UdpTransport {
+    socket: UdpSocket,
+    pub peer: SocketAddr,
+}
+
+impl UdpTransport {
+    pub fn new(socket: UdpSocket, peer: SocketAddr) -> io::Result<Self> {
+        socket.set_nonblocking(true)?;
+        Ok(Self { socket, peer })
+    }
+}
+
+impl Transport for UdpTransport {
+    fn send(&mut self, data: &[u8]) {
+        if let Err(e) = self.socket.send_to(data, self.peer) {
+            crate::println!("failed to send to {}: {}", self.peer, e);
+        }
+    }
+
+    fn recv(&mut self) -> Option<(Box<[u8]>, Instant)> {
+        let mut buf = [0; 2048];
+        loop {
+            match self.socket.recv_from(&mut buf) {
+                Ok((len, from)) if from == self.peer => {
+                    return Some((buf[..len].into(), Instant::now()))
+                }
+                Ok(_) => (),
+                // e.g. the port of the peer was closed when we sent to it; the next packets are
+                // received on the next call
+                Err(_) => return None,
+            }
+        }
+    }
+}
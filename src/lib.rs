@@ -17,15 +17,18 @@ use std::{
     any::type_name,
     collections::HashMap,
     ffi::c_void,
+    fs::File,
+    io::BufWriter,
     mem::align_of,
+    net::ToSocketAddrs,
     os::windows::prelude::OsStringExt,
     path::{Path, PathBuf},
-    ptr::{addr_of_mut, null_mut},
+    ptr::{addr_of_mut, null, null_mut},
     sync::{
         atomic::{AtomicI32, AtomicU32, Ordering::Relaxed},
         Mutex,
     },
-    time::{Duration, Instant},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 mod netcode;
 mod replay;
@@ -38,10 +41,23 @@ use ilhook::x86::{HookPoint, HookType};
 #[cfg(feature = "logtofile")]
 use log::info;
 use mininip::datas::{Identifier, Value};
-use netcode::{Netcoder, NetworkPacket};
+use netcode::SokuTransport;
+use netcore::{
+    auth::{SessionAuth, NONCE_LEN},
+    autodelay::AutoDelay,
+    autorollback::{AutoRollback, RollbackBounds},
+    capabilities::Capabilities,
+    desync::RecoveryNotice,
+    netcode::{Netcoder, PauseReason, EXT_DELAY_CHANGES},
+    predict::{PredictionStats, Predictor, PredictorKind},
+    record::{Recorder, Recording, SessionHeader},
+    rollback::Rollbacker,
+    stats::{MatchStats, StatsFormat},
+    INPUT_KEYS_NUMBERS,
+};
 
 //use notify::{RecursiveMode, Watcher};
-use rollback::{Rollbacker, DUMP_FRAME_TIME, LAST_M_LEN, MEMORY_LEAK};
+use rollback::{Soku, DUMP_FRAME_TIME, LAST_M_LEN, MEMORY_LEAK};
 use sound::RollbackSoundManager;
 use winapi::ctypes::c_char;
 use windows::core::PCWSTR;
@@ -80,7 +96,45 @@ pub struct Callbacks {
     pub free_state: unsafe extern "C" fn(u32, bool),
 }
 
-static mut CALLBACK_ARRAY: Vec<Callbacks> = Vec::new();
+/// The rollback callbacks of a mod, registered with `addRollbackCbV2`. Each callback gets
+/// `context` back. New fields are only added at the end, with a new `version`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct CallbacksV2 {
+    /// `sizeof` the struct of the mod
+    pub size: u32,
+    /// 2, or later
+    pub version: u32,
+    pub context: *mut c_void,
+    /// a nul-terminated name, for the logs, and to match the states of the mod in the snapshots
+    /// sent to the opponent; may be null
+    pub name: *const c_char,
+    pub save_state: unsafe extern "C" fn(*mut c_void) -> u32,
+    pub load_state_pre: unsafe extern "C" fn(*mut c_void, usize, u32),
+    pub load_state_post: unsafe extern "C" fn(*mut c_void, u32),
+    pub free_state: unsafe extern "C" fn(*mut c_void, u32, bool),
+    /// optional: writes a state into the buffer of the given length, and returns the length of
+    /// the serialized state; if the buffer is too short, it's called again once with a long enough
+    /// one, of at most `rollback::MAX_EXTRA_STATE_LEN` bytes, else the state isn't sent
+    pub serialize: Option<unsafe extern "C" fn(*mut c_void, u32, *mut u8, usize) -> usize>,
+    /// optional: makes a new state from the bytes serialized by the mod of the opponent into the
+    /// last argument, and returns whether it could
+    pub deserialize: Option<unsafe extern "C" fn(*mut c_void, *const u8, usize, *mut u32) -> bool>,
+}
+
+unsafe impl Send for CallbacksV2 {}
+
+impl CallbacksV2 {
+    pub fn name(&self) -> Option<String> {
+        (!self.name.is_null()).then(|| {
+            unsafe { std::ffi::CStr::from_ptr(self.name) }
+                .to_string_lossy()
+                .into_owned()
+        })
+    }
+}
+
+static mut CALLBACK_ARRAY: Vec<CallbacksV2> = Vec::new();
 
 //#[cfg(not(debug_assertions))]
 //const ISDEBUG: bool = false;
@@ -285,7 +339,55 @@ pub extern "C" fn getPriority() -> i32 {
 
 #[no_mangle]
 pub unsafe extern "C" fn addRollbackCb(cb: *const Callbacks) {
-    CALLBACK_ARRAY.push(*cb);
+    // the context of the v1 callbacks is their copy
+    unsafe extern "C" fn save_state(cb: *mut c_void) -> u32 {
+        ((*(cb as *const Callbacks)).save_state)()
+    }
+    unsafe extern "C" fn load_state_pre(cb: *mut c_void, frame: usize, state: u32) {
+        ((*(cb as *const Callbacks)).load_state_pre)(frame, state)
+    }
+    unsafe extern "C" fn load_state_post(cb: *mut c_void, state: u32) {
+        ((*(cb as *const Callbacks)).load_state_post)(state)
+    }
+    unsafe extern "C" fn free_state(cb: *mut c_void, state: u32, never_happened: bool) {
+        ((*(cb as *const Callbacks)).free_state)(state, never_happened)
+    }
+
+    CALLBACK_ARRAY.push(CallbacksV2 {
+        size: std::mem::size_of::<CallbacksV2>() as u32,
+        version: 2,
+        context: Box::leak(Box::new(*cb)) as *mut Callbacks as *mut c_void,
+        name: null(),
+        save_state,
+        load_state_pre,
+        load_state_post,
+        free_state,
+        serialize: None,
+        deserialize: None,
+    });
+}
+
+/// returns false, and doesn't register the callbacks, if the struct is of an unknown version
+///
+/// # Safety
+/// `cb` is null, or points to callbacks of at least `size` bytes, whose `context` and `name`
+/// stay valid
+#[no_mangle]
+pub unsafe extern "C" fn addRollbackCbV2(cb: *const CallbacksV2) -> bool {
+    if cb.is_null()
+        || (*cb).version < 2
+        || ((*cb).size as usize) < std::mem::size_of::<CallbacksV2>()
+    {
+        println!("invalid rollback callbacks");
+        return false;
+    }
+    let cb = *cb;
+    println!(
+        "rollback callbacks of {} added",
+        cb.name().as_deref().unwrap_or("a mod")
+    );
+    CALLBACK_ARRAY.push(cb);
+    true
 }
 
 #[no_mangle]
@@ -342,8 +444,6 @@ pub extern "cdecl" fn CheckVersion(a: *const [u8; 16]) -> bool {
     unsafe { *ptr_wrap!(a) == HASH110A }
 }
 
-const INPUT_KEYS_NUMBERS: usize = 12;
-
 static mut REAL_INPUT: Option<[bool; INPUT_KEYS_NUMBERS]> = None;
 static mut REAL_INPUT2: Option<[bool; INPUT_KEYS_NUMBERS]> = None;
 
@@ -353,6 +453,8 @@ static mut TARGET: Option<u128> = None;
 static mut WARNING_FRAME_MISSING_1_COUNTDOWN: usize = 0;
 static mut WARNING_FRAME_MISSING_2_COUNTDOWN: usize = 0;
 static mut WARNING_FRAME_LOST_COUNTDOWN: AtomicU32 = AtomicU32::new(0);
+static mut RECOVERY_NOTICE: Option<RecoveryNotice> = None;
+static mut RECOVERY_NOTICE_COUNTDOWN: usize = 0;
 static SOKU_LOOP_EVENT: Mutex<Option<isize>> = Mutex::new(None);
 static TARGET_OFFSET: AtomicI32 = AtomicI32::new(0);
 //static TARGET_OFFSET_COUNT: AtomicI32 = AtomicI32::new(0);
@@ -402,9 +504,13 @@ static mut OUTER_HALF_WIDTH: i32 = 60;
 
 static mut FREEZE_MITIGATION: bool = false;
 static mut ENABLE_CHECK_MODE: bool = false;
+/// in bytes, 0 for no limit
+static mut REWIND_MEMORY_BUDGET: usize = 0;
 static mut WARNING_WHEN_LAGGING: bool = true;
 
 static mut MAX_ROLLBACK_PREFERENCE: u8 = 6;
+/// the bounds of the max rollback if it adapts to the latency
+static mut ADAPTIVE_MAX_ROLLBACK: Option<RollbackBounds> = None;
 
 static mut DISABLE_SOUND: bool = false;
 
@@ -689,7 +795,7 @@ fn truer_exec(filename: PathBuf, pretend_to_be_vanilla: bool) -> Result<(), Stri
     filepath.push("giuroll.ini");
     //println!("{:?}", filepath);
 
-    let conf = match mininip::parse::parse_file(filepath) {
+    let conf = match mininip::parse::parse_file(&filepath) {
         Ok(x) => x,
         Err(e) => return Err(format!("Failed to parse ini: {}", e)),
     };
@@ -707,6 +813,10 @@ fn truer_exec(filename: PathBuf, pretend_to_be_vanilla: bool) -> Result<(), Stri
         DATA_RECEIVER = Some(r);
         DATA_SENDER = Some(s);
 
+        let (s, r) = std::sync::mpsc::channel();
+        PACKET_COPY_SENDER = Some(s);
+        std::thread::spawn(move || netcode::send_copies(r));
+
         let (s, r) = std::sync::mpsc::channel();
         MEMORY_RECEIVER_FREE = Some(r);
         MEMORY_SENDER_FREE = Some(s);
@@ -755,7 +865,7 @@ fn truer_exec(filename: PathBuf, pretend_to_be_vanilla: bool) -> Result<(), Stri
     ) -> String {
         conf.get(&Identifier::new(Some(section.to_string()), key.to_string()))
             .map(|x| match x {
-                Value::Str(x) => x.clone(),
+                Value::Raw(x) | Value::Str(x) => x.clone(),
                 _ => todo!("non string .ini entry"),
             })
             .unwrap_or(default)
@@ -777,6 +887,60 @@ fn truer_exec(filename: PathBuf, pretend_to_be_vanilla: bool) -> Result<(), Stri
     let autodelay_enabled = read_ini_bool(&conf, "Netplay", "enable_auto_delay", true);
     let freeze_mitigation = read_ini_bool(&conf, "Netplay", "freeze_mitigation__", false);
     let autodelay_rollback = read_ini_int_hex(&conf, "Netplay", "auto_delay_rollback", 0);
+    let desync_recovery = read_ini_bool(&conf, "Netplay", "enable_desync_recovery", false);
+    let packet_copy_delay =
+        read_ini_int_hex(&conf, "Netplay", "packet_copy_delay", 0).clamp(0, 255) as u64;
+    let input_predictor = read_ini_string(
+        &conf,
+        "Netplay",
+        "input_predictor",
+        PredictorKind::default().name().to_string(),
+    );
+    let input_predictor = PredictorKind::from_name(&input_predictor).unwrap_or_else(|| {
+        println!("unknown input_predictor: {}", input_predictor);
+        PredictorKind::default()
+    });
+    let match_stats = read_ini_string(&conf, "Netplay", "match_stats", "json".to_string());
+    let match_stats = match match_stats.as_str() {
+        "none" => None,
+        x => StatsFormat::from_name(x).or_else(|| {
+            println!("unknown match_stats: {}", x);
+            None
+        }),
+    };
+    let record_sessions = read_ini_bool(&conf, "Netplay", "record_sessions", false);
+    let symmetric_delay = read_ini_bool(&conf, "Netplay", "symmetric_delay", false);
+    let relay_host = read_ini_string(&conf, "Netplay", "relay_host", String::new());
+    let relay_port = read_ini_int_hex(&conf, "Netplay", "relay_port", 10900).clamp(0, 65535) as u16;
+    let relay_room = read_ini_string(&conf, "Netplay", "relay_room", String::new());
+    let relay = match relay_host.as_str() {
+        "" => None,
+        x if !netcore::relay::valid_room(&relay_room) => {
+            println!(
+                "invalid relay_room {:?}, not using the relay {}",
+                relay_room, x
+            );
+            None
+        }
+        // soku plays on an IPv4 socket, unless a mod changes it
+        x => match (x, relay_port)
+            .to_socket_addrs()
+            .map(|x| x.collect::<Vec<_>>())
+        {
+            Ok(addrs) => addrs
+                .iter()
+                .find(|x| x.is_ipv4())
+                .or(addrs.first())
+                .map(|x| netcode::Relay {
+                    address: PeerAddress::from_socket_addr(*x),
+                    room: relay_room,
+                }),
+            Err(e) => {
+                println!("failed to resolve relay_host {}: {}", x, e);
+                None
+            }
+        },
+    };
     let smooth_camera = read_ini_bool(&conf, "Netplay", "smooth_camera", true);
     let smooth_decreasing_scale_correction = read_ini_int_hex(
         &conf,
@@ -796,6 +960,11 @@ fn truer_exec(filename: PathBuf, pretend_to_be_vanilla: bool) -> Result<(), Stri
         read_ini_int_hex(&conf, "SmoothCamera", "y_correction_half_life__", 21);
     let max_rollback_preference =
         read_ini_int_hex(&conf, "Netplay", "max_rollback_preference", 6).clamp(0, 15) as u8;
+    let adaptive_max_rollback = read_ini_bool(&conf, "Netplay", "adaptive_max_rollback", false);
+    let adaptive_max_rollback_min =
+        read_ini_int_hex(&conf, "Netplay", "adaptive_max_rollback_min", 2).clamp(0, 15);
+    let adaptive_max_rollback_max =
+        read_ini_int_hex(&conf, "Netplay", "adaptive_max_rollback_max", 10).clamp(0, 15);
     let warning_when_lagging = read_ini_bool(&conf, "Misc", "warning_when_lagging", true);
     let soku2_compat_mode = read_ini_bool(&conf, "Misc", "soku2_compatibility_mode", false);
     let enable_println = read_ini_bool(
@@ -805,6 +974,30 @@ fn truer_exec(filename: PathBuf, pretend_to_be_vanilla: bool) -> Result<(), Stri
         cfg!(feature = "allocconsole") || ISDEBUG,
     );
     let enable_check_mode = read_ini_bool(&conf, "Misc", "enable_check_mode", false);
+    let rewind_memory_budget =
+        read_ini_int_hex(&conf, "Misc", "rewind_memory_budget", 256).clamp(0, 2048) as usize;
+    let snapshot_keyframe_interval =
+        read_ini_int_hex(&conf, "Misc", "snapshot_keyframe_interval", 0).clamp(0, 600) as usize;
+    // relative to the directory of giuroll.ini
+    let snapshot_layout = match read_ini_string(&conf, "Misc", "snapshot_layout", String::new()) {
+        x if x.is_empty() => None,
+        x => {
+            let path = filepath.with_file_name(&x);
+            let layout = std::fs::read_to_string(&path)
+                .map_err(|e| e.to_string())
+                .and_then(|text| netcore::layout::Layout::parse(&text));
+            match layout {
+                Ok(layout) => Some(layout),
+                Err(e) => {
+                    return Err(format!(
+                        "Failed to parse the snapshot layout {}: {}",
+                        path.display(),
+                        e
+                    ))
+                }
+            }
+        }
+    };
     let turning_off_all_extra_ui = read_ini_bool(
         &conf,
         "Misc",
@@ -894,6 +1087,34 @@ fn truer_exec(filename: PathBuf, pretend_to_be_vanilla: bool) -> Result<(), Stri
         }
     }
 
+    // `character_<id>=<size of a player>,<size of a bullet>`, over the sizes above and those
+    // set by mods
+    for (key, value) in conf.iter() {
+        let Some(id) = (key.section() == Some("CharacterSizes"))
+            .then(|| key.name().strip_prefix("character_"))
+            .flatten()
+        else {
+            continue;
+        };
+        let parse = |x: &str| match x.trim().strip_prefix("0x") {
+            Some(x) => usize::from_str_radix(x, 16).ok(),
+            None => x.trim().parse().ok(),
+        };
+        let sizes = match (id.parse::<u8>(), value) {
+            (Ok(id), Value::Raw(x)) => match x.split_once(',') {
+                Some((a, b)) => parse(a).zip(parse(b)).map(|x| (id, x)),
+                None => None,
+            },
+            _ => None,
+        };
+        match sizes {
+            Some((id, sizes)) => unsafe {
+                rollback::set_char_data_pos(id as usize, sizes.0, sizes.1)
+            },
+            None => println!("invalid character size {}={:?}", key.name(), value),
+        }
+    }
+
     #[allow(unused_mut)]
     let mut verstr: String = VERSION_STR.to_string();
     if let Some(remark) = option_env!("VERSION_REMARK") {
@@ -939,6 +1160,13 @@ fn truer_exec(filename: PathBuf, pretend_to_be_vanilla: bool) -> Result<(), Stri
         DEFAULT_DELAY_VALUE = default_delay as usize;
         AUTODELAY_ENABLED = autodelay_enabled;
         AUTODELAY_ROLLBACK = autodelay_rollback as i8;
+        DESYNC_RECOVERY_ENABLED = desync_recovery;
+        PACKET_COPY_DELAY = packet_copy_delay;
+        INPUT_PREDICTOR = input_predictor;
+        MATCH_STATS_FORMAT = match_stats;
+        RECORD_SESSIONS = record_sessions;
+        SYMMETRIC_DELAY = symmetric_delay;
+        netcode::RELAY = relay;
         LAST_DELAY_VALUE_TAKEOVER = default_delay_takeover as usize;
         OUTER_COLOR = outer_color;
         INSIDE_COLOR = inside_color;
@@ -954,9 +1182,19 @@ fn truer_exec(filename: PathBuf, pretend_to_be_vanilla: bool) -> Result<(), Stri
         OUTER_HALF_WIDTH = outer_half_width as i32;
         FREEZE_MITIGATION = freeze_mitigation;
         ENABLE_PRINTLN = enable_println;
+        netcore::ENABLE_PRINTLN.store(enable_println, Relaxed);
         ENABLE_CHECK_MODE = enable_check_mode;
+        rollback::SNAPSHOTS.keyframe_interval = snapshot_keyframe_interval;
+        rollback::LAYOUT = snapshot_layout;
+        REWIND_MEMORY_BUDGET = rewind_memory_budget << 20;
         WARNING_WHEN_LAGGING = warning_when_lagging;
         MAX_ROLLBACK_PREFERENCE = max_rollback_preference;
+        ADAPTIVE_MAX_ROLLBACK = adaptive_max_rollback.then(|| {
+            RollbackBounds::new(
+                adaptive_max_rollback_min as usize,
+                adaptive_max_rollback_max as usize,
+            )
+        });
         SMOOTH_ENABLED_CONFIG = smooth_camera;
         let half_life_to_correction = |half_life: i64| match half_life {
             0 => 1.0,
@@ -1153,7 +1391,10 @@ fn truer_exec(filename: PathBuf, pretend_to_be_vanilla: bool) -> Result<(), Stri
         LAST_GAME_REQUEST = None;
         LAST_MATCH_ACK = None;
         LAST_MATCH_LOAD = None;
-        LIKELY_DESYNCED = false;
+        LIKELY_DESYNCED = None;
+        rollback::HASH_CONFIRMED_FRAMES = false;
+        RECOVERY_NOTICE = None;
+        RECOVERY_NOTICE_COUNTDOWN = 0;
 
         REQUESTED_THREAD_ID.store(0, Relaxed);
         NEXT_DRAW_PING = None;
@@ -1179,19 +1420,56 @@ fn truer_exec(filename: PathBuf, pretend_to_be_vanilla: bool) -> Result<(), Stri
         //    println!("freed but not alloced: {}", a);
         //}
 
-        if let Some(x) = NETCODER.take() {
-            let r = x.receiver;
+        if let Some(mut x) = NETCODER.take() {
+            x.log_packets();
+            if x.auth.rejected > 0 {
+                println!(
+                    "dropped {} packets which failed authentication",
+                    x.auth.rejected
+                );
+            }
+            if x.malformed_packets > 0 {
+                println!("{} malformed packets", x.malformed_packets);
+            }
+            AUTODELAY = x.autodelay;
+            if let Some(mut stats) = x.stats
+                && let Some(format) = MATCH_STATS_FORMAT
+            {
+                // soku saves the replays there
+                match stats.write(Path::new("replay"), format, env!("CARGO_PKG_VERSION")) {
+                    Ok(path) => println!("match stats written to {}", path.display()),
+                    Err(e) => println!("failed to write the match stats: {}", e),
+                }
+            }
+            if let Some(mut recorder) = x.transport.recorder {
+                if let Some(e) = recorder.error.take() {
+                    println!("failed to record the session: {}", e);
+                } else if let Err(e) = recorder.flush() {
+                    println!("failed to record the session: {}", e);
+                }
+            }
+            let r = x.transport.inner.receiver;
             while r.try_recv().is_ok() {}
             DATA_RECEIVER = Some(r);
         }
 
         // it cannot be used by any different thread now
         if let Some(x) = ROLLBACKER.take() {
+            PREDICTION_STATS.add(x.enemy_inputs.stats);
+            println!(
+                "input prediction ({}): {} of {} frames mispredicted, {} keys",
+                INPUT_PREDICTOR.name(),
+                PREDICTION_STATS.mispredicted,
+                PREDICTION_STATS.predicted,
+                PREDICTION_STATS.wrong_keys
+            );
+            PREDICTOR = Some(x.enemy_inputs.predictor);
             for mut a in x.guessed {
                 if !a.prev_state.has_called_never_happened && !a.prev_state.has_happened {
                     a.prev_state.did_happen();
                 }
             }
+            rollback::SNAPSHOTS.reset();
         }
 
         for a in MEMORY_RECEIVER_FREE.as_ref().unwrap().try_iter() {
@@ -1203,6 +1481,10 @@ fn truer_exec(filename: PathBuf, pretend_to_be_vanilla: bool) -> Result<(), Stri
         GIRLSTALKED = false;
         NEXT_DRAW_ROLLBACK = None;
         NEXT_DRAW_ENEMY_DELAY = None;
+        NEXT_DRAW_PACKET_LOSS = None;
+        NEXT_DRAW_PACKET_ANOMALIES = None;
+        NEXT_DRAW_CLOCK = None;
+        NEXT_DRAW_PENDING_DELAY = None;
         DUMP_FRAME_TIME = None;
         println!("Memory leak: {} bytes", MEMORY_LEAK);
         MEMORY_LEAK = 0;
@@ -1502,9 +1784,59 @@ fn truer_exec(filename: PathBuf, pretend_to_be_vanilla: bool) -> Result<(), Stri
             }
         }
 
+        if let Some(x) = NEXT_DRAW_PACKET_LOSS {
+            draw_num((375.0, 466.0), x);
+        }
+
+        // the packets of the opponent duplicated, reordered and too late, in the last second
+        if let Some([duplicate, reordered, late]) = NEXT_DRAW_PACKET_ANOMALIES {
+            draw_num((325.0, 466.0 - 32.0), duplicate);
+            draw_num((350.0, 466.0 - 32.0), reordered);
+            draw_num((375.0, 466.0 - 32.0), late);
+        }
+
+        // how far the opponent is behind (0.1 ms), and the correction of the skew (µs/frame)
+        if let Some((offset, skew)) = NEXT_DRAW_CLOCK {
+            draw_num((350.0, 466.0 - 16.0), offset);
+            draw_num((375.0, 466.0 - 16.0), skew);
+        }
+
         if let Some(x) = NEXT_DRAW_ENEMY_DELAY {
             draw_num((20.0, 466.0), x);
         }
+
+        // the delay both sides are going to switch to
+        if let Some(x) = NEXT_DRAW_PENDING_DELAY {
+            let inner = D3DRECT {
+                x1: 45 - get_num_length(x, false) as i32,
+                x2: 45 + 2,
+                y1: 466,
+                y2: 480 - 2,
+            };
+            draw_block(*d3d9_devic3, &inner, D3DCOLOR_ARGB(0xff, 0, 0x80, 0xff));
+            draw_num((45.0, 466.0), x);
+        }
+
+        if RECOVERY_NOTICE_COUNTDOWN != 0
+            && let Some(notice) = RECOVERY_NOTICE
+        {
+            // blue: recovering, green: recovered, red: failed, orange: the objects differ, which
+            // can't be recovered; with the frame of the state
+            let (color, frame) = match notice {
+                RecoveryNotice::InProgress(x) => (D3DCOLOR_ARGB(0xff, 0, 0x80, 0xff), x),
+                RecoveryNotice::Done(x) => (D3DCOLOR_ARGB(0xff, 0, 0xc0, 0), x),
+                RecoveryNotice::Failed(x) => (red, x),
+                RecoveryNotice::Unrecoverable(x) => (D3DCOLOR_ARGB(0xff, 0xff, 0x80, 0), x),
+            };
+            let inner = D3DRECT {
+                x1: 420 - get_num_length(frame as i32, false) as i32,
+                x2: 420 + 2,
+                y1: 466,
+                y2: 480 - 2,
+            };
+            draw_block(*d3d9_devic3, &inner, color);
+            draw_num((420.0, 466.0), frame as i32);
+        }
         render_replay_progress_bar_and_numbers();
 
         if WARNING_FRAME_LOST_COUNTDOWN.load(Relaxed) != 0
@@ -1947,6 +2279,7 @@ fn truer_exec(filename: PathBuf, pretend_to_be_vanilla: bool) -> Result<(), Stri
             } else {
             }
             WARNING_FRAME_LOST_COUNTDOWN.store(115, Relaxed);
+            FRAMESKIPS.fetch_add(1, Relaxed);
         } else {
             WaitForSingleObject(HANDLE(waithandle as isize), ddiff as u32);
             if SPIN_TIME_MICROSECOND != 0 {
@@ -2162,8 +2495,11 @@ static mut NEXT_DRAW_PING: Option<i32> = None;
 static mut NEXT_DRAW_ROLLBACK: Option<i32> = None;
 static mut NEXT_DRAW_ENEMY_DELAY: Option<i32> = None;
 
-static mut _NEXT_DRAW_PACKET_LOSS: Option<i32> = None;
-static mut _NEXT_DRAW_PACKET_DESYNC: Option<i32> = None;
+static mut NEXT_DRAW_PACKET_LOSS: Option<i32> = None;
+static mut NEXT_DRAW_CLOCK: Option<(i32, i32)> = None;
+static mut NEXT_DRAW_PENDING_DELAY: Option<i32> = None;
+/// the duplicate, reordered and late packets of the last second
+static mut NEXT_DRAW_PACKET_ANOMALIES: Option<[i32; 3]> = None;
 
 const SOKU_FRAMECOUNT: *mut usize = 0x8985d8 as *mut usize;
 use windows::Win32::System::Threading::GetCurrentThreadId;
@@ -2282,11 +2618,18 @@ fn store_alloc(u: usize) {
     }
 }
 
-static mut LIKELY_DESYNCED: bool = false;
+/// the first frame whose state differs from the opponent's one
+static mut LIKELY_DESYNCED: Option<usize> = None;
 
 #[no_mangle]
 pub extern "cdecl" fn is_likely_desynced() -> bool {
-    unsafe { LIKELY_DESYNCED }
+    unsafe { LIKELY_DESYNCED.is_some() }
+}
+
+/// returns the first frame which is likely desynced, or -1 if there is none
+#[no_mangle]
+pub extern "cdecl" fn likely_desynced_frame() -> i32 {
+    unsafe { LIKELY_DESYNCED.map_or(-1, |x| x as i32) }
 }
 
 unsafe extern "stdcall" fn heap_alloc_override(heap: isize, flags: u32, s: usize) -> *mut c_void {
@@ -2343,7 +2686,7 @@ unsafe extern "stdcall" fn heap_realloc_override(
 use core::sync::atomic::AtomicU8;
 
 use crate::{
-    netcode::send_packet_untagged,
+    netcode::{register_to_relay, send_packet_untagged, sockaddr_len, PeerAddress},
     replay::{
         apause, clean_replay_statics, handle_replay, is_replay_over,
         render_replay_progress_bar_and_numbers,
@@ -2374,23 +2717,11 @@ unsafe extern "stdcall" fn recvfrom_with_fake_packet(
     if let Some(ori_recvfrom) = ORI_RECVFROM {
         if AFTER_GAME_REQUEST_FROM_P1 {
             // AFTER_GAME_REQUEST_FROM_P1 = false;
-            let netmanager = *(0x8986a0 as *const usize);
-            let to;
-            if *(netmanager as *const usize) == 0x858cac {
-                let it = (netmanager + 0x4c8) as *const usize;
-                if *it == 0 {
-                    panic!();
-                }
-                to = *(it as *const *const SOCKADDR);
-            } else {
-                if *(netmanager as *const usize) != 0x858d14 {
-                    panic!();
-                }
-                to = (netmanager + 0x47c) as *const SOCKADDR
-            }
+            let Some(to) = PeerAddress::of_opponent() else {
+                panic!();
+            };
 
-            *from = *to;
-            *fromlen = 0x10;
+            to.write_to(from, fromlen);
             *buf = 0xd;
             *buf.offset(1) = 0x5;
             println!("Send a simulated LAST_MATCH_ACK packet to myself to get my GAME_REQUEST.");
@@ -2543,7 +2874,7 @@ unsafe extern "cdecl" fn readonlinedata(a: *mut ilhook::x86::Registers, _b: usiz
             &buf,
             0,
             to as *const windows::Win32::Networking::WinSock::SOCKADDR,
-            0x10,
+            sockaddr_len(to as *const windows::Win32::Networking::WinSock::SOCKADDR),
         );
 
         (*a).eax = 0x400;
@@ -2555,11 +2886,11 @@ unsafe extern "cdecl" fn readonlinedata(a: *mut ilhook::x86::Registers, _b: usiz
         let m = DISABLE_SEND.load(Relaxed);
 
         if BATTLE_STARTED {
-            let z = NetworkPacket::decode(&slic[0..len as usize]);
+            let data = slic[0..(len as usize).min(slic.len())].into();
             DATA_SENDER
                 .as_ref()
                 .unwrap()
-                .send((z, Instant::now()))
+                .send((data, Instant::now()))
                 .unwrap();
         }
 
@@ -2581,6 +2912,15 @@ unsafe extern "cdecl" fn readonlinedata(a: *mut ilhook::x86::Registers, _b: usiz
         }
     }
 
+    if type1 == 0x6f && BATTLE_STARTED {
+        let data = slic[0..(len as usize).min(slic.len())].into();
+        DATA_SENDER
+            .as_ref()
+            .unwrap()
+            .send((data, Instant::now()))
+            .unwrap();
+    }
+
     if (type1 == 14 || type1 == 13) && type2 == 3 && sceneid == 0x5 && false {
         let is_p1 = unsafe {
             let netmanager = *(0x8986a0 as *const usize);
@@ -2721,16 +3061,6 @@ static DISABLE_SEND: AtomicU8 = AtomicU8::new(0);
 
 //todo: improve rewind mechanism
 
-fn input_to_accum(inp: &[bool; INPUT_KEYS_NUMBERS]) -> u16 {
-    let mut inputaccum = 0u16;
-    for a in 0..INPUT_KEYS_NUMBERS {
-        if inp[a] {
-            inputaccum += 0x1 << a;
-        }
-    }
-    inputaccum
-}
-
 unsafe fn read_key_better(key: u8) -> bool {
     let raw_input_buffer = 0x8a01b8;
 
@@ -2783,11 +3113,16 @@ unsafe fn read_current_input() -> [bool; INPUT_KEYS_NUMBERS] {
     input
 }
 
-static mut ROLLBACKER: Option<Rollbacker> = None;
-static mut NETCODER: Option<Netcoder> = None;
+static mut ROLLBACKER: Option<Rollbacker<Soku>> = None;
+/// the traffic is recorded into a session file if `record_sessions` is enabled
+type SokuNetcoder = Netcoder<Recording<SokuTransport, BufWriter<File>>>;
+static mut NETCODER: Option<SokuNetcoder> = None;
 
-static mut DATA_SENDER: Option<std::sync::mpsc::Sender<(NetworkPacket, Instant)>> = None;
-static mut DATA_RECEIVER: Option<std::sync::mpsc::Receiver<(NetworkPacket, Instant)>> = None;
+/// received 0x6b and 0x6f packets, for `SokuTransport`
+static mut DATA_SENDER: Option<std::sync::mpsc::Sender<(Box<[u8]>, Instant)>> = None;
+static mut DATA_RECEIVER: Option<std::sync::mpsc::Receiver<(Box<[u8]>, Instant)>> = None;
+/// to the thread running `netcode::send_copies`
+static mut PACKET_COPY_SENDER: Option<std::sync::mpsc::Sender<(Box<[u8]>, Instant)>> = None;
 
 static mut MEMORY_SENDER_FREE: Option<std::sync::mpsc::Sender<usize>> = None;
 static mut MEMORY_RECEIVER_FREE: Option<std::sync::mpsc::Receiver<usize>> = None;
@@ -2803,6 +3138,30 @@ static mut LAST_DELAY_VALUE_TAKEOVER: usize = 0;
 
 static mut AUTODELAY_ENABLED: bool = false;
 static mut AUTODELAY_ROLLBACK: i8 = 0;
+/// kept across the rounds of a match, lent to the `Netcoder` of each round
+static mut AUTODELAY: Option<AutoDelay> = None;
+static mut DESYNC_RECOVERY_ENABLED: bool = false;
+/// in milliseconds, 0 for no copies
+static mut PACKET_COPY_DELAY: u64 = 0;
+static mut INPUT_PREDICTOR: PredictorKind = PredictorKind::RepeatLast;
+/// the format of the stats written after each netplay match, `None` for no stats
+static mut MATCH_STATS_FORMAT: Option<StatsFormat> = None;
+/// the incompatibility of the opponent, or the warning about it, has been shown during this match
+static mut INCOMPATIBILITY_SHOWN: bool = false;
+/// record the traffic of each netplay round into a session file
+static mut RECORD_SESSIONS: bool = false;
+/// delay changes during a round are negotiated, and made on both sides at the same frame
+static mut SYMMETRIC_DELAY: bool = false;
+/// counted by `timing_loop`, taken into the stats of the match
+static FRAMESKIPS: AtomicU32 = AtomicU32::new(0);
+/// kept across the rounds of a match, lent to the `Rollbacker` of each round
+static mut PREDICTOR: Option<Box<dyn Predictor>> = None;
+/// of the whole match
+static mut PREDICTION_STATS: PredictionStats = PredictionStats {
+    predicted: 0,
+    mispredicted: 0,
+    wrong_keys: 0,
+};
 
 static mut LAST_DELAY_MANIP: u8 = 0; // 0 neither, 1 up, 2 down, 3 both
 
@@ -2938,23 +3297,64 @@ unsafe fn handle_online(
         SOUND_MANAGER = Some(RollbackSoundManager::new());
         let m = DATA_RECEIVER.take().unwrap();
 
-        let rollbacker = Rollbacker::new();
+        let mut rollbacker = Rollbacker::new(Soku);
+        rollback::HASH_CONFIRMED_FRAMES = true;
+        // the first frame is a keyframe, not a delta keeping the last round alive
+        rollback::SNAPSHOTS.reset();
+        if round == 1 {
+            PREDICTOR = Some(INPUT_PREDICTOR.build());
+            PREDICTION_STATS = PredictionStats::default();
+        }
+        if let Some(predictor) = PREDICTOR.take() {
+            rollbacker.enemy_inputs.predictor = predictor;
+        }
 
         ROLLBACKER = Some(rollbacker);
-        let mut netcoder = Netcoder::new(m, MAX_ROLLBACK_PREFERENCE);
+        let auth = SessionAuth::new();
+        let fps = if F62_ENABLED { 62 } else { 60 };
+        let mut capabilities = Capabilities {
+            fps,
+            layout: Some(
+                rollback::LAYOUT
+                    .get_or_insert_with(netcore::layout::Layout::default_layout)
+                    .hash(),
+            ),
+            ..Capabilities::with_version(env!("CARGO_PKG_VERSION"))
+        };
+        if !SYMMETRIC_DELAY {
+            // so that the opponent doesn't propose changes either
+            capabilities.extensions &= !(1 << EXT_DELAY_CHANGES);
+        }
+        let recorder = RECORD_SESSIONS
+            .then(|| create_recorder(round, auth.nonce, capabilities))
+            .flatten();
+        let mut netcoder = Netcoder::new(
+            Recording::new(SokuTransport { receiver: m }, recorder),
+            is_p1(),
+            MAX_ROLLBACK_PREFERENCE,
+        );
+        netcoder.auth = auth;
+        netcoder.capabilities = capabilities;
         if round == 1 {
-            netcoder.autodelay_enabled = if AUTODELAY_ENABLED {
-                Some(AUTODELAY_ROLLBACK)
-            } else {
-                None
-            };
+            INCOMPATIBILITY_SHOWN = false;
+        }
+        if round == 1 {
+            AUTODELAY = AUTODELAY_ENABLED.then(|| AutoDelay::new(AUTODELAY_ROLLBACK, fps));
             netcoder.delay = DEFAULT_DELAY_VALUE;
         } else {
             netcoder.delay = LAST_DELAY_VALUE;
         }
+        netcoder.autodelay = AUTODELAY.take();
         netcoder.max_rollback = 6;
+        netcoder.autorollback = ADAPTIVE_MAX_ROLLBACK.map(|x| AutoRollback::new(x, fps));
         netcoder.display_stats = TOGGLE_STAT;
+        netcoder.recovery_enabled = DESYNC_RECOVERY_ENABLED;
+        netcoder.resend_after =
+            (PACKET_COPY_DELAY != 0).then(|| Duration::from_millis(PACKET_COPY_DELAY));
+        netcoder.stats = MATCH_STATS_FORMAT.map(|_| MatchStats::new(is_p1()));
+        FRAMESKIPS.store(0, Relaxed);
         NETCODER = Some(netcoder);
+        register_to_relay();
 
         if SMOOTH_ENABLED_CONFIG {
             SMOOTH = true;
@@ -2976,12 +3376,39 @@ unsafe fn handle_online(
     netcoder.display_stats = TOGGLE_STAT;
 
     if *cur_speed_iter == 0 {
-        LAST_DELAY_VALUE = change_delay_from_keys(netcoder.delay);
+        // nobody can move during the round start animation, so the delay can change freely
+        if *battle_state == 1 {
+            netcoder.apply_agreed_delay();
+        }
+        if SYMMETRIC_DELAY && *battle_state != 1 {
+            let requested = netcoder.pending_delay().map_or(netcoder.delay, |x| x.delay);
+            let delay = change_delay_from_keys(requested);
+            if delay != requested && !netcoder.propose_delay(delay) {
+                println!(
+                    "the delay change to {} can't be negotiated, made locally",
+                    delay
+                );
+                netcoder.delay = delay;
+            }
+        } else {
+            netcoder.delay = change_delay_from_keys(netcoder.delay);
+        }
+        LAST_DELAY_VALUE = netcoder.delay;
 
-        netcoder.delay = LAST_DELAY_VALUE;
+        let netmanager = *(0x8986a0 as *const usize);
+        //host only
+        *ptr_wrap!((netmanager + 0x80) as *mut u8) = netcoder.delay as u8;
+        //client only
+        *ptr_wrap!((netmanager + 0x81) as *mut u8) = netcoder.delay as u8;
+
+        //because it looks like soku locks the netcode untill the start of a new frame, we sometimes reach this point before the netcode has finished processing it's packet, for that reason:
+        std::thread::sleep(Duration::from_millis(1));
 
         let input = read_current_input();
         let speed = netcoder.process_and_send(rollbacker, input);
+        update_from_netcoder(netcoder);
+        // a negotiated change may have been applied
+        LAST_DELAY_VALUE = netcoder.delay;
 
         *cur_speed = speed;
 
@@ -3018,6 +3445,100 @@ unsafe fn handle_online(
     }
 }
 
+/// creates the session file of the round, next to the replays
+unsafe fn create_recorder(
+    round: u8,
+    nonce: [u8; NONCE_LEN],
+    capabilities: Capabilities,
+) -> Option<Recorder<BufWriter<File>>> {
+    let header = SessionHeader {
+        is_p1: is_p1(),
+        max_rollback: MAX_ROLLBACK_PREFERENCE,
+        compact_inputs: true,
+        recovery_enabled: DESYNC_RECOVERY_ENABLED,
+        resend_after: PACKET_COPY_DELAY as u8,
+        auto_delay: AUTODELAY_ENABLED.then_some(AUTODELAY_ROLLBACK),
+        input_predictor: INPUT_PREDICTOR,
+        fps: capabilities.fps,
+        nonce,
+        capabilities,
+        auto_rollback: ADAPTIVE_MAX_ROLLBACK,
+    };
+    let time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |x| x.as_secs());
+    let path = Path::new("replay").join(format!(
+        "giuroll_session_{}_{}_round{}.rec",
+        time,
+        if header.is_p1 { "p1" } else { "p2" },
+        round
+    ));
+    let recorder = std::fs::create_dir_all("replay")
+        .and_then(|_| File::create(&path))
+        .and_then(|x| Recorder::new(BufWriter::new(x), &header));
+    match recorder {
+        Ok(x) => {
+            println!("recording the session into {}", path.display());
+            Some(x)
+        }
+        Err(e) => {
+            println!("failed to create {}: {}", path.display(), e);
+            None
+        }
+    }
+}
+
+/// shows what `Netcoder::process_and_send` has found
+unsafe fn update_from_netcoder(netcoder: &mut SokuNetcoder) {
+    TARGET_OFFSET.fetch_add(netcoder.take_target_offset(), Relaxed);
+    LIKELY_DESYNCED = netcoder.likely_desynced;
+
+    match netcoder.pause {
+        Some(PauseReason::FrameMissing) => WARNING_FRAME_MISSING_1_COUNTDOWN = 120,
+        Some(PauseReason::InputMissing) => WARNING_FRAME_MISSING_2_COUNTDOWN = 120,
+        Some(PauseReason::Recovery) | None => (),
+    }
+    NEXT_DRAW_PING = netcoder.draw_ping;
+    NEXT_DRAW_ROLLBACK = netcoder.draw_rollback;
+    NEXT_DRAW_ENEMY_DELAY = netcoder.draw_enemy_delay;
+    NEXT_DRAW_PACKET_LOSS = netcoder.draw_packet_loss;
+    NEXT_DRAW_PACKET_ANOMALIES = netcoder.draw_packet_anomalies;
+    NEXT_DRAW_CLOCK = netcoder.draw_clock;
+    NEXT_DRAW_PENDING_DELAY = netcoder.pending_delay().map(|x| x.delay as i32);
+    if let Some(Err(e)) = netcoder.agreement
+        && !INCOMPATIBILITY_SHOWN
+    {
+        INCOMPATIBILITY_SHOWN = true;
+        let text = e.to_string();
+        // the game goes on while the box is shown
+        std::thread::spawn(move || warning_box(&text, "Giuroll: incompatible opponent"));
+    }
+    if let Some(Ok(agreement)) = netcoder.agreement
+        && let Some(text) = agreement.warning()
+        && !INCOMPATIBILITY_SHOWN
+    {
+        INCOMPATIBILITY_SHOWN = true;
+        std::thread::spawn(move || warning_box(&text, "Giuroll: different snapshot layout"));
+    }
+    if let Some(stats) = netcoder.stats.as_mut() {
+        stats.current.frameskips += FRAMESKIPS.swap(0, Relaxed);
+    }
+    if let Some(copy) = netcoder.take_copy() {
+        let _ = PACKET_COPY_SENDER.as_ref().unwrap().send(copy);
+    }
+
+    if let Some(notice) = netcoder.recovery_notice.take() {
+        RECOVERY_NOTICE = Some(notice);
+        RECOVERY_NOTICE_COUNTDOWN = 180;
+    }
+    if let Some(report) = netcoder.finished_region_report.take() {
+        match report.write(env!("CARGO_PKG_VERSION")) {
+            Ok(path) => println!("desync report written to {}", path.display()),
+            Err(e) => println!("failed to write the desync report: {}", e),
+        }
+    }
+}
+
 unsafe extern "cdecl" fn main_hook(a: *mut ilhook::x86::Registers, _b: usize) {
     #[cfg(feature = "logtofile")]
     std::panic::set_hook(Box::new(|x| info!("panic! {:?}", x)));
@@ -3101,6 +3622,7 @@ unsafe extern "cdecl" fn main_hook(a: *mut ilhook::x86::Registers, _b: usize) {
     if cur_speed_iter + 1 >= cur_speed {
         WARNING_FRAME_MISSING_1_COUNTDOWN = WARNING_FRAME_MISSING_1_COUNTDOWN.saturating_sub(1);
         WARNING_FRAME_MISSING_2_COUNTDOWN = WARNING_FRAME_MISSING_2_COUNTDOWN.saturating_sub(1);
+        RECOVERY_NOTICE_COUNTDOWN = RECOVERY_NOTICE_COUNTDOWN.saturating_sub(1);
     }
 
     let battle_manaer = (*a).esi as *const *const u8;
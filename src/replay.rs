@@ -1,19 +1,24 @@
 use crate::{
     change_delay_from_keys, draw_num, draw_num_x_center, get_num_length, pause, println, ptr_wrap,
     read_current_input, read_key_better, resume,
-    rollback::{dump_frame, Frame, DUMP_FRAME_TIME},
+    rollback::{dump_frame, Frame, DUMP_FRAME_TIME, SNAPSHOTS},
     soku_heap_free, CENTER_X_P1, CENTER_X_P2, CENTER_Y_P1, CENTER_Y_P2, DISABLE_SOUND,
     ENABLE_CHECK_MODE, F32, INPUT_KEYS_NUMBERS, INSIDE_COLOR, INSIDE_HALF_HEIGHT,
     INSIDE_HALF_WIDTH, LAST_DELAY_VALUE_TAKEOVER, MEMORY_RECEIVER_ALLOC, MEMORY_RECEIVER_FREE,
     NEXT_DRAW_ROLLBACK, OUTER_COLOR, OUTER_HALF_HEIGHT, OUTER_HALF_WIDTH, PROGRESS_COLOR,
-    REAL_INPUT, REAL_INPUT2, SMOOTH, SMOOTH_ENABLED_CONFIG, SOKU_FRAMECOUNT, TAKEOVER_COLOR,
+    REAL_INPUT, REAL_INPUT2, REWIND_MEMORY_BUDGET, SMOOTH, SMOOTH_ENABLED_CONFIG, SOKU_FRAMECOUNT,
+    TAKEOVER_COLOR,
 };
+use netcore::snapshot::Snapshot;
 use std::{
     collections::{HashMap, HashSet, VecDeque},
     io::Write,
     iter::Empty,
     os::raw::c_void,
-    sync::atomic::{AtomicU8, Ordering::Relaxed},
+    sync::{
+        atomic::{AtomicU8, Ordering::Relaxed},
+        Arc,
+    },
     time::Duration,
     u32,
 };
@@ -103,6 +108,114 @@ static mut REPLAY_KO_FRAMECOUNT: Option<usize> = None;
 
 static mut LAST_TARGET: Option<usize> = None;
 
+/// frames this close to the newest saved one are all kept, whatever the memory budget
+const DENSE_FRAMES: usize = 600;
+/// the widest spacing of the frames kept beyond `DENSE_FRAMES`, which a rewind to a dropped
+/// frame simulates again in one step
+const MAX_KEYFRAME_SPACING: usize = 256;
+/// `Frame::memory_size` of all the `FRAMES`, and the size of `ORPHANED_KEYFRAMES`
+static mut FRAMES_MEMORY: usize = 0;
+/// the snapshots of frames dropped from `FRAMES` which are still the keyframe of others
+static mut ORPHANED_KEYFRAMES: Vec<Arc<Snapshot>> = Vec::new();
+/// the spacing of the frames kept beyond `DENSE_FRAMES`, 0 while the budget has not been reached
+static mut KEYFRAME_SPACING: usize = 0;
+/// the frames up to this one have been thinned with `KEYFRAME_SPACING`
+static mut THINNED_TO: usize = 0;
+
+unsafe fn push_frame(frame: Frame) {
+    FRAMES_MEMORY += frame.memory_size();
+    FRAMES.push_back(frame);
+}
+
+unsafe fn pop_frame_back() -> Option<Frame> {
+    let frame = FRAMES.pop_back()?;
+    FRAMES_MEMORY -= frame.memory_size();
+    // the frames saved again after a rewind have to be thinned again
+    THINNED_TO = THINNED_TO.min(FRAMES.back().map_or(0, |x| x.number));
+    Some(frame)
+}
+
+unsafe fn pop_frame_front() -> Option<Frame> {
+    let frame = FRAMES.pop_front()?;
+    forget_memory_of(&frame);
+    Some(frame)
+}
+
+/// takes `frame`, which is dropped for good, out of `FRAMES_MEMORY`, apart from its snapshot if
+/// the deltas of other frames are made from it
+unsafe fn forget_memory_of(frame: &Frame) {
+    FRAMES_MEMORY -= frame.memory_size();
+    if Arc::strong_count(&frame.addresses_buf) > 1 {
+        FRAMES_MEMORY += frame.addresses_buf.stored_len();
+        ORPHANED_KEYFRAMES.push(frame.addresses_buf.clone());
+    }
+}
+
+/// lets go of the orphaned keyframes which no delta is made from anymore
+unsafe fn release_orphaned_keyframes() {
+    ORPHANED_KEYFRAMES.retain(|x| {
+        let needed = Arc::strong_count(x) > 1;
+        if !needed {
+            FRAMES_MEMORY -= x.stored_len();
+        }
+        needed
+    });
+}
+
+/// Drops the frames older than `DENSE_FRAMES` and newer than `after` which aren't
+/// `KEYFRAME_SPACING` apart, apart from the oldest frame and the one a takeover is retried from.
+unsafe fn thin_frames(after: usize) {
+    let Some(newest) = FRAMES.back().map(|x| x.number) else {
+        return;
+    };
+    let takeover = RE_PLAY.as_ref().map(|x| x.frame - 1);
+    let end = FRAMES.partition_point(|x| x.number + DENSE_FRAMES <= newest);
+    let start = FRAMES.partition_point(|x| x.number <= after);
+    for i in (start..end).rev() {
+        let frame = &mut FRAMES[i];
+        if i == 0 || (frame.number - 1) % KEYFRAME_SPACING == 0 || Some(frame.number) == takeover {
+            FRAMES_MEMORY -= frame.memory_size();
+            frame.flatten_snapshot();
+            FRAMES_MEMORY += frame.memory_size();
+        } else {
+            let mut frame = FRAMES.remove(i).unwrap();
+            forget_memory_of(&frame);
+            // the next frame is kept, or is newer than `DENSE_FRAMES`
+            frame.forget_into(&mut FRAMES[i]);
+        }
+    }
+    let end = FRAMES.partition_point(|x| x.number + DENSE_FRAMES <= newest);
+    if let Some(x) = end.checked_sub(1).map(|x| &FRAMES[x]) {
+        THINNED_TO = x.number;
+    }
+}
+
+/// Keeps `FRAMES` within `REWIND_MEMORY_BUDGET`: once it is reached, the frames older than
+/// `DENSE_FRAMES` are only kept every 16 frames, then every 32 frames, and so on until they fit
+/// (or up to `MAX_KEYFRAME_SPACING`). Rewinding to a dropped frame restores the frame before it,
+/// and simulates the frames in between again.
+///
+/// Called after each frame saved, so only the frames which have just left `DENSE_FRAMES` are
+/// thinned, unless the spacing widens.
+unsafe fn enforce_memory_budget() {
+    release_orphaned_keyframes();
+    if REWIND_MEMORY_BUDGET == 0 {
+        return;
+    }
+    if KEYFRAME_SPACING == 0 {
+        if FRAMES_MEMORY <= REWIND_MEMORY_BUDGET {
+            return;
+        }
+        KEYFRAME_SPACING = 16;
+        THINNED_TO = 0;
+    }
+    thin_frames(THINNED_TO);
+    while FRAMES_MEMORY > REWIND_MEMORY_BUDGET && KEYFRAME_SPACING < MAX_KEYFRAME_SPACING {
+        KEYFRAME_SPACING *= 2;
+        thin_frames(0);
+    }
+}
+
 pub unsafe extern "cdecl" fn apause(_a: *mut ilhook::x86::Registers, _b: usize) {
     //let pinput = 0x89a248;
     //let input = read_addr(0x89a248, 0x58).usize_align();
@@ -233,6 +346,11 @@ pub unsafe fn clean_replay_statics() {
         }
     }
     ALLOCS = None;
+    SNAPSHOTS.reset();
+    FRAMES_MEMORY = 0;
+    ORPHANED_KEYFRAMES.clear();
+    KEYFRAME_SPACING = 0;
+    THINNED_TO = 0;
 
     DISABLE_PAUSE = false;
     if RE_PLAY.take().is_some() {
@@ -616,7 +734,7 @@ pub unsafe fn handle_replay(
                 if is_over {
                     override_target_frame = Some(1);
                     check.check_step = CheckStep::TestPlay2;
-                    FRAMES.push_back(dump_frame(None::<Empty<_>>, None::<Empty<_>>));
+                    push_frame(dump_frame(None::<Empty<_>>, None::<Empty<_>>));
                     println!("Step 1 got {} frames.", check.check_data.len());
                     println!("Start step 2: rollbacking to the beginning and replaying the replay.")
                 } else {
@@ -695,7 +813,7 @@ pub unsafe fn handle_replay(
                                         && frame.number <= being_tested_frame_
                                     {
                                         frame.did_happen();
-                                        FRAMES.pop_front();
+                                        pop_frame_front();
                                     }
                                     if being_tested_frame % 300 == 0 {
                                         println!(
@@ -745,7 +863,7 @@ pub unsafe fn handle_replay(
                 && frame.number < test.base_framecount
             {
                 frame.did_happen();
-                FRAMES.pop_front();
+                pop_frame_front();
             }
             override_target_frame = Some(
                 (test.base_framecount + test.max_rollback - 1)
@@ -866,6 +984,7 @@ pub unsafe fn handle_replay(
                         IS_REWINDING.store(1, Relaxed);
                         //good
                         let diff = target - framenum;
+                        SNAPSHOTS.rebase(&x.addresses_buf);
                         x.restore(
                             Some(dropped_frame.iter_mut().rev()),
                             Some(ALLOCS.replace(HashSet::new()).unwrap().into_iter()),
@@ -889,7 +1008,7 @@ pub unsafe fn handle_replay(
                         }
                         break;
                     } else {
-                        dropped_frame.push(FRAMES.pop_back().unwrap());
+                        dropped_frame.push(pop_frame_back().unwrap());
                         continue;
                     }
                 } else {
@@ -898,12 +1017,13 @@ pub unsafe fn handle_replay(
 
                     if let Some(last) = dropped_frame.pop() {
                         // it can happen when rewind to frame 1
+                        SNAPSHOTS.rebase(&last.addresses_buf);
                         last.restore(
                             Some(dropped_frame.iter_mut().rev()),
                             Some(ALLOCS.replace(HashSet::new()).unwrap().into_iter()),
                             Some(FREES.replace(HashSet::new()).unwrap().into_iter()),
                         );
-                        FRAMES.push_back(last);
+                        push_frame(last);
                     }
                     pause(battle_state, weird_counter);
                     *cur_speed_iter = *cur_speed;
@@ -947,7 +1067,8 @@ pub unsafe fn handle_replay(
                 Some(FREES.replace(HashSet::new()).unwrap().into_iter()),
             );
             // println!("push {}", x.number);
-            FRAMES.push_back(x);
+            push_frame(x);
+            enforce_memory_budget();
         }
     } else {
         // println!("cannot push?");
@@ -1,27 +1,28 @@
 #[cfg(feature = "logtofile")]
+use crate::ISDEBUG;
+#[cfg(feature = "logtofile")]
 use log::info;
 use std::{
-    arch::asm,
-    collections::{HashMap, HashSet},
-    ffi::c_void,
-    iter::Empty,
-    ops::Deref,
-    ptr::null_mut,
-    time::Duration,
+    arch::asm, collections::HashSet, ffi::c_void, iter::Empty, ops::Deref, ptr::null_mut,
+    sync::Arc, time::Duration,
 };
 
+use netcore::{
+    desync::{MergeError, RegionInfo, RegionKind, RegionTable},
+    layout::{BulletOp, CharacterOp, Layout, Op, PatternOp, PlayerOp, MAX_NESTED_PLAYERS},
+    rollback::{Game, GameState, RInput},
+    snapshot::{Snapshot, Snapshots},
+};
 use windows::Win32::Foundation::HANDLE;
 
 #[allow(unused_imports)]
 use crate::println;
 use crate::{
-    ptr_wrap, set_input_buffer, soku_heap_free, Callbacks, CameraTransform, CALLBACK_ARRAY,
-    INPUT_KEYS_NUMBERS, ISDEBUG, LAST_CAMERA_BEFORE_SMOOTH, MEMORY_RECEIVER_ALLOC,
-    MEMORY_RECEIVER_FREE, SOKU_FRAMECOUNT, SOUND_MANAGER,
+    ptr_wrap, set_input_buffer, soku_heap_free, warning_box, CallbacksV2, CameraTransform,
+    CALLBACK_ARRAY, LAST_CAMERA_BEFORE_SMOOTH, MEMORY_RECEIVER_ALLOC, MEMORY_RECEIVER_FREE,
+    SOKU_FRAMECOUNT, SOUND_MANAGER,
 };
 
-type RInput = [bool; INPUT_KEYS_NUMBERS];
-
 pub static mut CHARSIZEDATA: Vec<(usize, usize)> = vec![];
 
 #[no_mangle]
@@ -33,136 +34,88 @@ pub unsafe extern "cdecl" fn set_char_data_size(s: usize) {
 
 #[no_mangle]
 pub unsafe extern "cdecl" fn set_char_data_pos(pos: usize, a: usize, b: usize) {
-    set_char_data_size(pos);
+    set_char_data_size(pos + 1);
     CHARSIZEDATA[pos] = (a, b);
 }
 
-// pub enum MemoryManip {
-//     Alloc(usize),
-//     Free(usize),
-// }
-pub struct EnemyInputHolder {
-    pub i: Vec<Option<RInput>>,
-}
+/// a read past the data of a character has been reported
+static mut LAYOUT_ERROR_SHOWN: bool = false;
 
-impl EnemyInputHolder {
-    fn new() -> Self {
-        Self { i: Vec::new() }
+/// the pointer at `offset` of `data`, a player or a bullet of `char`, for the operation at
+/// `line` of the layout; `None` if it is past the end, which is reported once
+unsafe fn layout_ptr(data: &[u8], offset: usize, char: u8, line: usize) -> Option<usize> {
+    if offset + 4 <= data.len() {
+        return Some(get_ptr(data, offset));
     }
-    fn get(&self, count: usize) -> RInput {
-        match self.get_result(count) {
-            Ok(x) => x,
-            Err(x) => x,
-        }
+    if !std::mem::replace(&mut LAYOUT_ERROR_SHOWN, true) {
+        let text = format!(
+            "The operation at line {} of the snapshot layout reads offset {:#x} of character {}, \
+             past the {:#x} bytes of its data. It is skipped.",
+            line,
+            offset,
+            char,
+            data.len()
+        );
+        println!("{}", text);
+        // the game goes on while the box is shown
+        std::thread::spawn(move || warning_box(&text, "Giuroll: invalid snapshot layout"));
     }
+    None
+}
 
-    pub fn insert(&mut self, input: RInput, frame: usize) {
-        while frame >= self.i.len() {
-            self.i.push(None);
-        }
-        if let Some(x) = self.i[frame].replace(input) {
-            //doubled input
-            if x != input {
-                panic!("replacing existing input");
-            }
-        }
+/// a character without a known data size has been reported
+static mut CHAR_SIZE_ERROR_SHOWN: bool = false;
+
+/// the size of `data`, a player (`bullet == false`) or a bullet of the character `char`, from
+/// `CHARSIZEDATA` or else from its heap block, for the characters of mods which don't set it;
+/// `None` if neither knows it, which is reported once, and the data isn't saved
+unsafe fn char_data_size(char: u8, data: usize, bullet: bool) -> Option<usize> {
+    let known = CHARSIZEDATA
+        .get(char as usize)
+        .map(|x| if bullet { x.1 } else { x.0 })
+        .unwrap_or(0);
+    if known != 0 {
+        return Some(known);
     }
-
-    fn get_result(&self, frame: usize) -> Result<RInput, RInput> {
-        match self.i.get(frame) {
-            Some(Some(x)) => Ok(*x),
-            None if frame == 0 => Err([false; INPUT_KEYS_NUMBERS]),
-            Some(None) | None => {
-                /*
-                    in the future maybe try dropping inputs for attacks that are about to charge?
-                    let mut w = (1..3)
-                        .map(|x| self.get(frame.saturating_sub(x)))
-                        .reduce(|x, y| {
-                            (0..INPUT_KEYS_NUMBERS)
-                                .map(|idx| x[idx] & y[idx])
-                                .collect::<Vec<_>>()
-                                .try_into()
-                                .unwrap()
-                        })
-                        .unwrap();
-
-                    w[0..4].copy_from_slice(&self.get(frame - 1)[0..4]);
-                */
-
-                Err(self.get(frame - 1))
+    match read_heap(data) {
+        0 | usize::MAX => {
+            if !std::mem::replace(&mut CHAR_SIZE_ERROR_SHOWN, true) {
+                let text = format!(
+                    "The data size of character {} is missing, and couldn't be found from the \
+                     heap. Its data isn't saved, so rolling back may desync.\n\
+                     It can be set as character_{}=<size of a player>,<size of a bullet> in the \
+                     [CharacterSizes] section of giuroll.ini.",
+                    char, char
+                );
+                println!("{}", text);
+                std::thread::spawn(move || warning_box(&text, "Giuroll: unknown character"));
             }
+            None
         }
+        x => Some(x),
     }
 }
 
-pub struct Rollbacker {
-    pub guessed: Vec<RollFrame>,
-
-    current: usize,
-    rolling_back: bool,
+/// soku itself, as seen by the `Rollbacker`
+pub struct Soku;
 
-    // pub future_sound: HashMap<usize, usize>,
-    // first element is the sound, second is the frame it occured at, whenever a frame comes true we can delete all future sounds with that value
+impl Game for Soku {
+    type State = Frame;
 
-    // stores all the sounds that happened in "guessed" frames. Will also need to be topped up *after* last frame.
-    // every frame we can store this as "past_sounds", and if any sound in future sounds did not appear in past_sounds, we can then cancell that sound by force calling 0x401d50
-    // which we hook, and set a static to ignore the sound.
-
-    //also, while rolling back, we should not play sounds that already did appear in past_sounds (and instead remove them, so we can see what is)
-    pub enemy_inputs: EnemyInputHolder,
-    pub self_inputs: Vec<RInput>,
-
-    pub weathers: HashMap<usize, u8>,
-}
-
-impl Rollbacker {
-    pub fn new() -> Self {
-        Self {
-            guessed: Vec::new(),
-            current: 0,
-            rolling_back: false,
-            enemy_inputs: EnemyInputHolder::new(),
-            self_inputs: Vec::new(),
-            weathers: HashMap::new(),
-            // future_sound: HashMap::new(),
-        }
+    fn frame_count(&self) -> usize {
+        unsafe { *SOKU_FRAMECOUNT }
     }
 
-    /// fill in inputs before calling this function
-    pub fn start(&mut self) -> usize {
-        //this should only be called on the 0th iteration.
-        self.current = unsafe { *SOKU_FRAMECOUNT };
-        //let newsound = std::mem::replace(&mut *SOUNDS_THAT_DID_HAPPEN.lock().unwrap(), BTreeMap::new());
-
-        while self.guessed.len() > 0
-            && (self
-                .enemy_inputs
-                .get_result(self.guessed[0].prev_state.number)
-                .map(|x| x == self.guessed[0].enemy_input)
-                .unwrap_or(false))
-        {
-            let mut m = self.guessed.remove(0);
-
-            self.weathers
-                .insert(m.prev_state.number, m.prev_state.weather_sync_check);
-            m.prev_state.did_happen();
-            #[cfg(feature = "logrollback")]
-            println!("did_happen {}", m.prev_state.number);
-            //let b = &mut *FREEMUTEX.lock().unwrap();
-            //for a in m.prev_state.frees {
-            //    b.insert(a);
-            //}
-        }
-
-        //*SOUND_DELET_MUTEX.lock().unwrap() = newsound;
+    fn dump(&mut self) -> Frame {
+        unsafe { dump_frame(None::<Empty<_>>, None::<Empty<_>>) }
+    }
 
-        self.rolling_back = false;
-        self.guessed.len() + 1
+    fn restore<'a>(&mut self, state: &Frame, dropped: impl Iterator<Item = &'a mut Frame>) {
+        unsafe { SNAPSHOTS.rebase(&state.addresses_buf) };
+        state.restore(Some(dropped), None::<Empty<_>>, None::<Empty<_>>);
     }
 
-    fn apply_input(input: RInput, opponent_input: RInput) {
-        #[cfg(feature = "logrollback")]
-        println!("apply input {:?}", opponent_input);
+    fn apply_input(&mut self, input: RInput, opponent_input: RInput) {
         let is_p1 = unsafe {
             let netmanager = *(0x8986a0 as *const usize);
             *ptr_wrap!(netmanager as *const usize) == 0x858cac
@@ -175,136 +128,59 @@ impl Rollbacker {
         }
     }
 
-    pub fn step(&mut self, iteration_number: usize) -> Option<()> {
-        let tbr = if self.guessed.len() == iteration_number {
-            //last iteration for this frame, handle sound here
-            if self.rolling_back {
-                unsafe {
-                    let manager = SOUND_MANAGER.as_mut().unwrap();
-                    manager.delete_non_matched();
-                }
-            }
-            /*
-            let mut to_be_skipped = vec![];
-            {
-                let new_sounds = &mut *SOUNDS_THAT_DID_HAPPEN.lock().unwrap();
-                let old_sounds = &mut *SOUND_THAT_MAYBE_HAPPEN.lock().unwrap();
-
-                let new_col = new_sounds
-                    .values()
-                    .map(|x| x.into_iter())
-                    .flatten()
-                    .collect::<HashSet<_>>();
-
-                for idx in (self.current).saturating_sub(INPUT_KEYS_NUMBERS)..=(self.current + 1) {
-                    if new_sounds.contains_key(&(self.current + 1)) {
-                        println!("HERE, CONTAINS")
-                    }
-                    if !new_sounds.contains_key(&idx) {
-                        continue;
-                    }
-                    if let Some(x) = old_sounds.get(&idx) {
-                        for a in x {
-                            if !new_col.contains(a) {
-                                to_be_skipped.push(*a);
-                            }
-                        }
-                    }
-                }
+    fn rollback_from(&mut self, frame: usize, current: usize) {
+        unsafe {
+            let manager = SOUND_MANAGER.as_mut().unwrap();
+            manager.pop_sounds_since(frame, current);
+        }
+    }
 
-                std::mem::swap(old_sounds, new_sounds);
-            };
-            if to_be_skipped.len() != 0{
-                println!("len: {}", to_be_skipped.len());
-            }
-            for a in to_be_skipped {
-                force_sound_skip(a);
-            }
-            */
+    fn rollback_done(&mut self) {
+        unsafe {
+            let manager = SOUND_MANAGER.as_mut().unwrap();
+            manager.delete_non_matched();
+        }
+    }
+}
 
-            //if self.current != unsafe { *SOKU_FRAMECOUNT } {
-            //    println!("here");
-            //}
+impl GameState for Frame {
+    fn number(&self) -> usize {
+        self.number
+    }
 
-            let current = unsafe { *SOKU_FRAMECOUNT };
+    fn weather_sync_check(&self) -> u8 {
+        self.weather_sync_check
+    }
 
-            let si = self.self_inputs[current];
-            let ei = self.enemy_inputs.get(current);
-            Self::apply_input(si, ei);
-            self.guessed.push(RollFrame::dump_with_guess(si, ei));
+    fn did_happen(&mut self) {
+        Frame::did_happen(self)
+    }
 
-            Some(())
-        } else {
-            let (fr_, remain) = self.guessed[iteration_number..].split_at_mut(1);
-            let fr = &mut fr_[0];
-            if self.rolling_back {
-                unsafe {
-                    fr.prev_state = dump_frame(None::<Empty<_>>, None::<Empty<_>>);
-                    #[cfg(feature = "logrollback")]
-                    println!("dump {}", fr.prev_state.number);
-                    // the following have been done when `restore`:
-                    // let prev = std::mem::replace(&mut fr.prev_state, frame);
-                    // prev.never_happened();
-                    //let b = &mut *ALLOCMUTEX.lock().unwrap();
-                    //for a in prev.allocs {
-                    //    b.insert(a);
-                    //}
-                };
-                fr.enemy_input = self.enemy_inputs.get(fr.prev_state.number);
-                Self::apply_input(fr.player_input, fr.enemy_input);
-                Some(())
-            } else if fr.enemy_input != self.enemy_inputs.get(fr.prev_state.number) {
-                //info!("ROLLBACK");
-                unsafe {
-                    let manager = SOUND_MANAGER.as_mut().unwrap();
-                    manager.pop_sounds_since(fr.prev_state.number, self.current);
-                }
-                self.rolling_back = true;
-                fr.prev_state.restore(
-                    Some(remain.iter_mut().map(|x| &mut x.prev_state)),
-                    None::<Empty<_>>,
-                    None::<Empty<_>>,
-                );
-                #[cfg(feature = "logrollback")]
-                println!("restore {}", fr.prev_state.number);
-                //fr.prev_state.clone().never_happened();
+    fn state_hash(&self) -> Option<u64> {
+        self.state_hash
+    }
 
-                fr.enemy_input = self.enemy_inputs.get(fr.prev_state.number);
-                Self::apply_input(fr.player_input, fr.enemy_input);
-                Some(())
-            } else {
-                None
-            }
-        };
+    fn take_region_table(&mut self) -> Option<RegionTable> {
+        self.region_table.take()
+    }
 
-        tbr
+    fn snapshot(&self) -> Box<[u8]> {
+        Frame::snapshot(self)
     }
-}
 
-pub struct RollFrame {
-    pub prev_state: Frame,
-    pub player_input: RInput,
-    pub enemy_input: RInput,
+    fn merge_snapshot(&mut self, snapshot: &[u8]) -> Result<(), MergeError> {
+        Frame::merge_snapshot(self, snapshot)
+    }
 }
 
 pub static mut LAST_M_LEN: usize = 0;
 
-impl RollFrame {
-    fn dump_with_guess(player_input: RInput, guess: RInput) -> Self {
-        let prev_state = unsafe { dump_frame(None::<Empty<_>>, None::<Empty<_>>) };
-        #[cfg(feature = "logrollback")]
-        println!("dump {} with guess", prev_state.number);
-
-        Self {
-            prev_state,
-            player_input: player_input,
-            enemy_input: guess,
-        }
-    }
-}
 static mut FPST: [u8; 108] = [0u8; 108];
 pub static mut DUMP_FRAME_TIME: Option<Duration> = None;
 pub static mut MEMORY_LEAK: usize = 0;
+/// makes `Frame::addresses_buf`, a delta from the last keyframe if `snapshot_keyframe_interval`
+/// is set
+pub static mut SNAPSHOTS: Snapshots = Snapshots::new(0);
 pub unsafe fn dump_frame(
     extra_allocs: Option<impl Iterator<Item = usize>>,
     extra_frees: Option<impl Iterator<Item = usize>>,
@@ -327,58 +203,159 @@ pub unsafe fn dump_frame(
 
     // guess the length to avoid reallocation as far as possible
     let mut m: Vec<ReadAddr> = Vec::with_capacity(LAST_M_LEN.next_power_of_two());
+    // (index of the first region, kind), for desync diagnostics
+    let mut sections: Vec<(usize, RegionKind)> = vec![];
 
-    #[cfg(feature = "logtofile")]
-    if ISDEBUG {
-        info!("0x895ec")
-    };
-    let ptr1 = read_addr(0x8985ec, 0x4);
-    let first = get_ptr(&ptr1.content[0..4], 0);
-    m.push(read_addr(first, 0xec));
+    read_frame_layout(
+        LAYOUT.get_or_insert_with(Layout::default_layout),
+        &mut m,
+        &mut sections,
+    );
 
-    {
-        let t = read_vec(first + 0x1c);
+    let mut extra_states: Vec<ExtraState> = Vec::with_capacity(CALLBACK_ARRAY.len());
 
-        m.push(t.read_underlying());
+    for cb in CALLBACK_ARRAY.iter() {
+        let i = (cb.save_state)(cb.context);
 
-        m.push(t.to_addr());
+        extra_states.push(ExtraState { cb: *cb, state: i })
     }
 
-    {
-        let t = read_vec(first + 0x68);
-        if t.start != 0 {
-            m.push(t.read_underlying());
-        }
+    // aligned to 4
+    let buf_size: usize = m
+        .iter()
+        .map(|x| x.content.metadata.size.div_ceil(4) * 4)
+        .sum();
+
+    let mut buf = Vec::with_capacity(buf_size);
+    for addr in &m {
+        buf.extend_from_slice(&addr.content);
+        buf.resize(buf.len().div_ceil(4) * 4, 0);
     }
+    assert_eq!(buf_size, buf.len());
 
-    {
-        let t = read_linked_list(first + 0x78);
+    LAST_M_LEN = m.len();
 
-        m.extend(t.read_all(0));
-    }
+    let mut alloc: Vec<usize> = MEMORY_RECEIVER_ALLOC.as_ref().unwrap().try_iter().collect();
+    let mut frees: Vec<usize> = MEMORY_RECEIVER_FREE.as_ref().unwrap().try_iter().collect();
+    extra_allocs.and_then(|x| Some(alloc.extend(x)));
+    extra_frees.and_then(|x| Some(frees.extend(x)));
 
+    let f = Frame {
+        number: *SOKU_FRAMECOUNT,
+        addresses: m.into_iter().map(|x| x.content.metadata).collect(),
+        addresses_buf: SNAPSHOTS.push(buf.into_boxed_slice()),
+        fp: w,
+        frees: MEMORY_RECEIVER_FREE.as_ref().unwrap().try_iter().collect(),
+        allocs: MEMORY_RECEIVER_ALLOC.as_ref().unwrap().try_iter().collect(),
+        extra_states,
+        weather_sync_check: ((*(0x8971c4 as *const usize) * 16) + (*(0x8971c4 as *const usize) * 1)
+            & 0xFF) as u8,
+        has_happened: false,
+        has_called_never_happened: false,
+        last_shake_before_smooth: LAST_CAMERA_BEFORE_SMOOTH.clone(),
+        sections: sections.into_boxed_slice(),
+        state_hash: None,
+        region_table: None,
+    };
+    if let Some(time) = &mut DUMP_FRAME_TIME
+        && let Some(now) = now
     {
-        let t = read_linked_list(first + 0xa4);
+        *time += now.elapsed();
+    }
+    f
+}
+
+/// The layout of the memory saved for each frame, from `snapshot_layout` in the ini, or the
+/// built-in one
+pub static mut LAYOUT: Option<Layout> = None;
+
+/// saves the regions described by `layout.frame`, see `netcore::layout`
+unsafe fn read_frame_layout(
+    layout: &Layout,
+    m: &mut Vec<ReadAddr>,
+    sections: &mut Vec<(usize, RegionKind)>,
+) {
+    // the static address of the current object, and the object it points to
+    let mut object = 0;
+    let mut first = 0;
+    // the current object is optional and null
+    let mut skipping = false;
+    for op in &layout.frame {
+        match op {
+            Op::Object {
+                address, optional, ..
+            } => {
+                object = *address;
+                first = *(*address as *const usize);
+                skipping = *optional && first == 0;
+            }
+            Op::Section(_) => skipping = false,
+            _ => (),
+        }
+        if skipping {
+            continue;
+        }
+        if let Some(kind) = op.region_kind(object) {
+            sections.push((m.len(), kind));
+        }
 
-        m.extend(t.read_all(0x180));
+        #[cfg(feature = "logtofile")]
+        if ISDEBUG {
+            info!("{:?}", op)
+        };
+        match *op {
+            Op::Object { .. } | Op::Section(_) => (),
+            Op::Static { address, size } => m.push(read_addr(address, size)),
+            Op::Field { offset, size } => m.push(read_addr(first + offset, size)),
+            Op::Vec { offset } => m.push(read_vec(first + offset).to_addr()),
+            Op::VecData { offset, nonempty } => {
+                let t = read_vec(first + offset);
+                if !nonempty || t.start != 0 {
+                    m.push(t.read_underlying());
+                }
+            }
+            Op::List { offset, size } => m.extend(read_linked_list(first + offset).read_all(size)),
+            Op::Ring { offset, size } => {
+                m.extend(read_maybe_ring_buffer(first + offset).read_whole(size))
+            }
+            Op::Deque { offset, size } => read_weird_structure(m, first + offset, size),
+            Op::AutosizeList { offset } => read_autosize_list(m, first + offset),
+            Op::ReplayInputs { offset } => read_replay_inputs(m, first + offset),
+            Op::Players => read_players(layout, first, m, sections),
+        }
     }
+}
 
-    {
-        m.extend(read_maybe_ring_buffer(first + 0x28).read_whole(0x10));
+unsafe fn read_weird_structure(m: &mut Vec<ReadAddr>, pos: usize, size: usize) {
+    //I'm not quite sure what's going on here, or if it's infact correct
+    let dat = read_addr(pos, 0x14);
+    let n = dat.usize_align();
+
+    let v1 = n[2];
+    let v2 = n[3];
+    let read_from = n[1];
+    let v3 = n[4];
+
+    if read_from == 0 {
+        //println!("read_from is zero {:?}", n);
+        if n[2] != 0 || n[3] != 0 || n[4] != 0 {
+            #[cfg(feature = "logtofile")]
+            if ISDEBUG {
+                info!("read_from is zero {:?}", n)
+            };
+        }
+    } else {
+        m.push(read_addr(read_from, v1 * 4));
     }
-    #[cfg(feature = "logtofile")]
-    //0x8985e0
-    if ISDEBUG {
-        info!("0x8985e0")
-    };
-    let ptr1 = read_addr(0x8985e0, 0x4);
-    let first = get_ptr(&ptr1.content[0..4], 0);
-    m.push(read_addr(first, 0x118));
+    for a in 0..v3 {
+        let addr = *ptr_wrap!((read_from + ((a + v2) % v1) * 4) as *const usize);
 
-    m.extend(read_linked_list(first + 0x4).read_all(0));
+        m.push(read_addr(addr, size));
+    }
+}
 
-    let llautosize = read_linked_list(first + 0x2c);
-    m.push(read_addr(first + 0x2c + 0xc, 4));
+unsafe fn read_autosize_list(m: &mut Vec<ReadAddr>, pos: usize) {
+    let llautosize = read_linked_list(pos);
 
     let mut lit = llautosize.read_underlying();
     m.push(llautosize.to_addr());
@@ -396,151 +373,164 @@ pub unsafe fn dump_frame(
             m.push(a.to_addr());
         }
     }
+}
 
-    m.extend(read_linked_list(first + 0x38).read_all(0));
+unsafe fn read_replay_inputs(m: &mut Vec<ReadAddr>, pos: usize) {
+    let sc2 = read_maybe_ring_buffer(pos);
+    let z = sc2.obj_s as i32;
 
     #[cfg(feature = "logtofile")]
-    //0x8985f0
     if ISDEBUG {
-        info!("0x8985f0")
-    };
-    //let ptr1 = read_addr(0x8985f0, 0x4);
-    //let first = get_ptr(&ptr1.content[0..4], 0);
+        info!("weird deque done");
+    }
 
-    let first = *(0x8985f0 as *const usize);
+    if z != 0 {
+        let size = sc2.size as i32;
+        let ptr = sc2.data as i32;
 
-    m.push(read_addr(first, 0x94));
+        let z = {
+            let y = (sc2.f3 as i32 - 1 + z) % (size * 8);
+            (ptr + ((y + (((y >> 0x1f) * 7) & 7)) >> 3)) as i32
+        };
 
-    m.push(read_vec(first + 0x10).read_underlying());
+        let w = if ptr <= z - 0x50 { z - 0x50 } else { ptr };
 
-    m.push(read_vec(first + 0x20).read_underlying());
-    #[cfg(feature = "logtofile")]
-    if ISDEBUG {
-        info!("0x8985f02")
-    };
-    m.extend(read_linked_list(first + 0x30).read_all(0));
+        let x = (ptr + size).min(w + 0x28);
 
-    #[cfg(feature = "logtofile")]
-    if ISDEBUG {
-        info!("0x8985f03")
-    };
-
-    // effect_linked_list
-    m.extend(read_linked_list(first + 0x5c).read_all(0x178));
+        m.push(read_addr(w as usize, (((x - w) >> 2) * 4) as usize));
+    }
+}
 
-    #[cfg(feature = "logtofile")]
-    //0x8985e8
-    if ISDEBUG {
-        info!("0x8985e8")
-    };
-    unsafe fn read_weird_structure(m: &mut Vec<ReadAddr>, pos: usize, size: usize) {
-        //I'm not quite sure what's going on here, or if it's infact correct
-        let dat = read_addr(pos, 0x14);
-        let n = dat.usize_align();
-
-        let v1 = n[2];
-        let v2 = n[3];
-        let read_from = n[1];
-        let v3 = n[4];
-
-        if read_from == 0 {
-            //println!("read_from is zero {:?}", n);
-            if n[2] != 0 || n[3] != 0 || n[4] != 0 {
-                #[cfg(feature = "logtofile")]
-                if ISDEBUG {
-                    info!("read_from is zero {:?}", n)
-                };
-            }
+unsafe fn read_players(
+    layout: &Layout,
+    p_game_manager: usize,
+    m: &mut Vec<ReadAddr>,
+    sections: &mut Vec<(usize, RegionKind)>,
+) {
+    let get_player = |p_game_manager: usize, offset: usize| {
+        assert!(offset < 4);
+        if *((p_game_manager + 0x38 + offset) as *const u8) != 0 {
+            Some(*((p_game_manager + 0x28 + offset * 4) as *const usize))
         } else {
-            m.push(read_addr(read_from, v1 * 4));
-        }
-        for a in 0..v3 {
-            let addr = *ptr_wrap!((read_from + ((a + v2) % v1) * 4) as *const usize);
-
-            m.push(read_addr(addr, size));
+            None
         }
-    }
-
-    let ptr1 = read_addr(0x8985e8, 0x4);
-    let first = get_ptr(&ptr1.content[0..4], 0);
-
-    m.push(read_addr(first, 0x688));
+    };
 
-    m.push(read_vec(first + 0x14).read_underlying());
-    m.push(read_vec(first + 0x24).read_underlying());
+    let p1 = get_player(p_game_manager, 0).unwrap();
+    read_player_data(layout, p1, 0, 0, m, sections);
 
-    m.extend(read_linked_list(first + 0x34).read_all(0));
+    let p2 = get_player(p_game_manager, 1).unwrap();
+    read_player_data(layout, p2, 1, 0, m, sections);
 
-    m.extend(read_linked_list(first + 0x60).read_all(0x178));
+    // dumping characters (players) data for 2v2 mod
+    get_player(p_game_manager, 2)
+        .and_then(|p| Some(read_player_data(layout, p, 2, 0, m, sections)));
+    get_player(p_game_manager, 3)
+        .and_then(|p| Some(read_player_data(layout, p, 3, 0, m, sections)));
 
-    read_weird_structure(&mut m, first + 0x18c, 0xc);
-    read_weird_structure(&mut m, first + 0x1c0, 0xc);
+    let p_battle_manager = *(0x8985e4 as *const usize);
+    assert_eq!(*((p_battle_manager + 0xc + 0 * 4) as *const usize), p1);
+    assert_eq!(*((p_battle_manager + 0xc + 1 * 4) as *const usize), p2);
 
     #[cfg(feature = "logtofile")]
-    //0x8985e4
     if ISDEBUG {
-        info!("0x8985e4")
-    };
-
-    let p_battle_manager = read_addr(0x8985e4, 0x4);
-    let p_battle_manager = get_ptr(&p_battle_manager.content[0..4], 0);
-    m.push(read_addr(p_battle_manager, 0x908));
-    m.extend(read_linked_list(p_battle_manager + 0x30).read_all(0));
-    m.extend(read_linked_list(p_battle_manager + 0x3c).read_all(0));
-    m.extend(read_linked_list(p_battle_manager + 0x48).read_all(0));
-    m.extend(read_linked_list(p_battle_manager + 0x54).read_all(0));
-    m.extend(read_linked_list(p_battle_manager + 0x60).read_all(0));
-    m.extend(read_linked_list(p_battle_manager + 0x6c).read_all(0));
-
-    {
-        let w = read_vec(p_battle_manager + 0x9c);
-        if w.start != 0 {
-            m.push(w.read_underlying());
-            #[cfg(feature = "logtofile")]
-            info!("battle+x9c wasn't 0");
-        }
-        let w = read_vec(p_battle_manager + 0xac);
-
-        if w.start != 0 {
-            m.push(w.read_underlying());
-            #[cfg(feature = "logtofile")]
-            //seems to have never triggered, same as the one above
-            info!("battle+xac wasn't 0");
-        }
+        info!("bullets done");
     }
-    m.extend(read_linked_list(p_battle_manager + 0xbc).read_all(0));
-
-    m.extend(read_linked_list(p_battle_manager + 0xe8).read_all(0));
-
-    //0x8985dc
-    if ISDEBUG {
-        #[cfg(feature = "logtofile")]
-        info!("0x8985dc")
-    };
+}
 
-    //0x8986a0
-    #[cfg(feature = "logtofile")]
-    if ISDEBUG {
-        info!("0x8986a0")
+/// saves a bullet as described by `layout.bullet`, from the data of its node
+unsafe fn read_bullet(layout: &Layout, d: usize, char: u8, m: &mut Vec<ReadAddr>) {
+    let Some(z) = char_data_size(char, d, true) else {
+        return;
     };
-
-    //here sokuroll locks a mutex, but it seems unnecceseary
-
-    let ptr1 = read_addr(0x8986a0, 0x4);
-    let first = get_ptr(&ptr1.content[0..4], 0);
-    // netplay input buffer. TODO: find corresponding input buffers in replay mode
-    if first != 0 {
-        m.push(read_addr(first + 0xf8, 0x68));
-        m.push(read_addr(first + 0x174, 0x68));
+    let bullet = read_addr(d, z);
+    for (op, &line) in layout.bullet.iter().zip(&layout.bullet_lines) {
+        let ptr = |offset| layout_ptr(&bullet.content, offset, char, line).unwrap_or(0);
+        match *op {
+            BulletOp::List { offset } => {
+                if ptr(offset) != 0 {
+                    let ll = read_linked_list(d + offset);
+                    m.extend(ll.read_all(0));
+                }
+            }
+            BulletOp::Heap { offset, max } => {
+                let p3 = ptr(offset);
+                if p3 != 0 {
+                    let s = read_heap(p3);
+                    if s > max {
+                        panic!("bullet data too big! {}", s)
+                    } else {
+                        m.push(read_addr(p3, s));
+                    }
+                }
+            }
+            BulletOp::Itself => m.push(read_addr(d, z)),
+            BulletOp::Pattern { offset } => {
+                let p4 = ptr(offset);
+                if p4 != 0 {
+                    read_pattern(layout, p4, char, m);
+                }
+            }
+        }
     }
+}
 
-    #[cfg(feature = "logtofile")]
-    if ISDEBUG {
-        info!("0x8985e4")
-    };
+/// saves the pattern at `pos` of a bullet of `char` as described by `layout.pattern`
+unsafe fn read_pattern(layout: &Layout, pos: usize, char: u8, m: &mut Vec<ReadAddr>) {
+    let size = layout
+        .pattern
+        .iter()
+        .find_map(|x| match *x {
+            PatternOp::Header { size } => Some(size),
+            _ => None,
+        })
+        .unwrap();
+    let header = read_addr(pos, size);
+    let fields = header.content.to_vec();
+    let mut header = Some(header);
+    for (op, &line) in layout.pattern.iter().zip(&layout.pattern_lines) {
+        let field = |offset| layout_ptr(&fields, offset, char, line).unwrap_or(0);
+        match *op {
+            PatternOp::Header { .. } => m.extend(header.take()),
+            PatternOp::Pointers {
+                pointer,
+                count,
+                size,
+            } => {
+                let array = read_addr(field(pointer), field(count) * 4);
+                for a in 0..field(count) {
+                    let p = get_ptr(&array.content, a * 4);
+                    if p != 0 {
+                        m.push(read_addr(p, size));
+                    }
+                }
+                m.push(array);
+            }
+            PatternOp::Grid {
+                pointer,
+                width,
+                height,
+            } => m.push(read_addr(
+                field(pointer),
+                field(width) * field(height) * 2 + 2,
+            )),
+        }
+    }
+}
 
-    unsafe fn read_player_data(player: usize, m: &mut Vec<ReadAddr>) {
-        let read_bullets = |pos: usize, char: u8, m: &mut Vec<_>| {
+/// `depth` is how many players this one is a part of, see `CharacterOp::Player`
+unsafe fn read_player_data(
+    layout: &Layout,
+    player: usize,
+    index: u8,
+    depth: usize,
+    m: &mut Vec<ReadAddr>,
+    sections: &mut Vec<(usize, RegionKind)>,
+) {
+    sections.push((m.len(), RegionKind::Player(index)));
+    let read_bullets =
+        |pos: usize, char: u8, m: &mut Vec<_>, sections: &mut Vec<(usize, RegionKind)>| {
+            sections.push((m.len(), RegionKind::Bullets(index)));
             let list = read_linked_list(pos);
 
             m.extend(list.read_all(0));
@@ -551,307 +541,103 @@ pub unsafe fn dump_frame(
                 m.push(a.to_addr());
                 let d = a.additional_data;
                 if d != 0 {
-                    let z = CHARSIZEDATA[char as usize].1;
-                    let bullet = read_addr(d, z);
-                    let p1 = get_ptr(&bullet.content, 0x3a4);
-
-                    if p1 != 0 {
-                        let ll = read_linked_list(d + 0x3a4);
-
-                        m.extend(ll.read_all(0));
-                    }
-
-                    let p1 = get_ptr(&bullet.content, 0x17c);
-                    if p1 != 0 {
-                        let ll = read_linked_list(d + 0x17c);
-                        m.extend(ll.read_all(0));
-                    }
-
-                    let p3 = get_ptr(&bullet.content, 0x35c);
-                    if p3 != 0 {
-                        let s = read_heap(p3);
-                        if s > 4000 {
-                            panic!("bullet data too big! {}", s)
-                        } else {
-                            m.push(read_addr(p3, s));
-                        }
-                    }
-
-                    let p4 = get_ptr(&bullet.content, 0x354);
-                    m.push(bullet);
-                    if p4 != 0 {
-                        let nd = read_addr(p4, 0x54);
+                    read_bullet(layout, d, char, m);
+                }
+            }
+            sections.push((m.len(), RegionKind::Player(index)));
+        };
 
-                        let size = usize::from_le_bytes(nd.content[0x30..0x34].try_into().unwrap());
-                        let ptr = usize::from_le_bytes(nd.content[0x2c..0x30].try_into().unwrap());
+    let PlayerOp::Id { offset } = layout.player[0] else {
+        unreachable!()
+    };
+    let char = *((player + offset) as *const u8);
 
-                        let n2 = read_addr(ptr, size * 4);
+    let Some(z) = char_data_size(char, player, false) else {
+        return;
+    };
+    let cdat = read_addr(player, z);
 
-                        for a in 0..size {
-                            let p = get_ptr(&n2.content, a * 4);
-                            if p != 0 {
-                                m.push(read_addr(p, 0x10));
-                            }
+    // where the offsets are from, the player or the last object; `None` if it is null
+    let mut base = Some(player);
+    for (op, &line) in layout.player.iter().zip(&layout.player_lines) {
+        #[cfg(feature = "logtofile")]
+        if ISDEBUG {
+            info!("{:?}", op)
+        };
+        match *op {
+            PlayerOp::Characters => {
+                let ops = layout.characters.get(&char).into_iter().flatten();
+                let lines = layout.character_lines.get(&char).into_iter().flatten();
+                for (op, &line) in ops.zip(lines) {
+                    match *op {
+                        CharacterOp::Deque { offset, size } => {
+                            read_weird_structure(m, player + offset, size)
                         }
-
-                        let size = usize::from_le_bytes(nd.content[0x44..0x48].try_into().unwrap());
-                        let ptr = usize::from_le_bytes(nd.content[0x40..0x44].try_into().unwrap());
-                        m.push(n2);
-
-                        let n2 = read_addr(ptr, size * 4);
-
-                        for a in 0..size {
-                            let p = get_ptr(&n2.content, a * 4);
-                            if p != 0 {
-                                m.push(read_addr(p, 0x10));
+                        CharacterOp::Player { offset } => {
+                            let extra_char =
+                                layout_ptr(&cdat.content, offset, char, line).unwrap_or(0);
+                            if extra_char != 0 && depth < MAX_NESTED_PLAYERS {
+                                read_player_data(layout, extra_char, index, depth + 1, m, sections);
                             }
                         }
-
-                        let size = usize::from_le_bytes(nd.content[0x8..0xc].try_into().unwrap())
-                            * usize::from_le_bytes(nd.content[0x4..0x8].try_into().unwrap())
-                            * 2
-                            + 2;
-
-                        let ptr = usize::from_le_bytes(nd.content[0x50..0x54].try_into().unwrap());
-                        m.push(n2);
-                        m.push(nd);
-
-                        m.push(read_addr(ptr, size));
                     }
                 }
             }
-        };
-
-        let char = player + 0x34c;
-        let char = *(char as *const u8);
-
-        assert_ne!(
-            CHARSIZEDATA[char as usize].0, 0,
-            "The data size of character {} is missing?",
-            char
-        );
-        let cdat = read_addr(player, CHARSIZEDATA[char as usize].0);
-
-        let bullets = player + 0x17c;
-        read_bullets(bullets, char, m);
-
-        if char == 5 {
-            //youmu
-            read_weird_structure(m, player + 0x8bc, 0x2c);
-        }
-
-        if char == 36 {
-            // Mamizou of CharacterEngine (https://github.com/SokuDev/CharacterEngine).
-            // Hardcoding it is just a temporary workaround.
-            // TODO: it should be exposed with APIs and implemented by developers of characters mods.
-            // More specific:
-            // - Comments. renaming, and even refactoring (for self-documenting code) to figure what they are exactly (mostly for myself to write APIs clearly);
-            // - APIs for developers to ask GR to save/restore an address as a specific data structure, mostly Player, List, Vector, GameObject, String, and sized array;
-            // - C++ header for these APIs.
-            let extra_char = get_ptr(&cdat.content, 0x890);
-            if extra_char != 0 {
-                read_player_data(extra_char, m);
+            PlayerOp::Itself => m.push(read_addr(player, z)),
+            PlayerOp::Object { offset, size } => {
+                let object = layout_ptr(&cdat.content, offset, char, line).unwrap_or(0);
+                if object != 0 {
+                    m.push(read_addr(object, size));
+                }
+                base = Some(object).filter(|&x| x != 0);
             }
+            PlayerOp::Player => base = Some(player),
+            _ => (),
         }
-
-        let ll = read_linked_list(player + 0x718);
-
-        m.push(read_addr(ll.ll4, 0xf4));
-
-        for _ in 0..ll.listcount {
-            let zcop = m.last().unwrap();
-            let ptr = get_ptr(&zcop.content, 0);
-            m.push(read_addr(ptr, 0xf4));
-        }
-
-        let new = get_ptr(&cdat.content, 0x6f8);
-        m.push(cdat);
-
-        m.push(read_addr(new, 0x68));
-
-        let p4 = read_vec(new + 0x10);
-        let w = p4.read_underlying();
-
-        let i = p4.maybecapacity - p4.start;
-        let i = (((i >> 0x1f) & 3) + i) >> 2;
-
-        for a in 0..i {
-            let p = get_ptr(&w.content, a * 4);
-
-            if p != 0 {
-                let o = read_addr(p, 4);
-                let o2 = read_addr(p + 0x154, 4);
-
-                m.push(o);
-                m.push(o2);
+        let Some(base) = base else {
+            continue;
+        };
+        match *op {
+            PlayerOp::Id { .. }
+            | PlayerOp::Characters
+            | PlayerOp::Itself
+            | PlayerOp::Object { .. }
+            | PlayerOp::Player => (),
+            PlayerOp::Bullets { offset } => read_bullets(base + offset, char, m, sections),
+            PlayerOp::Chain { offset, size } => {
+                let ll = read_linked_list(base + offset);
+                m.push(read_addr(ll.ll4, size));
+                for _ in 0..ll.listcount {
+                    let ptr = get_ptr(&m.last().unwrap().content, 0);
+                    m.push(read_addr(ptr, size));
+                }
+            }
+            PlayerOp::PointerVec { offset, ref fields } => {
+                let v = read_vec(base + offset);
+                let w = v.read_underlying();
+
+                let i = v.maybecapacity - v.start;
+                let i = (((i >> 0x1f) & 3) + i) >> 2;
+
+                for a in 0..i {
+                    let p = get_ptr(&w.content, a * 4);
+                    if p != 0 {
+                        for &(field, size) in fields {
+                            m.push(read_addr(p + field, size));
+                        }
+                    }
+                }
+            }
+            PlayerOp::Vec { offset } => m.push(read_vec(base + offset).to_addr()),
+            PlayerOp::VecData { offset } => m.push(read_vec(base + offset).read_underlying()),
+            PlayerOp::List { offset, size } => {
+                m.extend(read_linked_list(base + offset).read_all(size))
+            }
+            PlayerOp::Ring { offset, size } => {
+                m.extend(read_maybe_ring_buffer(base + offset).read_whole(size))
             }
         }
-
-        m.push(w);
-
-        m.push(p4.to_addr());
-
-        let p5 = read_vec(new + 0x20);
-        m.push(p5.read_underlying());
-        m.push(p5.to_addr());
-
-        let p6 = read_linked_list(new + 0x30);
-        m.extend(p6.read_all(0));
-
-        read_bullets(new + 0x5c, char, m);
-
-        let p8 = read_maybe_ring_buffer(player + 0x7b0);
-        m.extend(p8.read_whole(0x10));
-
-        let p9 = read_maybe_ring_buffer(player + 0x5e8);
-        m.extend(p9.read_whole(0x98));
-
-        let p10 = read_maybe_ring_buffer(player + 0x5b0);
-        m.extend(p10.read_whole(0x10));
-
-        let p11 = read_maybe_ring_buffer(player + 0x5fc);
-        m.extend(p11.read_whole(0x10));
-    }
-
-    let get_player = |p_game_manager: usize, offset: usize| {
-        assert!(offset < 4);
-        if *((p_game_manager + 0x38 + offset) as *const u8) != 0 {
-            Some(*((p_game_manager + 0x28 + offset * 4) as *const usize))
-        } else {
-            None
-        }
-    };
-
-    let p_game_manager = read_addr(0x8985dc, 0x4);
-    let p_game_manager = get_ptr(&p_game_manager.content[0..4], 0);
-
-    m.push(read_addr(p_game_manager, 0x58));
-    m.push(read_vec(p_game_manager + 0x40).read_underlying());
-
-    let p1 = get_player(p_game_manager, 0).unwrap();
-    read_player_data(p1, &mut m);
-
-    let p2 = get_player(p_game_manager, 1).unwrap();
-    read_player_data(p2, &mut m);
-
-    // dumping characters (players) data for 2v2 mod
-    get_player(p_game_manager, 2).and_then(|p| Some(read_player_data(p, &mut m)));
-    get_player(p_game_manager, 3).and_then(|p| Some(read_player_data(p, &mut m)));
-
-    assert_eq!(*((p_battle_manager + 0xc + 0 * 4) as *const usize), p1);
-    assert_eq!(*((p_battle_manager + 0xc + 1 * 4) as *const usize), p2);
-
-    #[cfg(feature = "logtofile")]
-    if ISDEBUG {
-        info!("bullets done");
-    }
-
-    m.push(read_addr(0x898718, 0x128));
-
-    let sc1 = *(0x89881c as *const usize);
-    // not sure what this is
-    if sc1 != 0 {
-        m.push(read_addr(sc1, 0x50));
-
-        let sc2 = read_maybe_ring_buffer(sc1 + 0x3c);
-        let z = sc2.obj_s as i32;
-
-        #[cfg(feature = "logtofile")]
-        if ISDEBUG {
-            info!("weird deque done");
-        }
-
-        if z != 0 {
-            let size = sc2.size as i32;
-            let ptr = sc2.data as i32;
-
-            let z = {
-                let y = (sc2.f3 as i32 - 1 + z) % (size * 8);
-                (ptr + ((y + (((y >> 0x1f) * 7) & 7)) >> 3)) as i32
-            };
-
-            let w = if ptr <= z - 0x50 { z - 0x50 } else { ptr };
-
-            let x = (ptr + size).min(w + 0x28);
-
-            m.push(read_addr(w as usize, (((x - w) >> 2) * 4) as usize));
-        }
-    }
-
-    let to_be_read = [
-        (0x898600, 0x6c),
-        (0x8985d8, 4),
-        (0x8971b8, 0x20),
-        (0x8971d8, 0x1400), // 0x8971d8 ~ 0x8985d8
-        (0x883cc8, 4),
-        (0x89a88c, 4),
-        (0x89a454, 4),
-        (0x896d64, 8),
-        (0x896b20, 4),
-        (0x89b65c, 4),
-        (0x89b660, 0x9c0),
-        (0x89c01c, 4),
-        (0x89aaf8, 4),
-        (0x88526c, 4),
-    ];
-
-    for (pos, size) in to_be_read {
-        let x = read_addr(pos, size);
-
-        m.push(x);
-    }
-
-    // For F1, F5, F6 and F7
-    m.push(read_addr(*(0x008971c8 as *mut usize) + 4, 8));
-
-    let mut extra_states: Vec<ExtraState> = Vec::with_capacity(CALLBACK_ARRAY.len());
-
-    for cb in CALLBACK_ARRAY.iter() {
-        let i = (cb.save_state)();
-
-        extra_states.push(ExtraState { cb: *cb, state: i })
     }
-
-    // aligned to 4
-    let buf_size: usize = m
-        .iter()
-        .map(|x| x.content.metadata.size.div_ceil(4) * 4)
-        .sum();
-
-    let mut buf = Vec::with_capacity(buf_size);
-    for addr in &m {
-        buf.extend_from_slice(&addr.content);
-        buf.resize(buf.len().div_ceil(4) * 4, 0);
-    }
-    assert_eq!(buf_size, buf.len());
-
-    LAST_M_LEN = m.len();
-
-    let mut alloc: Vec<usize> = MEMORY_RECEIVER_ALLOC.as_ref().unwrap().try_iter().collect();
-    let mut frees: Vec<usize> = MEMORY_RECEIVER_FREE.as_ref().unwrap().try_iter().collect();
-    extra_allocs.and_then(|x| Some(alloc.extend(x)));
-    extra_frees.and_then(|x| Some(frees.extend(x)));
-
-    let f = Frame {
-        number: *SOKU_FRAMECOUNT,
-        addresses: m.into_iter().map(|x| x.content.metadata).collect(),
-        addresses_buf: buf.into_boxed_slice(),
-        fp: w,
-        frees: MEMORY_RECEIVER_FREE.as_ref().unwrap().try_iter().collect(),
-        allocs: MEMORY_RECEIVER_ALLOC.as_ref().unwrap().try_iter().collect(),
-        extra_states,
-        weather_sync_check: ((*(0x8971c4 as *const usize) * 16) + (*(0x8971c4 as *const usize) * 1)
-            & 0xFF) as u8,
-        has_happened: false,
-        has_called_never_happened: false,
-        last_shake_before_smooth: LAST_CAMERA_BEFORE_SMOOTH.clone(),
-    };
-    if let Some(time) = &mut DUMP_FRAME_TIME
-        && let Some(now) = now
-    {
-        *time += now.elapsed();
-    }
-    f
 }
 
 pub fn read_heap(pos: usize) -> usize {
@@ -1150,9 +936,12 @@ fn get_ptr(from: &[u8], offset: usize) -> usize {
     usize::from_le_bytes(from[offset..offset + 4].try_into().unwrap())
 }
 
+/// the largest state of a mod sent in a snapshot
+pub const MAX_EXTRA_STATE_LEN: usize = 1 << 20;
+
 #[derive(Debug)]
 pub struct ExtraState {
-    cb: Callbacks,
+    cb: CallbacksV2,
     state: u32,
 }
 
@@ -1160,7 +949,7 @@ pub struct ExtraState {
 pub struct Frame {
     pub number: usize,
     pub addresses: Box<[ReadAddrMetadata]>,
-    pub addresses_buf: Box<[u8]>,
+    pub addresses_buf: Arc<Snapshot>,
     pub fp: [u8; 108],
     pub frees: Vec<usize>,
     pub allocs: Vec<usize>,
@@ -1170,6 +959,22 @@ pub struct Frame {
     pub has_called_never_happened: bool,
     pub has_happened: bool,
     pub last_shake_before_smooth: Option<CameraTransform>,
+
+    /// (index of the first region in `addresses`, kind), sorted by the index
+    pub sections: Box<[(usize, RegionKind)]>,
+    /// set by `did_happen` when `HASH_CONFIRMED_FRAMES` is enabled
+    pub state_hash: Option<u64>,
+    pub region_table: Option<RegionTable>,
+}
+
+/// whether `Frame::did_happen` computes `Frame::state_hash`, which is only needed for netplay
+pub static mut HASH_CONFIRMED_FRAMES: bool = false;
+
+const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+
+fn fnv_mix(hash: u64, x: u64) -> u64 {
+    const FNV_PRIME: u64 = 0x100000001b3;
+    (hash ^ x).wrapping_mul(FNV_PRIME)
 }
 
 impl Drop for Frame {
@@ -1205,12 +1010,40 @@ impl Frame {
         // }
         for a in self.extra_states.iter() {
             unsafe {
-                (a.cb.free_state)(a.state, true);
+                (a.cb.free_state)(a.cb.context, a.state, true);
             }
         }
         (allocs, frees)
     }
 
+    /// Drops this frame, which did happen but isn't going to be restored to, from the middle of
+    /// the saved frames: `newer`, the next frame kept, takes the allocations and frees made
+    /// before this frame, so that they are undone if the game is restored to an older frame.
+    pub fn forget_into(&mut self, newer: &mut Frame) {
+        assert!(self.number < newer.number);
+        newer.allocs.append(&mut self.allocs);
+        newer.frees.append(&mut self.frees);
+        self.did_happen();
+    }
+
+    /// roughly the memory taken by this frame, not counting the keyframe of a delta snapshot; it
+    /// leaves out `allocs` and `frees`, so that it only changes with `flatten_snapshot`
+    pub fn memory_size(&self) -> usize {
+        std::mem::size_of::<Frame>()
+            + self.addresses_buf.stored_len()
+            + self.addresses.len() * std::mem::size_of::<ReadAddrMetadata>()
+            + self.sections.len() * std::mem::size_of::<(usize, RegionKind)>()
+            + self.extra_states.len() * std::mem::size_of::<ExtraState>()
+    }
+
+    /// stores the snapshot in full, so that its keyframe can be let go
+    pub fn flatten_snapshot(&mut self) {
+        if self.addresses_buf.depth() > 0 {
+            let bytes = self.addresses_buf.bytes().into_owned();
+            self.addresses_buf = Arc::new(Snapshot::Full(bytes.into()));
+        }
+    }
+
     pub fn did_happen(&mut self) {
         //let m = &mut *ALLOCMUTEX.lock().unwrap();
         //
@@ -1225,11 +1058,264 @@ impl Frame {
         }
         for a in self.extra_states.iter() {
             unsafe {
-                (a.cb.free_state)(a.state, false);
+                (a.cb.free_state)(a.cb.context, a.state, false);
             }
         }
         self.frees.clear();
         self.allocs.clear();
+
+        if unsafe { HASH_CONFIRMED_FRAMES } {
+            let table = self.compute_region_table();
+            self.state_hash = Some(
+                table
+                    .regions
+                    .iter()
+                    .filter(|x| x.kind != RegionKind::Local)
+                    .fold(FNV_OFFSET, |hash, x| fnv_mix(hash, x.hash)),
+            );
+            self.region_table = Some(table);
+        }
+    }
+
+    /// the kind of every region in `addresses`
+    pub fn region_kinds(&self) -> impl Iterator<Item = RegionKind> + '_ {
+        let mut sections = self.sections.iter().peekable();
+        let mut kind = RegionKind::Object(0);
+        (0..self.addresses.len()).map(move |n| {
+            while let Some((_, k)) = sections.next_if(|(start, _)| *start <= n) {
+                kind = *k;
+            }
+            kind
+        })
+    }
+
+    /// The heap range covering all the heap blocks of this frame, rounded to 16MiB.
+    ///
+    /// Words in this range are most likely pointers, whose values differ between processes.
+    fn heap_span(&self) -> std::ops::Range<usize> {
+        const ROUND: usize = 0xffffff;
+        let (lo, hi) = self
+            .addresses
+            .iter()
+            .zip(self.region_kinds())
+            .filter(|(x, kind)| {
+                !x.pos.is_null() && !matches!(kind, RegionKind::Static | RegionKind::Local)
+            })
+            .fold((usize::MAX, 0), |(lo, hi), (x, _)| {
+                (lo.min(x.pos as usize), hi.max(x.pos as usize + x.size))
+            });
+        if lo > hi {
+            return 0..0;
+        }
+        (lo & !ROUND)..(hi.saturating_add(ROUND) & !ROUND)
+    }
+
+    /// 64-bit FNV-1a over every region of `addresses_buf`, word by word.
+    ///
+    /// Positions of heap blocks and words that look like heap pointers are left out,
+    /// since they are not expected to be the same for both players.
+    pub fn compute_region_table(&self) -> RegionTable {
+        const POINTER: u64 = 0x9e3779b97f4a7c15;
+
+        let heap = self.heap_span();
+        let buf = self.addresses_buf.bytes();
+        let mut regions = Vec::with_capacity(self.addresses.len());
+
+        let mut index = 0;
+        for (a, kind) in self.addresses.iter().zip(self.region_kinds()) {
+            let new_index = index + a.size.div_ceil(4) * 4;
+            let mut hash = fnv_mix(FNV_OFFSET, a.size as u64);
+            if matches!(kind, RegionKind::Static | RegionKind::Local) {
+                hash = fnv_mix(hash, a.pos as u64);
+            }
+            for word in buf[index..new_index].chunks_exact(4) {
+                let word = u32::from_le_bytes(word.try_into().unwrap()) as usize;
+                hash = fnv_mix(
+                    hash,
+                    match heap.contains(&word) {
+                        true => POINTER,
+                        false => word as u64,
+                    },
+                );
+            }
+            regions.push(RegionInfo {
+                kind,
+                pos: a.pos as usize,
+                size: a.size,
+                hash,
+            });
+            index = new_index;
+        }
+        RegionTable {
+            frame: self.number,
+            regions: regions.into_boxed_slice(),
+        }
+    }
+    /// Serializes the synced state for desync recovery: the frame number, the heap span,
+    /// the region sizes, `addresses_buf` and the `extra_states` which can be serialized.
+    ///
+    /// Positions aren't included, since heap blocks are at different addresses for the
+    /// opponent.
+    pub fn snapshot(&self) -> Box<[u8]> {
+        let heap = self.heap_span();
+        let mut buf = Vec::with_capacity(16 + self.addresses.len() * 4 + self.addresses_buf.len());
+        buf.extend_from_slice(&(self.number as u32).to_le_bytes());
+        buf.extend_from_slice(&(heap.start as u32).to_le_bytes());
+        buf.extend_from_slice(&(heap.end as u32).to_le_bytes());
+        buf.extend_from_slice(&(self.addresses.len() as u32).to_le_bytes());
+        for a in self.addresses.iter() {
+            buf.extend_from_slice(&(a.size as u32).to_le_bytes());
+        }
+        buf.extend_from_slice(&self.addresses_buf.bytes());
+        self.serialize_extra_states(&mut buf);
+        buf.into_boxed_slice()
+    }
+
+    /// Appends the `extra_states` of the mods which have a name and can serialize them: their
+    /// count, then the name and the state of each, all prefixed with their length.
+    pub fn serialize_extra_states(&self, buf: &mut Vec<u8>) {
+        let skip = |name: &str, len: usize| {
+            println!("the state of {} ({} bytes) isn't sent", name, len);
+            None
+        };
+        let states: Vec<(String, Vec<u8>)> = self
+            .extra_states
+            .iter()
+            .filter_map(|a| {
+                let serialize = a.cb.serialize?;
+                let name = a.cb.name()?;
+                let mut data = vec![0; 64];
+                let len =
+                    unsafe { serialize(a.cb.context, a.state, data.as_mut_ptr(), data.len()) };
+                if len > MAX_EXTRA_STATE_LEN {
+                    return skip(&name, len);
+                }
+                if len > data.len() {
+                    data.resize(len, 0);
+                    let again =
+                        unsafe { serialize(a.cb.context, a.state, data.as_mut_ptr(), data.len()) };
+                    if again > data.len() {
+                        return skip(&name, again);
+                    }
+                    data.truncate(again);
+                } else {
+                    data.truncate(len);
+                }
+                Some((name, data))
+            })
+            .collect();
+        buf.extend_from_slice(&(states.len() as u32).to_le_bytes());
+        for (name, data) in states {
+            buf.extend_from_slice(&(name.len() as u32).to_le_bytes());
+            buf.extend_from_slice(name.as_bytes());
+            buf.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            buf.extend_from_slice(&data);
+        }
+    }
+
+    /// Replaces the `extra_states` with those serialized by `serialize_extra_states` (of the
+    /// opponent) by the mods of the same name. Those missing in `data` are kept, and none are
+    /// replaced if `data` is malformed.
+    pub fn deserialize_extra_states(&mut self, data: &[u8]) -> Result<(), String> {
+        let mut states = vec![];
+        let mut rest = data;
+        let mut take = |len: usize| -> Result<&[u8], String> {
+            let (x, r) = rest
+                .split_at_checked(len)
+                .ok_or_else(|| "truncated extra states".to_string())?;
+            rest = r;
+            Ok(x)
+        };
+        let len = |x: &[u8]| u32::from_le_bytes(x.try_into().unwrap()) as usize;
+        let count = len(take(4)?);
+        for _ in 0..count {
+            let name = take(4).map(len).and_then(&mut take)?;
+            let data = take(4).map(len).and_then(&mut take)?;
+            states.push((name, data));
+        }
+        for a in self.extra_states.iter_mut() {
+            let (Some(deserialize), Some(name)) = (a.cb.deserialize, a.cb.name()) else {
+                continue;
+            };
+            let Some((_, data)) = states.iter().find(|x| x.0 == name.as_bytes()) else {
+                println!("no state of {} from the opponent", name);
+                continue;
+            };
+            let mut state = 0;
+            if unsafe { deserialize(a.cb.context, data.as_ptr(), data.len(), &mut state) } {
+                unsafe { (a.cb.free_state)(a.cb.context, a.state, true) };
+                a.state = state;
+            } else {
+                println!("{} failed to deserialize its state from the opponent", name);
+            }
+        }
+        Ok(())
+    }
+
+    /// Overwrites `addresses_buf` with a `snapshot` of the opponent.
+    ///
+    /// The regions must have the same sizes, so the objects (bullets, effects...) of both sides
+    /// must be the same: they can't be rebuilt from the bytes. Words which look like heap
+    /// pointers on either side, and the regions local to this player, are kept as they are.
+    pub fn merge_snapshot(&mut self, snapshot: &[u8]) -> Result<(), MergeError> {
+        let word = |pos: usize| -> Result<usize, MergeError> {
+            snapshot
+                .get(pos..pos + 4)
+                .map(|x| u32::from_le_bytes(x.try_into().unwrap()) as usize)
+                .ok_or_else(|| MergeError::Invalid("truncated snapshot".to_string()))
+        };
+        let frame = word(0)?;
+        if frame != self.number {
+            return Err(MergeError::Invalid(format!(
+                "snapshot of frame {} for frame {}",
+                frame, self.number
+            )));
+        }
+        let remote_heap = word(4)?..word(8)?;
+        let count = word(12)?;
+        if count != self.addresses.len() {
+            return Err(MergeError::DifferentLayout(format!(
+                "{} regions in the snapshot, {} here",
+                count,
+                self.addresses.len()
+            )));
+        }
+        let kinds: Vec<RegionKind> = self.region_kinds().collect();
+        for (n, a) in self.addresses.iter().enumerate() {
+            let size = word(16 + n * 4)?;
+            if size != a.size {
+                return Err(MergeError::DifferentLayout(format!(
+                    "region {} ({}) has {:#x} bytes in the snapshot, {:#x} here",
+                    n, kinds[n], size, a.size
+                )));
+            }
+        }
+        let remote_buf = snapshot
+            .get(16 + count * 4..16 + count * 4 + self.addresses_buf.len())
+            .ok_or_else(|| MergeError::Invalid("size of the snapshot differs".to_string()))?;
+        self.deserialize_extra_states(&snapshot[16 + count * 4 + self.addresses_buf.len()..])
+            .map_err(MergeError::Invalid)?;
+
+        let local_heap = self.heap_span();
+        let mut buf = self.addresses_buf.bytes().into_owned();
+        let mut index = 0;
+        for (a, kind) in self.addresses.iter().zip(kinds) {
+            let new_index = index + a.size.div_ceil(4) * 4;
+            if kind != RegionKind::Local {
+                for pos in (index..new_index).step_by(4) {
+                    let local = &mut buf[pos..pos + 4];
+                    let remote = &remote_buf[pos..pos + 4];
+                    let local_word = u32::from_le_bytes((&*local).try_into().unwrap()) as usize;
+                    let remote_word = u32::from_le_bytes(remote.try_into().unwrap()) as usize;
+                    if !local_heap.contains(&local_word) && !remote_heap.contains(&remote_word) {
+                        local.copy_from_slice(remote);
+                    }
+                }
+            }
+            index = new_index;
+        }
+        self.addresses_buf = Arc::new(Snapshot::Full(buf.into()));
+        Ok(())
     }
 
     #[allow(unused)]
@@ -1322,24 +1408,38 @@ impl Frame {
 
         for a in self.extra_states.iter() {
             unsafe {
-                (a.cb.load_state_pre)(self.number, a.state);
+                (a.cb.load_state_pre)(a.cb.context, self.number, a.state);
             }
         }
-        let mut index = 0;
-        for a in self.addresses.iter() {
-            let new_index = index + a.size.div_ceil(4) * 4;
-            if a.pos != null_mut() {
-                unsafe {
-                    a.pos
-                        .copy_from(self.addresses_buf[index..new_index].as_ptr(), a.size);
+        let len: usize = self.addresses.iter().map(|a| a.size.div_ceil(4) * 4).sum();
+        assert_eq!(self.addresses_buf.len(), len);
+        // the pieces come in order, so the region they start in is only looked for forward
+        let mut regions = self.addresses.iter();
+        let mut region = (0, 0, None);
+        self.addresses_buf.for_each_piece(|mut offset, mut piece| {
+            while !piece.is_empty() {
+                let (start, end, a) = region;
+                if offset >= end {
+                    let a = regions.next().unwrap();
+                    region = (end, end + a.size.div_ceil(4) * 4, Some(a));
+                    continue;
+                }
+                let a: &ReadAddrMetadata = a.unwrap();
+                let n = piece.len().min(end - offset);
+                // the bytes past `size` only pad the region to a word
+                let copied = n.min((start + a.size).saturating_sub(offset));
+                if a.pos != null_mut() && copied > 0 {
+                    unsafe {
+                        a.pos.add(offset - start).copy_from(piece.as_ptr(), copied);
+                    }
                 }
+                offset += n;
+                piece = &piece[n..];
             }
-            index = new_index;
-        }
-        assert_eq!(self.addresses_buf.len(), index);
+        });
         for a in self.extra_states.iter() {
             unsafe {
-                (a.cb.load_state_post)(a.state);
+                (a.cb.load_state_post)(a.cb.context, a.state);
             }
         }
         unsafe {
@@ -1567,3 +1667,47 @@ unsafe fn deasm() {
     )
 }
  */
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// a frame with one heap block at `0x0a000000` and the static block at `0x898718`
+    fn frame(values: [u32; 3]) -> Frame {
+        let addresses = [(0x0a000000, 4), (0x898718, 8)].map(|(pos, size)| ReadAddrMetadata {
+            pos: pos as *mut u8,
+            size,
+        });
+        Frame {
+            number: 0,
+            addresses: Box::new(addresses),
+            addresses_buf: Arc::new(Snapshot::Full(
+                values.iter().flat_map(|x| x.to_le_bytes()).collect(),
+            )),
+            fp: [0; 108],
+            frees: vec![],
+            allocs: vec![],
+            extra_states: vec![],
+            weather_sync_check: 0,
+            has_called_never_happened: false,
+            has_happened: true,
+            last_shake_before_smooth: None,
+            sections: Box::new([(0, RegionKind::Player(0)), (1, RegionKind::Static)]),
+            state_hash: None,
+            region_table: None,
+        }
+    }
+
+    #[test]
+    fn small_values_of_static_blocks_change_the_hash() {
+        let hashes = |values| {
+            let table = frame(values).compute_region_table();
+            table.regions.iter().map(|x| x.hash).collect::<Vec<_>>()
+        };
+        let hash = hashes([0x0a000010, 1, 2]);
+        assert_ne!(hash[1], hashes([0x0a000010, 1, 3])[1]);
+        assert_ne!(hash[1], hashes([0x0a000010, 0, 2])[1]);
+        // pointers into the heap are not compared
+        assert_eq!(hash, hashes([0x0a000020, 1, 2]));
+    }
+}